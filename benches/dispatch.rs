@@ -0,0 +1,235 @@
+// cargo bench --bench dispatch
+//
+// Measures the overhead of routing SQLite's io methods through the Vfs trait by
+// running the same workloads against a minimal in-memory Vfs and against
+// SQLite's built-in memdb vfs, and how reads scale across threads when each
+// file's data is behind a RwLock.
+
+use std::{collections::HashMap, ffi::CString, sync::Arc, thread, time::Instant};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use parking_lot::{Mutex, RwLock};
+use rusqlite::{Connection, OpenFlags};
+use sqlite_plugin::{
+    flags::{AccessFlags, OpenOpts},
+    logger::SqliteLogger,
+    vfs::{RegisterOpts, UriParams, Vfs, VfsHandle, VfsResult, register_static},
+};
+
+struct Handle {
+    name: Option<String>,
+    data: Arc<RwLock<Vec<u8>>>,
+    delete_on_close: bool,
+}
+
+impl VfsHandle for Handle {
+    fn readonly(&self) -> bool {
+        false
+    }
+
+    fn in_memory(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Default)]
+struct BenchVfs {
+    files: Mutex<HashMap<String, Arc<RwLock<Vec<u8>>>>>,
+}
+
+impl Vfs for BenchVfs {
+    type Handle = Handle;
+
+    fn register_logger(&self, _logger: SqliteLogger) {}
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        _params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let data = match path {
+            Some(path) => self
+                .files
+                .lock()
+                .entry(path.to_owned())
+                .or_default()
+                .clone(),
+            None => Default::default(),
+        };
+        Ok(Handle {
+            name: path.map(ToOwned::to_owned),
+            data,
+            delete_on_close: opts.delete_on_close(),
+        })
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.files.lock().remove(path);
+        Ok(())
+    }
+
+    fn access(&self, path: &str, _flags: AccessFlags) -> VfsResult<bool> {
+        Ok(self.files.lock().contains_key(path))
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        Ok(handle.data.read().len())
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        handle.data.write().resize(size, 0);
+        Ok(())
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, buf: &[u8]) -> VfsResult<usize> {
+        let mut data = handle.data.write();
+        if offset + buf.len() > data.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<usize> {
+        let data = handle.data.read();
+        if offset > data.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        if handle.delete_on_close {
+            if let Some(name) = handle.name {
+                self.delete(&name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open(vfs: &str, name: &str) -> Connection {
+    let conn = Connection::open_with_flags_and_vfs(
+        name,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+    .unwrap();
+    // keep the page cache small so that reads actually reach the vfs
+    conn.execute_batch(
+        "pragma cache_size = 8;
+        create table t (id integer primary key, val blob);",
+    )
+    .unwrap();
+    conn
+}
+
+fn dispatch(c: &mut Criterion) {
+    register_static(
+        CString::new("bench").unwrap(),
+        BenchVfs::default(),
+        RegisterOpts::default(),
+    )
+    .unwrap();
+
+    // memdb is SQLite's built-in in-memory vfs; it shares files whose name
+    // starts with a slash between connections
+    let vfses = [("memdb", "/bench.db"), ("bench", "bench.db")];
+
+    let mut group = c.benchmark_group("insert");
+    for (vfs, name) in vfses {
+        let conn = open(vfs, name);
+        let mut id = 0;
+        group.bench_function(BenchmarkId::from_parameter(vfs), |b| {
+            b.iter(|| {
+                id += 1;
+                conn.execute("insert into t (id, val) values (?, randomblob(512))", [id])
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("point_select");
+    for (vfs, name) in vfses {
+        let conn = open(vfs, &format!("{name}-select"));
+        conn.execute(
+            "with recursive ids(id) as (select 1 union all select id + 1 from ids where id < 10000)
+            insert into t (id, val) select id, randomblob(512) from ids",
+            [],
+        )
+        .unwrap();
+        let mut stmt = conn
+            .prepare("select length(val) from t where id = ?")
+            .unwrap();
+        let mut id = 0;
+        group.bench_function(BenchmarkId::from_parameter(vfs), |b| {
+            b.iter(|| {
+                id = id % 10000 + 1;
+                let len: i64 = stmt.query_row([id], |row| row.get(0)).unwrap();
+                assert_eq!(len, 512);
+            })
+        });
+    }
+    group.finish();
+
+    // every thread runs point selects on its own connection to a shared file
+    let mut group = c.benchmark_group("concurrent_point_select");
+    for (vfs, name) in vfses {
+        let name = format!("{name}-concurrent");
+        let conn = open(vfs, &name);
+        conn.execute(
+            "with recursive ids(id) as (select 1 union all select id + 1 from ids where id < 10000)
+            insert into t (id, val) select id, randomblob(512) from ids",
+            [],
+        )
+        .unwrap();
+
+        for threads in [1, 2, 4, 8] {
+            let mut conns: Vec<Connection> = (0..threads)
+                .map(|_| {
+                    let conn = Connection::open_with_flags_and_vfs(
+                        &name,
+                        OpenFlags::SQLITE_OPEN_READ_ONLY,
+                        vfs,
+                    )
+                    .unwrap();
+                    conn.execute_batch("pragma cache_size = 8").unwrap();
+                    conn
+                })
+                .collect();
+
+            group.throughput(Throughput::Elements(threads));
+            let id = BenchmarkId::new(vfs, threads);
+            group.bench_function(id, |b| {
+                b.iter_custom(|iters| {
+                    let start = Instant::now();
+                    thread::scope(|s| {
+                        for (i, conn) in conns.iter_mut().enumerate() {
+                            s.spawn(move || select_loop(conn, i as i64 * 1000, iters));
+                        }
+                    });
+                    start.elapsed()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn select_loop(conn: &mut Connection, mut id: i64, iters: u64) {
+    let mut stmt = conn
+        .prepare_cached("select length(val) from t where id = ?")
+        .unwrap();
+    for _ in 0..iters {
+        id = id % 10000 + 1;
+        let len: i64 = stmt.query_row([id], |row| row.get(0)).unwrap();
+        assert_eq!(len, 512);
+    }
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,196 @@
+// Plumbing shared by the on-disk vfs examples: opening files, deleting and
+// checking them, and the locks which exclude the connections of this
+// process from each other.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use sqlite_plugin::flags::{AccessFlags, CreateMode, LockLevel, OpenMode, OpenOpts};
+use sqlite_plugin::vars;
+use sqlite_plugin::vfs::{VfsError, VfsHandle, VfsResult};
+
+// the locks held on one file by the connections of this process
+#[derive(Default)]
+struct Locks {
+    shared: usize,
+    reserved: bool,
+    pending: bool,
+    exclusive: bool,
+}
+
+/// An open file, and the lock its connection holds on it.
+pub struct File {
+    pub file: fs::File,
+    name: Option<String>,
+    readonly: bool,
+    delete_on_close: bool,
+    level: LockLevel,
+    locks: Arc<Mutex<Locks>>,
+}
+
+impl VfsHandle for File {
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn in_memory(&self) -> bool {
+        false
+    }
+}
+
+impl File {
+    pub fn lock(&mut self, level: LockLevel) -> VfsResult<()> {
+        let mut locks = self.locks.lock().unwrap();
+        match level {
+            LockLevel::Shared if self.level == LockLevel::Unlocked => {
+                if locks.pending || locks.exclusive {
+                    return Err(VfsError::Busy);
+                }
+                locks.shared += 1;
+            }
+            LockLevel::Reserved => {
+                if locks.reserved {
+                    return Err(VfsError::Busy);
+                }
+                locks.reserved = true;
+            }
+            LockLevel::Pending | LockLevel::Exclusive => {
+                // new readers are kept out while the current ones finish
+                locks.pending = true;
+                self.level = LockLevel::Pending;
+                if locks.shared > 1 {
+                    return Err(VfsError::Busy);
+                }
+                locks.exclusive = true;
+            }
+            _ => {}
+        }
+        self.level = level;
+        Ok(())
+    }
+
+    pub fn unlock(&mut self, level: LockLevel) -> VfsResult<()> {
+        let mut locks = self.locks.lock().unwrap();
+        if self.level >= LockLevel::Reserved && level <= LockLevel::Shared {
+            locks.reserved = false;
+            locks.pending = false;
+            locks.exclusive = false;
+        }
+        if self.level >= LockLevel::Shared && level == LockLevel::Unlocked {
+            locks.shared -= 1;
+        }
+        self.level = level.min(self.level);
+        Ok(())
+    }
+
+    pub fn check_reserved_lock(&self) -> bool {
+        let locks = self.locks.lock().unwrap();
+        locks.reserved || locks.pending || locks.exclusive
+    }
+}
+
+/// The files of one vfs, which are opened from the given paths. Temporary
+/// files are unnamed and unlinked as soon as they are open, under
+/// `std::env::temp_dir()`. Vfs using this return `VfsError::NotFound` from
+/// `temp_filename` so that `SQLite` opens them without a name, rather than
+/// relative to the working directory.
+#[derive(Default)]
+pub struct Disk {
+    locks: Mutex<HashMap<String, Arc<Mutex<Locks>>>>,
+}
+
+impl Disk {
+    pub fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<File> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let readonly = match opts.mode() {
+            OpenMode::ReadOnly => true,
+            OpenMode::ReadWrite { create } => {
+                options.write(true);
+                match create {
+                    CreateMode::None => {}
+                    CreateMode::Create => {
+                        options.create(true);
+                    }
+                    CreateMode::MustCreate => {
+                        options.create_new(true);
+                    }
+                }
+                false
+            }
+        };
+        let (file, locks) = match path {
+            Some(path) => {
+                let file = options.open(path).map_err(io_err)?;
+                let mut locks = self.locks.lock().unwrap();
+                (file, locks.entry(path.to_owned()).or_default().clone())
+            }
+            None => {
+                let name = std::env::temp_dir()
+                    .join(format!("sqlite-{}-{}", std::process::id(), rand_id()))
+                    .to_string_lossy()
+                    .into_owned();
+                let file = options.create_new(true).open(&name).map_err(io_err)?;
+                fs::remove_file(&name).map_err(io_err)?;
+                (file, Arc::default())
+            }
+        };
+        Ok(File {
+            file,
+            name: path.map(str::to_owned),
+            readonly,
+            delete_on_close: opts.delete_on_close(),
+            level: LockLevel::Unlocked,
+            locks,
+        })
+    }
+
+    pub fn close(&self, file: File) -> VfsResult<()> {
+        let File { name, delete_on_close, locks, .. } = file;
+        let Some(name) = name else {
+            return Ok(());
+        };
+        {
+            // the entry goes with the last handle on the file
+            let mut table = self.locks.lock().unwrap();
+            drop(locks);
+            if table.get(&name).is_some_and(|l| Arc::strong_count(l) == 1) {
+                table.remove(&name);
+            }
+        }
+        match delete_on_close {
+            true => delete(&name),
+            false => Ok(()),
+        }
+    }
+}
+
+pub fn io_err(err: io::Error) -> VfsError {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => VfsError::CantOpen,
+        io::ErrorKind::StorageFull => VfsError::Full,
+        _ => VfsError::Io,
+    }
+}
+
+pub fn delete(path: &str) -> VfsResult<()> {
+    fs::remove_file(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => VfsError::Other(vars::SQLITE_IOERR_DELETE_NOENT),
+        _ => VfsError::Other(vars::SQLITE_IOERR_DELETE),
+    })
+}
+
+pub fn access(path: &str, flags: AccessFlags) -> bool {
+    match fs::metadata(path) {
+        Ok(meta) => flags != AccessFlags::ReadWrite || !meta.permissions().readonly(),
+        Err(_) => false,
+    }
+}
+
+fn rand_id() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
@@ -2,26 +2,85 @@
 
 use std::{
     ffi::{CStr, c_void},
+    fmt,
     os::raw::c_char,
-    sync::Arc,
+    ptr::NonNull,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use sqlite_plugin::{
-    flags::{AccessFlags, LockLevel, OpenOpts},
+    flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode, SyncFlags},
     logger::{SqliteLogLevel, SqliteLogger},
     sqlite3_api_routines, vars,
-    vfs::{Pragma, PragmaErr, RegisterOpts, Vfs, VfsHandle, VfsResult, register_dynamic},
+    vfs::{
+        Pragma, PragmaErr, RegisterOpts, SyncMode, SyncPolicy, UriParams, Vfs, VfsError, VfsHandle,
+        VfsResult, register_dynamic,
+    },
 };
 
+// logs through the vfs's own SqliteLogger rather than a process wide logger,
+// so that every vfs registered by this extension can log independently. Lines
+// are only formatted when the vfs is verbose.
+macro_rules! debug {
+    ($vfs:expr, $($arg:tt)*) => {
+        if $vfs.verbose {
+            $vfs.log(format_args!($($arg)*))
+        }
+    };
+}
+
 #[derive(Debug, Clone)]
 struct File {
+    // unique per open, so that wal-index locks can tell connections apart
+    id: usize,
     name: Option<String>,
-    data: Arc<Mutex<Vec<u8>>>,
+    // readers share the lock so that concurrent connections don't serialize
+    data: Arc<RwLock<Vec<u8>>>,
+    shm: Arc<Mutex<Shm>>,
     delete_on_close: bool,
     opts: OpenOpts,
 }
 
+/// The wal-index shared by every connection to a database.
+#[derive(Debug, Default)]
+struct Shm {
+    // boxed so that mapped regions never move
+    regions: Vec<Box<[u8]>>,
+    locks: [ShmSlot; vars::SQLITE_SHM_NLOCK as usize],
+}
+
+#[derive(Debug, Default)]
+struct ShmSlot {
+    shared: Vec<usize>,
+    exclusive: Option<usize>,
+}
+
+impl ShmSlot {
+    fn lock(&mut self, id: usize, exclusive: bool) -> VfsResult<()> {
+        let others_shared = self.shared.iter().any(|&holder| holder != id);
+        if self.exclusive.is_some_and(|holder| holder != id) || (exclusive && others_shared) {
+            return Err(VfsError::Busy);
+        }
+        if exclusive {
+            self.exclusive = Some(id);
+        } else if !self.shared.contains(&id) {
+            self.shared.push(id);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self, id: usize) {
+        self.shared.retain(|&holder| holder != id);
+        if self.exclusive == Some(id) {
+            self.exclusive = None;
+        }
+    }
+}
+
 impl File {
     fn is_named(&self, s: &str) -> bool {
         self.name.as_ref().is_some_and(|f| f == s)
@@ -39,63 +98,75 @@ impl VfsHandle for File {
 }
 
 struct MemVfs {
+    name: &'static str,
     files: Arc<Mutex<Vec<File>>>,
+    next_id: AtomicUsize,
+    logger: OnceLock<SqliteLogger>,
+    // logs every operation to the SQLite log
+    verbose: bool,
 }
 
-impl Vfs for MemVfs {
-    type Handle = File;
-
-    fn register_logger(&self, logger: SqliteLogger) {
-        struct LogCompat {
-            logger: Mutex<SqliteLogger>,
+impl MemVfs {
+    fn new(name: &'static str, verbose: bool) -> Self {
+        Self {
+            name,
+            files: Default::default(),
+            next_id: AtomicUsize::new(0),
+            logger: OnceLock::new(),
+            verbose,
         }
+    }
 
-        impl log::Log for LogCompat {
-            fn enabled(&self, _metadata: &log::Metadata) -> bool {
-                true
-            }
-
-            fn log(&self, record: &log::Record) {
-                let level = match record.level() {
-                    log::Level::Error => SqliteLogLevel::Error,
-                    log::Level::Warn => SqliteLogLevel::Warn,
-                    _ => SqliteLogLevel::Notice,
-                };
-                let msg = format!("{}", record.args());
-                self.logger.lock().log(level, msg.as_bytes());
-            }
-
-            fn flush(&self) {}
+    fn log(&self, args: fmt::Arguments<'_>) {
+        if let Some(logger) = self.logger.get() {
+            let msg = format!("[{}] {args}", self.name);
+            logger.log(SqliteLogLevel::Notice, msg.as_bytes());
         }
+    }
+}
+
+impl Vfs for MemVfs {
+    type Handle = File;
 
-        let log = LogCompat { logger: Mutex::new(logger) };
-        log::set_boxed_logger(Box::new(log)).expect("failed to setup global logger");
+    fn register_logger(&self, logger: SqliteLogger) {
+        let _ = self.logger.set(logger);
     }
 
-    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
-        log::debug!("open: path={:?}, opts={:?}", path, opts);
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        debug!(
+            self,
+            "open: path={path:?}, opts={opts:?}, params={params:?}"
+        );
         let mode = opts.mode();
         if mode.is_readonly() {
             // readonly makes no sense since an in-memory VFS is not backed by
             // any pre-existing data.
-            return Err(vars::SQLITE_CANTOPEN);
+            return Err(VfsError::CantOpen);
         }
 
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
         if let Some(path) = path {
             let mut files = self.files.lock();
 
             for file in files.iter() {
                 if file.is_named(path) {
                     if mode.must_create() {
-                        return Err(vars::SQLITE_CANTOPEN);
+                        return Err(VfsError::CantOpen);
                     }
-                    return Ok(file.clone());
+                    return Ok(File { id, ..file.clone() });
                 }
             }
 
             let file = File {
+                id,
                 name: Some(path.to_owned()),
                 data: Default::default(),
+                shm: Default::default(),
                 delete_on_close: opts.delete_on_close(),
                 opts,
             };
@@ -103,8 +174,10 @@ impl Vfs for MemVfs {
             Ok(file)
         } else {
             let file = File {
+                id,
                 name: None,
                 data: Default::default(),
+                shm: Default::default(),
                 delete_on_close: opts.delete_on_close(),
                 opts,
             };
@@ -113,7 +186,7 @@ impl Vfs for MemVfs {
     }
 
     fn delete(&self, path: &str) -> VfsResult<()> {
-        log::debug!("delete: path={}", path);
+        debug!(self, "delete: path={path}");
         let mut found = false;
         self.files.lock().retain(|file| {
             if file.is_named(path) {
@@ -124,24 +197,24 @@ impl Vfs for MemVfs {
             }
         });
         if !found {
-            return Err(vars::SQLITE_IOERR_DELETE_NOENT);
+            return Err(VfsError::Other(vars::SQLITE_IOERR_DELETE_NOENT));
         }
         Ok(())
     }
 
     fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
-        log::debug!("access: path={}, flags={:?}", path, flags);
+        debug!(self, "access: path={path}, flags={flags:?}");
         Ok(self.files.lock().iter().any(|f| f.is_named(path)))
     }
 
     fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
-        log::debug!("file_size: file={:?}", handle.name);
-        Ok(handle.data.lock().len())
+        debug!(self, "file_size: file={:?}", handle.name);
+        Ok(handle.data.read().len())
     }
 
     fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
-        log::debug!("truncate: file={:?}, size={}", handle.name, size);
-        let mut data = handle.data.lock();
+        debug!(self, "truncate: file={:?}, size={}", handle.name, size);
+        let mut data = handle.data.write();
         if size > data.len() {
             data.resize(size, 0);
         } else {
@@ -151,23 +224,24 @@ impl Vfs for MemVfs {
     }
 
     fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
-        log::debug!("lock: file={:?}, level={:?}", handle.name, level);
+        debug!(self, "lock: file={:?}, level={:?}", handle.name, level);
         Ok(())
     }
 
     fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
-        log::debug!("unlock: file={:?}, level={:?}", handle.name, level);
+        debug!(self, "unlock: file={:?}, level={:?}", handle.name, level);
         Ok(())
     }
 
     fn write(&self, handle: &mut Self::Handle, offset: usize, buf: &[u8]) -> VfsResult<usize> {
-        log::debug!(
+        debug!(
+            self,
             "write: file={:?}, offset={}, len={}",
             handle.name,
             offset,
             buf.len()
         );
-        let mut data = handle.data.lock();
+        let mut data = handle.data.write();
         if offset + buf.len() > data.len() {
             data.resize(offset + buf.len(), 0);
         }
@@ -176,13 +250,14 @@ impl Vfs for MemVfs {
     }
 
     fn read(&self, handle: &mut Self::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<usize> {
-        log::debug!(
+        debug!(
+            self,
             "read: file={:?}, offset={}, len={}",
             handle.name,
             offset,
             buf.len()
         );
-        let data = handle.data.lock();
+        let data = handle.data.read();
         if offset > data.len() {
             return Ok(0);
         }
@@ -191,13 +266,13 @@ impl Vfs for MemVfs {
         Ok(len)
     }
 
-    fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
-        log::debug!("sync: file={:?}", handle.name);
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        debug!(self, "sync: file={:?}, flags={:?}", handle.name, flags);
         Ok(())
     }
 
     fn close(&self, handle: Self::Handle) -> VfsResult<()> {
-        log::debug!("close: file={:?}", handle.name);
+        debug!(self, "close: file={:?}", handle.name);
         if handle.delete_on_close {
             if let Some(ref name) = handle.name {
                 self.delete(name)?;
@@ -211,13 +286,77 @@ impl Vfs for MemVfs {
         handle: &mut Self::Handle,
         pragma: Pragma<'_>,
     ) -> Result<Option<String>, PragmaErr> {
-        log::debug!("pragma: file={:?}, pragma={:?}", handle.name, pragma);
+        debug!(self, "pragma: file={:?}, pragma={:?}", handle.name, pragma);
         Err(PragmaErr::NotFound)
     }
+
+    fn supports_shm(&self) -> bool {
+        true
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        debug!(
+            self,
+            "shm_map: file={:?}, region={}, size={}, extend={}", handle.name, region, size, extend
+        );
+        let mut shm = handle.shm.lock();
+        while extend && shm.regions.len() <= region {
+            shm.regions.push(vec![0; size].into_boxed_slice());
+        }
+        Ok(shm
+            .regions
+            .get_mut(region)
+            .map(|region| NonNull::from(&mut region[0])))
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        debug!(
+            self,
+            "shm_lock: file={:?}, offset={}, count={}, mode={:?}", handle.name, offset, count, mode
+        );
+        let mut shm = handle.shm.lock();
+        let slots = &mut shm.locks[offset as usize..(offset + count) as usize];
+        if !mode.is_lock() {
+            slots.iter_mut().for_each(|slot| slot.unlock(handle.id));
+            return Ok(());
+        }
+        for i in 0..slots.len() {
+            if let Err(err) = slots[i].lock(handle.id, mode.is_exclusive()) {
+                // a failed request must not leave part of the range locked
+                slots[..i]
+                    .iter_mut()
+                    .for_each(|slot| slot.unlock(handle.id));
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        debug!(self, "shm_unmap: file={:?}, delete={}", handle.name, delete);
+        let mut shm = handle.shm.lock();
+        shm.locks.iter_mut().for_each(|slot| slot.unlock(handle.id));
+        if delete {
+            shm.regions.clear();
+        }
+        Ok(())
+    }
 }
 
 /// This function is called by `SQLite` when the extension is loaded. It registers
-/// the memvfs VFS with `SQLite`.
+/// the mem and `mem_nosync` VFSes with `SQLite`.
 /// # Safety
 /// This function should only be called by sqlite's extension loading mechanism.
 #[unsafe(no_mangle)]
@@ -226,21 +365,34 @@ pub unsafe extern "C" fn sqlite3_memvfs_init(
     _pz_err_msg: *mut *mut c_char,
     p_api: *mut sqlite3_api_routines,
 ) -> std::os::raw::c_int {
-    let vfs = MemVfs { files: Default::default() };
-    const MEMVFS_NAME: &CStr = c"mem";
-    if let Err(err) = unsafe {
-        register_dynamic(
-            p_api,
-            MEMVFS_NAME.to_owned(),
-            vfs,
-            RegisterOpts { make_default: true },
-        )
-    } {
-        return err;
-    }
-
-    // set the log level to trace
-    log::set_max_level(log::LevelFilter::Trace);
+    // an extension can register any number of independently configured vfses,
+    // each of which is given its own logger
+    let never = SyncPolicy {
+        main_db: SyncMode::Never,
+        main_journal: SyncMode::Never,
+        wal: SyncMode::Never,
+        super_journal: SyncMode::Never,
+        sub_journal: SyncMode::Never,
+        temp: SyncMode::Never,
+    };
+    let vfses: [(&'static CStr, RegisterOpts); 2] = [
+        (
+            c"mem",
+            RegisterOpts { make_default: true, ..Default::default() },
+        ),
+        (
+            c"mem_nosync",
+            RegisterOpts { sync_policy: never, ..Default::default() },
+        ),
+    ];
+    // set MEMVFS_VERBOSE to log every operation
+    let verbose = std::env::var_os("MEMVFS_VERBOSE").is_some();
+    for (name, opts) in vfses {
+        let vfs = MemVfs::new(name.to_str().expect("vfs names are ascii"), verbose);
+        if let Err(err) = unsafe { register_dynamic(p_api, name.to_owned(), vfs, opts) } {
+            return err.code(vars::SQLITE_ERROR);
+        }
+    }
 
     vars::SQLITE_OK_LOAD_PERMANENTLY
 }
@@ -1,22 +1,26 @@
 // cargo build --example memvfs_static --features static
 
 use std::{ffi::CStr, sync::Arc};
-use core::ffi::{c_int, c_void};
 
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use sqlite_plugin::{
-    flags::{AccessFlags, LockLevel, OpenOpts},
+    flags::{AccessFlags, LockLevel, OpenKind, OpenOpts, SyncFlags},
     logger::{SqliteLogLevel, SqliteLogger},
     vars,
-    vfs::{register_static, Pragma, PragmaErr, RegisterOpts, Vfs, VfsHandle, VfsResult, DEFAULT_DEVICE_CHARACTERISTICS},
+    vfs::{
+        Chunking, DEFAULT_DEVICE_CHARACTERISTICS, FileControlOp, Pragma, PragmaErr, RegisterOpts,
+        UriParams, Vfs, VfsError, VfsHandle, VfsResult, register_static,
+    },
 };
 
 #[derive(Debug, Clone)]
 struct File {
     name: Option<String>,
-    data: Arc<Mutex<Vec<u8>>>,
+    // readers share the lock so that concurrent connections don't serialize
+    data: Arc<RwLock<Vec<u8>>>,
     delete_on_close: bool,
     opts: OpenOpts,
+    chunking: Chunking,
 }
 
 impl File {
@@ -70,13 +74,18 @@ impl Vfs for MemVfs {
         log::debug!("registered logger");
     }
 
-    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
-        log::debug!("open: path={:?}, opts={:?}", path, opts);
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        log::debug!("open: path={path:?}, opts={opts:?}, params={params:?}");
         let mode = opts.mode();
         if mode.is_readonly() {
             // readonly makes no sense since an in-memory VFS is not backed by
             // any pre-existing data.
-            return Err(vars::SQLITE_CANTOPEN);
+            return Err(VfsError::CantOpen);
         }
 
         if let Some(path) = path {
@@ -85,7 +94,7 @@ impl Vfs for MemVfs {
             for file in files.iter() {
                 if file.is_named(path) {
                     if mode.must_create() {
-                        return Err(vars::SQLITE_CANTOPEN);
+                        return Err(VfsError::CantOpen);
                     }
                     return Ok(file.clone());
                 }
@@ -96,6 +105,7 @@ impl Vfs for MemVfs {
                 data: Default::default(),
                 delete_on_close: opts.delete_on_close(),
                 opts,
+                chunking: Chunking::default(),
             };
             files.push(file.clone());
             Ok(file)
@@ -105,13 +115,14 @@ impl Vfs for MemVfs {
                 data: Default::default(),
                 delete_on_close: opts.delete_on_close(),
                 opts,
+                chunking: Chunking::default(),
             };
             Ok(file)
         }
     }
 
     fn delete(&self, path: &str) -> VfsResult<()> {
-        log::debug!("delete: path={}", path);
+        log::debug!("delete: path={path}");
         let mut found = false;
         self.files.lock().retain(|file| {
             if file.is_named(path) {
@@ -122,24 +133,25 @@ impl Vfs for MemVfs {
             }
         });
         if !found {
-            return Err(vars::SQLITE_IOERR_DELETE_NOENT);
+            return Err(VfsError::Other(vars::SQLITE_IOERR_DELETE_NOENT));
         }
         Ok(())
     }
 
     fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
-        log::debug!("access: path={}, flags={:?}", path, flags);
+        log::debug!("access: path={path}, flags={flags:?}");
         Ok(self.files.lock().iter().any(|f| f.is_named(path)))
     }
 
     fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
         log::debug!("file_size: file={:?}", handle.name);
-        Ok(handle.data.lock().len())
+        Ok(handle.data.read().len())
     }
 
     fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
         log::debug!("truncate: file={:?}, size={}", handle.name, size);
-        let mut data = handle.data.lock();
+        let size = handle.chunking.round(size);
+        let mut data = handle.data.write();
         if size > data.len() {
             data.resize(size, 0);
         } else {
@@ -165,7 +177,7 @@ impl Vfs for MemVfs {
             offset,
             buf.len()
         );
-        let mut data = handle.data.lock();
+        let mut data = handle.data.write();
         if offset + buf.len() > data.len() {
             data.resize(offset + buf.len(), 0);
         }
@@ -180,7 +192,7 @@ impl Vfs for MemVfs {
             offset,
             buf.len()
         );
-        let data = handle.data.lock();
+        let data = handle.data.read();
         if offset > data.len() {
             return Ok(0);
         }
@@ -189,8 +201,8 @@ impl Vfs for MemVfs {
         Ok(len)
     }
 
-    fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
-        log::debug!("sync: file={:?}", handle.name);
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        log::debug!("sync: file={:?}, flags={:?}", handle.name, flags);
         Ok(())
     }
 
@@ -213,33 +225,52 @@ impl Vfs for MemVfs {
         Err(PragmaErr::NotFound)
     }
 
-    fn device_characteristics(&self) -> i32 {
-        log::debug!("device_characteristics given with batch atomic");
-        DEFAULT_DEVICE_CHARACTERISTICS | vars::SQLITE_IOCAP_BATCH_ATOMIC
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        // batch atomic writes only apply to the main db
+        if handle.opts.kind() == OpenKind::MainDb {
+            log::debug!("device_characteristics given with batch atomic");
+            DEFAULT_DEVICE_CHARACTERISTICS | vars::SQLITE_IOCAP_BATCH_ATOMIC
+        } else {
+            DEFAULT_DEVICE_CHARACTERISTICS
+        }
     }
 
-    fn file_control(&self, handle: &mut Self::Handle, op: c_int, _p_arg: *mut c_void) -> VfsResult<()> {
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
         log::debug!("file_control: file={:?}, op={:?}", handle.name, op);
+        if handle.chunking.file_control(&op) {
+            return Ok(());
+        }
         match op {
-            vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => {
+            FileControlOp::CommitAtomicWrite => {
                 log::debug!("commit_atomic_write control given");
                 Ok(())
             }
-            vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => {
+            FileControlOp::RollbackAtomicWrite => {
                 log::debug!("rollback_atomic_write control given");
                 Ok(())
             }
-            vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => {
+            FileControlOp::BeginAtomicWrite => {
                 log::debug!("begin_atomic_write control given");
                 Ok(())
             }
-            _ => Err(vars::SQLITE_NOTFOUND),
+            FileControlOp::SizeHint(size) => {
+                // allocate ahead of a large transaction rather than growing
+                // the file write by write
+                let mut data = handle.data.write();
+                let size = handle.chunking.round(size as usize);
+                let additional = size.saturating_sub(data.len());
+                data.reserve(additional);
+                Ok(())
+            }
+            _ => Err(VfsError::NotFound),
         }
     }
 }
 
 /// This function initializes the memvfs VFS statically.
 /// Called automatically when the library is loaded.
+/// # Safety
+/// This function should only be called once, before any connections are opened.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn initialize_memvfs() -> i32 {
     let vfs = MemVfs { files: Default::default() };
@@ -248,10 +279,10 @@ pub unsafe extern "C" fn initialize_memvfs() -> i32 {
     if let Err(err) = register_static(
         MEMVFS_NAME.to_owned(),
         vfs,
-        RegisterOpts { make_default: true },
+        RegisterOpts { make_default: true, ..Default::default() },
     ) {
-        eprintln!("Failed to initialize memvfs: {}", err);
-        return err;
+        eprintln!("Failed to initialize memvfs: {err}");
+        return err.code(vars::SQLITE_ERROR);
     }
 
     // set the log level to trace
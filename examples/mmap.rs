@@ -0,0 +1,347 @@
+// cargo run --example mmap --features static
+//
+// An on-disk vfs which maps each file into memory and serves reads from the
+// mapping.
+//
+// `fetch` hands out pointers into the same mapping, so once `pragma
+// mmap_size` is set `SQLite` reads pages without copying them at all. The
+// tricky part is growth. A pointer returned by `fetch` must stay valid until
+// it is released, so the mapping is only replaced while no pointers are
+// outstanding. `SQLite` releases all of them with `unfetch(None)` before the
+// file changes size and whenever another connection may have changed it,
+// which is when the mapping is dropped and mapped again at the new size on
+// the next fetch. Writes go through `pwrite`. The mapping is shared, so it
+// sees them through the page cache.
+
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+mod vfs {
+    use std::fs;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::FileExt;
+    use std::ptr::{self, NonNull};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use sqlite_plugin::flags::{AccessFlags, LockLevel, OpenOpts, SyncFlags};
+    use sqlite_plugin::logger::SqliteLogger;
+    use sqlite_plugin::vars;
+    use sqlite_plugin::vfs::{Pragma, PragmaErr, UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+    use crate::common::{self, Disk, io_err};
+
+    /// Reads served from a mapping, including fetches.
+    pub static MAPPED_READS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Times a file was mapped.
+    pub static MAPS: AtomicUsize = AtomicUsize::new(0);
+
+    // a read only view of the start of a file
+    struct Mapping {
+        ptr: NonNull<u8>,
+        len: usize,
+    }
+
+    // SAFETY: the mapping is only read, and is owned by one handle
+    unsafe impl Send for Mapping {}
+
+    impl Mapping {
+        fn new(file: &fs::File, len: usize) -> io::Result<Self> {
+            // SAFETY: maps a fresh region, which nothing else refers to
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            MAPS.fetch_add(1, Ordering::Relaxed);
+            let ptr = NonNull::new(ptr.cast()).ok_or(io::ErrorKind::Other)?;
+            Ok(Self { ptr, len })
+        }
+
+        fn covers(&self, offset: usize, len: usize) -> bool {
+            offset.checked_add(len).is_some_and(|end| end <= self.len)
+        }
+
+        fn slice(&self, offset: usize, len: usize) -> &[u8] {
+            debug_assert!(self.covers(offset, len));
+            // SAFETY: the range is within the mapping, which is only written
+            // by the kernel
+            unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(offset), len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            // SAFETY: the region was mapped by Mapping::new
+            unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+        }
+    }
+
+    pub struct File {
+        disk: common::File,
+        mapping: Option<Mapping>,
+        // the pointers handed out by fetch and not yet released
+        fetched: usize,
+    }
+
+    impl File {
+        fn size(&self) -> VfsResult<usize> {
+            let meta = self.disk.file.metadata().map_err(io_err)?;
+            usize::try_from(meta.len()).map_err(|_| VfsError::Io)
+        }
+
+        // drops the mapping unless SQLite still reads from it
+        fn unmap(&mut self) {
+            if self.fetched == 0 {
+                self.mapping = None;
+            }
+        }
+    }
+
+    impl VfsHandle for File {
+        fn readonly(&self) -> bool {
+            self.disk.readonly()
+        }
+
+        fn in_memory(&self) -> bool {
+            false
+        }
+    }
+
+    /// Files on disk, read through a memory mapping of each file. Locks only
+    /// exclude the connections of this process.
+    #[derive(Default)]
+    pub struct MmapVfs {
+        disk: Disk,
+    }
+
+    impl Vfs for MmapVfs {
+        type Handle = File;
+
+        fn register_logger(&self, _logger: SqliteLogger) {}
+
+        fn open(
+            &self,
+            path: Option<&str>,
+            opts: OpenOpts,
+            _params: UriParams<'_>,
+        ) -> VfsResult<Self::Handle> {
+            Ok(File {
+                disk: self.disk.open(path, opts)?,
+                mapping: None,
+                fetched: 0,
+            })
+        }
+
+        fn delete(&self, path: &str) -> VfsResult<()> {
+            common::delete(path)
+        }
+
+        fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+            Ok(common::access(path, flags))
+        }
+
+        fn temp_filename(&self) -> VfsResult<String> {
+            Err(VfsError::NotFound)
+        }
+
+        fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+            handle.size()
+        }
+
+        fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+            // touching a mapped page past the end of the file raises SIGBUS
+            handle.unmap();
+            if handle.mapping.as_ref().is_some_and(|m| m.len > size) {
+                return Err(VfsError::Other(vars::SQLITE_IOERR_TRUNCATE));
+            }
+            handle.disk.file.set_len(size as u64).map_err(io_err)
+        }
+
+        fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+            handle
+                .disk
+                .file
+                .write_all_at(data, offset as u64)
+                .map_err(io_err)?;
+            Ok(data.len())
+        }
+
+        fn read(
+            &self,
+            handle: &mut Self::Handle,
+            offset: usize,
+            data: &mut [u8],
+        ) -> VfsResult<usize> {
+            if let Some(mapping) = handle
+                .mapping
+                .as_ref()
+                .filter(|m| m.covers(offset, data.len()))
+            {
+                data.copy_from_slice(mapping.slice(offset, data.len()));
+                MAPPED_READS.fetch_add(1, Ordering::Relaxed);
+                return Ok(data.len());
+            }
+            let mut done = 0;
+            while done < data.len() {
+                match handle
+                    .disk
+                    .file
+                    .read_at(&mut data[done..], (offset + done) as u64)
+                {
+                    Ok(0) => break,
+                    Ok(n) => done += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(io_err(err)),
+                }
+            }
+            // SQLite requires the rest of a read past the end to be zeroed
+            data[done..].fill(0);
+            Ok(done)
+        }
+
+        fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+            handle.disk.lock(level)
+        }
+
+        fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+            handle.disk.unlock(level)
+        }
+
+        fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+            Ok(handle.disk.check_reserved_lock())
+        }
+
+        fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+            let result = match flags.data_only() {
+                true => handle.disk.file.sync_data(),
+                false => handle.disk.file.sync_all(),
+            };
+            result.map_err(io_err)
+        }
+
+        fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+            self.disk.close(handle.disk)
+        }
+
+        fn pragma(
+            &self,
+            _handle: &mut Self::Handle,
+            _pragma: Pragma<'_>,
+        ) -> Result<Option<String>, PragmaErr> {
+            Err(PragmaErr::NotFound)
+        }
+
+        fn supports_mmap(&self) -> bool {
+            true
+        }
+
+        fn fetch(
+            &self,
+            handle: &mut Self::Handle,
+            offset: usize,
+            len: usize,
+        ) -> VfsResult<Option<NonNull<u8>>> {
+            if !handle
+                .mapping
+                .as_ref()
+                .is_some_and(|m| m.covers(offset, len))
+            {
+                // the file grew past the mapping. It can only be replaced
+                // while SQLite holds no pointers into it, otherwise the
+                // page is read instead.
+                if handle.fetched > 0 {
+                    return Ok(None);
+                }
+                let size = handle.size()?;
+                if offset.checked_add(len).is_none_or(|end| end > size) {
+                    return Ok(None);
+                }
+                handle.mapping = None;
+                handle.mapping = Some(Mapping::new(&handle.disk.file, size).map_err(io_err)?);
+            }
+            let Some(mapping) = &handle.mapping else {
+                return Ok(None);
+            };
+            handle.fetched += 1;
+            MAPPED_READS.fetch_add(1, Ordering::Relaxed);
+            Ok(NonNull::new(mapping.slice(offset, len).as_ptr().cast_mut()))
+        }
+
+        fn unfetch(
+            &self,
+            handle: &mut Self::Handle,
+            _offset: usize,
+            ptr: Option<NonNull<u8>>,
+        ) -> VfsResult<()> {
+            match ptr {
+                Some(_) => handle.fetched = handle.fetched.saturating_sub(1),
+                None => {
+                    // SQLite holds no pointers, and the file may be about to
+                    // change size
+                    handle.fetched = 0;
+                    handle.unmap();
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::Ordering;
+
+    use rusqlite::{Connection, OpenFlags};
+    use sqlite_plugin::vfs::{RegisterOpts, register_static};
+
+    register_static(
+        c"mmap".into(),
+        vfs::MmapVfs::default(),
+        RegisterOpts::default(),
+    )?;
+
+    let dir = std::env::temp_dir().join(format!("mmap-example-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("main.db");
+    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+    let result = (|| -> rusqlite::Result<()> {
+        let conn = Connection::open_with_flags_and_vfs(&path, flags, "mmap")?;
+        conn.execute_batch(
+            "pragma mmap_size = 268435456;
+            create table t(x);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 10000)
+            insert into t select randomblob(100) from n;",
+        )?;
+        drop(conn);
+
+        let conn = Connection::open_with_flags_and_vfs(&path, flags, "mmap")?;
+        conn.execute_batch("pragma mmap_size = 268435456")?;
+        let count: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        let check: String = conn.query_row("pragma integrity_check", [], |row| row.get(0))?;
+        println!("{count} rows, integrity check: {check}");
+        println!(
+            "{} reads served from {} mappings",
+            vfs::MAPPED_READS.load(Ordering::Relaxed),
+            vfs::MAPS.load(Ordering::Relaxed),
+        );
+        Ok(())
+    })();
+    std::fs::remove_dir_all(&dir)?;
+    Ok(result?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("this example maps files with the linux system calls");
+}
@@ -24,3 +24,18 @@ drop table t1;
 vacuum;
 
 select * from dbstat;
+
+-- the extension also registers mem_nosync, which logs separately and skips
+-- sync requests
+.vfslist
+.open file:nosync.db?vfs=mem_nosync
+CREATE TABLE t2(a);
+INSERT INTO t2 VALUES(1);
+SELECT * FROM t2;
+
+-- memvfs implements the shared memory methods, so WAL mode is available
+.open file:wal.db?vfs=mem
+pragma journal_mode=wal;
+CREATE TABLE t3(a);
+INSERT INTO t3 VALUES(1);
+SELECT * FROM t3;
@@ -0,0 +1,490 @@
+// cargo run --example uring --features static
+//
+// An on-disk vfs which reads, writes and syncs files through io_uring.
+//
+// The `uring` module is a small driver for one ring, built directly on the
+// system calls rather than on a io_uring crate. It shows the bridge from
+// completion-based I/O to the synchronous `Vfs` callbacks: each callback
+// submits one request and waits for its completion before returning. One
+// ring per vfs behind a mutex keeps the example short. A ring per thread
+// would avoid serializing connections on different threads.
+
+#[cfg(target_os = "linux")]
+mod uring {
+    use std::io;
+    use std::os::fd::RawFd;
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const IORING_OFF_SQ_RING: i64 = 0;
+    const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+    const IORING_OFF_SQES: i64 = 0x1000_0000;
+    const IORING_ENTER_GETEVENTS: u32 = 1;
+    const IORING_FSYNC_DATASYNC: u32 = 1;
+
+    const IORING_OP_FSYNC: u8 = 3;
+    const IORING_OP_READ: u8 = 22;
+    const IORING_OP_WRITE: u8 = 23;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct SqRingOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        flags: u32,
+        dropped: u32,
+        array: u32,
+        resv1: u32,
+        user_addr: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct CqRingOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        overflow: u32,
+        cqes: u32,
+        flags: u32,
+        resv1: u32,
+        user_addr: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Params {
+        sq_entries: u32,
+        cq_entries: u32,
+        flags: u32,
+        sq_thread_cpu: u32,
+        sq_thread_idle: u32,
+        features: u32,
+        wq_fd: u32,
+        resv: [u32; 3],
+        sq_off: SqRingOffsets,
+        cq_off: CqRingOffsets,
+    }
+
+    /// A submission queue entry, as laid out by the kernel.
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    pub struct Sqe {
+        opcode: u8,
+        flags: u8,
+        ioprio: u16,
+        fd: i32,
+        off: u64,
+        addr: u64,
+        len: u32,
+        op_flags: u32,
+        user_data: u64,
+        buf_index: u16,
+        personality: u16,
+        splice_fd_in: i32,
+        addr3: u64,
+        pad: u64,
+    }
+
+    #[repr(C)]
+    struct Cqe {
+        user_data: u64,
+        res: i32,
+        flags: u32,
+    }
+
+    impl Sqe {
+        /// Reads into `buf` at `offset`. The buffer must outlive the request.
+        pub fn read(fd: RawFd, buf: &mut [u8], offset: u64) -> Self {
+            Self {
+                opcode: IORING_OP_READ,
+                fd,
+                off: offset,
+                addr: buf.as_mut_ptr() as u64,
+                len: buf.len() as u32,
+                ..Self::default()
+            }
+        }
+
+        /// Writes `buf` at `offset`. The buffer must outlive the request.
+        pub fn write(fd: RawFd, buf: &[u8], offset: u64) -> Self {
+            Self {
+                opcode: IORING_OP_WRITE,
+                fd,
+                off: offset,
+                addr: buf.as_ptr() as u64,
+                len: buf.len() as u32,
+                ..Self::default()
+            }
+        }
+
+        pub fn fsync(fd: RawFd, data_only: bool) -> Self {
+            Self {
+                opcode: IORING_OP_FSYNC,
+                fd,
+                op_flags: if data_only { IORING_FSYNC_DATASYNC } else { 0 },
+                ..Self::default()
+            }
+        }
+    }
+
+    // a region mapped from the ring's file descriptor
+    struct Mapping {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    impl Mapping {
+        fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<Self> {
+            // SAFETY: maps a fresh region of the ring, which the kernel sized
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_POPULATE,
+                    fd,
+                    offset,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { ptr: ptr.cast(), len })
+        }
+
+        fn at<T>(&self, offset: u32) -> *mut T {
+            // SAFETY: the kernel's offsets are within the mapping
+            unsafe { self.ptr.add(offset as usize).cast() }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            // SAFETY: the region was mapped by Mapping::new
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+
+    /// An `io_uring` instance which runs one request at a time.
+    pub struct Ring {
+        fd: RawFd,
+        sq: Mapping,
+        cq: Mapping,
+        sqes: Mapping,
+        params: Params,
+        next_id: u64,
+        poisoned: bool,
+    }
+
+    // SAFETY: the ring is only used through &mut self, and the kernel
+    // synchronizes with it through the ring's head and tail
+    unsafe impl Send for Ring {}
+
+    impl Ring {
+        pub fn new(entries: u32) -> io::Result<Self> {
+            let mut params = Params::default();
+            // SAFETY: params is a valid io_uring_params for the kernel to fill
+            let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &raw mut params) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = fd as RawFd;
+            let close = |err| {
+                // SAFETY: fd is the ring, which nothing else uses yet
+                unsafe { libc::close(fd) };
+                err
+            };
+            let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+            let cq_len =
+                params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+            let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+            let sq = Mapping::new(fd, sq_len, IORING_OFF_SQ_RING).map_err(close)?;
+            let cq = Mapping::new(fd, cq_len, IORING_OFF_CQ_RING).map_err(close)?;
+            let sqes = Mapping::new(fd, sqes_len, IORING_OFF_SQES).map_err(close)?;
+            Ok(Self {
+                fd,
+                sq,
+                cq,
+                sqes,
+                params,
+                next_id: 0,
+                poisoned: false,
+            })
+        }
+
+        fn head_tail(map: &Mapping, head: u32, tail: u32) -> (&AtomicU32, &AtomicU32) {
+            // SAFETY: the kernel's offsets point at aligned u32 counters
+            unsafe { (&*map.at(head), &*map.at(tail)) }
+        }
+
+        /// Submits `sqe` and waits for it to complete, returning its result:
+        /// a byte count for reads and writes, or an error. A request which
+        /// could not be submitted poisons the ring, failing every later one,
+        /// since it stays queued and would go out with the next request.
+        /// # Safety
+        /// The buffers `sqe` points to must be valid for the request.
+        pub unsafe fn run(&mut self, mut sqe: Sqe) -> io::Result<u32> {
+            if self.poisoned {
+                return Err(io::Error::other("an earlier request failed to submit"));
+            }
+            self.next_id += 1;
+            sqe.user_data = self.next_id;
+
+            let off = &self.params.sq_off;
+            let (_, tail) = Self::head_tail(&self.sq, off.head, off.tail);
+            // SAFETY: the mask is a u32 counter in the mapping
+            let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+            let at = tail.load(Ordering::Relaxed);
+            let index = at & mask;
+            // SAFETY: index is within the entries, and the kernel is done
+            // with the slot since the previous request completed
+            unsafe {
+                *self.sqes.at::<Sqe>(0).add(index as usize) = sqe;
+                *self.sq.at::<u32>(off.array).add(index as usize) = index;
+            }
+            tail.store(at.wrapping_add(1), Ordering::Release);
+
+            let mut submit = 1;
+            let mut stray = false;
+            loop {
+                let off = &self.params.cq_off;
+                let (head, tail) = Self::head_tail(&self.cq, off.head, off.tail);
+                let at = head.load(Ordering::Relaxed);
+                if at != tail.load(Ordering::Acquire) {
+                    // SAFETY: the entry between head and tail is complete
+                    let cqe = unsafe {
+                        let mask = *self.cq.at::<u32>(off.ring_mask);
+                        &*self.cq.at::<Cqe>(off.cqes).add((at & mask) as usize)
+                    };
+                    let (id, res) = (cqe.user_data, cqe.res);
+                    head.store(at.wrapping_add(1), Ordering::Release);
+                    if id != self.next_id {
+                        // one request runs at a time, so this is not ours.
+                        // Ours may still be using its buffers, so it is
+                        // waited for before failing.
+                        stray = true;
+                        continue;
+                    }
+                    if stray {
+                        return Err(io::Error::other("completion for an unknown request"));
+                    }
+                    return match u32::try_from(res) {
+                        Ok(n) => Ok(n),
+                        Err(_) => Err(io::Error::from_raw_os_error(-res)),
+                    };
+                }
+                // SAFETY: the ring's fd, submitting what was queued above
+                let rc = unsafe {
+                    libc::syscall(
+                        libc::SYS_io_uring_enter,
+                        self.fd,
+                        submit,
+                        1,
+                        IORING_ENTER_GETEVENTS,
+                        ptr::null::<libc::sigset_t>(),
+                        0,
+                    )
+                };
+                if rc >= 0 {
+                    submit -= rc.min(submit as libc::c_long) as u32;
+                    continue;
+                }
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted && submit > 0 {
+                    // the kernel has not seen the request, so its buffers
+                    // are free again
+                    self.poisoned = true;
+                    return Err(err);
+                }
+                // otherwise the request is in flight and is reaped before
+                // its buffers are given back
+            }
+        }
+    }
+
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            // SAFETY: the ring is idle, and its mappings stay valid until
+            // they are dropped after this
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+mod vfs {
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::sync::Mutex;
+
+    use sqlite_plugin::flags::{AccessFlags, LockLevel, OpenOpts, SyncFlags};
+    use sqlite_plugin::logger::SqliteLogger;
+    use sqlite_plugin::vfs::{Pragma, PragmaErr, UriParams, Vfs, VfsError, VfsResult};
+
+    use crate::common::{self, Disk, File, io_err};
+    use crate::uring::{Ring, Sqe};
+
+    /// Files on disk, read and written through `io_uring`. Locks only exclude
+    /// the connections of this process.
+    pub struct UringVfs {
+        ring: Mutex<Ring>,
+        disk: Disk,
+    }
+
+    impl UringVfs {
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                ring: Mutex::new(Ring::new(8)?),
+                disk: Disk::default(),
+            })
+        }
+
+        fn run(&self, sqe: Sqe) -> VfsResult<u32> {
+            // SAFETY: the callers' buffers outlive the request, which has
+            // completed when this returns
+            unsafe { self.ring.lock().unwrap().run(sqe) }.map_err(io_err)
+        }
+    }
+
+    impl Vfs for UringVfs {
+        type Handle = File;
+
+        fn register_logger(&self, _logger: SqliteLogger) {}
+
+        fn open(
+            &self,
+            path: Option<&str>,
+            opts: OpenOpts,
+            _params: UriParams<'_>,
+        ) -> VfsResult<Self::Handle> {
+            self.disk.open(path, opts)
+        }
+
+        fn delete(&self, path: &str) -> VfsResult<()> {
+            common::delete(path)
+        }
+
+        fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+            Ok(common::access(path, flags))
+        }
+
+        fn temp_filename(&self) -> VfsResult<String> {
+            Err(VfsError::NotFound)
+        }
+
+        fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+            let meta = handle.file.metadata().map_err(io_err)?;
+            usize::try_from(meta.len()).map_err(|_| VfsError::Io)
+        }
+
+        fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+            handle.file.set_len(size as u64).map_err(io_err)
+        }
+
+        fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+            let fd = handle.file.as_raw_fd();
+            let mut done = 0;
+            // a request may write less than asked, like pwrite
+            while done < data.len() {
+                let sqe = Sqe::write(fd, &data[done..], (offset + done) as u64);
+                match self.run(sqe)? {
+                    0 => return Err(VfsError::Io),
+                    n => done += n as usize,
+                }
+            }
+            Ok(done)
+        }
+
+        fn read(
+            &self,
+            handle: &mut Self::Handle,
+            offset: usize,
+            data: &mut [u8],
+        ) -> VfsResult<usize> {
+            let fd = handle.file.as_raw_fd();
+            let mut done = 0;
+            while done < data.len() {
+                let sqe = Sqe::read(fd, &mut data[done..], (offset + done) as u64);
+                match self.run(sqe)? {
+                    0 => break,
+                    n => done += n as usize,
+                }
+            }
+            // SQLite requires the rest of a read past the end to be zeroed
+            data[done..].fill(0);
+            Ok(done)
+        }
+
+        fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+            handle.lock(level)
+        }
+
+        fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+            handle.unlock(level)
+        }
+
+        fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+            Ok(handle.check_reserved_lock())
+        }
+
+        fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+            let sqe = Sqe::fsync(handle.file.as_raw_fd(), flags.data_only());
+            self.run(sqe).map(|_| ())
+        }
+
+        fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+            self.disk.close(handle)
+        }
+
+        fn pragma(
+            &self,
+            _handle: &mut Self::Handle,
+            _pragma: Pragma<'_>,
+        ) -> Result<Option<String>, PragmaErr> {
+            Err(PragmaErr::NotFound)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlite_plugin::vfs::{RegisterOpts, register_static};
+
+    let vfs = vfs::UringVfs::new().map_err(|err| format!("io_uring is unavailable: {err}"))?;
+    register_static(c"uring".into(), vfs, RegisterOpts::default())?;
+
+    let dir = std::env::temp_dir().join(format!("uring-example-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("main.db");
+    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+    let result = (|| -> rusqlite::Result<()> {
+        let conn = Connection::open_with_flags_and_vfs(&path, flags, "uring")?;
+        conn.execute_batch(
+            "create table t(x);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 10000)
+            insert into t select randomblob(100) from n;",
+        )?;
+        drop(conn);
+
+        let conn = Connection::open_with_flags_and_vfs(&path, flags, "uring")?;
+        let count: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        let check: String = conn.query_row("pragma integrity_check", [], |row| row.get(0))?;
+        println!("{count} rows, integrity check: {check}");
+        Ok(())
+    })();
+    std::fs::remove_dir_all(&dir)?;
+    Ok(result?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("io_uring is only available on linux");
+}
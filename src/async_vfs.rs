@@ -0,0 +1,560 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::future::Future;
+use core::time::Duration;
+
+use crate::flags::{AccessFlags, LockLevel, OpenOpts, SyncFlags};
+use crate::logger::SqliteLogger;
+use crate::vfs::{
+    DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, FileControlOp, JournalPolicy, Pragma,
+    PragmaErr, UriParams, Vfs, VfsError, VfsHandle, VfsResult, next_temp_filename,
+};
+
+/// A version of [`Vfs`] whose io methods are async, for backends such as
+/// object stores that only offer an async client. Adapt it to a [`Vfs`] with
+/// [`BlockingVfs`].
+///
+/// Shared memory and memory mapped io are not available through the bridge.
+#[allow(unused_variables)]
+pub trait AsyncVfs: Send + Sync {
+    type Handle: VfsHandle;
+
+    /// See [`Vfs::register_logger`].
+    fn register_logger(&self, logger: SqliteLogger);
+
+    /// See [`Vfs::probe`].
+    fn probe(&self) -> impl Future<Output = VfsResult<()>> {
+        async { Ok(()) }
+    }
+
+    /// See [`Vfs::last_error`]. As futures may run on other threads, errors
+    /// should be kept per handle or per task rather than per thread.
+    fn last_error(&self) -> Option<(i32, String)> {
+        None
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        Ok(path)
+    }
+
+    // file system operations
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> impl Future<Output = VfsResult<Self::Handle>>;
+    fn delete(&self, path: &str) -> impl Future<Output = VfsResult<()>>;
+    fn access(&self, path: &str, flags: AccessFlags) -> impl Future<Output = VfsResult<bool>>;
+
+    /// See [`Vfs::temp_filename`].
+    fn temp_filename(&self) -> VfsResult<String> {
+        Ok(next_temp_filename())
+    }
+
+    /// See [`Vfs::journal_policy`].
+    fn journal_policy(&self, db_path: &str) -> JournalPolicy {
+        JournalPolicy::Backend
+    }
+
+    /// See [`Vfs::bootstrap`].
+    fn bootstrap(&self, path: &str) -> impl Future<Output = VfsResult<()>> {
+        async { Ok(()) }
+    }
+
+    // file operations
+    fn file_size(&self, handle: &mut Self::Handle) -> impl Future<Output = VfsResult<usize>>;
+    fn truncate(
+        &self,
+        handle: &mut Self::Handle,
+        size: usize,
+    ) -> impl Future<Output = VfsResult<()>>;
+    fn write(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &[u8],
+    ) -> impl Future<Output = VfsResult<usize>>;
+    fn read(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [u8],
+    ) -> impl Future<Output = VfsResult<usize>>;
+
+    fn lock(
+        &self,
+        handle: &mut Self::Handle,
+        level: LockLevel,
+    ) -> impl Future<Output = VfsResult<()>> {
+        async { Ok(()) }
+    }
+
+    fn unlock(
+        &self,
+        handle: &mut Self::Handle,
+        level: LockLevel,
+    ) -> impl Future<Output = VfsResult<()>> {
+        async { Ok(()) }
+    }
+
+    /// See [`Vfs::check_reserved_lock`].
+    fn check_reserved_lock(
+        &self,
+        handle: &mut Self::Handle,
+    ) -> impl Future<Output = VfsResult<bool>> {
+        async { Ok(false) }
+    }
+
+    fn sync(
+        &self,
+        handle: &mut Self::Handle,
+        flags: SyncFlags,
+    ) -> impl Future<Output = VfsResult<()>> {
+        async { Ok(()) }
+    }
+
+    fn close(&self, handle: Self::Handle) -> impl Future<Output = VfsResult<()>>;
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        Err(PragmaErr::NotFound)
+    }
+
+    // system queries
+    fn sector_size(&self, handle: &mut Self::Handle) -> usize {
+        DEFAULT_SECTOR_SIZE
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        DEFAULT_DEVICE_CHARACTERISTICS
+    }
+
+    /// See [`Vfs::file_control`]. Like pragmas, file controls are answered
+    /// synchronously, as their arguments only live for the duration of the
+    /// call.
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        Err(VfsError::NotFound)
+    }
+
+    /// See [`Vfs::vfs_name`].
+    fn vfs_name(&self, registered: &str) -> Option<String> {
+        Some(registered.into())
+    }
+
+    // system services
+    /// See [`Vfs::sleep`].
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        None
+    }
+
+    /// See [`Vfs::current_time`].
+    fn current_time(&self) -> Option<i64> {
+        None
+    }
+
+    /// See [`Vfs::randomness`].
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        None
+    }
+}
+
+/// Runs a future to completion on the calling thread. This is how
+/// [`BlockingVfs`] waits on an [`AsyncVfs`] from within the synchronous
+/// callbacks `SQLite` makes.
+///
+/// With the `tokio` feature, [`TokioBlockOn`] runs futures on a tokio
+/// runtime. Other runtimes are adapted by implementing this in the
+/// application.
+pub trait BlockOn: Send + Sync {
+    fn block_on<F: Future>(&self, future: F) -> F::Output;
+}
+
+/// A [`BlockOn`] which runs futures on the tokio runtime of a
+/// [`Handle`](tokio::runtime::Handle). Queries must run on threads which the
+/// runtime is not driving, as `Handle::block_on` panics when called from
+/// within an async context, so connections are used from
+/// `spawn_blocking` or from threads of their own:
+///
+/// ```no_run
+/// # use sqlite_plugin::async_vfs::{AsyncVfs, BlockingVfs, TokioBlockOn};
+/// fn bridge<V: AsyncVfs>(vfs: V, runtime: &tokio::runtime::Runtime) -> BlockingVfs<V, TokioBlockOn> {
+///     BlockingVfs::new(vfs, TokioBlockOn::new(runtime.handle().clone()))
+/// }
+/// ```
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone)]
+pub struct TokioBlockOn {
+    handle: tokio::runtime::Handle,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioBlockOn {
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        Self { handle }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl BlockOn for TokioBlockOn {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+}
+
+/// Adapts an [`AsyncVfs`] into a [`Vfs`] by blocking on each call with `B`,
+/// such as a [`TokioBlockOn`].
+pub struct BlockingVfs<V, B> {
+    vfs: V,
+    executor: B,
+}
+
+impl<V: AsyncVfs, B: BlockOn> BlockingVfs<V, B> {
+    pub fn new(vfs: V, executor: B) -> Self {
+        Self { vfs, executor }
+    }
+
+    pub fn inner(&self) -> &V {
+        &self.vfs
+    }
+}
+
+impl<V: AsyncVfs, B: BlockOn> Vfs for BlockingVfs<V, B> {
+    type Handle = V::Handle;
+
+    fn register_logger(&self, logger: SqliteLogger) {
+        self.vfs.register_logger(logger)
+    }
+
+    fn probe(&self) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.probe())
+    }
+
+    fn last_error(&self) -> Option<(i32, String)> {
+        self.vfs.last_error()
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        self.vfs.canonical_path(path)
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        self.executor.block_on(self.vfs.open(path, opts, params))
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.delete(path))
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        self.executor.block_on(self.vfs.access(path, flags))
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        self.vfs.temp_filename()
+    }
+
+    fn journal_policy(&self, db_path: &str) -> JournalPolicy {
+        self.vfs.journal_policy(db_path)
+    }
+
+    fn bootstrap(&self, path: &str) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.bootstrap(path))
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.executor.block_on(self.vfs.file_size(handle))
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.truncate(handle, size))
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.executor.block_on(self.vfs.write(handle, offset, data))
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        self.executor.block_on(self.vfs.read(handle, offset, data))
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.lock(handle, level))
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.unlock(handle, level))
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.executor.block_on(self.vfs.check_reserved_lock(handle))
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.sync(handle, flags))
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        self.executor.block_on(self.vfs.close(handle))
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        self.vfs.pragma(handle, pragma)
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> usize {
+        self.vfs.sector_size(handle)
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        self.vfs.device_characteristics(handle)
+    }
+
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        self.vfs.file_control(handle, op)
+    }
+
+    fn vfs_name(&self, registered: &str) -> Option<String> {
+        self.vfs.vfs_name(registered)
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> Option<i64> {
+        self.vfs.current_time()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        self.vfs.randomness(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::mock::{Hooks, MockHandle, MockVfs, NoopHooks};
+    use crate::vfs::{RegisterOpts, register_static};
+    use crate::{ffi, vars};
+    use alloc::ffi::CString;
+    use alloc::sync::Arc;
+    use core::ffi::c_int;
+    use core::future::poll_fn;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use rusqlite::{Connection, OpenFlags};
+    use spin::Mutex;
+    use std::boxed::Box;
+
+    // busy polls, which is enough for the futures of YieldingVfs as they wake
+    // themselves
+    struct SpinBlockOn;
+
+    impl BlockOn for SpinBlockOn {
+        fn block_on<F: Future>(&self, future: F) -> F::Output {
+            let mut future = pin!(future);
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                if let Poll::Ready(out) = future.as_mut().poll(&mut cx) {
+                    return out;
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    // an async backend which is pending once before each operation completes
+    struct YieldingVfs(MockVfs);
+
+    async fn yield_once() {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    impl AsyncVfs for YieldingVfs {
+        type Handle = MockHandle;
+
+        fn register_logger(&self, logger: SqliteLogger) {
+            self.0.register_logger(logger)
+        }
+
+        async fn open(
+            &self,
+            path: Option<&str>,
+            opts: OpenOpts,
+            params: UriParams<'_>,
+        ) -> VfsResult<MockHandle> {
+            yield_once().await;
+            self.0.open(path, opts, params)
+        }
+
+        async fn delete(&self, path: &str) -> VfsResult<()> {
+            yield_once().await;
+            self.0.delete(path)
+        }
+
+        async fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+            yield_once().await;
+            self.0.access(path, flags)
+        }
+
+        async fn file_size(&self, handle: &mut MockHandle) -> VfsResult<usize> {
+            yield_once().await;
+            self.0.file_size(handle)
+        }
+
+        async fn truncate(&self, handle: &mut MockHandle, size: usize) -> VfsResult<()> {
+            yield_once().await;
+            self.0.truncate(handle, size)
+        }
+
+        async fn write(
+            &self,
+            handle: &mut MockHandle,
+            offset: usize,
+            data: &[u8],
+        ) -> VfsResult<usize> {
+            yield_once().await;
+            self.0.write(handle, offset, data)
+        }
+
+        async fn read(
+            &self,
+            handle: &mut MockHandle,
+            offset: usize,
+            data: &mut [u8],
+        ) -> VfsResult<usize> {
+            yield_once().await;
+            self.0.read(handle, offset, data)
+        }
+
+        async fn close(&self, handle: MockHandle) -> VfsResult<()> {
+            yield_once().await;
+            self.0.close(handle)
+        }
+
+        fn file_control(&self, handle: &mut MockHandle, op: FileControlOp<'_>) -> VfsResult<()> {
+            self.0.file_control(handle, op)
+        }
+    }
+
+    #[test]
+    fn blocking_bridge() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = BlockingVfs::new(YieldingVfs(MockVfs::new(Box::new(NoopHooks))), SpinBlockOn);
+        register_static(CString::from(c"mock_async"), vfs, RegisterOpts::default())
+            .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_async",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1), (2)", [])?;
+        let n: i64 = conn.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 3);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_bridge() -> Result<(), Box<dyn std::error::Error>> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let vfs = BlockingVfs::new(
+            YieldingVfs(MockVfs::new(Box::new(NoopHooks))),
+            TokioBlockOn::new(runtime.handle().clone()),
+        );
+        register_static(CString::from(c"mock_tokio"), vfs, RegisterOpts::default())
+            .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_tokio",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1), (2)", [])?;
+        let n: i64 = conn.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn blocking_file_control() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            chunk_size: Arc<Mutex<Option<usize>>>,
+        }
+        impl Hooks for H {
+            fn file_control(
+                &mut self,
+                _handle: MockHandle,
+                op: FileControlOp<'_>,
+            ) -> VfsResult<()> {
+                match op {
+                    FileControlOp::ChunkSize(size) => {
+                        *self.chunk_size.lock() = Some(size);
+                        Ok(())
+                    }
+                    _ => Err(VfsError::NotFound),
+                }
+            }
+        }
+
+        let chunk_size = Arc::new(Mutex::new(None));
+        let hooks = H { chunk_size: chunk_size.clone() };
+        let vfs = BlockingVfs::new(YieldingVfs(MockVfs::new(Box::new(hooks))), SpinBlockOn);
+        register_static(
+            CString::from(c"mock_async_fcntl"),
+            vfs,
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_async_fcntl",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        let mut size: c_int = 65536;
+        let rc = unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle().cast(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_CHUNK_SIZE,
+                (&raw mut size).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(*chunk_size.lock(), Some(65536));
+
+        Ok(())
+    }
+}
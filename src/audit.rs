@@ -0,0 +1,246 @@
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::flags::OpenOpts;
+use crate::shim;
+use crate::vfs::{UriParams, Vfs, VfsHandle, VfsResult};
+
+/// A destructive operation recorded by an [`Audit`] vfs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOp {
+    /// The file was deleted.
+    Delete,
+    /// The file was truncated to `size` bytes.
+    Truncate { size: usize },
+    /// `len` bytes of existing data starting at `offset` were overwritten.
+    Overwrite { offset: usize, len: usize },
+}
+
+/// A single entry in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry<'a> {
+    /// Milliseconds since the unix epoch, as reported by the [`Audit`] clock.
+    pub timestamp_ms: i64,
+    /// Identifies the handle which performed the operation. Every connection
+    /// opens its own handles, so this tells apart connections to the same
+    /// file. `None` for deletes, which don't go through a handle.
+    pub handle_id: Option<usize>,
+    pub path: Option<&'a str>,
+    pub op: AuditOp,
+    /// The size of the file before the operation, if it is known. Deletes
+    /// are not given a handle, so their size is never known.
+    pub size_before: Option<usize>,
+}
+
+/// Receives the entries recorded by an [`Audit`] vfs. Entries are delivered
+/// synchronously, before the operation is passed to the wrapped vfs, so a
+/// sink which must not lose entries should persist them before returning.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: AuditEntry<'_>);
+}
+
+/// A Vfs wrapper which reports every delete, truncate to a smaller size and
+/// overwrite of existing data to an [`AuditSink`], so that the destruction of
+/// data can be traced.
+///
+/// Writes past the end of a file only append data and are not recorded.
+/// Detecting overwrites and truncates requires a `file_size` call on the
+/// wrapped vfs before each write and truncate. Files which the wrapped vfs
+/// deletes on its own, such as delete-on-close files when they are closed,
+/// are not seen by the wrapper and so are not recorded.
+///
+/// ```no_run
+/// # use std::time::SystemTime;
+/// # use sqlite_plugin::audit::{Audit, AuditEntry, AuditSink};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// struct Stderr;
+///
+/// impl AuditSink for Stderr {
+///     fn record(&self, entry: AuditEntry<'_>) {
+///         eprintln!("{} {:?} {:?}", entry.timestamp_ms, entry.op, entry.path);
+///     }
+/// }
+///
+/// fn now_ms() -> i64 {
+///     let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+///     now.map_or(0, |now| now.as_millis() as i64)
+/// }
+///
+/// # fn main() -> VfsResult<()> {
+/// let vfs = Audit::new(BaseVfs::find(None)?, Stderr, now_ms);
+/// register_static(c"audited".to_owned(), vfs, RegisterOpts::default())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Audit<V, S> {
+    vfs: V,
+    sink: S,
+    clock: fn() -> i64,
+    next_id: AtomicUsize,
+}
+
+pub struct AuditHandle<H> {
+    id: usize,
+    path: Option<String>,
+    inner: H,
+}
+
+impl<V: Vfs, S: AuditSink> Audit<V, S> {
+    /// Wraps `vfs`, reporting to `sink`. Entries are timestamped with `clock`,
+    /// which returns milliseconds since the unix epoch.
+    pub fn new(vfs: V, sink: S, clock: fn() -> i64) -> Self {
+        Self {
+            vfs,
+            sink,
+            clock,
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    fn record(&self, handle: &AuditHandle<V::Handle>, op: AuditOp, size_before: usize) {
+        self.sink.record(AuditEntry {
+            timestamp_ms: (self.clock)(),
+            handle_id: Some(handle.id),
+            path: handle.path.as_deref(),
+            op,
+            size_before: Some(size_before),
+        });
+    }
+}
+
+impl<H: VfsHandle> VfsHandle for AuditHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for AuditHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs, S: AuditSink> shim::VfsShim for Audit<V, S> {
+    type Inner = V;
+    type Handle = AuditHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let inner = self.vfs.open(path, opts, params)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Ok(AuditHandle {
+            id,
+            path: path.map(ToOwned::to_owned),
+            inner,
+        })
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.sink.record(AuditEntry {
+            timestamp_ms: (self.clock)(),
+            handle_id: None,
+            path: Some(path),
+            op: AuditOp::Delete,
+            size_before: None,
+        });
+        self.vfs.delete(path)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        let size_before = self.vfs.file_size(&mut handle.inner)?;
+        if size < size_before {
+            self.record(handle, AuditOp::Truncate { size }, size_before);
+        }
+        self.vfs.truncate(&mut handle.inner, size)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let size_before = self.vfs.file_size(&mut handle.inner)?;
+        if offset < size_before {
+            let len = data.len().min(size_before - offset);
+            self.record(handle, AuditOp::Overwrite { offset, len }, size_before);
+        }
+        self.vfs.write(&mut handle.inner, offset, data)
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs, S: AuditSink] for Audit<V, S>);
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::logger::SqliteLogger;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::vars;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+    use std::boxed::Box;
+
+    type Recorded = (Option<String>, AuditOp, Option<usize>);
+
+    #[derive(Default)]
+    struct VecSink(spin::Mutex<Vec<Recorded>>);
+
+    impl AuditSink for VecSink {
+        fn record(&self, entry: AuditEntry<'_>) {
+            let path = entry.path.map(ToString::to_string);
+            self.0.lock().push((path, entry.op, entry.size_before));
+        }
+    }
+
+    #[test]
+    fn destructive_ops() {
+        let vfs = Audit::new(MockVfs::new(Box::new(NoopHooks)), VecSink::default(), || 0);
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let mut handle = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+        // appends are not destructive
+        vfs.write(&mut handle, 0, &[1; 8]).unwrap();
+        vfs.write(&mut handle, 8, &[2; 8]).unwrap();
+        vfs.write(&mut handle, 12, &[3; 8]).unwrap();
+        vfs.truncate(&mut handle, 32).unwrap();
+        vfs.truncate(&mut handle, 4).unwrap();
+        vfs.close(handle).unwrap();
+        vfs.delete("main.db").unwrap();
+
+        let main = Some("main.db".to_string());
+        assert_eq!(
+            *vfs.sink().0.lock(),
+            [
+                (
+                    main.clone(),
+                    AuditOp::Overwrite { offset: 12, len: 4 },
+                    Some(16)
+                ),
+                (main.clone(), AuditOp::Truncate { size: 4 }, Some(32)),
+                (main, AuditOp::Delete, None),
+            ]
+        );
+    }
+}
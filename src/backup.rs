@@ -0,0 +1,670 @@
+#[cfg(feature = "static")]
+use alloc::format;
+#[cfg(feature = "static")]
+use alloc::string::String;
+#[cfg(feature = "static")]
+use core::ffi::{c_int, c_uint};
+use core::fmt::{self, Display, Formatter};
+
+#[cfg(feature = "static")]
+use crate::flags::AccessFlags;
+#[cfg(feature = "static")]
+use crate::vars;
+use crate::vfs::VfsError;
+#[cfg(feature = "static")]
+use crate::vfs::{Vfs, VfsResult};
+
+#[cfg(feature = "static")]
+const BACKUP_CHUNK_SIZE: usize = 64 * 1024;
+
+const HEADER_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const HEADER_SIZE: usize = 100;
+
+/// Stream a consistent image of the database at `path` on the vfs registered
+/// as `vfs_name` to `write`.
+///
+/// The image is taken with `SQLite`'s online backup api over a connection
+/// through the vfs, in a single read transaction. It includes transactions
+/// still in the WAL, and a hot journal left by a crashed writer is rolled
+/// back first, as it would be by any connection. The image is copied into
+/// memory and then streamed, so that writers are only held up for as long as
+/// the copy: in rollback journal mode they cannot commit until it completes,
+/// while in WAL mode they are never blocked.
+///
+/// If a writer holds a lock which keeps readers out this returns
+/// `SQLITE_BUSY`, and `SQLITE_CANTOPEN` if the database does not exist.
+#[cfg(feature = "static")]
+pub fn backup_consistent(
+    vfs_name: &str,
+    path: &str,
+    mut write: impl FnMut(&[u8]) -> VfsResult<()>,
+) -> VfsResult<()> {
+    use core::ptr::null_mut;
+    use rusqlite::{Connection, OpenFlags};
+
+    let vfs = crate::shim::BaseVfs::find(Some(vfs_name))?;
+    if !vfs.access(path, AccessFlags::Exists)? {
+        return Err(VfsError::CantOpen);
+    }
+    // read-write, so that a hot journal can be rolled back
+    let src =
+        Connection::open_with_flags_and_vfs(path, OpenFlags::SQLITE_OPEN_READ_WRITE, vfs_name)
+            .map_err(sqlite_code)?;
+    let dst = Connection::open_in_memory().map_err(sqlite_code)?;
+    // an empty memdb, whose image can be read in place once it is copied
+    let rc = unsafe {
+        crate::ffi::sqlite3_deserialize(
+            dst.handle().cast(),
+            c"main".as_ptr(),
+            null_mut(),
+            0,
+            0,
+            (vars::SQLITE_DESERIALIZE_FREEONCLOSE | vars::SQLITE_DESERIALIZE_RESIZEABLE) as c_uint,
+        )
+    };
+    if rc != vars::SQLITE_OK {
+        return Err(rc.into());
+    }
+    let rc = unsafe { copy_database(&src, &dst) };
+    if rc != vars::SQLITE_OK {
+        return Err(rc.into());
+    }
+    drop(src);
+
+    let mut size = 0;
+    let image = unsafe {
+        crate::ffi::sqlite3_serialize(
+            dst.handle().cast(),
+            c"main".as_ptr(),
+            &mut size,
+            vars::SQLITE_SERIALIZE_NOCOPY as c_uint,
+        )
+    };
+    let size = usize::try_from(size).map_err(|_| VfsError::Io)?;
+    if size == 0 {
+        return Ok(());
+    }
+    if image.is_null() {
+        return Err(VfsError::Io);
+    }
+    // the image belongs to dst, which is not written to until it is dropped
+    let image = unsafe { core::slice::from_raw_parts(image, size) };
+    for chunk in image.chunks(BACKUP_CHUNK_SIZE) {
+        write(chunk)?;
+    }
+    Ok(())
+}
+
+// copies the main database of `src` over that of `dst` with the online
+// backup api, in one step so that the copy is a single read transaction
+#[cfg(feature = "static")]
+unsafe fn copy_database(src: &rusqlite::Connection, dst: &rusqlite::Connection) -> c_int {
+    unsafe {
+        let p_dst = dst.handle().cast();
+        let backup = crate::ffi::sqlite3_backup_init(
+            p_dst,
+            c"main".as_ptr(),
+            src.handle().cast(),
+            c"main".as_ptr(),
+        );
+        if backup.is_null() {
+            return crate::ffi::sqlite3_extended_errcode(p_dst);
+        }
+        let stepped = crate::ffi::sqlite3_backup_step(backup, -1);
+        let finished = crate::ffi::sqlite3_backup_finish(backup);
+        if stepped == vars::SQLITE_DONE {
+            finished
+        } else {
+            stepped
+        }
+    }
+}
+
+/// Checks a page of a database image, given its 1-based page number.
+pub type VerifyPage<'a> = &'a dyn Fn(u32, &[u8]) -> bool;
+
+/// The layout of a database image accepted by [`validate_image`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub page_size: usize,
+    pub page_count: usize,
+}
+
+/// Why a database image was rejected by [`validate_image`] or [`import`], or
+/// why [`migrate`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// The image does not start with the `SQLite` header.
+    BadMagic,
+    /// The header has a page size which is not a power of two between 512
+    /// and 65536.
+    BadPageSize(usize),
+    /// The image is not as long as the header says it should be.
+    SizeMismatch { expected: usize, actual: usize },
+    /// The page verifier rejected this page.
+    BadChecksum { page: u32 },
+    /// `pragma quick_check` found problems, which are listed one per line.
+    #[cfg(feature = "static")]
+    IntegrityCheck(String),
+    /// Loading, writing or migrating the image failed.
+    Vfs(VfsError),
+}
+
+impl From<VfsError> for ImageError {
+    fn from(err: VfsError) -> Self {
+        Self::Vfs(err)
+    }
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => f.write_str("not a sqlite database image"),
+            Self::BadPageSize(size) => write!(f, "invalid page size {size}"),
+            Self::SizeMismatch { expected, actual } => {
+                write!(f, "image is {actual} bytes, expected {expected}")
+            }
+            Self::BadChecksum { page } => write!(f, "checksum mismatch on page {page}"),
+            #[cfg(feature = "static")]
+            Self::IntegrityCheck(errors) => write!(f, "integrity check failed: {errors}"),
+            Self::Vfs(err) => write!(f, "vfs error: {err}"),
+        }
+    }
+}
+
+impl core::error::Error for ImageError {}
+
+/// Checks that `image` is a complete database image: it starts with the
+/// `SQLite` header, has a valid page size, and is exactly as many pages long
+/// as the header records. If `verify_page` is passed, it is called with
+/// every page and rejecting any of them rejects the image.
+pub fn validate_image(
+    image: &[u8],
+    verify_page: Option<VerifyPage<'_>>,
+) -> Result<ImageInfo, ImageError> {
+    if image.len() < HEADER_SIZE || &image[..16] != HEADER_MAGIC {
+        return Err(ImageError::BadMagic);
+    }
+    let be_u32 =
+        |offset: usize| u32::from_be_bytes(image[offset..offset + 4].try_into().unwrap()) as usize;
+
+    // a page size of 1 stands for 65536, which does not fit in two bytes
+    let page_size = match u16::from_be_bytes([image[16], image[17]]) {
+        1 => 65536,
+        size => size as usize,
+    };
+    if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+        return Err(ImageError::BadPageSize(page_size));
+    }
+
+    // the in-header page count is only valid if it was written by the same
+    // version of SQLite that last changed the file
+    let page_count = if be_u32(24) == be_u32(92) && be_u32(28) != 0 {
+        be_u32(28)
+    } else {
+        image.len().div_ceil(page_size)
+    };
+    let expected = page_size * page_count;
+    if image.len() != expected {
+        return Err(ImageError::SizeMismatch { expected, actual: image.len() });
+    }
+
+    if let Some(verify_page) = verify_page {
+        for (i, page) in image.chunks(page_size).enumerate() {
+            let page_no = i as u32 + 1;
+            if !verify_page(page_no, page) {
+                return Err(ImageError::BadChecksum { page: page_no });
+            }
+        }
+    }
+
+    Ok(ImageInfo { page_size, page_count })
+}
+
+/// Validates `image` and, if it is sound, writes it to `path` through `vfs`.
+///
+/// The image is first checked with [`validate_image`], then loaded into an
+/// in-memory database and checked with `pragma quick_check`, so a corrupt
+/// image is never written. It is then copied to `path` with `SQLite`'s
+/// online backup api over a connection through `vfs`, which takes the locks
+/// other connections expect and journals the write, so a crash part way
+/// through leaves the previous database to be rolled back to.
+///
+/// `vfs` must be the instance registered with this crate, such as the one
+/// returned by [`lookup`](crate::vfs::lookup), rather than a copy of it.
+#[cfg(feature = "static")]
+pub fn import<V: Vfs + 'static>(
+    vfs: &V,
+    path: &str,
+    image: &[u8],
+    verify_page: Option<VerifyPage<'_>>,
+) -> Result<ImageInfo, ImageError> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let info = validate_image(image, verify_page)?;
+    let vfs_name = crate::vfs::registered_name(vfs).ok_or(VfsError::Other(vars::SQLITE_MISUSE))?;
+
+    let src = Connection::open_in_memory().map_err(sqlite_err)?;
+    // the image is only read, and outlives the connection
+    let rc = unsafe {
+        crate::ffi::sqlite3_deserialize(
+            src.handle().cast(),
+            c"main".as_ptr(),
+            image.as_ptr().cast_mut(),
+            image.len() as i64,
+            image.len() as i64,
+            vars::SQLITE_DESERIALIZE_READONLY as c_uint,
+        )
+    };
+    if rc != vars::SQLITE_OK {
+        return Err(ImageError::Vfs(rc.into()));
+    }
+    quick_check(&src)?;
+
+    let dst = Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs_name.as_str(),
+    )
+    .map_err(sqlite_err)?;
+    // the backup is finished before either connection is closed
+    let rc = unsafe { copy_database(&src, &dst) };
+    if rc != vars::SQLITE_OK {
+        return Err(ImageError::Vfs(rc.into()));
+    }
+    Ok(info)
+}
+
+#[cfg(feature = "static")]
+fn sqlite_code(err: rusqlite::Error) -> VfsError {
+    err.sqlite_error()
+        .map_or(vars::SQLITE_ERROR, |err| err.extended_code)
+        .into()
+}
+
+#[cfg(feature = "static")]
+fn sqlite_err(err: rusqlite::Error) -> ImageError {
+    ImageError::Vfs(sqlite_code(err))
+}
+
+#[cfg(feature = "static")]
+fn quick_check(conn: &rusqlite::Connection) -> Result<(), ImageError> {
+    use alloc::vec::Vec;
+
+    let mut stmt = conn.prepare("pragma quick_check").map_err(sqlite_err)?;
+    let errors = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(sqlite_err)?;
+    if errors != ["ok"] {
+        return Err(ImageError::IntegrityCheck(errors.join("\n")));
+    }
+    Ok(())
+}
+
+/// The page layout of the database written by [`migrate`]. Settings left as
+/// None are copied from the source database.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageLayout {
+    /// A power of two between 512 and 65536.
+    pub page_size: Option<usize>,
+    /// The bytes reserved at the end of each page, such as for the checksum
+    /// or nonce of a page level shim. This can only grow: a smaller value
+    /// than the source database uses is ignored.
+    pub reserved_bytes: Option<u8>,
+}
+
+/// Rewrites the database at `src_path` on the vfs registered as `src_vfs` to
+/// a new database at `dst_path` on the vfs registered as `dst_vfs`, with the
+/// page layout given by `layout`. This is how existing data is moved onto a
+/// shim that needs a different page size or reserved bytes.
+///
+/// The copy is made with `VACUUM INTO` on a connection to the source, so it
+/// is a consistent snapshot which includes transactions still in the WAL.
+/// Pages are streamed to the destination through the page cache rather than
+/// loaded into memory at once. `dst_path` must not exist or must be empty.
+/// Returns the layout of the written database.
+#[cfg(feature = "static")]
+pub fn migrate(
+    src_vfs: &str,
+    src_path: &str,
+    dst_vfs: &str,
+    dst_path: &str,
+    layout: PageLayout,
+) -> Result<ImageInfo, ImageError> {
+    use core::ffi::c_void;
+    use rusqlite::{Connection, OpenFlags};
+
+    if let Some(page_size) = layout.page_size {
+        if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+            return Err(ImageError::BadPageSize(page_size));
+        }
+    }
+
+    // the uri flag lets VACUUM INTO write through another vfs
+    let conn = Connection::open_with_flags_and_vfs(
+        src_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        src_vfs,
+    )
+    .map_err(sqlite_err)?;
+    // on a database which already has content these only take effect when
+    // it is vacuumed
+    if let Some(page_size) = layout.page_size {
+        conn.execute_batch(&format!("pragma page_size = {page_size}"))
+            .map_err(sqlite_err)?;
+    }
+    if let Some(reserved_bytes) = layout.reserved_bytes {
+        let mut reserved_bytes = c_int::from(reserved_bytes);
+        let rc = unsafe {
+            crate::ffi::sqlite3_file_control(
+                conn.handle().cast(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_RESERVE_BYTES,
+                (&raw mut reserved_bytes).cast::<c_void>(),
+            )
+        };
+        if rc != vars::SQLITE_OK {
+            return Err(ImageError::Vfs(rc.into()));
+        }
+    }
+
+    let dst_uri = format!("file:{}?vfs={}", uri_escape(dst_path), uri_escape(dst_vfs));
+    conn.execute("vacuum into ?1", [dst_uri])
+        .map_err(sqlite_err)?;
+    drop(conn);
+
+    let conn =
+        Connection::open_with_flags_and_vfs(dst_path, OpenFlags::SQLITE_OPEN_READ_ONLY, dst_vfs)
+            .map_err(sqlite_err)?;
+    let page_size = conn
+        .query_row("pragma page_size", [], |row| row.get(0))
+        .map_err(sqlite_err)?;
+    let page_count = conn
+        .query_row("pragma page_count", [], |row| row.get(0))
+        .map_err(sqlite_err)?;
+    Ok(ImageInfo { page_size, page_count })
+}
+
+// escapes the characters which end the path or a value in a URI filename
+#[cfg(feature = "static")]
+fn uri_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | '?' | '#' | '&' | '=' => out.push_str(&format!("%{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::flags::OpenOpts;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::testing::{TempDir, TestVfs, os_vfs};
+    use crate::vfs::{RegisterOpts, UriParams, lookup, register_static};
+    use alloc::ffi::CString;
+    use alloc::vec::Vec;
+    use rusqlite::{Connection, OpenFlags};
+    use std::boxed::Box;
+
+    #[test]
+    fn backup() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = MockVfs::new(Box::new(NoopHooks));
+        register_static(
+            CString::new("mock_backup").unwrap(),
+            vfs.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_backup",
+        )?;
+        conn.execute("create table t (val blob)", [])?;
+        conn.execute("insert into t (val) values (zeroblob(20000))", [])?;
+        let page_size: usize = conn.query_row("pragma page_size", [], |row| row.get(0))?;
+        let page_count: usize = conn.query_row("pragma page_count", [], |row| row.get(0))?;
+
+        let mut image = Vec::new();
+        backup_consistent("mock_backup", "main.db", |buf| {
+            image.extend_from_slice(buf);
+            Ok(())
+        })
+        .map_err(|_| "backup failed")?;
+
+        assert_eq!(image.len(), page_size * page_count);
+        assert_eq!(&image[..16], b"SQLite format 3\0");
+
+        assert_eq!(
+            backup_consistent("mock_backup", "missing.db", |_| Ok(())),
+            Err(VfsError::CantOpen)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_hot_journal() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = MockVfs::new(Box::new(NoopHooks));
+        register_static(
+            CString::new("mock_backup_journal").unwrap(),
+            vfs.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_backup_journal",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        drop(conn);
+
+        // a journal left behind by a writer which crashed mid-transaction
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_JOURNAL | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut journal = vfs.open(Some("main.db-journal"), opts, UriParams::empty())?;
+        vfs.write(
+            &mut journal,
+            0,
+            &[0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7],
+        )?;
+        vfs.close(journal)?;
+
+        // SQLite deals with the journal before the copy is made
+        let mut image = Vec::new();
+        backup_consistent("mock_backup_journal", "main.db", |buf| {
+            image.extend_from_slice(buf);
+            Ok(())
+        })?;
+        validate_image(&image, None)?;
+        assert!(!vfs.access("main.db-journal", AccessFlags::Exists)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_wal() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = TestVfs::scoped_with("backup_wal", os_vfs()?)?;
+        let dir = TempDir::new("backup_wal")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            dir.file("main.db"),
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs.name(),
+        )?;
+        conn.execute_batch(
+            "pragma journal_mode = wal;
+            pragma wal_autocheckpoint = 0;
+            create table t (val int);
+            insert into t (val) values (1), (2);",
+        )?;
+
+        // the transactions are only in the WAL while conn is open
+        let mut image = Vec::new();
+        backup_consistent(vfs.name(), &dir.file("main.db"), |buf| {
+            image.extend_from_slice(buf);
+            Ok(())
+        })?;
+        validate_image(&image, None)?;
+        std::fs::write(dir.file("copy.db"), &image)?;
+        let copy = Connection::open_with_flags_and_vfs(
+            dir.file("copy.db"),
+            OpenFlags::SQLITE_OPEN_READ_WRITE,
+            vfs.name(),
+        )?;
+        let n: i64 = copy.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_image() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = MockVfs::new(Box::new(NoopHooks));
+        register_static(
+            CString::new("mock_import").unwrap(),
+            vfs.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let open = |path: &str| {
+            Connection::open_with_flags_and_vfs(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                "mock_import",
+            )
+        };
+
+        let conn = open("main.db")?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1), (2)", [])?;
+        let mut image = Vec::new();
+        backup_consistent("mock_import", "main.db", |buf| {
+            image.extend_from_slice(buf);
+            Ok(())
+        })?;
+
+        // the registered instance, not the clone kept here
+        let registered = lookup::<MockVfs>("mock_import").ok_or("vfs is not registered")?;
+        assert_eq!(
+            import(&vfs, "copy.db", &image, None),
+            Err(ImageError::Vfs(VfsError::Other(vars::SQLITE_MISUSE)))
+        );
+        let info = import(&*registered, "copy.db", &image, None)?;
+        assert_eq!(info.page_size * info.page_count, image.len());
+        let n: i64 = open("copy.db")?.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 3);
+
+        let mut bad_magic = image.clone();
+        bad_magic[0] = b's';
+        assert_eq!(validate_image(&bad_magic, None), Err(ImageError::BadMagic));
+        assert!(matches!(
+            validate_image(&image[..image.len() - 1], None),
+            Err(ImageError::SizeMismatch { .. })
+        ));
+        let reject_second: VerifyPage<'_> = &|page, _| page != 2;
+        assert_eq!(
+            validate_image(&image, Some(reject_second)),
+            Err(ImageError::BadChecksum { page: 2 })
+        );
+
+        // a damaged table page passes the header checks but not quick_check,
+        // and is never written to the destination
+        let mut corrupt = image.clone();
+        corrupt[info.page_size..].fill(0xff);
+        assert!(import(&*registered, "corrupt.db", &corrupt, None).is_err());
+        assert!(!vfs.access("corrupt.db", AccessFlags::Exists)?);
+
+        // importing over a database replaces it in a transaction
+        let conn = open("copy.db")?;
+        conn.execute("insert into t (val) values (3)", [])?;
+        import(&*registered, "copy.db", &image, None)?;
+        let n: i64 = conn.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_layout() -> Result<(), Box<dyn std::error::Error>> {
+        let dst = MockVfs::new(Box::new(NoopHooks));
+        register_static(
+            CString::new("mock_migrate_src").unwrap(),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        register_static(
+            CString::new("mock_migrate_dst").unwrap(),
+            dst.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_migrate_src",
+        )?;
+        conn.execute("create table t (val blob)", [])?;
+        conn.execute("insert into t (val) values (zeroblob(20000))", [])?;
+
+        let layout = PageLayout {
+            page_size: Some(8192),
+            reserved_bytes: Some(16),
+        };
+        let info = migrate(
+            "mock_migrate_src",
+            "main.db",
+            "mock_migrate_dst",
+            "copy.db",
+            layout,
+        )?;
+        assert_eq!(info.page_size, 8192);
+
+        let mut image = Vec::new();
+        backup_consistent("mock_migrate_dst", "copy.db", |buf| {
+            image.extend_from_slice(buf);
+            Ok(())
+        })?;
+        assert_eq!(validate_image(&image, None), Ok(info));
+        // the reserved bytes per page are recorded at offset 20 of the header
+        assert_eq!(image[20], 16);
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "copy.db",
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            "mock_migrate_dst",
+        )?;
+        let len: i64 = conn.query_row("select length(val) from t", [], |row| row.get(0))?;
+        assert_eq!(len, 20000);
+
+        let bad_layout = PageLayout {
+            page_size: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            migrate(
+                "mock_migrate_src",
+                "main.db",
+                "mock_migrate_dst",
+                "bad.db",
+                bad_layout
+            ),
+            Err(ImageError::BadPageSize(1000))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,459 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::flags::{LockLevel, OpenKind, OpenOpts};
+use crate::logger::{SqliteLogLevel, SqliteLogger};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{Pragma, PragmaErr, UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+/// The number of reserved bytes at the end of each page which hold its
+/// checksum.
+pub const CHECKSUM_LEN: usize = 8;
+
+/// A Vfs wrapper which stores a checksum in the last 8 bytes of every page of
+/// a database, and verifies it when the page is read, like `SQLite`'s
+/// cksumvfs extension and compatible with the databases it writes. A page
+/// which fails verification is reported in the `SQLite` log and fails the
+/// read with `SQLITE_IOERR_DATA`.
+///
+/// Checksums are only kept for databases with 8 reserved bytes per page,
+/// which are set with `SQLITE_FCNTL_RESERVE_BYTES` before the database is
+/// created, or followed by a `vacuum` to rewrite an existing one. Pages
+/// written to a WAL file are covered by `SQLite`'s own frame checksums, so
+/// they get a checksum when they are checkpointed into the database.
+///
+/// `pragma checksum_verification` reports whether reads are verified, and
+/// turns verification off or back on for the connection. A [`Scrubber`]
+/// verifies pages which are not being read, to find damage before a query
+/// does.
+///
+/// ```no_run
+/// # use std::ffi::c_int;
+/// # use rusqlite::{Connection, ffi};
+/// # use sqlite_plugin::checksum::ChecksumVfs;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, register_static};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// register_static(c"checksum".to_owned(), ChecksumVfs::new(BaseVfs::find(None)?), RegisterOpts::default())?;
+/// let conn = Connection::open_with_flags_and_vfs("main.db", Default::default(), "checksum")?;
+/// let mut reserve: c_int = 8;
+/// unsafe {
+///     let db = conn.handle();
+///     ffi::sqlite3_file_control(db, c"main".as_ptr(), ffi::SQLITE_FCNTL_RESERVE_BYTES, (&raw mut reserve).cast());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChecksumVfs<V> {
+    vfs: V,
+    logger: spin::Mutex<Option<SqliteLogger>>,
+}
+
+pub struct ChecksumHandle<H> {
+    inner: H,
+    name: Option<String>,
+    // None for files other than main databases
+    state: Option<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // whether the database has room for checksums, as of its last header
+    enabled: bool,
+    verify: bool,
+}
+
+impl State {
+    // updates the state from the first `data` bytes of the database
+    fn header(&mut self, data: &[u8]) {
+        if data.len() >= 100 && data.starts_with(b"SQLite format 3\0") {
+            self.enabled = data[20] as usize == CHECKSUM_LEN;
+            self.verify = self.enabled;
+        }
+    }
+}
+
+// whether `len` is a valid page size, so that the data is a page
+fn is_page(len: usize) -> bool {
+    len >= 512 && len.is_power_of_two()
+}
+
+/// The checksum of a page, computed over all but its last 8 bytes the way
+/// cksumvfs does.
+pub fn page_checksum(page: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let data = &page[..page.len() - CHECKSUM_LEN];
+    let (mut s1, mut s2) = (0u32, 0u32);
+    for pair in data.chunks_exact(8) {
+        let a = u32::from_le_bytes([pair[0], pair[1], pair[2], pair[3]]);
+        let b = u32::from_le_bytes([pair[4], pair[5], pair[6], pair[7]]);
+        s1 = s1.wrapping_add(a).wrapping_add(s2);
+        s2 = s2.wrapping_add(b).wrapping_add(s1);
+    }
+    let mut out = [0; CHECKSUM_LEN];
+    // stored in native order by cksumvfs, which swaps them to little endian
+    // on big endian machines
+    out[..4].copy_from_slice(&s1.to_le_bytes());
+    out[4..].copy_from_slice(&s2.to_le_bytes());
+    out
+}
+
+impl<H: VfsHandle> VfsHandle for ChecksumHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for ChecksumHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> ChecksumVfs<V> {
+    pub fn new(vfs: V) -> Self {
+        Self { vfs, logger: spin::Mutex::new(None) }
+    }
+
+    fn log_fault(&self, name: &str, offset: usize) {
+        if let Some(logger) = *self.logger.lock() {
+            let msg = format!("checksum fault offset {offset} of \"{name}\"");
+            logger.log(SqliteLogLevel::Error, msg.as_bytes());
+        }
+    }
+
+    // verifies up to `max_pages` pages of the database at `path` from page
+    // `next`, holding a shared lock so that no writer changes them meanwhile
+    fn scrub(
+        &self,
+        path: &str,
+        next: u32,
+        max_pages: usize,
+        report: &mut ScrubReport,
+    ) -> VfsResult<u32> {
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READONLY);
+        let mut handle = self.vfs.open(Some(path), opts, UriParams::empty())?;
+        let result = self
+            .vfs
+            .lock(&mut handle, LockLevel::Shared)
+            .and_then(|()| {
+                let result = self.scrub_locked(&mut handle, path, next, max_pages, report);
+                self.vfs.unlock(&mut handle, LockLevel::Unlocked)?;
+                result
+            });
+        self.vfs.close(handle)?;
+        result
+    }
+
+    fn scrub_locked(
+        &self,
+        handle: &mut V::Handle,
+        path: &str,
+        mut next: u32,
+        max_pages: usize,
+        report: &mut ScrubReport,
+    ) -> VfsResult<u32> {
+        let mut header = [0; 100];
+        if self.vfs.read(handle, 0, &mut header)? < header.len()
+            || !header.starts_with(b"SQLite format 3\0")
+            || header[20] as usize != CHECKSUM_LEN
+        {
+            // an empty database, or one without checksums
+            report.done = true;
+            return Ok(1);
+        }
+        let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+            1 => 65536,
+            size => size as usize,
+        };
+        if !is_page(page_size) {
+            return Err(VfsError::Other(vars::SQLITE_NOTADB));
+        }
+        let pages = self.vfs.file_size(handle)? / page_size;
+        let mut page = vec![0; page_size];
+        while report.checked < max_pages {
+            if next as usize > pages {
+                report.done = true;
+                return Ok(1);
+            }
+            let offset = (next as usize - 1) * page_size;
+            if self.vfs.read(handle, offset, &mut page)? < page_size {
+                report.done = true;
+                return Ok(1);
+            }
+            if page[page_size - CHECKSUM_LEN..] != page_checksum(&page) {
+                self.log_fault(path, offset);
+                report.faults.push(next);
+            }
+            report.checked += 1;
+            next += 1;
+        }
+        Ok(next)
+    }
+}
+
+/// Walks the pages of a database on a [`ChecksumVfs`] a few at a time,
+/// verifying their checksums, so that damage to pages no query has read is
+/// found and reported before it is relied on. Call [`Scrubber::step`]
+/// whenever the database is idle, such as from a timer; each step picks up
+/// where the last one stopped, and starts over once the whole database has
+/// been checked.
+///
+/// Each step holds a shared lock on the database, which keeps writers from
+/// committing until it is done. In WAL mode, a checkpoint may still copy
+/// pages into the database during a step, and a page caught half copied is
+/// reported as a fault; a real fault is reported again by the next pass.
+///
+/// ```no_run
+/// # use sqlite_plugin::checksum::{ChecksumVfs, Scrubber};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::VfsResult;
+/// fn scrub_some(vfs: &ChecksumVfs<BaseVfs>, scrubber: &mut Scrubber) -> VfsResult<()> {
+///     let report = scrubber.step(vfs, 64)?;
+///     for page in report.faults {
+///         eprintln!("page {page} of main.db is damaged");
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    path: String,
+    // the next page to verify, numbered from 1
+    next: u32,
+}
+
+/// What one [`Scrubber::step`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub checked: usize,
+    /// The pages whose checksum didn't match, numbered from 1 as by
+    /// `SQLite`. Each is also reported in the `SQLite` log.
+    pub faults: Vec<u32>,
+    /// The step reached the end of the database, so the next one starts
+    /// over from the first page.
+    pub done: bool,
+}
+
+impl Scrubber {
+    /// Scrubs the database at `path`, as passed to `SQLite` when opening it.
+    pub fn new(path: &str) -> Self {
+        Self { path: path.into(), next: 1 }
+    }
+
+    /// Verifies up to `max_pages` pages. This fails with `SQLITE_BUSY` while
+    /// a writer holds a lock which keeps readers out, in which case the step
+    /// can be retried later.
+    pub fn step<V: Vfs>(
+        &mut self,
+        vfs: &ChecksumVfs<V>,
+        max_pages: usize,
+    ) -> VfsResult<ScrubReport> {
+        let mut report = ScrubReport::default();
+        self.next = vfs.scrub(&self.path, self.next, max_pages, &mut report)?;
+        Ok(report)
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for ChecksumVfs<V> {
+    type Inner = V;
+    type Handle = ChecksumHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn register_logger(&self, logger: SqliteLogger) {
+        *self.logger.lock() = Some(logger);
+        self.vfs.register_logger(logger)
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let inner = self.vfs.open(path, opts, params)?;
+        let state = (opts.kind() == OpenKind::MainDb).then(State::default);
+        Ok(ChecksumHandle { inner, name: path.map(Into::into), state })
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let Some(state) = &mut handle.state else {
+            return self.vfs.write(&mut handle.inner, offset, data);
+        };
+        if offset == 0 {
+            state.header(data);
+        }
+        if !state.enabled || !is_page(data.len()) {
+            return self.vfs.write(&mut handle.inner, offset, data);
+        }
+        let mut page: Vec<u8> = data.into();
+        let checksum = page_checksum(&page);
+        let len = page.len();
+        page[len - CHECKSUM_LEN..].copy_from_slice(&checksum);
+        self.vfs.write(&mut handle.inner, offset, &page)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let n = self.vfs.read(&mut handle.inner, offset, data)?;
+        let Some(state) = &mut handle.state else {
+            return Ok(n);
+        };
+        if offset == 0 {
+            state.header(&data[..n]);
+        }
+        // a short read is of a page past the end of the database
+        if !state.verify || !is_page(data.len()) || n < data.len() {
+            return Ok(n);
+        }
+        let len = data.len();
+        if data[len - CHECKSUM_LEN..] != page_checksum(data) {
+            self.log_fault(handle.name.as_deref().unwrap_or("<anonymous>"), offset);
+            return Err(VfsError::Other(vars::SQLITE_IOERR_DATA));
+        }
+        Ok(n)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        let Some(state) = &mut handle.state else {
+            return self.vfs.pragma(&mut handle.inner, pragma);
+        };
+        if !pragma.name.eq_ignore_ascii_case("checksum_verification") {
+            return self.vfs.pragma(&mut handle.inner, pragma);
+        }
+        if let Some(verify) = pragma.as_bool()? {
+            // there is nothing to verify without checksums
+            state.verify = verify && state.enabled;
+        }
+        Ok(Some(if state.verify { "1" } else { "0" }.into()))
+    }
+
+    fn supports_mmap(&self) -> bool {
+        // mapped pages would be used without being verified
+        false
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for ChecksumVfs<V>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::shim::BaseVfs;
+    use crate::testing::{TempDir, os_vfs};
+    use crate::vfs::{RegisterOpts, lookup, register_static};
+    use core::ffi::c_int;
+    use rusqlite::{Connection, OpenFlags, ffi};
+
+    #[test]
+    fn cksumvfs_vector() {
+        // the checksum cksumvfs computes for a 512 byte page of i * 7
+        let mut page: Vec<u8> = (0..512).map(|i| (i * 7) as u8).collect();
+        let checksum = [0xa8, 0x9a, 0x30, 0x0a, 0xb8, 0xfa, 0x53, 0x4e];
+        assert_eq!(page_checksum(&page), checksum);
+        // the stored checksum is not part of what it covers
+        page[504..].fill(0xff);
+        assert_eq!(page_checksum(&page), checksum);
+    }
+
+    #[test]
+    fn checksums() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let base = os_vfs()?;
+        register_static(
+            c"checksum_test".into(),
+            ChecksumVfs::new(base),
+            RegisterOpts::default(),
+        )?;
+
+        let dir = TempDir::new("checksum")?;
+        let path = dir.file("main.db");
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let open = || Connection::open_with_flags_and_vfs(&path, flags, "checksum_test");
+        let count = |conn: &Connection| -> rusqlite::Result<i64> {
+            conn.query_row("select count(*) from t", [], |row| row.get(0))
+        };
+
+        let conn = open()?;
+        let mut reserve: c_int = CHECKSUM_LEN as c_int;
+        // SAFETY: the connection is open and the argument is an int
+        let rc = unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                ffi::SQLITE_FCNTL_RESERVE_BYTES,
+                (&raw mut reserve).cast(),
+            )
+        };
+        assert_eq!(rc, ffi::SQLITE_OK);
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.execute_batch(
+            "create table t(x);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 100)
+            insert into t select randomblob(100) from n;",
+        )?;
+        let verify: String =
+            conn.query_row("pragma checksum_verification", [], |row| row.get(0))?;
+        assert_eq!(verify, "1");
+        // closing checkpoints the wal, which checksums its pages
+        drop(conn);
+
+        let mut raw = std::fs::read(&path)?;
+        assert_eq!(raw[20] as usize, CHECKSUM_LEN);
+        for page in raw.chunks_exact(4096) {
+            assert_eq!(page[4096 - CHECKSUM_LEN..], page_checksum(page));
+        }
+        assert_eq!(count(&open()?)?, 100);
+
+        // damage the checksum of the table's root page
+        raw[2 * 4096 - 1] ^= 1;
+        std::fs::write(&path, &raw)?;
+
+        // the scrubber finds it a few pages at a time, then starts over
+        let vfs = lookup::<ChecksumVfs<BaseVfs>>("checksum_test").ok_or("vfs is not registered")?;
+        let mut scrubber = Scrubber::new(&path);
+        let pages = raw.len() / 4096;
+        let mut report = scrubber.step(&vfs, 1)?;
+        assert_eq!(
+            report,
+            ScrubReport {
+                checked: 1,
+                faults: Vec::new(),
+                done: false
+            }
+        );
+        report = scrubber.step(&vfs, pages)?;
+        assert_eq!(report.checked, pages - 1);
+        assert_eq!(report.faults, [2]);
+        assert!(report.done);
+        assert_eq!(scrubber.step(&vfs, 2)?.faults, [2]);
+
+        let conn = open()?;
+        let err = count(&conn).unwrap_err();
+        assert_eq!(
+            err.sqlite_error().map(|e| e.extended_code),
+            Some(vars::SQLITE_IOERR_DATA)
+        );
+        conn.pragma_update(None, "checksum_verification", "off")?;
+        assert_eq!(count(&conn)?, 100);
+        Ok(())
+    }
+}
@@ -0,0 +1,741 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::flags::{OpenKind, OpenOpts};
+use crate::pool::{BufferPool, PoolBuf, PoolStats};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{
+    FileControlOp, Pragma, PragmaErr, UriParams, Vfs, VfsError, VfsHandle, VfsResult,
+};
+
+/// The size of the blocks databases are compressed in. Writes which cover
+/// part of a block have to read and rewrite all of it, so databases should
+/// use this page size.
+pub const BLOCK_SIZE: usize = 4096;
+
+// the start of every compressed database
+const MAGIC: &[u8; 16] = b"compressed-vfs1\0";
+
+// a compressed database is MAGIC followed by an append-only log of records,
+// each a header followed by `stored_len` bytes of data:
+//   kind u32, stored_len u32, offset u64, len u32, data_sum u32,
+//   reserved u32, header_sum u32
+// all little endian. A block record holds the `len` bytes of the block at
+// `offset`, and a truncate record sets the size of the database to `offset`.
+const HEADER_LEN: usize = 32;
+
+const KIND_COMPRESSED: u32 = 1;
+const KIND_RAW: u32 = 2;
+const KIND_TRUNCATE: u32 = 3;
+
+/// A compression algorithm which a [`CompressedVfs`] compresses blocks with.
+/// `Lz4` and `Zstd` are included behind the `lz4` and `zstd` features;
+/// other algorithms are adapted by implementing this over their crate.
+pub trait Codec: Send + Sync {
+    /// Compresses `data`, a block of at most [`BLOCK_SIZE`] bytes, appending
+    /// the result to `out`. Blocks which don't get smaller are stored as is.
+    fn compress(&self, data: &[u8], out: &mut Vec<u8>);
+
+    /// Decompresses `data` into `out`, which is as long as the original
+    /// block.
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> VfsResult<()>;
+}
+
+/// A [`Codec`] which compresses blocks in the LZ4 block format with
+/// `lz4_flex`. Enable the `lz4` feature to use it.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn compress(&self, data: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.resize(
+            start + lz4_flex::block::get_maximum_output_size(data.len()),
+            0,
+        );
+        match lz4_flex::block::compress_into(data, &mut out[start..]) {
+            Ok(len) => out.truncate(start + len),
+            // never smaller, so the block is stored as is
+            Err(_) => out.truncate(start + data.len()),
+        }
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> VfsResult<()> {
+        match lz4_flex::block::decompress_into(data, out) {
+            Ok(len) if len == out.len() => Ok(()),
+            _ => Err(VfsError::Io),
+        }
+    }
+}
+
+/// A [`Codec`] which compresses blocks with zstd at a compression level,
+/// by default `zstd::DEFAULT_COMPRESSION_LEVEL`. Enable the `zstd` feature
+/// to use it.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct Zstd {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Zstd {
+    pub fn new(level: i32) -> Self {
+        Self { level }
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn compress(&self, data: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.resize(start + zstd::zstd_safe::compress_bound(data.len()), 0);
+        match zstd::bulk::compress_to_buffer(data, &mut out[start..], self.level) {
+            Ok(len) => out.truncate(start + len),
+            // never smaller, so the block is stored as is
+            Err(_) => out.truncate(start + data.len()),
+        }
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> VfsResult<()> {
+        match zstd::bulk::decompress_to_buffer(data, out) {
+            Ok(len) if len == out.len() => Ok(()),
+            _ => Err(VfsError::Io),
+        }
+    }
+}
+
+/// A Vfs wrapper which stores main databases compressed, for databases which
+/// are read-only or mostly appended to.
+///
+/// Data is compressed a block at a time. A write never changes a database in
+/// place: it appends the new version of each block it touches, and an index
+/// of where the latest version of every block is stored is rebuilt when a
+/// database is opened and kept up to date as other connections append. Space
+/// taken by old versions is not reclaimed, so a database which is rewritten
+/// often keeps growing; copy it with `vacuum into` to compact it.
+///
+/// Journals, WAL files and temporary files are passed through uncompressed,
+/// and memory mapping is disabled. `pragma compression_stats` reports the
+/// size of a database, the space it takes and the ratio between the two.
+///
+/// ```no_run
+/// # use rusqlite::Connection;
+/// # use sqlite_plugin::compress::{Codec, CompressedVfs};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, register_static};
+/// fn archive(conn: &Connection, codec: impl Codec + 'static) -> Result<(), Box<dyn std::error::Error>> {
+///     let vfs = CompressedVfs::new(BaseVfs::find(None)?, codec);
+///     register_static(c"compressed".to_owned(), vfs, RegisterOpts::default())?;
+///     conn.execute("vacuum into 'file:archive.db?vfs=compressed'", [])?;
+///     Ok(())
+/// }
+/// ```
+pub struct CompressedVfs<V, C> {
+    vfs: V,
+    codec: C,
+    // scratch space for the blocks and records being read and written
+    pool: BufferPool,
+}
+
+pub struct CompressedHandle<H> {
+    inner: H,
+    // None for files other than main databases
+    index: Option<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    blocks: BTreeMap<usize, Block>,
+    // the size of the uncompressed database
+    size: usize,
+    // the end of the valid records in the file
+    end: usize,
+    // the size of the file when it was last read, which is past `end` if a
+    // record was torn by a crash while it was appended
+    file_size: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    // where the data is stored in the file
+    offset: usize,
+    stored_len: usize,
+    raw: bool,
+    data_sum: u32,
+    len: usize,
+    // how much of the block is within the database, after truncates
+    visible: usize,
+}
+
+struct RecordHeader {
+    kind: u32,
+    stored_len: u32,
+    offset: u64,
+    len: u32,
+    data_sum: u32,
+}
+
+impl RecordHeader {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[0..4].copy_from_slice(&self.kind.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.stored_len.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.len.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.data_sum.to_le_bytes());
+        let header_sum = checksum(&buf[..28]);
+        buf[28..32].copy_from_slice(&header_sum.to_le_bytes());
+        buf
+    }
+
+    // returns None for a header which was torn or never written
+    fn decode(buf: &[u8; HEADER_LEN]) -> Option<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        if u32_at(28) != checksum(&buf[..28]) {
+            return None;
+        }
+        let header = Self {
+            kind: u32_at(0),
+            stored_len: u32_at(4),
+            offset: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            len: u32_at(16),
+            data_sum: u32_at(20),
+        };
+        matches!(header.kind, KIND_COMPRESSED | KIND_RAW | KIND_TRUNCATE).then_some(header)
+    }
+}
+
+// 32 bit FNV-1a, to detect torn records
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |sum, &b| {
+        (sum ^ u32::from(b)).wrapping_mul(0x01000193)
+    })
+}
+
+impl Index {
+    fn apply(&mut self, header: &RecordHeader, data_offset: usize) -> VfsResult<()> {
+        let offset = usize::try_from(header.offset).map_err(|_| VfsError::Io)?;
+        if header.kind == KIND_TRUNCATE {
+            self.size = offset;
+            self.blocks.retain(|&block, _| block * BLOCK_SIZE < offset);
+            if let Some(last) = self.blocks.get_mut(&(offset / BLOCK_SIZE)) {
+                last.visible = last.visible.min(offset % BLOCK_SIZE);
+            }
+            return Ok(());
+        }
+        let len = header.len as usize;
+        if offset % BLOCK_SIZE != 0 || len > BLOCK_SIZE {
+            return Err(VfsError::Other(vars::SQLITE_IOERR_CORRUPTFS));
+        }
+        self.blocks.insert(
+            offset / BLOCK_SIZE,
+            Block {
+                offset: data_offset,
+                stored_len: header.stored_len as usize,
+                raw: header.kind == KIND_RAW,
+                data_sum: header.data_sum,
+                len,
+                visible: len,
+            },
+        );
+        self.size = self.size.max(offset + len);
+        Ok(())
+    }
+
+    // the space taken by the latest version of every block
+    fn live_size(&self) -> usize {
+        let records: usize = self
+            .blocks
+            .values()
+            .map(|block| HEADER_LEN + block.stored_len)
+            .sum();
+        MAGIC.len() + records
+    }
+}
+
+impl<H: VfsHandle> VfsHandle for CompressedHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for CompressedHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs, C: Codec> CompressedVfs<V, C> {
+    pub fn new(vfs: V, codec: C) -> Self {
+        Self { vfs, codec, pool: BufferPool::default() }
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// How the scratch buffers of reads and writes have been served.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    fn read_exact(&self, inner: &mut V::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<()> {
+        if self.vfs.read(inner, offset, buf)? < buf.len() {
+            return Err(VfsError::Other(vars::SQLITE_IOERR_SHORT_READ));
+        }
+        Ok(())
+    }
+
+    // reads the records appended since the index was last brought up to date
+    fn refresh(&self, inner: &mut V::Handle, index: &mut Index) -> VfsResult<()> {
+        let file_size = self.vfs.file_size(inner)?;
+        if file_size == index.file_size && file_size == index.end {
+            return Ok(());
+        }
+        if file_size < index.end {
+            // the file was replaced, so start over
+            *index = Index::default();
+        }
+        index.file_size = file_size;
+        if index.end == 0 {
+            if file_size == 0 {
+                return Ok(());
+            }
+            let mut magic = [0; MAGIC.len()];
+            if file_size < MAGIC.len() {
+                return Err(VfsError::Other(vars::SQLITE_NOTADB));
+            }
+            self.read_exact(inner, 0, &mut magic)?;
+            if &magic != MAGIC {
+                return Err(VfsError::Other(vars::SQLITE_NOTADB));
+            }
+            index.end = MAGIC.len();
+        }
+
+        let mut buf = [0; HEADER_LEN];
+        while index.end + HEADER_LEN <= file_size {
+            self.read_exact(inner, index.end, &mut buf)?;
+            let Some(header) = RecordHeader::decode(&buf) else {
+                break;
+            };
+            let data_offset = index.end + HEADER_LEN;
+            let next = data_offset + header.stored_len as usize;
+            if next > file_size {
+                break;
+            }
+            index.apply(&header, data_offset)?;
+            index.end = next;
+        }
+        Ok(())
+    }
+
+    fn append(
+        &self,
+        inner: &mut V::Handle,
+        index: &mut Index,
+        header: RecordHeader,
+        data: &[u8],
+    ) -> VfsResult<()> {
+        if index.end == 0 {
+            self.vfs.write(inner, 0, MAGIC)?;
+            index.end = MAGIC.len();
+        }
+        if index.file_size > index.end {
+            // drop a torn record, so that it isn't read as part of this one
+            self.vfs.truncate(inner, index.end)?;
+        }
+        let mut record = self.pool.get(HEADER_LEN + data.len());
+        record[..HEADER_LEN].copy_from_slice(&header.encode());
+        record[HEADER_LEN..].copy_from_slice(data);
+        self.vfs.write(inner, index.end, &record)?;
+        index.apply(&header, index.end + HEADER_LEN)?;
+        index.end += record.len();
+        index.file_size = index.end;
+        Ok(())
+    }
+
+    // the contents of `block`, padded with zeros to BLOCK_SIZE
+    fn read_block(
+        &self,
+        inner: &mut V::Handle,
+        index: &Index,
+        block: usize,
+    ) -> VfsResult<PoolBuf<'_>> {
+        let mut buf = self.pool.get(BLOCK_SIZE);
+        let Some(block) = index.blocks.get(&block) else {
+            return Ok(buf);
+        };
+        let mut data = self.pool.get(block.stored_len);
+        self.read_exact(inner, block.offset, &mut data)?;
+        if checksum(&data) != block.data_sum {
+            return Err(VfsError::Other(vars::SQLITE_IOERR_CORRUPTFS));
+        }
+        if block.raw {
+            buf[..block.len].copy_from_slice(&data);
+        } else {
+            self.codec.decompress(&data, &mut buf[..block.len])?;
+        }
+        buf[block.visible..].fill(0);
+        Ok(buf)
+    }
+}
+
+fn stats(index: &Index) -> String {
+    let ratio = if index.end == 0 {
+        0.0
+    } else {
+        index.size as f64 / index.end as f64
+    };
+    format!(
+        "size={} stored={} live={} ratio={ratio:.2}",
+        index.size,
+        index.end,
+        index.live_size()
+    )
+}
+
+impl<V: Vfs, C: Codec> shim::VfsShim for CompressedVfs<V, C> {
+    type Inner = V;
+    type Handle = CompressedHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let mut inner = self.vfs.open(path, opts, params)?;
+        if opts.kind() != OpenKind::MainDb {
+            return Ok(CompressedHandle { inner, index: None });
+        }
+        let mut index = Index::default();
+        if let Err(err) = self.refresh(&mut inner, &mut index) {
+            let _ = self.vfs.close(inner);
+            return Err(err);
+        }
+        Ok(CompressedHandle { inner, index: Some(index) })
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        let Some(index) = &mut handle.index else {
+            return self.vfs.file_size(&mut handle.inner);
+        };
+        self.refresh(&mut handle.inner, index)?;
+        Ok(index.size)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        let Some(index) = &mut handle.index else {
+            return self.vfs.truncate(&mut handle.inner, size);
+        };
+        self.refresh(&mut handle.inner, index)?;
+        if size == index.size {
+            return Ok(());
+        }
+        let header = RecordHeader {
+            kind: KIND_TRUNCATE,
+            stored_len: 0,
+            offset: size as u64,
+            len: 0,
+            data_sum: checksum(&[]),
+        };
+        self.append(&mut handle.inner, index, header, &[])
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let Some(index) = &mut handle.index else {
+            return self.vfs.write(&mut handle.inner, offset, data);
+        };
+        self.refresh(&mut handle.inner, index)?;
+        let end = offset + data.len();
+        let new_size = index.size.max(end);
+
+        // blocks read as zeros past the end of the database, so blocks in a
+        // gap before `offset` don't have to be written
+        let mut start = offset / BLOCK_SIZE * BLOCK_SIZE;
+        let mut compressed = self.pool.get(0);
+        while start < end {
+            let len = BLOCK_SIZE.min(new_size - start);
+            let covered = offset <= start && end >= start + len;
+            let mut block = if covered {
+                self.pool.get(BLOCK_SIZE)
+            } else {
+                self.read_block(&mut handle.inner, index, start / BLOCK_SIZE)?
+            };
+            let from = offset.max(start);
+            let to = end.min(start + len);
+            block[from - start..to - start].copy_from_slice(&data[from - offset..to - offset]);
+            let block = &block[..len];
+
+            compressed.clear();
+            self.codec.compress(block, &mut compressed);
+            let (kind, stored) = if compressed.len() < len {
+                (KIND_COMPRESSED, &compressed[..])
+            } else {
+                (KIND_RAW, block)
+            };
+            let header = RecordHeader {
+                kind,
+                stored_len: stored.len() as u32,
+                offset: start as u64,
+                len: len as u32,
+                data_sum: checksum(stored),
+            };
+            self.append(&mut handle.inner, index, header, stored)?;
+            start += BLOCK_SIZE;
+        }
+        Ok(data.len())
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let Some(index) = &mut handle.index else {
+            return self.vfs.read(&mut handle.inner, offset, data);
+        };
+        self.refresh(&mut handle.inner, index)?;
+        if offset >= index.size {
+            return Ok(0);
+        }
+        let end = index.size.min(offset + data.len());
+
+        let mut start = offset / BLOCK_SIZE * BLOCK_SIZE;
+        while start < end {
+            let block = self.read_block(&mut handle.inner, index, start / BLOCK_SIZE)?;
+            let from = offset.max(start);
+            let to = end.min(start + BLOCK_SIZE);
+            data[from - offset..to - offset].copy_from_slice(&block[from - start..to - start]);
+            start += BLOCK_SIZE;
+        }
+        Ok(end - offset)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        let Some(index) = &mut handle.index else {
+            return self.vfs.pragma(&mut handle.inner, pragma);
+        };
+        if !pragma.name.eq_ignore_ascii_case("compression_stats") {
+            return self.vfs.pragma(&mut handle.inner, pragma);
+        }
+        if !pragma.is_query() {
+            let msg = format!("{} is read only", pragma.name);
+            return Err(PragmaErr::Fail(vars::SQLITE_ERROR, Some(msg)));
+        }
+        self.refresh(&mut handle.inner, index)
+            .map_err(|err| PragmaErr::Fail(err.code(vars::SQLITE_IOERR_READ), None))?;
+        Ok(Some(stats(index)))
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        let characteristics = self.vfs.device_characteristics(&mut handle.inner);
+        if handle.index.is_none() {
+            return characteristics;
+        }
+        // a write is appended as a record per block
+        characteristics
+            & !(vars::SQLITE_IOCAP_ATOMIC
+                | vars::SQLITE_IOCAP_ATOMIC512
+                | vars::SQLITE_IOCAP_ATOMIC1K
+                | vars::SQLITE_IOCAP_ATOMIC2K
+                | vars::SQLITE_IOCAP_ATOMIC4K
+                | vars::SQLITE_IOCAP_ATOMIC8K
+                | vars::SQLITE_IOCAP_ATOMIC16K
+                | vars::SQLITE_IOCAP_ATOMIC32K
+                | vars::SQLITE_IOCAP_ATOMIC64K)
+    }
+
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        match op {
+            // the wrapped vfs would grow the file past the end of the records
+            FileControlOp::SizeHint(_) | FileControlOp::ChunkSize(_) if handle.index.is_some() => {
+                Ok(())
+            }
+            op => self.vfs.file_control(&mut handle.inner, op),
+        }
+    }
+
+    fn supports_mmap(&self) -> bool {
+        // mapped pages would be read without being decompressed
+        false
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs, C: Codec] for CompressedVfs<V, C>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{TempDir, TestVfs, os_vfs};
+    use alloc::vec;
+    use rusqlite::{Connection, OpenFlags};
+    use std::io::Write;
+
+    // run length encoding, as (count, byte) pairs
+    struct Rle;
+
+    impl Codec for Rle {
+        fn compress(&self, data: &[u8], out: &mut Vec<u8>) {
+            for run in data.chunk_by(|a, b| a == b) {
+                for chunk in run.chunks(255) {
+                    out.extend_from_slice(&[chunk.len() as u8, chunk[0]]);
+                }
+            }
+        }
+
+        fn decompress(&self, data: &[u8], out: &mut [u8]) -> VfsResult<()> {
+            let mut at = 0;
+            for pair in data.chunks_exact(2) {
+                let run = out.get_mut(at..at + pair[0] as usize).ok_or(VfsError::Io)?;
+                run.fill(pair[1]);
+                at += run.len();
+            }
+            if at == out.len() {
+                Ok(())
+            } else {
+                Err(VfsError::Io)
+            }
+        }
+    }
+
+    // runs a workload through a vfs compressing with `codec`
+    fn check_codec<C: Codec + 'static>(
+        codec: C,
+    ) -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let vfs = TestVfs::scoped_with("compressed", CompressedVfs::new(os_vfs()?, codec))?;
+        let dir = TempDir::new("compress")?;
+        let path = dir.file("main.db");
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let open = || Connection::open_with_flags_and_vfs(&path, flags, vfs.name());
+        let sum = |conn: &Connection| -> rusqlite::Result<i64> {
+            conn.query_row("select sum(length(x)) from t", [], |row| row.get(0))
+        };
+
+        let conn = open()?;
+        conn.execute_batch(
+            "create table t(x);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 100)
+            insert into t select zeroblob(1000) from n;",
+        )?;
+        let stats: String = conn.query_row("pragma compression_stats", [], |row| row.get(0))?;
+        let ratio: f64 = stats.rsplit_once("ratio=").ok_or("no ratio")?.1.parse()?;
+        assert!(ratio > 2.0, "{stats}");
+        let raw = std::fs::read(&path)?;
+        assert!(raw.starts_with(MAGIC));
+
+        // another connection sees the blocks this one appends
+        let reader = open()?;
+        assert_eq!(sum(&reader)?, 100_000);
+        conn.execute("delete from t where rowid > 50", [])?;
+        assert_eq!(sum(&reader)?, 50_000);
+        drop((conn, reader));
+
+        // a record torn by a crash is ignored, and dropped by the next write
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[7; 40])?;
+        let conn = open()?;
+        assert_eq!(sum(&conn)?, 50_000);
+        conn.execute("insert into t values (zeroblob(10))", [])?;
+        drop(conn);
+        assert_eq!(sum(&open()?)?, 50_010);
+        Ok(())
+    }
+
+    #[test]
+    fn compressed() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        check_blocks(&Rle)?;
+        check_codec(Rle)
+    }
+
+    // blocks round trip, including short ones which no codec makes smaller
+    fn check_blocks<C: Codec>(codec: &C) -> Result<(), VfsError> {
+        let mut text = Vec::new();
+        for i in 0..BLOCK_SIZE / 16 {
+            text.extend_from_slice(format!("row {:>11}\n", i % 40).as_bytes());
+        }
+        let noise: Vec<u8> = (0..BLOCK_SIZE as u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        for data in [&text[..], &noise, &[0; BLOCK_SIZE], b"short", &[]] {
+            let mut compressed = vec![7];
+            codec.compress(data, &mut compressed);
+            assert_eq!(compressed[0], 7);
+            let mut out = vec![0; data.len()];
+            codec.decompress(&compressed[1..], &mut out)?;
+            assert_eq!(out, data);
+        }
+
+        // blocks which decompress to the wrong length fail
+        let mut compressed = Vec::new();
+        codec.compress(&text, &mut compressed);
+        assert!(
+            codec
+                .decompress(&compressed, &mut [0; BLOCK_SIZE / 2])
+                .is_err()
+        );
+        assert!(
+            codec
+                .decompress(&compressed[..compressed.len() / 2], &mut [0; BLOCK_SIZE])
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        // zeros compressed by the lz4 command line tool: a literal, then a
+        // match which overlaps its own output and has a length taking extra
+        // bytes, then the literals every block ends with
+        let mut block = vec![0x1f, 0x00, 0x01, 0x00];
+        block.extend_from_slice(&[0xff; 15]);
+        block.extend_from_slice(&[0xf6, 0x50, 0, 0, 0, 0, 0]);
+        let mut out = vec![1; BLOCK_SIZE];
+        Lz4.decompress(&block, &mut out)?;
+        assert_eq!(out, [0; BLOCK_SIZE]);
+
+        check_blocks(&Lz4)?;
+        check_codec(Lz4)
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        check_blocks(&Zstd::default())?;
+        check_codec(Zstd::new(19))
+    }
+}
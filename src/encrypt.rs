@@ -0,0 +1,610 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::flags::{OpenKind, OpenOpts};
+use crate::pool::{BufferPool, PoolStats};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{FileControlOp, UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+/// The size of the blocks files are encrypted in. Databases with this page
+/// size are written a whole block at a time; other page sizes work, but
+/// writes which cover part of a block have to read and rewrite all of it.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// The length of the salt stored in place of the header string at the start
+/// of an encrypted database.
+pub const SALT_LEN: usize = 16;
+
+// every database starts with this, so storing the salt in its place loses
+// nothing
+const SQLITE_HEADER: &[u8; SALT_LEN] = b"SQLite format 3\0";
+
+/// A length preserving cipher which an [`EncryptedVfs`] encrypts blocks
+/// with, such as AES-XTS.
+pub trait Cipher: Send + Sync {
+    type Key: Send + Sync;
+
+    /// Returns a new random salt, for a new database or temporary file.
+    fn salt(&self) -> [u8; SALT_LEN];
+
+    /// Derives the key of a database from its passphrase and salt. This runs
+    /// once for every connection, so it may be a deliberately slow password
+    /// hash.
+    fn derive_key(&self, passphrase: &[u8], salt: &[u8; SALT_LEN]) -> Self::Key;
+
+    /// Encrypts `block` in place. Blocks are at most [`BLOCK_SIZE`] bytes
+    /// long, and shorter at the end of a file. No two blocks encrypted with
+    /// the same key are given the same `tweak`.
+    fn encrypt(&self, key: &Self::Key, tweak: u64, block: &mut [u8]);
+
+    /// Decrypts a block encrypted by [`Cipher::encrypt`] in place.
+    fn decrypt(&self, key: &Self::Key, tweak: u64, block: &mut [u8]);
+}
+
+/// A Vfs wrapper which encrypts the files of databases opened with a `key`
+/// uri parameter, as in `file:main.db?key=secret`. Databases opened without
+/// one are passed through unchanged.
+///
+/// Files are encrypted a block at a time with the key derived from the
+/// passphrase and a random salt. The salt is stored unencrypted in the first
+/// 16 bytes of the database, in place of the `SQLite format 3` header string.
+/// Rollback journals, super-journals and WAL files are encrypted with the key
+/// of their database, and temporary files and statement journals with a
+/// random key which is never stored.
+///
+/// Like disk encryption, this hides the contents of files but not which
+/// blocks changed, and does not detect tampering. Memory mapping is disabled,
+/// and the wal-index in shared memory, which holds no page contents, is left
+/// unencrypted.
+///
+/// ```no_run
+/// # use rusqlite::{Connection, OpenFlags};
+/// # use sqlite_plugin::encrypt::{Cipher, EncryptedVfs, SALT_LEN};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, register_static};
+/// # #[derive(Default)]
+/// # struct MyCipher;
+/// # impl Cipher for MyCipher {
+/// #     type Key = ();
+/// #     fn salt(&self) -> [u8; SALT_LEN] { [0; SALT_LEN] }
+/// #     fn derive_key(&self, _: &[u8], _: &[u8; SALT_LEN]) {}
+/// #     fn encrypt(&self, _: &(), _: u64, _: &mut [u8]) {}
+/// #     fn decrypt(&self, _: &(), _: u64, _: &mut [u8]) {}
+/// # }
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let vfs = EncryptedVfs::new(BaseVfs::find(None)?, MyCipher::default());
+/// register_static(c"encrypted".to_owned(), vfs, RegisterOpts::default())?;
+/// let flags = OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI;
+/// let conn = Connection::open_with_flags_and_vfs("file:main.db?key=secret", flags, "encrypted")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct EncryptedVfs<V, C: Cipher> {
+    vfs: V,
+    cipher: C,
+    // the keys of open databases, which their journals and WAL files share
+    keys: Mutex<BTreeMap<String, Weak<FileKey<C::Key>>>>,
+    // scratch space for the blocks being encrypted and decrypted
+    pool: BufferPool,
+}
+
+struct FileKey<K> {
+    passphrase: Vec<u8>,
+    keyed: Mutex<Keyed<K>>,
+}
+
+struct Keyed<K> {
+    salt: [u8; SALT_LEN],
+    key: Arc<K>,
+}
+
+pub struct EncryptedHandle<H, K> {
+    inner: H,
+    // None for the files of databases opened without a key
+    key: Option<Arc<FileKey<K>>>,
+    kind: OpenKind,
+}
+
+impl<H: VfsHandle, K: Send + Sync> VfsHandle for EncryptedHandle<H, K> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle, K: Send + Sync> shim::ShimHandle<H> for EncryptedHandle<H, K> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs, C: Cipher> EncryptedVfs<V, C> {
+    pub fn new(vfs: V, cipher: C) -> Self {
+        Self {
+            vfs,
+            cipher,
+            keys: Mutex::new(BTreeMap::new()),
+            pool: BufferPool::default(),
+        }
+    }
+
+    /// How the scratch buffers of reads and writes have been served.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    fn main_key(
+        &self,
+        path: &str,
+        inner: &mut V::Handle,
+        passphrase: &[u8],
+    ) -> VfsResult<Arc<FileKey<C::Key>>> {
+        let salt = match self.vfs.file_size(inner)? {
+            0 => self.cipher.salt(),
+            size if size < SALT_LEN => return Err(VfsError::Other(vars::SQLITE_NOTADB)),
+            _ => {
+                let mut salt = [0; SALT_LEN];
+                self.read_exact(inner, 0, &mut salt)?;
+                if &salt == SQLITE_HEADER {
+                    // an unencrypted database
+                    return Err(VfsError::Other(vars::SQLITE_NOTADB));
+                }
+                salt
+            }
+        };
+
+        let mut keys = self.keys.lock();
+        keys.retain(|_, key| key.strong_count() > 0);
+        let existing = keys
+            .get(path)
+            .and_then(Weak::upgrade)
+            .filter(|key| key.passphrase == passphrase && key.keyed.lock().salt == salt);
+        if let Some(key) = existing {
+            return Ok(key);
+        }
+        let key = Arc::new(FileKey {
+            passphrase: passphrase.into(),
+            keyed: Mutex::new(Keyed {
+                salt,
+                key: Arc::new(self.cipher.derive_key(passphrase, &salt)),
+            }),
+        });
+        keys.insert(path.into(), Arc::downgrade(&key));
+        Ok(key)
+    }
+
+    // the key of the open database at `db_path`, if it is encrypted
+    fn db_key(&self, db_path: &str) -> Option<Arc<FileKey<C::Key>>> {
+        self.keys.lock().get(db_path).and_then(Weak::upgrade)
+    }
+
+    fn temp_key(&self) -> Arc<FileKey<C::Key>> {
+        // nothing else opens a temporary file, so a random passphrase works
+        let passphrase = self.cipher.salt();
+        let salt = self.cipher.salt();
+        Arc::new(FileKey {
+            passphrase: passphrase.into(),
+            keyed: Mutex::new(Keyed {
+                salt,
+                key: Arc::new(self.cipher.derive_key(&passphrase, &salt)),
+            }),
+        })
+    }
+
+    fn read_exact(&self, inner: &mut V::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<()> {
+        if self.vfs.read(inner, offset, buf)? < buf.len() {
+            return Err(VfsError::Other(vars::SQLITE_IOERR_SHORT_READ));
+        }
+        Ok(())
+    }
+
+    // reads the block starting at `start`, which is `buf.len()` bytes long,
+    // and decrypts it into `buf`
+    fn read_block(
+        &self,
+        handle: &mut EncryptedHandle<V::Handle, C::Key>,
+        key: &FileKey<C::Key>,
+        start: usize,
+        buf: &mut [u8],
+    ) -> VfsResult<()> {
+        self.read_exact(&mut handle.inner, start, buf)?;
+        let tweak = tweak(handle.kind, start);
+        if handle.kind != OpenKind::MainDb || start != 0 {
+            let key = key.keyed.lock().key.clone();
+            self.cipher.decrypt(&key, tweak, buf);
+            return Ok(());
+        }
+
+        let n = buf.len().min(SALT_LEN);
+        let key = {
+            let mut keyed = key.keyed.lock();
+            // another connection may have created the database with a
+            // different salt since this one opened it empty
+            if n == SALT_LEN && buf[..SALT_LEN] != keyed.salt {
+                keyed.salt.copy_from_slice(&buf[..SALT_LEN]);
+                keyed.key = Arc::new(self.cipher.derive_key(&key.passphrase, &keyed.salt));
+            }
+            keyed.key.clone()
+        };
+        self.cipher.decrypt(&key, tweak, &mut buf[n..]);
+        buf[..n].copy_from_slice(&SQLITE_HEADER[..n]);
+        Ok(())
+    }
+
+    // encrypts `buf` and writes it as the block starting at `start`
+    fn write_block(
+        &self,
+        handle: &mut EncryptedHandle<V::Handle, C::Key>,
+        key: &FileKey<C::Key>,
+        start: usize,
+        buf: &mut [u8],
+    ) -> VfsResult<()> {
+        let tweak = tweak(handle.kind, start);
+        let keyed = key.keyed.lock();
+        let (salt, key) = (keyed.salt, keyed.key.clone());
+        drop(keyed);
+        if handle.kind == OpenKind::MainDb && start == 0 {
+            let n = buf.len().min(SALT_LEN);
+            buf[..n].copy_from_slice(&salt[..n]);
+            self.cipher.encrypt(&key, tweak, &mut buf[n..]);
+        } else {
+            self.cipher.encrypt(&key, tweak, buf);
+        }
+        self.vfs.write(&mut handle.inner, start, buf)?;
+        Ok(())
+    }
+}
+
+// the database a super-journal belongs to, which SQLite names by appending
+// `-mj` and 9 hex digits to the path of the database
+fn super_journal_db(path: &str) -> Option<&str> {
+    let (db_path, suffix) = path.rsplit_once("-mj")?;
+    (suffix.len() == 9 && suffix.bytes().all(|b| b.is_ascii_hexdigit())).then_some(db_path)
+}
+
+// a tweak unique to the block starting at `start` among the files sharing a
+// key
+fn tweak(kind: OpenKind, start: usize) -> u64 {
+    let file = match kind {
+        OpenKind::MainDb => 0,
+        OpenKind::MainJournal => 1,
+        OpenKind::Wal => 2,
+        _ => 3,
+    };
+    (file << 56) | (start / BLOCK_SIZE) as u64
+}
+
+impl<V: Vfs, C: Cipher> shim::VfsShim for EncryptedVfs<V, C> {
+    type Inner = V;
+    type Handle = EncryptedHandle<V::Handle, C::Key>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let kind = opts.kind();
+        let passphrase = params.get("key");
+        let mut inner = self.vfs.open(path, opts, params)?;
+        let key = match kind {
+            OpenKind::MainDb => match (path, passphrase) {
+                (Some(path), Some(passphrase)) => {
+                    match self.main_key(path, &mut inner, passphrase.as_bytes()) {
+                        Ok(key) => Some(key),
+                        Err(err) => {
+                            let _ = self.vfs.close(inner);
+                            return Err(err);
+                        }
+                    }
+                }
+                _ => None,
+            },
+            OpenKind::MainJournal | OpenKind::Wal => path
+                .and_then(|path| path.strip_suffix("-journal").or(path.strip_suffix("-wal")))
+                .and_then(|db_path| self.db_key(db_path)),
+            // like the main database, a super-journal is only encrypted if
+            // its database is
+            OpenKind::SuperJournal => path
+                .and_then(super_journal_db)
+                .and_then(|db_path| self.db_key(db_path)),
+            kind if kind.is_temp() || kind == OpenKind::SubJournal => Some(self.temp_key()),
+            _ => None,
+        };
+        Ok(EncryptedHandle { inner, key, kind })
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        let Some(key) = handle.key.clone() else {
+            return self.vfs.truncate(&mut handle.inner, size);
+        };
+        let old_size = self.vfs.file_size(&mut handle.inner)?;
+        if size > old_size {
+            // zeros have to be encrypted, so grow the file by writing them
+            shim::VfsShim::write(self, handle, old_size, &vec![0; size - old_size])?;
+            return Ok(());
+        }
+
+        // a block cut short encrypts differently, so it is rewritten
+        let start = size / BLOCK_SIZE * BLOCK_SIZE;
+        let old_len = BLOCK_SIZE.min(old_size - start);
+        if size == start || size - start == old_len {
+            return self.vfs.truncate(&mut handle.inner, size);
+        }
+        let mut buf = self.pool.get(old_len);
+        self.read_block(handle, &key, start, &mut buf)?;
+        buf.truncate(size - start);
+        self.vfs.truncate(&mut handle.inner, size)?;
+        self.write_block(handle, &key, start, &mut buf)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let Some(key) = handle.key.clone() else {
+            return self.vfs.write(&mut handle.inner, offset, data);
+        };
+        let size = self.vfs.file_size(&mut handle.inner)?;
+        let end = offset + data.len();
+        let new_size = size.max(end);
+
+        // a write past the end also rewrites the old last block, which grows,
+        // and fills the gap up to it with encrypted zeros
+        let mut start = offset.min(size) / BLOCK_SIZE * BLOCK_SIZE;
+        while start < end {
+            let len = BLOCK_SIZE.min(new_size - start);
+            let old_len = BLOCK_SIZE.min(size.saturating_sub(start));
+            let mut buf = self.pool.get(len);
+            let covered = offset <= start && end >= start + old_len;
+            if old_len > 0 && !covered {
+                self.read_block(handle, &key, start, &mut buf[..old_len])?;
+            }
+            let from = offset.max(start);
+            let to = end.min(start + len);
+            if from < to {
+                buf[from - start..to - start].copy_from_slice(&data[from - offset..to - offset]);
+            }
+            self.write_block(handle, &key, start, &mut buf)?;
+            start += BLOCK_SIZE;
+        }
+        Ok(data.len())
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let Some(key) = handle.key.clone() else {
+            return self.vfs.read(&mut handle.inner, offset, data);
+        };
+        let size = self.vfs.file_size(&mut handle.inner)?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let end = size.min(offset + data.len());
+
+        let mut start = offset / BLOCK_SIZE * BLOCK_SIZE;
+        let mut buf = self.pool.get(BLOCK_SIZE);
+        while start < end {
+            let len = BLOCK_SIZE.min(size - start);
+            self.read_block(handle, &key, start, &mut buf[..len])?;
+            let from = offset.max(start);
+            let to = end.min(start + len);
+            data[from - offset..to - offset].copy_from_slice(&buf[from - start..to - start]);
+            start += BLOCK_SIZE;
+        }
+        Ok(end - offset)
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> usize {
+        // writes rewrite whole blocks
+        let sector_size = self.vfs.sector_size(&mut handle.inner);
+        if handle.key.is_some() {
+            sector_size.max(BLOCK_SIZE)
+        } else {
+            sector_size
+        }
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        let characteristics = self.vfs.device_characteristics(&mut handle.inner);
+        if handle.key.is_none() {
+            return characteristics;
+        }
+        // rewriting a block to change part of it may damage the rest on power
+        // loss, so writes are neither atomic nor powersafe
+        characteristics
+            & !(vars::SQLITE_IOCAP_ATOMIC
+                | vars::SQLITE_IOCAP_ATOMIC512
+                | vars::SQLITE_IOCAP_ATOMIC1K
+                | vars::SQLITE_IOCAP_ATOMIC2K
+                | vars::SQLITE_IOCAP_ATOMIC4K
+                | vars::SQLITE_IOCAP_ATOMIC8K
+                | vars::SQLITE_IOCAP_ATOMIC16K
+                | vars::SQLITE_IOCAP_ATOMIC32K
+                | vars::SQLITE_IOCAP_ATOMIC64K
+                | vars::SQLITE_IOCAP_SAFE_APPEND
+                | vars::SQLITE_IOCAP_POWERSAFE_OVERWRITE)
+    }
+
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        match op {
+            // the wrapped vfs would grow the file with unencrypted zeros
+            FileControlOp::SizeHint(_) | FileControlOp::ChunkSize(_) if handle.key.is_some() => {
+                Ok(())
+            }
+            op => self.vfs.file_control(&mut handle.inner, op),
+        }
+    }
+
+    fn supports_mmap(&self) -> bool {
+        // mapped pages would be read without being decrypted
+        false
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs, C: Cipher] for EncryptedVfs<V, C>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::testing::{TempDir, os_vfs};
+    use crate::vfs::{RegisterOpts, lookup, register_static};
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use rusqlite::{Connection, OpenFlags};
+
+    // xors blocks with a keystream, which is enough to see that nothing is
+    // stored in the clear
+    struct XorCipher(AtomicU64);
+
+    impl Cipher for XorCipher {
+        type Key = u64;
+
+        fn salt(&self) -> [u8; SALT_LEN] {
+            let mut salt = [0xa5; SALT_LEN];
+            salt[..8].copy_from_slice(&self.0.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+            salt
+        }
+
+        fn derive_key(&self, passphrase: &[u8], salt: &[u8; SALT_LEN]) -> u64 {
+            passphrase
+                .iter()
+                .chain(salt)
+                .fold(0xcbf29ce484222325, |hash, &b| {
+                    (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+                })
+        }
+
+        fn encrypt(&self, key: &u64, tweak: u64, block: &mut [u8]) {
+            let mut state = key ^ tweak.wrapping_mul(0x9e3779b97f4a7c15);
+            for chunk in block.chunks_mut(8) {
+                state = state.wrapping_add(0x9e3779b97f4a7c15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                for (b, k) in chunk.iter_mut().zip((z ^ (z >> 31)).to_le_bytes()) {
+                    *b ^= k;
+                }
+            }
+        }
+
+        fn decrypt(&self, key: &u64, tweak: u64, block: &mut [u8]) {
+            self.encrypt(key, tweak, block)
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn encrypted() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let base = os_vfs()?;
+        let vfs = EncryptedVfs::new(base, XorCipher(AtomicU64::new(1)));
+        register_static(c"encrypted_test".into(), vfs, RegisterOpts::default())?;
+
+        let dir = TempDir::new("encrypt")?;
+        let path = dir.file("main.db");
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI;
+        let open = |key: &str| {
+            let uri = std::format!("file:{path}{key}");
+            Connection::open_with_flags_and_vfs(uri, flags, "encrypted_test")
+        };
+        let count = |conn: &Connection| -> rusqlite::Result<i64> {
+            conn.query_row("select count(*) from t", [], |row| row.get(0))
+        };
+
+        let conn = open("?key=secret")?;
+        // smaller pages than blocks, so that writes rewrite parts of blocks
+        conn.execute_batch(
+            "pragma page_size = 1024;
+            create table t(x);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 200)
+            insert into t select 'plaintext ' || i from n;
+            begin; update t set x = x || '!'; rollback;",
+        )?;
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.execute("insert into t values ('plaintext wal')", [])?;
+        let wal = std::fs::read(std::format!("{path}-wal"))?;
+        assert!(!wal.is_empty() && !contains(&wal, b"plaintext"));
+        drop(conn);
+
+        let raw = std::fs::read(&path)?;
+        assert_ne!(&raw[..SALT_LEN], SQLITE_HEADER);
+        assert!(!contains(&raw, b"plaintext"));
+
+        assert_eq!(count(&open("?key=secret")?)?, 201);
+        let wrong = count(&open("?key=wrong")?).unwrap_err();
+        assert_eq!(
+            wrong.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::NotADatabase)
+        );
+        assert!(open("").and_then(|conn| count(&conn)).is_err());
+
+        // once the pool is warm, blocks are encrypted without allocating
+        let vfs = lookup::<EncryptedVfs<crate::shim::BaseVfs, XorCipher>>("encrypted_test")
+            .ok_or("vfs is not registered")?;
+        let stats = vfs.pool_stats();
+        assert!(stats.hits > 10 * stats.misses, "{stats:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn journal_keys() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let mock = MockVfs::new(std::boxed::Box::new(NoopHooks));
+        let vfs = EncryptedVfs::new(mock, XorCipher(AtomicU64::new(1)));
+        register_static(c"encrypted_keys".into(), vfs, RegisterOpts::default())?;
+        let vfs = lookup::<EncryptedVfs<MockVfs, XorCipher>>("encrypted_keys")
+            .ok_or("vfs is not registered")?;
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI;
+        let _keyed = Connection::open_with_flags_and_vfs(
+            "file:keyed.db?key=secret",
+            flags,
+            "encrypted_keys",
+        )?;
+        let _plain = Connection::open_with_flags_and_vfs("file:plain.db", flags, "encrypted_keys")?;
+
+        let keyed = |path: &str, flags| -> VfsResult<bool> {
+            let handle = vfs.open(Some(path), OpenOpts::new(flags), UriParams::empty())?;
+            let keyed = handle.key.is_some();
+            vfs.close(handle)?;
+            Ok(keyed)
+        };
+        let opts = vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE;
+        let super_journal = opts | vars::SQLITE_OPEN_SUPER_JOURNAL;
+        assert!(keyed("keyed.db-mj01234569A", super_journal)?);
+        assert!(!keyed("plain.db-mj01234569A", super_journal)?);
+        assert!(keyed(
+            "keyed.db-journal",
+            opts | vars::SQLITE_OPEN_MAIN_JOURNAL
+        )?);
+        assert!(!keyed(
+            "plain.db-journal",
+            opts | vars::SQLITE_OPEN_MAIN_JOURNAL
+        )?);
+        assert!(keyed("stmt", opts | vars::SQLITE_OPEN_SUBJOURNAL)?);
+        assert!(keyed("temp", opts | vars::SQLITE_OPEN_TEMP_JOURNAL)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use spin::Mutex;
+
+use crate::flags::{LockLevel, OpenKind, OpenOpts, SyncFlags};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+/// The file operations a [`FaultPlan`] can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOp {
+    Read,
+    Write,
+    Sync,
+    Truncate,
+}
+
+/// Which operations a fault fires on, counting the operations of its kind on
+/// matching files from 1.
+#[derive(Debug, Clone, Copy)]
+pub enum Schedule {
+    /// Only the nth operation.
+    Nth(u64),
+    /// The nth operation and every one after it.
+    From(u64),
+    /// Every nth operation.
+    Every(u64),
+    /// Each operation with a chance of one in `one_in`, drawn from a
+    /// generator seeded with `seed`, so a seed always picks the same
+    /// operations.
+    Random { seed: u64, one_in: u64 },
+}
+
+/// What happens when a fault fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails with the error.
+    Error(VfsError),
+    /// A read returns only its first n bytes, and fails with
+    /// `SQLITE_IOERR_SHORT_READ`.
+    ShortRead(usize),
+    /// The operation is lost along with every write not yet synced, as if
+    /// the machine lost power, see [`FaultVfs::crash`].
+    Crash,
+}
+
+/// A deterministic schedule of faults for a [`FaultVfs`]:
+///
+/// ```
+/// # use sqlite_plugin::fault::{FaultOp, FaultPlan, Schedule};
+/// # use sqlite_plugin::flags::OpenKind;
+/// let plan = FaultPlan::new()
+///     .kinds(&[OpenKind::MainDb])
+///     .fail_write(Schedule::Nth(3))
+///     .short_read(Schedule::Every(10), 100)
+///     .crash(FaultOp::Sync, Schedule::Nth(2));
+/// ```
+///
+/// When several faults fire on the same operation, the first one added wins.
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    kinds: Option<Vec<OpenKind>>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    op: FaultOp,
+    schedule: Schedule,
+    fault: Fault,
+    // the state of the generator for Schedule::Random
+    rng: u64,
+}
+
+impl FaultPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only counts and fails operations on files of these kinds, rather than
+    /// on every file.
+    pub fn kinds(mut self, kinds: &[OpenKind]) -> Self {
+        self.kinds = Some(kinds.into());
+        self
+    }
+
+    /// Fires `fault` on the operations `op` picked by `schedule`.
+    pub fn fault(mut self, op: FaultOp, schedule: Schedule, fault: Fault) -> Self {
+        let rng = match schedule {
+            Schedule::Random { seed, .. } => seed,
+            _ => 0,
+        };
+        self.rules.push(Rule { op, schedule, fault, rng });
+        self
+    }
+
+    /// Fails writes with `SQLITE_IOERR_WRITE`.
+    pub fn fail_write(self, schedule: Schedule) -> Self {
+        self.fault(FaultOp::Write, schedule, Fault::Error(VfsError::Io))
+    }
+
+    /// Fails syncs with `SQLITE_IOERR_FSYNC`.
+    pub fn fail_sync(self, schedule: Schedule) -> Self {
+        self.fault(FaultOp::Sync, schedule, Fault::Error(VfsError::Io))
+    }
+
+    /// Cuts reads short after `len` bytes.
+    pub fn short_read(self, schedule: Schedule, len: usize) -> Self {
+        self.fault(FaultOp::Read, schedule, Fault::ShortRead(len))
+    }
+
+    /// Crashes on `op`, losing every write not yet synced.
+    pub fn crash(self, op: FaultOp, schedule: Schedule) -> Self {
+        self.fault(op, schedule, Fault::Crash)
+    }
+}
+
+impl Rule {
+    fn fires(&mut self, count: u64) -> bool {
+        match self.schedule {
+            Schedule::Nth(n) => count == n,
+            Schedule::From(n) => count >= n,
+            Schedule::Every(n) => n > 0 && count % n == 0,
+            Schedule::Random { one_in, .. } => {
+                // splitmix64
+                self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = self.rng;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                one_in > 0 && (z ^ (z >> 31)) % one_in == 0
+            }
+        }
+    }
+}
+
+/// A Vfs wrapper for crash-consistency tests, which fails operations of the
+/// wrapped vfs as programmed by a [`FaultPlan`].
+///
+/// The wrapper keeps enough of the previous contents of every named file to
+/// undo the writes made since it was last synced, which a crash does. Files
+/// deleted on close are not kept, and deletes are durable. After a crash
+/// every file open at the time fails its operations, so connections using
+/// them see the crash. They must be closed, which releases their locks,
+/// before the database is opened again to recover it.
+///
+/// ```no_run
+/// # use sqlite_plugin::fault::{FaultPlan, FaultVfs, Schedule};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// # fn main() -> VfsResult<()> {
+/// # let plan = FaultPlan::new().fail_sync(Schedule::Nth(1));
+/// let vfs = FaultVfs::new(BaseVfs::find(None)?, plan);
+/// register_static(c"fault".to_owned(), vfs, RegisterOpts::default())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct FaultVfs<V> {
+    vfs: V,
+    state: Mutex<State>,
+}
+
+pub struct FaultHandle<H> {
+    inner: H,
+    name: Option<String>,
+    kind: OpenKind,
+    // the number of crashes before the handle was opened
+    epoch: u64,
+}
+
+struct State {
+    plan: FaultPlan,
+    counts: [u64; 4],
+    fired: u64,
+    crashes: u64,
+    files: BTreeMap<String, Unsynced>,
+}
+
+// what a crash must undo to return a file to how it was when last synced
+struct Unsynced {
+    opts: OpenOpts,
+    synced_size: usize,
+    // the previous contents of each range written since the last sync
+    undo: Vec<(usize, Vec<u8>)>,
+}
+
+impl<H: VfsHandle> VfsHandle for FaultHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for FaultHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> FaultVfs<V> {
+    pub fn new(vfs: V, plan: FaultPlan) -> Self {
+        let state = State {
+            plan,
+            counts: [0; 4],
+            fired: 0,
+            crashes: 0,
+            files: BTreeMap::new(),
+        };
+        Self { vfs, state: Mutex::new(state) }
+    }
+
+    /// Replaces the plan, and starts counting operations from 0 again.
+    pub fn set_plan(&self, plan: FaultPlan) {
+        let mut state = self.state.lock();
+        state.plan = plan;
+        state.counts = [0; 4];
+    }
+
+    /// The number of faults which have fired.
+    pub fn fired(&self) -> u64 {
+        self.state.lock().fired
+    }
+
+    /// Loses every write not yet synced, as if the machine lost power, and
+    /// fails every operation on the files open until they are closed.
+    pub fn crash(&self) -> VfsResult<()> {
+        self.crash_inner(&mut self.state.lock())
+    }
+
+    fn crash_inner(&self, state: &mut State) -> VfsResult<()> {
+        state.crashes += 1;
+        for (path, unsynced) in &mut state.files {
+            let flags = unsynced.opts.flags();
+            let opts =
+                OpenOpts::new(flags & !(vars::SQLITE_OPEN_CREATE | vars::SQLITE_OPEN_EXCLUSIVE));
+            let mut handle = self.vfs.open(Some(path), opts, UriParams::empty())?;
+            for (offset, data) in unsynced.undo.drain(..).rev() {
+                self.vfs.write(&mut handle, offset, &data)?;
+            }
+            self.vfs.truncate(&mut handle, unsynced.synced_size)?;
+            self.vfs.close(handle)?;
+        }
+        Ok(())
+    }
+
+    // counts the operation, and returns the fault to fire on it if any
+    fn check(&self, handle: &FaultHandle<V::Handle>, op: FaultOp) -> VfsResult<Option<Fault>> {
+        let mut state = self.state.lock();
+        if handle.epoch != state.crashes {
+            return Err(VfsError::Io);
+        }
+        if let Some(kinds) = &state.plan.kinds {
+            if !kinds.contains(&handle.kind) {
+                return Ok(None);
+            }
+        }
+        state.counts[op as usize] += 1;
+        let count = state.counts[op as usize];
+        let fault = state
+            .plan
+            .rules
+            .iter_mut()
+            .filter(|rule| rule.op == op)
+            .fold(None, |fault, rule| {
+                // every rule is asked, so that random ones stay deterministic
+                let fires = rule.fires(count);
+                fault.or(fires.then_some(rule.fault))
+            });
+        match fault {
+            Some(Fault::Crash) => {
+                state.fired += 1;
+                self.crash_inner(&mut state)?;
+                Err(VfsError::Io)
+            }
+            Some(fault) => {
+                state.fired += 1;
+                Ok(Some(fault))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // records the contents of `len` bytes at `offset` so a crash can restore
+    // them
+    fn save(
+        &self,
+        handle: &mut FaultHandle<V::Handle>,
+        offset: usize,
+        len: usize,
+    ) -> VfsResult<()> {
+        let Some(name) = &handle.name else {
+            return Ok(());
+        };
+        let size = self.vfs.file_size(&mut handle.inner)?;
+        let len = len.min(size.saturating_sub(offset));
+        let mut old = vec![0; len];
+        if len > 0 {
+            self.vfs.read(&mut handle.inner, offset, &mut old)?;
+        }
+        if let Some(unsynced) = self.state.lock().files.get_mut(name) {
+            unsynced.undo.push((offset, old));
+        }
+        Ok(())
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for FaultVfs<V> {
+    type Inner = V;
+    type Handle = FaultHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let mut inner = self.vfs.open(path, opts, params)?;
+        let name = path.filter(|_| !opts.delete_on_close());
+        let mut state = self.state.lock();
+        if let Some(name) = name {
+            if !state.files.contains_key(name) {
+                let synced_size = self.vfs.file_size(&mut inner)?;
+                let unsynced = Unsynced { opts, synced_size, undo: Vec::new() };
+                state.files.insert(name.into(), unsynced);
+            }
+        }
+        Ok(FaultHandle {
+            inner,
+            name: name.map(Into::into),
+            kind: opts.kind(),
+            epoch: state.crashes,
+        })
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.vfs.delete(path)?;
+        self.state.lock().files.remove(path);
+        Ok(())
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        if handle.epoch != self.state.lock().crashes {
+            return Err(VfsError::Io);
+        }
+        self.vfs.file_size(&mut handle.inner)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        if let Some(Fault::Error(err)) = self.check(handle, FaultOp::Truncate)? {
+            return Err(err);
+        }
+        self.save(handle, size, usize::MAX)?;
+        self.vfs.truncate(&mut handle.inner, size)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        if let Some(Fault::Error(err)) = self.check(handle, FaultOp::Write)? {
+            return Err(err);
+        }
+        self.save(handle, offset, data.len())?;
+        self.vfs.write(&mut handle.inner, offset, data)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        match self.check(handle, FaultOp::Read)? {
+            Some(Fault::Error(err)) => Err(err),
+            Some(Fault::ShortRead(len)) if len < data.len() => {
+                self.vfs.read(&mut handle.inner, offset, data)?;
+                // SQLite requires the rest of a short read to be zeroed
+                data[len..].fill(0);
+                Err(VfsError::Other(vars::SQLITE_IOERR_SHORT_READ))
+            }
+            _ => self.vfs.read(&mut handle.inner, offset, data),
+        }
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        if handle.epoch != self.state.lock().crashes {
+            return Err(VfsError::Io);
+        }
+        self.vfs.lock(&mut handle.inner, level)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        if let Some(Fault::Error(err)) = self.check(handle, FaultOp::Sync)? {
+            return Err(err);
+        }
+        self.vfs.sync(&mut handle.inner, flags)?;
+        if let Some(name) = &handle.name {
+            let size = self.vfs.file_size(&mut handle.inner)?;
+            if let Some(unsynced) = self.state.lock().files.get_mut(name) {
+                unsynced.synced_size = size;
+                unsynced.undo.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        if handle.epoch != self.state.lock().crashes {
+            return Err(VfsError::Io);
+        }
+        self.vfs.shm_map(&mut handle.inner, region, size, extend)
+    }
+
+    fn supports_mmap(&self) -> bool {
+        // mapped pages could be read after a crash has undone them
+        false
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for FaultVfs<V>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::logger::SqliteLogger;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::testing::TestVfs;
+    use rusqlite::{Connection, OpenFlags};
+    use std::boxed::Box;
+
+    fn mock() -> MockVfs {
+        let vfs = MockVfs::new(Box::new(NoopHooks));
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+        vfs
+    }
+
+    #[test]
+    fn schedules() {
+        let plan = FaultPlan::new()
+            .fail_write(Schedule::Every(2))
+            .short_read(Schedule::Nth(1), 2)
+            .fail_sync(Schedule::From(2));
+        let vfs = FaultVfs::new(mock(), plan);
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let mut file = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+
+        assert_eq!(vfs.write(&mut file, 0, &[1; 4]), Ok(4));
+        assert_eq!(vfs.write(&mut file, 0, &[2; 4]), Err(VfsError::Io));
+        assert_eq!(vfs.write(&mut file, 4, &[3; 4]), Ok(4));
+        let mut buf = [9; 8];
+        let err = VfsError::Other(vars::SQLITE_IOERR_SHORT_READ);
+        assert_eq!(vfs.read(&mut file, 0, &mut buf), Err(err));
+        assert_eq!(buf, [1, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(vfs.read(&mut file, 0, &mut buf), Ok(8));
+        assert_eq!(buf, [1, 1, 1, 1, 3, 3, 3, 3]);
+        assert_eq!(vfs.sync(&mut file, SyncFlags::new(0)), Ok(()));
+        assert_eq!(vfs.sync(&mut file, SyncFlags::new(0)), Err(VfsError::Io));
+        assert_eq!(vfs.fired(), 3);
+
+        // a crash undoes the writes since the last sync, and fails the handle
+        vfs.set_plan(FaultPlan::new());
+        vfs.write(&mut file, 2, &[4; 8]).unwrap();
+        vfs.crash().unwrap();
+        assert_eq!(vfs.read(&mut file, 0, &mut buf), Err(VfsError::Io));
+        vfs.close(file).unwrap();
+        let mut file = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+        assert_eq!(vfs.file_size(&mut file), Ok(8));
+        vfs.read(&mut file, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 1, 1, 3, 3, 3, 3]);
+        vfs.close(file).unwrap();
+
+        // random schedules pick the same operations for the same seed
+        let picks = || {
+            let plan = FaultPlan::new().fail_write(Schedule::Random { seed: 7, one_in: 3 });
+            let vfs = FaultVfs::new(mock(), plan);
+            let mut file = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+            let picks: Vec<bool> = (0..32)
+                .map(|i| vfs.write(&mut file, i, &[0]).is_err())
+                .collect();
+            vfs.close(file).unwrap();
+            picks
+        };
+        let first = picks();
+        assert!(first.contains(&true) && first.contains(&false));
+        assert_eq!(first, picks());
+    }
+
+    #[test]
+    fn torn_transaction() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = TestVfs::scoped_with("fault", FaultVfs::new(mock(), FaultPlan::new()))?;
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let open = || Connection::open_with_flags_and_vfs("main.db", flags, vfs.name());
+        let count = |conn: &Connection| -> rusqlite::Result<i64> {
+            conn.query_row("select count(*) from t", [], |row| row.get(0))
+        };
+
+        let conn = open()?;
+        conn.execute_batch(
+            "create table t(x);
+            insert into t values (1), (2), (3);",
+        )?;
+
+        // crash halfway through writing the transaction to the database,
+        // after its journal is synced
+        let plan = FaultPlan::new()
+            .kinds(&[OpenKind::MainDb])
+            .crash(FaultOp::Write, Schedule::Nth(2));
+        vfs.vfs().set_plan(plan);
+        conn.execute_batch(
+            "with recursive n(i) as (select 1 union all select i + 1 from n where i < 1000)
+            insert into t select randomblob(100) from n;",
+        )
+        .unwrap_err();
+        assert_eq!(vfs.vfs().fired(), 1);
+        drop(conn);
+
+        // the hot journal rolls the torn transaction back
+        vfs.vfs().set_plan(FaultPlan::new());
+        let conn = open()?;
+        assert_eq!(count(&conn)?, 3);
+        let check: String = conn.query_row("pragma integrity_check", [], |row| row.get(0))?;
+        assert_eq!(check, "ok");
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use core::fmt::{self, Debug, Formatter};
 
 use crate::vars;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpenKind {
     Unknown,
     MainDb,
@@ -102,8 +102,10 @@ impl OpenOpts {
         self.flags & vars::SQLITE_OPEN_DELETEONCLOSE > 0
     }
 
+    /// Opens the file read-only, which also means it is not created.
     pub fn set_readonly(&mut self) {
-        self.flags &= !vars::SQLITE_OPEN_READWRITE;
+        self.flags &=
+            !(vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE | vars::SQLITE_OPEN_EXCLUSIVE);
         self.flags |= vars::SQLITE_OPEN_READONLY;
     }
 }
@@ -125,6 +127,62 @@ impl Debug for OpenOpts {
     }
 }
 
+/// The flags passed with a sync request.
+/// See [SQLite documentation](https://www.sqlite.org/c3ref/c_sync_dataonly.html) for more information.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SyncFlags {
+    flags: i32,
+}
+
+impl SyncFlags {
+    /// Not a flag of `SQLite`'s, this marks the sync requests forwarded by
+    /// [`SyncMode::Barrier`](crate::vfs::SyncMode::Barrier).
+    pub const BARRIER: i32 = 0x100;
+
+    pub fn new(flags: i32) -> Self {
+        Self { flags }
+    }
+
+    pub fn flags(&self) -> i32 {
+        self.flags
+    }
+
+    /// A full sync was requested, as with `pragma fullfsync`, rather than a
+    /// normal one.
+    pub fn is_full(&self) -> bool {
+        self.flags & 0x0f == vars::SQLITE_SYNC_FULL
+    }
+
+    /// Only the file's data needs to be synced, not its metadata.
+    pub fn data_only(&self) -> bool {
+        self.flags & vars::SQLITE_SYNC_DATAONLY > 0
+    }
+
+    /// Only the order of the writes before the sync against the writes after
+    /// it needs to be kept, not their durability, so it may be served with a
+    /// write barrier rather than a flush to stable storage.
+    pub fn is_barrier(&self) -> bool {
+        self.flags & Self::BARRIER > 0
+    }
+}
+
+impl From<i32> for SyncFlags {
+    fn from(flags: i32) -> Self {
+        Self::new(flags)
+    }
+}
+
+impl Debug for SyncFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncFlags")
+            .field("flags", &self.flags)
+            .field("full", &self.is_full())
+            .field("data_only", &self.data_only())
+            .field("barrier", &self.is_barrier())
+            .finish()
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum AccessFlags {
     Exists,
@@ -178,3 +236,35 @@ impl From<i32> for LockLevel {
         }
     }
 }
+
+/// A lock or unlock request on a range of the wal-index shared memory locks.
+/// See [SQLite documentation](https://www.sqlite.org/c3ref/io_methods.html) for more information.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShmLockMode {
+    LockShared,
+    LockExclusive,
+    UnlockShared,
+    UnlockExclusive,
+}
+
+impl From<i32> for ShmLockMode {
+    fn from(flags: i32) -> Self {
+        let exclusive = flags & vars::SQLITE_SHM_EXCLUSIVE > 0;
+        match (flags & vars::SQLITE_SHM_LOCK > 0, exclusive) {
+            (true, false) => Self::LockShared,
+            (true, true) => Self::LockExclusive,
+            (false, false) => Self::UnlockShared,
+            (false, true) => Self::UnlockExclusive,
+        }
+    }
+}
+
+impl ShmLockMode {
+    pub fn is_lock(&self) -> bool {
+        matches!(self, Self::LockShared | Self::LockExclusive)
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        matches!(self, Self::LockExclusive | Self::UnlockExclusive)
+    }
+}
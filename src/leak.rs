@@ -0,0 +1,278 @@
+// backtraces and the panic check on drop need std, which the testing feature
+// links anyway
+extern crate std;
+
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+use spin::Mutex;
+use std::backtrace::{Backtrace, BacktraceStatus};
+
+use crate::flags::{AccessFlags, OpenKind, OpenOpts};
+use crate::shim;
+use crate::vfs::{UriParams, Vfs, VfsHandle, VfsResult};
+
+/// A Vfs wrapper which keeps track of every handle opened through it, and
+/// every file opened with delete-on-close, so that tests can assert that
+/// nothing was leaked. The backtrace of each open is captured when
+/// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` is set, and reported with the
+/// handles that were never closed.
+///
+/// ```no_run
+/// # use sqlite_plugin::leak::LeakCheck;
+/// # use sqlite_plugin::testing::os_vfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// # fn main() -> VfsResult<()> {
+/// let (vfs, tracker) = LeakCheck::new(os_vfs()?);
+/// register_static(c"leakcheck".to_owned(), vfs, RegisterOpts::default())?;
+/// // ... open and close connections ...
+/// drop(tracker); // panics if any handles or temporary files were leaked
+/// # Ok(())
+/// # }
+/// ```
+pub struct LeakCheck<V> {
+    shared: Arc<Shared<V>>,
+}
+
+/// Reports leaks from the paired [`LeakCheck`] vfs, and panics when dropped if
+/// there are any. Drop it only after every connection using the vfs has been
+/// closed. Nothing is checked if the tracker is dropped while the thread is
+/// already panicking, such as when a test assertion failed, so that the
+/// original failure is reported.
+pub struct LeakTracker<V: Vfs> {
+    shared: Arc<Shared<V>>,
+}
+
+pub struct LeakCheckHandle<H> {
+    id: usize,
+    inner: H,
+}
+
+struct Shared<V> {
+    vfs: V,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    open: BTreeMap<usize, OpenFile>,
+    // files opened with delete-on-close which must be gone by the end
+    temp_files: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenFile {
+    pub name: Option<String>,
+    pub kind: OpenKind,
+    /// Where the file was opened from. This is only captured if enabled
+    /// through the environment, see [`Backtrace::capture`].
+    pub backtrace: Arc<Backtrace>,
+}
+
+/// The handles and temporary files a [`LeakCheck`] vfs has leaked.
+#[derive(Debug)]
+pub struct Leaks {
+    pub handles: Vec<OpenFile>,
+    pub temp_files: Vec<String>,
+}
+
+impl Leaks {
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty() && self.temp_files.is_empty()
+    }
+}
+
+impl Display for Leaks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for file in &self.handles {
+            let name = file.name.as_deref().unwrap_or("<anonymous>");
+            writeln!(f, "handle never closed: {name} ({:?})", file.kind)?;
+            if file.backtrace.status() == BacktraceStatus::Captured {
+                writeln!(f, "opened at:\n{}", file.backtrace)?;
+            }
+        }
+        for name in &self.temp_files {
+            writeln!(f, "temporary file never deleted: {name}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<V: Vfs> LeakCheck<V> {
+    pub fn new(vfs: V) -> (Self, LeakTracker<V>) {
+        let shared = Arc::new(Shared { vfs, state: Mutex::new(State::default()) });
+        (Self { shared: shared.clone() }, LeakTracker { shared })
+    }
+}
+
+impl<V: Vfs> LeakTracker<V> {
+    /// Returns every handle that is still open and every delete-on-close file
+    /// which still exists.
+    pub fn leaks(&self) -> VfsResult<Leaks> {
+        let state = self.shared.state.lock();
+        let handles = state.open.values().cloned().collect();
+        let mut temp_files = Vec::new();
+        for name in &state.temp_files {
+            if self.shared.vfs.access(name, AccessFlags::Exists)? {
+                temp_files.push(name.clone());
+            }
+        }
+        Ok(Leaks { handles, temp_files })
+    }
+
+    pub fn assert_no_leaks(&self) {
+        match self.leaks() {
+            Ok(leaks) if leaks.is_empty() => {}
+            Ok(leaks) => panic!("vfs leaked files:\n{leaks}"),
+            Err(err) => panic!("failed to check vfs for leaks: {err}"),
+        }
+    }
+}
+
+impl<V: Vfs> Drop for LeakTracker<V> {
+    fn drop(&mut self) {
+        // panicking again while unwinding would abort the test binary
+        if !std::thread::panicking() {
+            self.assert_no_leaks();
+        }
+    }
+}
+
+impl<H: VfsHandle> VfsHandle for LeakCheckHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for LeakCheckHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for LeakCheck<V> {
+    type Inner = V;
+    type Handle = LeakCheckHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.shared.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let inner = self.shared.vfs.open(path, opts, params)?;
+        let mut state = self.shared.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(
+            id,
+            OpenFile {
+                name: path.map(ToOwned::to_owned),
+                kind: opts.kind(),
+                backtrace: Arc::new(Backtrace::capture()),
+            },
+        );
+        if let Some(path) = path.filter(|_| opts.delete_on_close()) {
+            if !state.temp_files.iter().any(|name| name == path) {
+                state.temp_files.push(path.to_owned());
+            }
+        }
+        Ok(LeakCheckHandle { id, inner })
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        self.shared.state.lock().open.remove(&handle.id);
+        self.shared.vfs.close(handle.inner)
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for LeakCheck<V>);
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::logger::SqliteLogger;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::vars;
+    use std::boxed::Box;
+
+    #[test]
+    fn leaks() {
+        let (vfs, tracker) = LeakCheck::new(MockVfs::new(Box::new(NoopHooks)));
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let main = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+        let temp_opts = OpenOpts::new(
+            vars::SQLITE_OPEN_TEMP_JOURNAL
+                | vars::SQLITE_OPEN_READWRITE
+                | vars::SQLITE_OPEN_DELETEONCLOSE,
+        );
+        let temp = vfs
+            .open(Some("temp.db-journal"), temp_opts, UriParams::empty())
+            .unwrap();
+
+        let leaks = tracker.leaks().unwrap();
+        assert_eq!(leaks.handles.len(), 2);
+        assert_eq!(leaks.temp_files, ["temp.db-journal"]);
+
+        // closing the journal deletes it, so only the main db handle remains
+        vfs.close(temp).unwrap();
+        let leaks = tracker.leaks().unwrap();
+        assert_eq!(leaks.handles.len(), 1);
+        assert_eq!(leaks.handles[0].name.as_deref(), Some("main.db"));
+        assert!(leaks.temp_files.is_empty());
+
+        vfs.close(main).unwrap();
+        tracker.assert_no_leaks();
+    }
+
+    #[test]
+    fn panicking() {
+        // a failed assertion with a handle still open reports the assertion,
+        // rather than panicking again while the tracker is dropped
+        let result = std::panic::catch_unwind(|| {
+            let (vfs, _tracker) = LeakCheck::new(MockVfs::new(Box::new(NoopHooks)));
+            vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+            let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+            let _main = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+            panic!("assertion failed");
+        });
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<&str>(), Some(&"assertion failed"));
+
+        // otherwise dropping the tracker reports the leak
+        let result = std::panic::catch_unwind(|| {
+            let (vfs, tracker) = LeakCheck::new(MockVfs::new(Box::new(NoopHooks)));
+            vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+            let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+            let _main = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+            drop(tracker);
+        });
+        let err = result.unwrap_err();
+        let msg = err.downcast_ref::<std::string::String>().unwrap();
+        assert!(
+            msg.contains("handle never closed: main.db (MainDb)"),
+            "{msg}"
+        );
+    }
+}
@@ -15,10 +15,30 @@ mod ffi {
 
 mod mock;
 
+pub mod async_vfs;
+pub mod audit;
+pub mod backup;
+pub mod checksum;
+pub mod compress;
+pub mod encrypt;
+pub mod fault;
 pub mod flags;
+#[cfg(all(feature = "static", any(test, feature = "testing")))]
+pub mod leak;
 pub mod logger;
+pub mod pool;
+pub mod quota;
+pub mod readonly;
+pub mod shim;
+pub mod span;
+pub mod stack;
+pub mod stats;
+#[cfg(all(feature = "static", any(test, feature = "testing")))]
+pub mod testing;
+pub mod throttle;
+pub mod trace;
 pub mod vfs;
-pub use ffi::sqlite3_api_routines;
+pub use ffi::{sqlite3_api_routines, sqlite3_file, sqlite3_io_methods, sqlite3_vfs};
 
 #[cfg(test)]
 mod tests {
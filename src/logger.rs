@@ -1,9 +1,19 @@
+#[cfg(feature = "static")]
+use alloc::boxed::Box;
 use alloc::ffi::CString;
+use alloc::format;
+use alloc::vec::Vec;
+#[cfg(feature = "static")]
+use core::ffi::{CStr, c_void};
 use core::ffi::{c_char, c_int};
+use core::mem;
+use core::time::Duration;
 
 use crate::vars;
+#[cfg(feature = "static")]
+use crate::{ffi, vfs::VfsResult};
 
-type Sqlite3Log = unsafe extern "C" fn(iErrCode: c_int, arg2: *const c_char, ...);
+type Sqlite3Log = unsafe extern "C" fn(err_code: c_int, arg2: *const c_char, ...);
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SqliteLogLevel {
@@ -44,8 +54,298 @@ impl SqliteLogger {
                 continue;
             }
 
-            let z_format = CString::new(line).unwrap();
-            unsafe { (self.log)(code, z_format.as_ptr()) }
+            // pass the line as an argument so that any % in it is logged as is
+            let line = CString::new(line).unwrap();
+            unsafe { (self.log)(code, c"%s".as_ptr(), line.as_ptr()) }
         }
     }
 }
+
+/// Wraps a [`SqliteLogger`] to collapse runs of an identical log line, such
+/// as the same io error reported thousands of times while a backend is down,
+/// into periodic summaries.
+///
+/// The first occurrence of a line is logged immediately. Repeats are counted
+/// and reported as a single "repeated" line once `summary_interval` has
+/// passed since the line or its last summary was logged, and when a different
+/// line is logged or [`flush`] is called. Summaries are only written from
+/// calls to the logger, so call `flush` periodically to report the repeats
+/// of a storm that has ended.
+///
+/// ```
+/// # use core::time::Duration;
+/// # use sqlite_plugin::logger::{DedupLogger, SqliteLogger};
+/// # fn clock() -> Duration { Duration::ZERO }
+/// fn register_logger(logger: SqliteLogger) -> DedupLogger {
+///     DedupLogger::new(logger, Duration::from_secs(10), clock)
+/// }
+/// ```
+///
+/// [`flush`]: DedupLogger::flush
+pub struct DedupLogger {
+    logger: SqliteLogger,
+    clock: fn() -> Duration,
+    dedup: spin::Mutex<Dedup>,
+}
+
+impl DedupLogger {
+    /// Wraps `logger`, measuring the time between summaries with `clock`,
+    /// which returns the time elapsed since an arbitrary fixed point.
+    pub fn new(logger: SqliteLogger, summary_interval: Duration, clock: fn() -> Duration) -> Self {
+        Self {
+            logger,
+            clock,
+            dedup: spin::Mutex::new(Dedup::new(summary_interval)),
+        }
+    }
+
+    pub fn log(&self, level: SqliteLogLevel, buf: &[u8]) {
+        for line in buf.split(|b| *b == b'\n') {
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            // release the lock before logging, in case a log hook logs again
+            let now = (self.clock)();
+            let out = self.dedup.lock().push(level, line, now);
+            self.emit(out);
+        }
+    }
+
+    /// Logs a summary of any repeats which have not been reported yet.
+    pub fn flush(&self) {
+        let now = (self.clock)();
+        let out = self.dedup.lock().flush(now);
+        self.emit(out.into_iter().collect());
+    }
+
+    fn emit(&self, out: Vec<(SqliteLogLevel, Vec<u8>)>) {
+        for (level, line) in out {
+            self.logger.log(level, &line);
+        }
+    }
+}
+
+struct Dedup {
+    summary_interval: Duration,
+    last: Option<(SqliteLogLevel, Vec<u8>)>,
+    repeats: usize,
+    // when the last line or summary was logged
+    reported: Duration,
+}
+
+impl Dedup {
+    fn new(summary_interval: Duration) -> Self {
+        Self {
+            summary_interval,
+            last: None,
+            repeats: 0,
+            reported: Duration::ZERO,
+        }
+    }
+
+    // returns the lines to log in response to `line`
+    fn push(
+        &mut self,
+        level: SqliteLogLevel,
+        line: &[u8],
+        now: Duration,
+    ) -> Vec<(SqliteLogLevel, Vec<u8>)> {
+        let mut out = Vec::new();
+        if let Some((last_level, last)) = &self.last {
+            if *last_level == level && last.as_slice() == line {
+                self.repeats += 1;
+                if now.saturating_sub(self.reported) >= self.summary_interval {
+                    out.extend(self.flush(now));
+                }
+                return out;
+            }
+        }
+        out.extend(self.flush(now));
+        out.push((level, line.to_vec()));
+        self.last = Some((level, line.to_vec()));
+        self.reported = now;
+        out
+    }
+
+    fn flush(&mut self, now: Duration) -> Option<(SqliteLogLevel, Vec<u8>)> {
+        let repeats = mem::take(&mut self.repeats);
+        let (level, _) = self.last.as_ref().filter(|_| repeats > 0)?;
+        self.reported = now;
+        let times = if repeats == 1 { "time" } else { "times" };
+        let summary = format!("last message repeated {repeats} {times}");
+        Some((*level, summary.into_bytes()))
+    }
+}
+
+// the log hook is process wide, so tests which install one take turns
+#[cfg(all(test, feature = "static"))]
+pub(crate) static TEST_LOG_HOOK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// A callback which receives every message written to the `SQLite` error log,
+/// along with its result code.
+#[cfg(feature = "static")]
+pub type LogHook = Box<dyn Fn(c_int, &str) + Send + Sync>;
+
+/// Installs `hook` as `SQLite`'s error log callback via `SQLITE_CONFIG_LOG`,
+/// or removes the current callback when passed `None`. Lines written by a
+/// [`SqliteLogger`] go through the same log, so the hook sees plugin and core
+/// engine messages as a single stream.
+///
+/// This replaces any log callback installed by other means. The hook must be
+/// thread safe and must not call back into `SQLite`. Replaced hooks are leaked,
+/// as `SQLite` may still be invoking them on another thread.
+#[cfg(feature = "static")]
+pub fn set_log_hook(hook: Option<LogHook>) -> VfsResult<()> {
+    unsafe extern "C" fn x_log(p_arg: *mut c_void, err_code: c_int, z_msg: *const c_char) {
+        let hook = unsafe { &*p_arg.cast::<LogHook>() };
+        if !z_msg.is_null() {
+            hook(
+                err_code,
+                &unsafe { CStr::from_ptr(z_msg) }.to_string_lossy(),
+            );
+        }
+    }
+
+    type XLog = unsafe extern "C" fn(*mut c_void, c_int, *const c_char);
+    let result = match hook {
+        Some(hook) => {
+            let p_arg = Box::into_raw(Box::new(hook));
+            unsafe {
+                ffi::sqlite3_config(
+                    vars::SQLITE_CONFIG_LOG,
+                    x_log as XLog,
+                    p_arg.cast::<c_void>(),
+                )
+            }
+        }
+        None => unsafe {
+            ffi::sqlite3_config(
+                vars::SQLITE_CONFIG_LOG,
+                core::ptr::null::<c_void>(),
+                core::ptr::null::<c_void>(),
+            )
+        },
+    };
+    if result == vars::SQLITE_OK {
+        Ok(())
+    } else {
+        Err(result.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use alloc::string::String;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use parking_lot::Mutex;
+
+    #[test]
+    fn dedup() {
+        let mut dedup = Dedup::new(Duration::from_secs(10));
+        let secs = Duration::from_secs;
+        let lines = |out: Vec<(SqliteLogLevel, Vec<u8>)>| -> Vec<String> {
+            out.into_iter()
+                .map(|(_, line)| String::from_utf8(line).unwrap())
+                .collect()
+        };
+
+        assert_eq!(
+            lines(dedup.push(SqliteLogLevel::Error, b"ioerr", secs(0))),
+            ["ioerr"]
+        );
+        assert!(
+            dedup
+                .push(SqliteLogLevel::Error, b"ioerr", secs(1))
+                .is_empty()
+        );
+        assert!(
+            dedup
+                .push(SqliteLogLevel::Error, b"ioerr", secs(5))
+                .is_empty()
+        );
+        // the interval has passed since the line was first logged
+        assert_eq!(
+            lines(dedup.push(SqliteLogLevel::Error, b"ioerr", secs(10))),
+            ["last message repeated 3 times"]
+        );
+        // and the next summary is due an interval after that one
+        assert!(
+            dedup
+                .push(SqliteLogLevel::Error, b"ioerr", secs(19))
+                .is_empty()
+        );
+        assert_eq!(
+            lines(dedup.push(SqliteLogLevel::Notice, b"recovered", secs(20))),
+            ["last message repeated 1 time", "recovered"]
+        );
+        assert!(dedup.flush(secs(30)).is_none());
+    }
+
+    #[cfg(feature = "static")]
+    #[test]
+    fn dedup_logger() {
+        use core::sync::atomic::{AtomicU64, Ordering};
+
+        // a simulated clock
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        fn clock() -> Duration {
+            Duration::from_secs(NOW.load(Ordering::Relaxed))
+        }
+
+        let _hook = TEST_LOG_HOOK.lock();
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let captured = lines.clone();
+        // other tests log through SQLite at the same time
+        set_log_hook(Some(Box::new(move |_, msg| {
+            if msg.starts_with("dedup logger storm") || msg.starts_with("last message repeated") {
+                captured.lock().push(msg.into());
+            }
+        })))
+        .unwrap();
+
+        let logger = DedupLogger::new(
+            SqliteLogger::new(ffi::sqlite3_log),
+            Duration::from_secs(10),
+            clock,
+        );
+        for _ in 0..1000 {
+            logger.log(SqliteLogLevel::Error, b"dedup logger storm");
+            NOW.fetch_add(1, Ordering::Relaxed);
+        }
+        logger.flush();
+        set_log_hook(None).unwrap();
+
+        // the first line, a summary every 10 seconds, and the rest on flush
+        let lines = lines.lock();
+        assert_eq!(lines.len(), 101);
+        assert_eq!(lines[0], "dedup logger storm");
+        assert_eq!(lines[1], "last message repeated 10 times");
+        assert_eq!(lines[100], "last message repeated 9 times");
+    }
+
+    #[cfg(feature = "static")]
+    #[test]
+    fn log_hook() {
+        let _hook = TEST_LOG_HOOK.lock();
+        let lines = Arc::new(Mutex::new(Vec::<(c_int, String)>::new()));
+        let captured = lines.clone();
+        set_log_hook(Some(Box::new(move |code, msg| {
+            captured.lock().push((code, msg.into()));
+        })))
+        .unwrap();
+
+        let logger = SqliteLogger::new(ffi::sqlite3_log);
+        logger.log(SqliteLogLevel::Warn, b"log hook test");
+        set_log_hook(None).unwrap();
+        logger.log(SqliteLogLevel::Warn, b"after removal");
+
+        let lines = lines.lock();
+        assert!(lines.contains(&(vars::SQLITE_WARNING, "log hook test".into())));
+        assert!(!lines.iter().any(|(_, msg)| msg == "after removal"));
+    }
+}
@@ -4,6 +4,8 @@
 extern crate std;
 
 use core::fmt::{self, Display};
+use core::ptr::NonNull;
+use core::time::Duration;
 use std::boxed::Box;
 use std::collections::HashMap;
 use std::println;
@@ -14,12 +16,11 @@ use alloc::format;
 use alloc::sync::Arc;
 use parking_lot::{Mutex, MutexGuard};
 
-use crate::flags::{self, AccessFlags, OpenOpts};
+use crate::flags::{self, AccessFlags, OpenOpts, ShmLockMode, SyncFlags};
 use crate::logger::{SqliteLogLevel, SqliteLogger};
-use crate::vars;
 use crate::vfs::{
-    DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, Pragma, PragmaErr, Vfs, VfsHandle,
-    VfsResult,
+    DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, FileControlOp, JournalPolicy, Pragma,
+    PragmaErr, UriParams, Vfs, VfsError, VfsHandle, VfsResult,
 };
 
 pub struct File {
@@ -30,15 +31,35 @@ pub struct File {
 
 #[allow(unused_variables)]
 pub trait Hooks {
+    fn probe(&mut self) -> VfsResult<()> {
+        Ok(())
+    }
+    fn last_error(&mut self) -> Option<(i32, String)> {
+        None
+    }
     fn canonical_path(&mut self, path: &str) {}
-    fn open(&mut self, path: &Option<&str>, opts: &OpenOpts) {}
+    fn open(&mut self, path: &Option<&str>, opts: &OpenOpts, params: &UriParams<'_>) {}
     fn delete(&mut self, path: &str) {}
     fn access(&mut self, path: &str, flags: AccessFlags) {}
+    /// The contents to create a new database with, if any.
+    fn bootstrap(&mut self, path: &str) -> Option<Vec<u8>> {
+        None
+    }
+    fn journal_policy(&mut self, db_path: &str) -> JournalPolicy {
+        JournalPolicy::Backend
+    }
+    /// Whether handles to the file at `path` report being in memory.
+    fn in_memory(&mut self, path: &str) -> bool {
+        false
+    }
     fn file_size(&mut self, handle: MockHandle) {}
     fn truncate(&mut self, handle: MockHandle, size: usize) {}
     fn write(&mut self, handle: MockHandle, offset: usize, buf: &[u8]) {}
     fn read(&mut self, handle: MockHandle, offset: usize, buf: &[u8]) {}
-    fn sync(&mut self, handle: MockHandle) {}
+    fn check_reserved_lock(&mut self, handle: MockHandle) -> bool {
+        false
+    }
+    fn sync(&mut self, handle: MockHandle, flags: SyncFlags) {}
     fn close(&mut self, handle: MockHandle) {}
     fn pragma(
         &mut self,
@@ -47,9 +68,30 @@ pub trait Hooks {
     ) -> Result<Option<String>, PragmaErr> {
         Err(PragmaErr::NotFound)
     }
-    fn sector_size(&mut self) {}
-    fn device_characteristics(&mut self) {
+    fn sector_size(&mut self, handle: MockHandle) -> usize {
+        DEFAULT_SECTOR_SIZE
+    }
+    fn device_characteristics(&mut self, handle: MockHandle) -> i32 {
         println!("device_characteristics");
+        DEFAULT_DEVICE_CHARACTERISTICS
+    }
+    fn file_control(&mut self, handle: MockHandle, op: FileControlOp<'_>) -> VfsResult<()> {
+        Err(VfsError::NotFound)
+    }
+    fn vfs_name(&mut self, registered: &str) -> Option<String> {
+        Some(registered.to_owned())
+    }
+    fn supports_shm(&mut self) -> bool {
+        false
+    }
+    fn sleep(&mut self, duration: Duration) -> Option<Duration> {
+        None
+    }
+    fn current_time(&mut self) -> Option<i64> {
+        None
+    }
+    fn randomness(&mut self, buf: &mut [u8]) -> Option<usize> {
+        None
     }
 }
 
@@ -60,6 +102,7 @@ impl Hooks for NoopHooks {}
 pub struct MockHandle {
     id: usize,
     readonly: bool,
+    in_memory: bool,
 }
 
 impl Display for MockHandle {
@@ -70,7 +113,7 @@ impl Display for MockHandle {
 
 impl MockHandle {
     pub fn new(id: usize, readonly: bool) -> Self {
-        Self { id, readonly }
+        Self { id, readonly, in_memory: false }
     }
 }
 
@@ -80,12 +123,13 @@ impl VfsHandle for MockHandle {
     }
 
     fn in_memory(&self) -> bool {
-        false
+        self.in_memory
     }
 }
 
 // MockVfs implements a very simple in-memory VFS for testing purposes.
 // See the memvfs example for a more complete implementation.
+#[derive(Clone)]
 pub struct MockVfs {
     shared: Arc<Mutex<Shared>>,
 }
@@ -93,6 +137,7 @@ pub struct MockVfs {
 struct Shared {
     next_id: usize,
     files: HashMap<MockHandle, File>,
+    shm: HashMap<MockHandle, Vec<Box<[u8]>>>,
     hooks: Box<dyn Hooks + Send>,
     log: Option<SqliteLogger>,
 }
@@ -103,6 +148,7 @@ impl MockVfs {
             shared: Arc::new(Mutex::new(Shared {
                 next_id: 0,
                 files: HashMap::new(),
+                shm: HashMap::new(),
                 hooks,
                 log: None,
             })),
@@ -140,6 +186,16 @@ impl Vfs for MockVfs {
         shared.log = Some(logger);
     }
 
+    fn probe(&self) -> VfsResult<()> {
+        let mut shared = self.shared();
+        shared.log(format_args!("probe"));
+        shared.hooks.probe()
+    }
+
+    fn last_error(&self) -> Option<(i32, String)> {
+        self.shared().hooks.last_error()
+    }
+
     fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
         let mut shared = self.shared();
         shared.log(format_args!("canonical_path: path={path:?}"));
@@ -147,13 +203,21 @@ impl Vfs for MockVfs {
         Ok(path)
     }
 
-    fn open(&self, path: Option<&str>, opts: flags::OpenOpts) -> VfsResult<Self::Handle> {
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: flags::OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
         let mut shared = self.shared();
-        shared.log(format_args!("open: path={path:?} opts={opts:?}"));
-        shared.hooks.open(&path, &opts);
+        shared.log(format_args!(
+            "open: path={path:?} opts={opts:?} params={params:?}"
+        ));
+        shared.hooks.open(&path, &opts, &params);
 
         let id = shared.next_id();
-        let file_handle = MockHandle::new(id, opts.mode().is_readonly());
+        let mut file_handle = MockHandle::new(id, opts.mode().is_readonly());
+        file_handle.in_memory = path.is_some_and(|path| shared.hooks.in_memory(path));
 
         if let Some(path) = path {
             // if file is already open return existing handle
@@ -189,6 +253,25 @@ impl Vfs for MockVfs {
         Ok(shared.files.values().any(|file| file.name == path))
     }
 
+    fn journal_policy(&self, db_path: &str) -> JournalPolicy {
+        let mut shared = self.shared();
+        shared.log(format_args!("journal_policy: db_path={db_path:?}"));
+        shared.hooks.journal_policy(db_path)
+    }
+
+    fn bootstrap(&self, path: &str) -> VfsResult<()> {
+        let mut shared = self.shared();
+        shared.log(format_args!("bootstrap: path={path:?}"));
+        if let Some(data) = shared.hooks.bootstrap(path) {
+            let handle = MockHandle::new(shared.next_id(), false);
+            let name = path.to_owned();
+            shared
+                .files
+                .insert(handle, File { name, data, delete_on_close: false });
+        }
+        Ok(())
+    }
+
     fn file_size(&self, meta: &mut Self::Handle) -> VfsResult<usize> {
         let mut shared = self.shared();
         shared.log(format_args!("file_size: handle={meta:?}"));
@@ -226,7 +309,7 @@ impl Vfs for MockVfs {
             file.data[offset..offset + buf.len()].copy_from_slice(buf);
             Ok(buf.len())
         } else {
-            Err(vars::SQLITE_IOERR_WRITE)
+            Err(VfsError::Io)
         }
     }
 
@@ -247,14 +330,20 @@ impl Vfs for MockVfs {
             buf[..len].copy_from_slice(&file.data[offset..offset + len]);
             Ok(len)
         } else {
-            Err(vars::SQLITE_IOERR_READ)
+            Err(VfsError::Io)
         }
     }
 
-    fn sync(&self, meta: &mut Self::Handle) -> VfsResult<()> {
+    fn check_reserved_lock(&self, meta: &mut Self::Handle) -> VfsResult<bool> {
+        let mut shared = self.shared();
+        shared.log(format_args!("check_reserved_lock: handle={meta:?}"));
+        Ok(shared.hooks.check_reserved_lock(*meta))
+    }
+
+    fn sync(&self, meta: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
         let mut shared = self.shared();
         shared.log(format_args!("sync: handle={meta:?}"));
-        shared.hooks.sync(*meta);
+        shared.hooks.sync(*meta, flags);
         Ok(())
     }
 
@@ -280,17 +369,86 @@ impl Vfs for MockVfs {
         shared.hooks.pragma(*meta, pragma)
     }
 
-    fn sector_size(&self) -> i32 {
+    fn sector_size(&self, meta: &mut Self::Handle) -> usize {
         let mut shared = self.shared();
-        shared.log(format_args!("sector_size"));
-        shared.hooks.sector_size();
-        DEFAULT_SECTOR_SIZE
+        shared.log(format_args!("sector_size: handle={meta:?}"));
+        shared.hooks.sector_size(*meta)
     }
 
-    fn device_characteristics(&self) -> i32 {
+    fn device_characteristics(&self, meta: &mut Self::Handle) -> i32 {
         let mut shared = self.shared();
-        shared.log(format_args!("device_characteristics"));
-        shared.hooks.device_characteristics();
-        DEFAULT_DEVICE_CHARACTERISTICS
+        shared.log(format_args!("device_characteristics: handle={meta:?}"));
+        shared.hooks.device_characteristics(*meta)
+    }
+
+    fn file_control(&self, meta: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        let mut shared = self.shared();
+        shared.log(format_args!("file_control: handle={meta:?} op={op:?}"));
+        shared.hooks.file_control(*meta, op)
+    }
+
+    fn vfs_name(&self, registered: &str) -> Option<String> {
+        self.shared().hooks.vfs_name(registered)
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.shared().hooks.sleep(duration)
+    }
+
+    fn current_time(&self) -> Option<i64> {
+        self.shared().hooks.current_time()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        self.shared().hooks.randomness(buf)
+    }
+
+    fn supports_shm(&self) -> bool {
+        self.shared().hooks.supports_shm()
+    }
+
+    fn shm_map(
+        &self,
+        meta: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        let mut shared = self.shared();
+        shared.log(format_args!(
+            "shm_map: handle={meta:?} region={region} size={size} extend={extend}"
+        ));
+        let regions = shared.shm.entry(*meta).or_default();
+        while extend && regions.len() <= region {
+            regions.push(std::vec![0; size].into_boxed_slice());
+        }
+        Ok(regions
+            .get_mut(region)
+            .map(|region| NonNull::from(&mut region[0])))
+    }
+
+    // the mock is only used by one connection at a time, so the wal-index
+    // locks never conflict
+    fn shm_lock(
+        &self,
+        meta: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        let shared = self.shared();
+        shared.log(format_args!(
+            "shm_lock: handle={meta:?} offset={offset} count={count} mode={mode:?}"
+        ));
+        Ok(())
+    }
+
+    fn shm_unmap(&self, meta: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        let mut shared = self.shared();
+        shared.log(format_args!("shm_unmap: handle={meta:?} delete={delete}"));
+        if delete {
+            shared.shm.remove(meta);
+        }
+        Ok(())
     }
 }
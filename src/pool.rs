@@ -0,0 +1,129 @@
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use spin::Mutex;
+
+/// How many buffers a [`BufferPool`] keeps by default, which covers a few
+/// connections doing io at once.
+pub const DEFAULT_MAX_FREE: usize = 16;
+
+/// A pool of byte buffers, which wrappers use as scratch space for the
+/// blocks they read and rewrite so that their io doesn't allocate once the
+/// pool is warm. Buffers are returned to the pool when dropped, and up to
+/// `max_free` of them are kept.
+pub struct BufferPool {
+    max_free: usize,
+    inner: Mutex<Pool>,
+}
+
+struct Pool {
+    free: Vec<Vec<u8>>,
+    stats: PoolStats,
+}
+
+/// How a [`BufferPool`] has served its buffers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers served from the pool without allocating.
+    pub hits: u64,
+    /// Buffers which had to be allocated, or grown.
+    pub misses: u64,
+    /// The buffers currently in the pool.
+    pub free: usize,
+}
+
+impl BufferPool {
+    pub const fn new(max_free: usize) -> Self {
+        Self {
+            max_free,
+            inner: Mutex::new(Pool {
+                free: Vec::new(),
+                stats: PoolStats { hits: 0, misses: 0, free: 0 },
+            }),
+        }
+    }
+
+    /// A buffer of `len` zeros.
+    pub fn get(&self, len: usize) -> PoolBuf<'_> {
+        let mut buf = {
+            let mut pool = self.inner.lock();
+            let buf = pool.free.pop().unwrap_or_default();
+            if buf.capacity() >= len {
+                pool.stats.hits += 1;
+            } else {
+                pool.stats.misses += 1;
+            }
+            buf
+        };
+        buf.resize(len, 0);
+        PoolBuf { buf, pool: self }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let pool = self.inner.lock();
+        PoolStats { free: pool.free.len(), ..pool.stats }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FREE)
+    }
+}
+
+/// A buffer taken from a [`BufferPool`], which it goes back to when dropped.
+pub struct PoolBuf<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PoolBuf<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PoolBuf<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PoolBuf<'_> {
+    fn drop(&mut self) {
+        let mut buf = core::mem::take(&mut self.buf);
+        buf.clear();
+        let mut pool = self.pool.inner.lock();
+        if pool.free.len() < self.pool.max_free {
+            pool.free.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(1);
+        let mut buf = pool.get(16);
+        assert_eq!(&buf[..], &[0; 16]);
+        buf.fill(7);
+        let other = pool.get(8);
+        drop(buf);
+        // only one buffer is kept
+        drop(other);
+        assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 2, free: 1 });
+
+        // a reused buffer is zeroed
+        let buf = pool.get(16);
+        assert_eq!(&buf[..], &[0; 16]);
+        drop(buf);
+        let buf = pool.get(4096);
+        assert_eq!(buf.len(), 4096);
+        drop(buf);
+        assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 3, free: 1 });
+    }
+}
@@ -0,0 +1,327 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::ops::Bound;
+use spin::Mutex;
+
+use crate::flags::OpenOpts;
+use crate::shim;
+use crate::vfs::{UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+/// A Vfs wrapper which limits how many bytes files may grow to, failing
+/// writes and truncates which would exceed a limit with `SQLITE_FULL`.
+///
+/// A file limit applies to one file, and a prefix limit to the total size of
+/// every file whose path starts with the prefix, so a prefix limit on a
+/// database's path also covers its journal and WAL, and one on a directory
+/// covers everything in it. Usage is the size of the files opened through
+/// this vfs, as of when they were first opened and as written since, until
+/// they are deleted. Temporary files and others deleted when they are
+/// closed are never limited.
+///
+/// ```no_run
+/// # use sqlite_plugin::quota::QuotaVfs;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// # fn main() -> VfsResult<()> {
+/// let (vfs, quotas) = QuotaVfs::new(BaseVfs::find(None)?);
+/// register_static(c"quota".to_owned(), vfs, RegisterOpts::default())?;
+/// quotas.set_prefix_limit("/tenants/a/", Some(64 << 20));
+/// // ... later
+/// let used = quotas.usage("/tenants/a/");
+/// # Ok(())
+/// # }
+/// ```
+pub struct QuotaVfs<V> {
+    vfs: V,
+    state: Arc<Mutex<State>>,
+}
+
+/// Adjusts the limits of the paired [`QuotaVfs`] and reports its usage, while
+/// it is registered.
+#[derive(Clone)]
+pub struct Quotas {
+    state: Arc<Mutex<State>>,
+}
+
+pub struct QuotaHandle<H> {
+    inner: H,
+    name: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    file_limits: BTreeMap<String, usize>,
+    prefix_limits: BTreeMap<String, usize>,
+    sizes: BTreeMap<String, usize>,
+}
+
+impl State {
+    fn usage(&self, prefix: &str) -> usize {
+        self.sizes
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(path, _)| path.starts_with(prefix))
+            .map(|(_, size)| size)
+            .sum()
+    }
+
+    // checks that `path` may change from its current size to `size`
+    fn check(&self, path: &str, size: usize) -> VfsResult<()> {
+        let current = self.sizes.get(path).copied().unwrap_or_default();
+        let Some(growth) = size.checked_sub(current).filter(|&n| n > 0) else {
+            return Ok(());
+        };
+        if self
+            .file_limits
+            .get(path)
+            .is_some_and(|&limit| size > limit)
+        {
+            return Err(VfsError::Full);
+        }
+        for (prefix, &limit) in &self.prefix_limits {
+            if path.starts_with(prefix.as_str()) && self.usage(prefix) + growth > limit {
+                return Err(VfsError::Full);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<V: Vfs> QuotaVfs<V> {
+    pub fn new(vfs: V) -> (Self, Quotas) {
+        let state = Arc::new(Mutex::new(State::default()));
+        (Self { vfs, state: state.clone() }, Quotas { state })
+    }
+
+    /// The limits and usage of the vfs, as returned by [`new`](QuotaVfs::new).
+    pub fn quotas(&self) -> Quotas {
+        Quotas { state: self.state.clone() }
+    }
+}
+
+impl Quotas {
+    /// Limits the size of the file at `path`, or removes its limit. Files
+    /// already past a new limit may still shrink, but not grow.
+    pub fn set_file_limit(&self, path: &str, limit: Option<usize>) {
+        let mut state = self.state.lock();
+        match limit {
+            Some(limit) => state.file_limits.insert(path.into(), limit),
+            None => state.file_limits.remove(path),
+        };
+    }
+
+    /// Limits the total size of the files whose paths start with `prefix`,
+    /// or removes its limit.
+    pub fn set_prefix_limit(&self, prefix: &str, limit: Option<usize>) {
+        let mut state = self.state.lock();
+        match limit {
+            Some(limit) => state.prefix_limits.insert(prefix.into(), limit),
+            None => state.prefix_limits.remove(prefix),
+        };
+    }
+
+    /// Replaces every limit at once, with the file limits by path and the
+    /// prefix limits by prefix.
+    pub fn set_limits(
+        &self,
+        file_limits: BTreeMap<String, usize>,
+        prefix_limits: BTreeMap<String, usize>,
+    ) {
+        let mut state = self.state.lock();
+        state.file_limits = file_limits;
+        state.prefix_limits = prefix_limits;
+    }
+
+    /// The total size of the known files whose paths start with `prefix`.
+    pub fn usage(&self, prefix: &str) -> usize {
+        self.state.lock().usage(prefix)
+    }
+
+    /// The size of the file at `path`, or `None` if it is not known.
+    pub fn file_size(&self, path: &str) -> Option<usize> {
+        self.state.lock().sizes.get(path).copied()
+    }
+}
+
+impl<H: VfsHandle> VfsHandle for QuotaHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for QuotaHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> QuotaVfs<V> {
+    // runs `op` if the handle's file may be resized from its current size
+    // to `size(current)`, and records the new size
+    fn resize<T>(
+        &self,
+        handle: &mut QuotaHandle<V::Handle>,
+        size: impl FnOnce(usize) -> usize,
+        op: impl FnOnce(&mut V::Handle) -> VfsResult<T>,
+    ) -> VfsResult<T> {
+        let Some(name) = &handle.name else {
+            return op(&mut handle.inner);
+        };
+        // the new size is reserved before the operation, so that concurrent
+        // writes can't both fit in the same remaining space
+        {
+            let mut state = self.state.lock();
+            let size = size(state.sizes.get(name).copied().unwrap_or_default());
+            state.check(name, size)?;
+            state.sizes.insert(name.clone(), size);
+        }
+        op(&mut handle.inner).inspect_err(|_| {
+            // the file may or may not have changed size
+            if let Ok(size) = self.vfs.file_size(&mut handle.inner) {
+                self.state.lock().sizes.insert(name.clone(), size);
+            }
+        })
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for QuotaVfs<V> {
+    type Inner = V;
+    type Handle = QuotaHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let mut inner = self.vfs.open(path, opts, params)?;
+        // temporary files are given generated names, but are deleted on close
+        // without a call to delete
+        let path = path.filter(|_| !opts.delete_on_close() && !opts.kind().is_temp());
+        if let Some(path) = path {
+            let size = self.vfs.file_size(&mut inner)?;
+            self.state.lock().sizes.insert(path.into(), size);
+        }
+        Ok(QuotaHandle { inner, name: path.map(Into::into) })
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.vfs.delete(path)?;
+        self.state.lock().sizes.remove(path);
+        Ok(())
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.resize(handle, |_| size, |inner| self.vfs.truncate(inner, size))
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let end = offset + data.len();
+        self.resize(
+            handle,
+            |current| current.max(end),
+            |inner| self.vfs.write(inner, offset, data),
+        )
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for QuotaVfs<V>);
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::logger::SqliteLogger;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::vars;
+    use std::boxed::Box;
+
+    #[test]
+    fn quotas() {
+        let (vfs, quotas) = QuotaVfs::new(MockVfs::new(Box::new(NoopHooks)));
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+        quotas.set_prefix_limit("a/", Some(1000));
+        quotas.set_file_limit("a/main.db-journal", Some(300));
+
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let mut main = vfs
+            .open(Some("a/main.db"), opts, UriParams::empty())
+            .unwrap();
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_JOURNAL | vars::SQLITE_OPEN_READWRITE);
+        let mut journal = vfs
+            .open(Some("a/main.db-journal"), opts, UriParams::empty())
+            .unwrap();
+
+        vfs.write(&mut main, 0, &[1; 600]).unwrap();
+        assert_eq!(quotas.usage("a/"), 600);
+        // overwriting doesn't grow the file
+        vfs.write(&mut main, 100, &[2; 500]).unwrap();
+        assert_eq!(quotas.file_size("a/main.db"), Some(600));
+
+        // the journal may not pass its own limit, nor the prefix's
+        assert_eq!(vfs.write(&mut journal, 0, &[3; 301]), Err(VfsError::Full));
+        vfs.write(&mut journal, 0, &[3; 300]).unwrap();
+        assert_eq!(vfs.write(&mut main, 600, &[4; 101]), Err(VfsError::Full));
+        assert_eq!(vfs.truncate(&mut main, 701), Err(VfsError::Full));
+        vfs.write(&mut main, 600, &[4; 100]).unwrap();
+        assert_eq!(quotas.usage("a/"), 1000);
+
+        // shrinking and deleting files frees space, as does raising the limit
+        vfs.truncate(&mut journal, 0).unwrap();
+        assert_eq!(quotas.usage("a/"), 700);
+        vfs.close(journal).unwrap();
+        vfs.delete("a/main.db-journal").unwrap();
+        assert_eq!(quotas.file_size("a/main.db-journal"), None);
+        assert_eq!(vfs.write(&mut main, 700, &[5; 400]), Err(VfsError::Full));
+        quotas.set_prefix_limit("a/", None);
+        vfs.write(&mut main, 700, &[5; 400]).unwrap();
+        assert_eq!(quotas.usage("a/"), 1100);
+        assert_eq!(quotas.usage("b/"), 0);
+        vfs.close(main).unwrap();
+    }
+
+    #[cfg(feature = "static")]
+    #[test]
+    fn temp_files() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::testing::TestVfs;
+        use rusqlite::{Connection, OpenFlags};
+
+        let (vfs, quotas) = QuotaVfs::new(MockVfs::new(Box::new(NoopHooks)));
+        let vfs = TestVfs::scoped_with("quota_temp", vfs)?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs.name(),
+        )?;
+        // a small cache spills the temporary table to a file
+        conn.execute_batch(
+            "pragma temp_store = file;
+            pragma temp.cache_size = 10;
+            create table t(x);
+            insert into t values (1);
+            create temp table u(x);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 500)
+            insert into u select randomblob(1000) from n;
+            drop table u;",
+        )?;
+        drop(conn);
+
+        let main = quotas.file_size("main.db").ok_or("main.db is not known")?;
+        assert_eq!(quotas.usage(""), main);
+        Ok(())
+    }
+}
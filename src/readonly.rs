@@ -0,0 +1,213 @@
+use crate::flags::{AccessFlags, LockLevel, OpenKind, OpenOpts, SyncFlags};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+/// A Vfs wrapper which serves the files of another vfs read-only, for
+/// snapshots which nothing may change.
+///
+/// Databases and their journals are opened read-only whatever the connection
+/// asks for, and writes, truncates and deletes fail with `SQLITE_READONLY`.
+/// Databases report `SQLITE_IOCAP_IMMUTABLE`, so `SQLite` treats them as if
+/// opened with `immutable=1`: it takes no locks, and ignores hot journals and
+/// WAL files. Temporary files, which `SQLite` needs for sorting and temporary
+/// tables, are still writable.
+///
+/// ```no_run
+/// # use sqlite_plugin::readonly::ReadOnlyVfs;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// # fn main() -> VfsResult<()> {
+/// let vfs = ReadOnlyVfs::new(BaseVfs::find(None)?);
+/// register_static(c"snapshot".to_owned(), vfs, RegisterOpts::default())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ReadOnlyVfs<V> {
+    vfs: V,
+}
+
+pub struct ReadOnlyHandle<H> {
+    inner: H,
+    // whether the file is a temporary file, which is left writable
+    scratch: bool,
+}
+
+fn readonly<T>() -> VfsResult<T> {
+    Err(VfsError::Other(vars::SQLITE_READONLY))
+}
+
+impl<H: VfsHandle> VfsHandle for ReadOnlyHandle<H> {
+    fn readonly(&self) -> bool {
+        !self.scratch || self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for ReadOnlyHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> ReadOnlyVfs<V> {
+    pub fn new(vfs: V) -> Self {
+        Self { vfs }
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for ReadOnlyVfs<V> {
+    type Inner = V;
+    type Handle = ReadOnlyHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        mut opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let kind = opts.kind();
+        let scratch = path.is_none() || kind.is_temp() || kind == OpenKind::SubJournal;
+        if !scratch {
+            if opts.mode().must_create() {
+                return readonly();
+            }
+            opts.set_readonly();
+        }
+        let inner = self.vfs.open(path, opts, params)?;
+        Ok(ReadOnlyHandle { inner, scratch })
+    }
+
+    fn delete(&self, _path: &str) -> VfsResult<()> {
+        readonly()
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        if flags == AccessFlags::ReadWrite {
+            return Ok(false);
+        }
+        self.vfs.access(path, flags)
+    }
+
+    fn bootstrap(&self, _path: &str) -> VfsResult<()> {
+        // a missing database is not created, so the open fails
+        Ok(())
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        if !handle.scratch {
+            return readonly();
+        }
+        self.vfs.truncate(&mut handle.inner, size)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        if !handle.scratch {
+            return readonly();
+        }
+        self.vfs.write(&mut handle.inner, offset, data)
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        if !handle.scratch {
+            // nothing can change the file, so readers need not exclude writers
+            return Ok(());
+        }
+        self.vfs.lock(&mut handle.inner, level)
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        if !handle.scratch {
+            return Ok(());
+        }
+        self.vfs.unlock(&mut handle.inner, level)
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        if !handle.scratch {
+            return Ok(false);
+        }
+        self.vfs.check_reserved_lock(&mut handle.inner)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        if !handle.scratch {
+            return Ok(());
+        }
+        self.vfs.sync(&mut handle.inner, flags)
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        let characteristics = self.vfs.device_characteristics(&mut handle.inner);
+        if handle.scratch {
+            characteristics
+        } else {
+            characteristics | vars::SQLITE_IOCAP_IMMUTABLE
+        }
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for ReadOnlyVfs<V>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{TempDir, os_vfs};
+    use crate::vfs::{RegisterOpts, register_static};
+    use rusqlite::{Connection, ErrorCode, OpenFlags};
+
+    #[test]
+    fn readonly() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let base = os_vfs()?;
+        register_static(
+            c"readonly_test".into(),
+            ReadOnlyVfs::new(base),
+            RegisterOpts::default(),
+        )?;
+
+        let dir = TempDir::new("readonly")?;
+        let path = dir.file("main.db");
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let open = |path: &str| Connection::open_with_flags_and_vfs(path, flags, "readonly_test");
+
+        Connection::open(&path)?.execute_batch(
+            "create table t(x);
+            insert into t values (1), (2), (3);",
+        )?;
+        let before = std::fs::read(&path)?;
+
+        let conn = open(&path)?;
+        let sum: i64 = conn.query_row("select sum(x) from t", [], |row| row.get(0))?;
+        assert_eq!(sum, 6);
+        let err = conn.execute("insert into t values (4)", []).unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly));
+        // temporary tables still work
+        conn.execute_batch("create temp table u as select x * 2 from t")?;
+        drop(conn);
+        assert_eq!(std::fs::read(&path)?, before);
+
+        // a missing database is not created
+        let missing = dir.file("missing.db");
+        assert!(
+            open(&missing)
+                .and_then(|conn| conn.execute_batch("select * from t"))
+                .is_err()
+        );
+        assert!(!std::fs::exists(&missing)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,1024 @@
+use alloc::borrow::Cow;
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::{CStr, c_char, c_int, c_void};
+use core::ptr::{NonNull, null_mut};
+use core::time::Duration;
+
+use crate::flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode, SyncFlags};
+use crate::logger::SqliteLogger;
+use crate::vfs::{
+    DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, FileControlOp, JournalPolicy, Pragma,
+    PragmaErr, UNIX_EPOCH_JULIAN_MS, UriParams, Vfs, VfsError, VfsHandle, VfsResult,
+};
+use crate::{ffi, vars};
+
+/// A Vfs which passes every operation to a vfs already registered with
+/// `SQLite`, such as the built-in `unix` or `win32` vfs. This is the usual
+/// [`VfsShim::Inner`] for a shim that adds behavior to real files.
+///
+/// Look up the base vfs before registering a shim over it with
+/// `make_default`, otherwise the default vfs is the shim itself.
+pub struct BaseVfs {
+    p_vfs: NonNull<ffi::sqlite3_vfs>,
+}
+
+// registered vfs are threadsafe, and from_raw requires them to outlive this
+unsafe impl Send for BaseVfs {}
+unsafe impl Sync for BaseVfs {}
+
+/// A file opened by a [`BaseVfs`].
+pub struct BaseHandle {
+    // the base vfs's sqlite3_file, sized by its szOsFile. u64s keep it aligned
+    file: Vec<u64>,
+    // the name given to xOpen, which the base vfs may keep a pointer to
+    _name: Option<Vec<u8>>,
+    readonly: bool,
+}
+
+// a file is only used by one connection at a time
+unsafe impl Send for BaseHandle {}
+
+impl VfsHandle for BaseHandle {
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn in_memory(&self) -> bool {
+        false
+    }
+}
+
+impl BaseHandle {
+    // the io method `get` picks from the file's methods, and the file to call
+    // it with
+    fn method<F>(
+        &mut self,
+        get: impl FnOnce(&ffi::sqlite3_io_methods) -> Option<F>,
+    ) -> VfsResult<(F, *mut ffi::sqlite3_file)> {
+        let p_file = self.file.as_mut_ptr().cast::<ffi::sqlite3_file>();
+        let methods = unsafe { (*p_file).pMethods.as_ref() }.ok_or(VfsError::Io)?;
+        let method = get(methods).ok_or(VfsError::NotFound)?;
+        Ok((method, p_file))
+    }
+
+    fn io_version(&mut self) -> c_int {
+        self.method(|methods| Some(methods.iVersion))
+            .map_or(0, |(version, _)| version)
+    }
+}
+
+fn check(rc: c_int) -> VfsResult<()> {
+    // the primary code, so that SQLITE_OK_SYMLINK counts as success
+    if rc & 0xff == vars::SQLITE_OK {
+        Ok(())
+    } else {
+        Err(rc.into())
+    }
+}
+
+fn c_path(path: &str) -> VfsResult<CString> {
+    CString::new(path).map_err(|_| VfsError::CantOpen)
+}
+
+impl BaseVfs {
+    /// Wraps the vfs registered as `name`, or the default vfs if `name` is
+    /// None.
+    #[cfg(feature = "static")]
+    pub fn find(name: Option<&str>) -> VfsResult<Self> {
+        let name = name
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| VfsError::NotFound)?;
+        let p_vfs = unsafe {
+            ffi::sqlite3_vfs_find(name.as_deref().map_or(core::ptr::null(), CStr::as_ptr))
+        };
+        unsafe { Self::from_raw(p_vfs) }.ok_or(VfsError::NotFound)
+    }
+
+    /// Wraps the vfs at `p_vfs`, or returns None if it is null.
+    ///
+    /// # Safety
+    /// `p_vfs` must be a threadsafe vfs which stays registered for as long as
+    /// the returned `BaseVfs` is used.
+    pub unsafe fn from_raw(p_vfs: *mut ffi::sqlite3_vfs) -> Option<Self> {
+        NonNull::new(p_vfs).map(|p_vfs| Self { p_vfs })
+    }
+
+    /// The name the base vfs is registered as.
+    pub fn name(&self) -> &str {
+        let z_name = self.base().zName;
+        if z_name.is_null() {
+            return "";
+        }
+        unsafe { CStr::from_ptr(z_name) }
+            .to_str()
+            .unwrap_or_default()
+    }
+
+    fn base(&self) -> &ffi::sqlite3_vfs {
+        unsafe { self.p_vfs.as_ref() }
+    }
+}
+
+impl Vfs for BaseVfs {
+    type Handle = BaseHandle;
+
+    fn register_logger(&self, _logger: SqliteLogger) {}
+
+    fn last_error(&self) -> Option<(i32, String)> {
+        let x_get_last_error = self.base().xGetLastError?;
+        let mut buf = vec![0u8; 256];
+        let errno = unsafe {
+            x_get_last_error(
+                self.p_vfs.as_ptr(),
+                buf.len() as c_int,
+                buf.as_mut_ptr().cast(),
+            )
+        };
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        buf.truncate(len);
+        let msg = String::from_utf8_lossy(&buf).into_owned();
+        (errno != 0 || !msg.is_empty()).then_some((errno, msg))
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        let x_full_pathname = self.base().xFullPathname.ok_or(VfsError::CantOpen)?;
+        let z_path = c_path(&path)?;
+        let n_out = usize::try_from(self.base().mxPathname).unwrap_or(0) + 1;
+        let mut out = vec![0u8; n_out];
+        check(unsafe {
+            x_full_pathname(
+                self.p_vfs.as_ptr(),
+                z_path.as_ptr(),
+                n_out as c_int,
+                out.as_mut_ptr().cast(),
+            )
+        })?;
+        let len = out.iter().position(|&b| b == 0).unwrap_or(n_out);
+        out.truncate(len);
+        String::from_utf8(out)
+            .map(Cow::Owned)
+            .map_err(|_| VfsError::CantOpen)
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<BaseHandle> {
+        let x_open = self.base().xOpen.ok_or(VfsError::CantOpen)?;
+        let size = usize::try_from(self.base().szOsFile).map_err(|_| VfsError::CantOpen)?;
+        let mut handle = BaseHandle {
+            file: vec![0; size.div_ceil(8).max(1)],
+            _name: None,
+            readonly: false,
+        };
+
+        // SQLite's filenames carry the uri parameters after them, which the
+        // base vfs may read, so pass them on. Other names are laid out the
+        // same way with no parameters: preceded by four NULs and followed by
+        // three.
+        let mut z_name = params.filename();
+        if z_name.is_null() {
+            if let Some(path) = path {
+                if path.contains('\0') {
+                    return Err(VfsError::CantOpen);
+                }
+                let mut name = vec![0u8; 4];
+                name.extend_from_slice(path.as_bytes());
+                name.extend_from_slice(&[0; 3]);
+                z_name = name[4..].as_ptr().cast::<c_char>();
+                handle._name = Some(name);
+            }
+        }
+
+        let mut out_flags = 0;
+        let p_file = handle.file.as_mut_ptr().cast();
+        let rc = unsafe {
+            x_open(
+                self.p_vfs.as_ptr(),
+                z_name,
+                p_file,
+                opts.flags(),
+                &mut out_flags,
+            )
+        };
+        if rc != vars::SQLITE_OK {
+            // a file whose open failed must still be closed if it has methods
+            if let Ok((x_close, p_file)) = handle.method(|methods| methods.xClose) {
+                unsafe { x_close(p_file) };
+            }
+            return Err(rc.into());
+        }
+        handle.readonly = out_flags & vars::SQLITE_OPEN_READONLY != 0;
+        Ok(handle)
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        let x_delete = self.base().xDelete.ok_or(VfsError::NotFound)?;
+        let z_path = c_path(path)?;
+        check(unsafe { x_delete(self.p_vfs.as_ptr(), z_path.as_ptr(), 0) })
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        let x_access = self.base().xAccess.ok_or(VfsError::NotFound)?;
+        let z_path = c_path(path)?;
+        let flags = match flags {
+            AccessFlags::Exists => vars::SQLITE_ACCESS_EXISTS,
+            AccessFlags::Read => vars::SQLITE_ACCESS_READ,
+            AccessFlags::ReadWrite => vars::SQLITE_ACCESS_READWRITE,
+        };
+        let mut res = 0;
+        check(unsafe { x_access(self.p_vfs.as_ptr(), z_path.as_ptr(), flags, &mut res) })?;
+        Ok(res != 0)
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        // nameless files are temporary files the base vfs names itself
+        Err(VfsError::NotFound)
+    }
+
+    fn file_size(&self, handle: &mut BaseHandle) -> VfsResult<usize> {
+        let (x_file_size, p_file) = handle.method(|methods| methods.xFileSize)?;
+        let mut size = 0;
+        check(unsafe { x_file_size(p_file, &mut size) })?;
+        usize::try_from(size).map_err(|_| VfsError::Io)
+    }
+
+    fn truncate(&self, handle: &mut BaseHandle, size: usize) -> VfsResult<()> {
+        let (x_truncate, p_file) = handle.method(|methods| methods.xTruncate)?;
+        let size = i64::try_from(size).map_err(|_| VfsError::Full)?;
+        check(unsafe { x_truncate(p_file, size) })
+    }
+
+    fn write(&self, handle: &mut BaseHandle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let (x_write, p_file) = handle.method(|methods| methods.xWrite)?;
+        let len = c_int::try_from(data.len()).map_err(|_| VfsError::Io)?;
+        let offset = i64::try_from(offset).map_err(|_| VfsError::Full)?;
+        check(unsafe { x_write(p_file, data.as_ptr().cast(), len, offset) })?;
+        Ok(data.len())
+    }
+
+    fn read(&self, handle: &mut BaseHandle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let (x_read, p_file) = handle.method(|methods| methods.xRead)?;
+        let len = c_int::try_from(data.len()).map_err(|_| VfsError::Io)?;
+        let i_offset = i64::try_from(offset).map_err(|_| VfsError::Io)?;
+        let rc = unsafe { x_read(p_file, data.as_mut_ptr().cast(), len, i_offset) };
+        if rc == vars::SQLITE_IOERR_SHORT_READ {
+            // the base vfs zeroed the rest, but doesn't say how much it read
+            let size = self.file_size(handle)?;
+            return Ok(size.saturating_sub(offset).min(data.len()));
+        }
+        check(rc)?;
+        Ok(data.len())
+    }
+
+    fn lock(&self, handle: &mut BaseHandle, level: LockLevel) -> VfsResult<()> {
+        let (x_lock, p_file) = handle.method(|methods| methods.xLock)?;
+        check(unsafe { x_lock(p_file, lock_level(level)) })
+    }
+
+    fn unlock(&self, handle: &mut BaseHandle, level: LockLevel) -> VfsResult<()> {
+        let (x_unlock, p_file) = handle.method(|methods| methods.xUnlock)?;
+        check(unsafe { x_unlock(p_file, lock_level(level)) })
+    }
+
+    fn check_reserved_lock(&self, handle: &mut BaseHandle) -> VfsResult<bool> {
+        let (x_check_reserved_lock, p_file) =
+            handle.method(|methods| methods.xCheckReservedLock)?;
+        let mut res = 0;
+        check(unsafe { x_check_reserved_lock(p_file, &mut res) })?;
+        Ok(res != 0)
+    }
+
+    fn sync(&self, handle: &mut BaseHandle, flags: SyncFlags) -> VfsResult<()> {
+        let (x_sync, p_file) = handle.method(|methods| methods.xSync)?;
+        // the barrier flag is this crate's, and unknown to the base vfs
+        check(unsafe { x_sync(p_file, flags.flags() & !SyncFlags::BARRIER) })
+    }
+
+    fn close(&self, mut handle: BaseHandle) -> VfsResult<()> {
+        let (x_close, p_file) = handle.method(|methods| methods.xClose)?;
+        check(unsafe { x_close(p_file) })
+    }
+
+    fn sector_size(&self, handle: &mut BaseHandle) -> usize {
+        let Ok((x_sector_size, p_file)) = handle.method(|methods| methods.xSectorSize) else {
+            return DEFAULT_SECTOR_SIZE;
+        };
+        usize::try_from(unsafe { x_sector_size(p_file) }).unwrap_or(DEFAULT_SECTOR_SIZE)
+    }
+
+    fn device_characteristics(&self, handle: &mut BaseHandle) -> i32 {
+        let Ok((x_device_characteristics, p_file)) =
+            handle.method(|methods| methods.xDeviceCharacteristics)
+        else {
+            return DEFAULT_DEVICE_CHARACTERISTICS;
+        };
+        unsafe { x_device_characteristics(p_file) }
+    }
+
+    fn file_control(&self, handle: &mut BaseHandle, op: FileControlOp<'_>) -> VfsResult<()> {
+        let (x_file_control, p_file) = handle.method(|methods| methods.xFileControl)?;
+        check(op.with_raw(|op, arg| unsafe { x_file_control(p_file, op, arg) }))
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        let x_sleep = self.base().xSleep?;
+        let micros = c_int::try_from(duration.as_micros()).unwrap_or(c_int::MAX);
+        let slept = unsafe { x_sleep(self.p_vfs.as_ptr(), micros) };
+        Some(Duration::from_micros(u64::try_from(slept).unwrap_or(0)))
+    }
+
+    fn current_time(&self) -> Option<i64> {
+        if self.base().iVersion < 2 {
+            return None;
+        }
+        let x_current_time_int64 = self.base().xCurrentTimeInt64?;
+        let mut now = 0;
+        (unsafe { x_current_time_int64(self.p_vfs.as_ptr(), &mut now) } == vars::SQLITE_OK)
+            .then_some(now - UNIX_EPOCH_JULIAN_MS)
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        let x_randomness = self.base().xRandomness?;
+        let len = c_int::try_from(buf.len()).unwrap_or(c_int::MAX);
+        let n = unsafe { x_randomness(self.p_vfs.as_ptr(), len, buf.as_mut_ptr().cast()) };
+        Some(usize::try_from(n).unwrap_or(0))
+    }
+
+    fn supports_shm(&self) -> bool {
+        true
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut BaseHandle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        let (x_shm_map, p_file) = handle.method(|methods| methods.xShmMap)?;
+        let region = c_int::try_from(region).map_err(|_| VfsError::Io)?;
+        let size = c_int::try_from(size).map_err(|_| VfsError::Io)?;
+        let mut p_region: *mut c_void = null_mut();
+        check(unsafe { x_shm_map(p_file, region, size, extend.into(), &mut p_region) })?;
+        Ok(NonNull::new(p_region.cast()))
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut BaseHandle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        let (x_shm_lock, p_file) = handle.method(|methods| methods.xShmLock)?;
+        let flags = match mode {
+            ShmLockMode::LockShared => vars::SQLITE_SHM_LOCK | vars::SQLITE_SHM_SHARED,
+            ShmLockMode::LockExclusive => vars::SQLITE_SHM_LOCK | vars::SQLITE_SHM_EXCLUSIVE,
+            ShmLockMode::UnlockShared => vars::SQLITE_SHM_UNLOCK | vars::SQLITE_SHM_SHARED,
+            ShmLockMode::UnlockExclusive => vars::SQLITE_SHM_UNLOCK | vars::SQLITE_SHM_EXCLUSIVE,
+        };
+        check(unsafe { x_shm_lock(p_file, offset as c_int, count as c_int, flags) })
+    }
+
+    fn shm_barrier(&self, handle: &mut BaseHandle) {
+        if let Ok((x_shm_barrier, p_file)) = handle.method(|methods| methods.xShmBarrier) {
+            unsafe { x_shm_barrier(p_file) }
+        }
+    }
+
+    fn shm_unmap(&self, handle: &mut BaseHandle, delete: bool) -> VfsResult<()> {
+        let (x_shm_unmap, p_file) = handle.method(|methods| methods.xShmUnmap)?;
+        check(unsafe { x_shm_unmap(p_file, delete.into()) })
+    }
+
+    fn supports_mmap(&self) -> bool {
+        true
+    }
+
+    fn fetch(
+        &self,
+        handle: &mut BaseHandle,
+        offset: usize,
+        len: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        if handle.io_version() < 3 {
+            return Ok(None);
+        }
+        let (x_fetch, p_file) = handle.method(|methods| methods.xFetch)?;
+        let offset = i64::try_from(offset).map_err(|_| VfsError::Io)?;
+        let len = c_int::try_from(len).map_err(|_| VfsError::Io)?;
+        let mut p_page: *mut c_void = null_mut();
+        check(unsafe { x_fetch(p_file, offset, len, &mut p_page) })?;
+        Ok(NonNull::new(p_page.cast()))
+    }
+
+    fn unfetch(
+        &self,
+        handle: &mut BaseHandle,
+        offset: usize,
+        ptr: Option<NonNull<u8>>,
+    ) -> VfsResult<()> {
+        if handle.io_version() < 3 {
+            return Ok(());
+        }
+        let (x_unfetch, p_file) = handle.method(|methods| methods.xUnfetch)?;
+        let offset = i64::try_from(offset).map_err(|_| VfsError::Io)?;
+        let ptr = ptr.map_or(null_mut(), |ptr| ptr.as_ptr().cast());
+        check(unsafe { x_unfetch(p_file, offset, ptr) })
+    }
+}
+
+fn lock_level(level: LockLevel) -> c_int {
+    match level {
+        LockLevel::Unlocked => vars::SQLITE_LOCK_NONE,
+        LockLevel::Shared => vars::SQLITE_LOCK_SHARED,
+        LockLevel::Reserved => vars::SQLITE_LOCK_RESERVED,
+        LockLevel::Pending => vars::SQLITE_LOCK_PENDING,
+        LockLevel::Exclusive => vars::SQLITE_LOCK_EXCLUSIVE,
+    }
+}
+
+/// The handle of a [`VfsShim`], which holds a handle of the vfs it wraps.
+/// Every handle is the `ShimHandle` of itself, for shims which use the
+/// handles of the wrapped vfs as they are.
+pub trait ShimHandle<H>: VfsHandle {
+    fn inner_handle(&mut self) -> &mut H;
+    fn into_inner_handle(self) -> H;
+}
+
+impl<H: VfsHandle> ShimHandle<H> for H {
+    fn inner_handle(&mut self) -> &mut H {
+        self
+    }
+
+    fn into_inner_handle(self) -> H {
+        self
+    }
+}
+
+/// The handles of the vfs a [`VfsShim`] wraps.
+pub type InnerHandle<S> = <<S as VfsShim>::Inner as Vfs>::Handle;
+
+/// A Vfs which wraps another, passing every operation it doesn't override to
+/// [`VfsShim::inner`]. The methods are those of [`Vfs`], so a shim only
+/// implements `open` and the operations it changes, and becomes a `Vfs`
+/// through [`impl_vfs_shim`](crate::impl_vfs_shim):
+///
+/// ```no_run
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// # use sqlite_plugin::flags::OpenOpts;
+/// # use sqlite_plugin::shim::{BaseHandle, BaseVfs, VfsShim};
+/// # use sqlite_plugin::vfs::{RegisterOpts, UriParams, Vfs, VfsResult, register_static};
+/// struct CountWrites {
+///     inner: BaseVfs,
+///     writes: AtomicUsize,
+/// }
+///
+/// impl VfsShim for CountWrites {
+///     type Inner = BaseVfs;
+///     type Handle = BaseHandle;
+///
+///     fn inner(&self) -> &BaseVfs {
+///         &self.inner
+///     }
+///
+///     fn open(&self, path: Option<&str>, opts: OpenOpts, params: UriParams<'_>) -> VfsResult<BaseHandle> {
+///         self.inner.open(path, opts, params)
+///     }
+///
+///     fn write(&self, handle: &mut BaseHandle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+///         self.writes.fetch_add(1, Ordering::Relaxed);
+///         self.inner.write(handle, offset, data)
+///     }
+/// }
+///
+/// sqlite_plugin::impl_vfs_shim!(for CountWrites);
+///
+/// # fn main() -> VfsResult<()> {
+/// let vfs = CountWrites { inner: BaseVfs::find(None)?, writes: AtomicUsize::new(0) };
+/// register_static(c"counted".to_owned(), vfs, RegisterOpts::default())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A shim which keeps state per file opens its own [`Handle`] around the
+/// handle of the wrapped vfs, and the operations it does not override are
+/// given the inner handle through [`ShimHandle`].
+///
+/// [`Handle`]: VfsShim::Handle
+pub trait VfsShim: Send + Sync {
+    type Inner: Vfs;
+    type Handle: ShimHandle<InnerHandle<Self>>;
+
+    fn inner(&self) -> &Self::Inner;
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle>;
+
+    fn register_logger(&self, logger: SqliteLogger) {
+        self.inner().register_logger(logger)
+    }
+
+    fn probe(&self) -> VfsResult<()> {
+        self.inner().probe()
+    }
+
+    fn last_error(&self) -> Option<(i32, String)> {
+        self.inner().last_error()
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        self.inner().canonical_path(path)
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.inner().delete(path)
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        self.inner().access(path, flags)
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        self.inner().temp_filename()
+    }
+
+    fn journal_policy(&self, db_path: &str) -> JournalPolicy {
+        self.inner().journal_policy(db_path)
+    }
+
+    fn bootstrap(&self, path: &str) -> VfsResult<()> {
+        self.inner().bootstrap(path)
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.inner().file_size(handle.inner_handle())
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.inner().truncate(handle.inner_handle(), size)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.inner().write(handle.inner_handle(), offset, data)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        self.inner().read(handle.inner_handle(), offset, data)
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.inner().lock(handle.inner_handle(), level)
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.inner().unlock(handle.inner_handle(), level)
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.inner().check_reserved_lock(handle.inner_handle())
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.inner().sync(handle.inner_handle(), flags)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        self.inner().close(handle.into_inner_handle())
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        self.inner().pragma(handle.inner_handle(), pragma)
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> usize {
+        self.inner().sector_size(handle.inner_handle())
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        self.inner().device_characteristics(handle.inner_handle())
+    }
+
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        self.inner().file_control(handle.inner_handle(), op)
+    }
+
+    fn vfs_name(&self, registered: &str) -> Option<String> {
+        self.inner().vfs_name(registered)
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.inner().sleep(duration)
+    }
+
+    fn current_time(&self) -> Option<i64> {
+        self.inner().current_time()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        self.inner().randomness(buf)
+    }
+
+    fn supports_shm(&self) -> bool {
+        self.inner().supports_shm()
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        self.inner()
+            .shm_map(handle.inner_handle(), region, size, extend)
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        self.inner()
+            .shm_lock(handle.inner_handle(), offset, count, mode)
+    }
+
+    fn shm_barrier(&self, handle: &mut Self::Handle) {
+        self.inner().shm_barrier(handle.inner_handle())
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        self.inner().shm_unmap(handle.inner_handle(), delete)
+    }
+
+    fn supports_mmap(&self) -> bool {
+        self.inner().supports_mmap()
+    }
+
+    fn fetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        len: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        // a shim which turns off mmap never hands out the inner vfs's pages
+        if !self.supports_mmap() {
+            return Ok(None);
+        }
+        self.inner().fetch(handle.inner_handle(), offset, len)
+    }
+
+    fn unfetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        ptr: Option<NonNull<u8>>,
+    ) -> VfsResult<()> {
+        if !self.supports_mmap() {
+            return Ok(());
+        }
+        self.inner().unfetch(handle.inner_handle(), offset, ptr)
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::borrow::Cow;
+    pub use alloc::string::String;
+}
+
+/// Implements [`Vfs`](crate::vfs::Vfs) for a [`VfsShim`](crate::shim::VfsShim),
+/// by calling the shim's methods:
+///
+/// ```
+/// # use sqlite_plugin::flags::OpenOpts;
+/// # use sqlite_plugin::shim::VfsShim;
+/// # use sqlite_plugin::vfs::{UriParams, Vfs, VfsResult};
+/// struct Wrapper<V>(V);
+///
+/// impl<V: Vfs> VfsShim for Wrapper<V> {
+///     type Inner = V;
+///     type Handle = V::Handle;
+///
+///     fn inner(&self) -> &V {
+///         &self.0
+///     }
+///
+///     fn open(&self, path: Option<&str>, opts: OpenOpts, params: UriParams<'_>) -> VfsResult<V::Handle> {
+///         self.0.open(path, opts, params)
+///     }
+/// }
+///
+/// sqlite_plugin::impl_vfs_shim!(impl[V: Vfs] for Wrapper<V>);
+/// ```
+///
+/// Shims without generics are written `impl_vfs_shim!(for CountWrites)`.
+///
+/// Shims are made a `Vfs` one type at a time, rather than by a blanket
+/// implementation, so that other blanket implementations of `Vfs` stay
+/// possible.
+#[macro_export]
+macro_rules! impl_vfs_shim {
+    (for $ty:ty) => {
+        $crate::impl_vfs_shim!(impl[] for $ty);
+    };
+    (impl[$($generics:tt)*] for $ty:ty) => {
+        impl<$($generics)*> $crate::vfs::Vfs for $ty {
+            type Handle = <Self as $crate::shim::VfsShim>::Handle;
+
+            fn register_logger(&self, logger: $crate::logger::SqliteLogger) {
+                $crate::shim::VfsShim::register_logger(self, logger)
+            }
+
+            fn probe(&self) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::probe(self)
+            }
+
+            fn last_error(&self) -> ::core::option::Option<(i32, $crate::shim::__private::String)> {
+                $crate::shim::VfsShim::last_error(self)
+            }
+
+            fn canonical_path<'a>(
+                &self,
+                path: $crate::shim::__private::Cow<'a, str>,
+            ) -> $crate::vfs::VfsResult<$crate::shim::__private::Cow<'a, str>> {
+                $crate::shim::VfsShim::canonical_path(self, path)
+            }
+
+            fn open(
+                &self,
+                path: ::core::option::Option<&str>,
+                opts: $crate::flags::OpenOpts,
+                params: $crate::vfs::UriParams<'_>,
+            ) -> $crate::vfs::VfsResult<Self::Handle> {
+                $crate::shim::VfsShim::open(self, path, opts, params)
+            }
+
+            fn delete(&self, path: &str) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::delete(self, path)
+            }
+
+            fn access(
+                &self,
+                path: &str,
+                flags: $crate::flags::AccessFlags,
+            ) -> $crate::vfs::VfsResult<bool> {
+                $crate::shim::VfsShim::access(self, path, flags)
+            }
+
+            fn temp_filename(&self) -> $crate::vfs::VfsResult<$crate::shim::__private::String> {
+                $crate::shim::VfsShim::temp_filename(self)
+            }
+
+            fn journal_policy(&self, db_path: &str) -> $crate::vfs::JournalPolicy {
+                $crate::shim::VfsShim::journal_policy(self, db_path)
+            }
+
+            fn bootstrap(&self, path: &str) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::bootstrap(self, path)
+            }
+
+            fn file_size(&self, handle: &mut Self::Handle) -> $crate::vfs::VfsResult<usize> {
+                $crate::shim::VfsShim::file_size(self, handle)
+            }
+
+            fn truncate(&self, handle: &mut Self::Handle, size: usize) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::truncate(self, handle, size)
+            }
+
+            fn write(
+                &self,
+                handle: &mut Self::Handle,
+                offset: usize,
+                data: &[u8],
+            ) -> $crate::vfs::VfsResult<usize> {
+                $crate::shim::VfsShim::write(self, handle, offset, data)
+            }
+
+            fn read(
+                &self,
+                handle: &mut Self::Handle,
+                offset: usize,
+                data: &mut [u8],
+            ) -> $crate::vfs::VfsResult<usize> {
+                $crate::shim::VfsShim::read(self, handle, offset, data)
+            }
+
+            fn lock(
+                &self,
+                handle: &mut Self::Handle,
+                level: $crate::flags::LockLevel,
+            ) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::lock(self, handle, level)
+            }
+
+            fn unlock(
+                &self,
+                handle: &mut Self::Handle,
+                level: $crate::flags::LockLevel,
+            ) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::unlock(self, handle, level)
+            }
+
+            fn check_reserved_lock(&self, handle: &mut Self::Handle) -> $crate::vfs::VfsResult<bool> {
+                $crate::shim::VfsShim::check_reserved_lock(self, handle)
+            }
+
+            fn sync(
+                &self,
+                handle: &mut Self::Handle,
+                flags: $crate::flags::SyncFlags,
+            ) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::sync(self, handle, flags)
+            }
+
+            fn close(&self, handle: Self::Handle) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::close(self, handle)
+            }
+
+            fn pragma(
+                &self,
+                handle: &mut Self::Handle,
+                pragma: $crate::vfs::Pragma<'_>,
+            ) -> ::core::result::Result<
+                ::core::option::Option<$crate::shim::__private::String>,
+                $crate::vfs::PragmaErr,
+            > {
+                $crate::shim::VfsShim::pragma(self, handle, pragma)
+            }
+
+            fn sector_size(&self, handle: &mut Self::Handle) -> usize {
+                $crate::shim::VfsShim::sector_size(self, handle)
+            }
+
+            fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+                $crate::shim::VfsShim::device_characteristics(self, handle)
+            }
+
+            fn file_control(
+                &self,
+                handle: &mut Self::Handle,
+                op: $crate::vfs::FileControlOp<'_>,
+            ) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::file_control(self, handle, op)
+            }
+
+            fn vfs_name(&self, registered: &str) -> ::core::option::Option<$crate::shim::__private::String> {
+                $crate::shim::VfsShim::vfs_name(self, registered)
+            }
+
+            fn sleep(&self, duration: ::core::time::Duration) -> ::core::option::Option<::core::time::Duration> {
+                $crate::shim::VfsShim::sleep(self, duration)
+            }
+
+            fn current_time(&self) -> ::core::option::Option<i64> {
+                $crate::shim::VfsShim::current_time(self)
+            }
+
+            fn randomness(&self, buf: &mut [u8]) -> ::core::option::Option<usize> {
+                $crate::shim::VfsShim::randomness(self, buf)
+            }
+
+            fn supports_shm(&self) -> bool {
+                $crate::shim::VfsShim::supports_shm(self)
+            }
+
+            fn shm_map(
+                &self,
+                handle: &mut Self::Handle,
+                region: usize,
+                size: usize,
+                extend: bool,
+            ) -> $crate::vfs::VfsResult<::core::option::Option<::core::ptr::NonNull<u8>>> {
+                $crate::shim::VfsShim::shm_map(self, handle, region, size, extend)
+            }
+
+            fn shm_lock(
+                &self,
+                handle: &mut Self::Handle,
+                offset: u32,
+                count: u32,
+                mode: $crate::flags::ShmLockMode,
+            ) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::shm_lock(self, handle, offset, count, mode)
+            }
+
+            fn shm_barrier(&self, handle: &mut Self::Handle) {
+                $crate::shim::VfsShim::shm_barrier(self, handle)
+            }
+
+            fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::shm_unmap(self, handle, delete)
+            }
+
+            fn supports_mmap(&self) -> bool {
+                $crate::shim::VfsShim::supports_mmap(self)
+            }
+
+            fn fetch(
+                &self,
+                handle: &mut Self::Handle,
+                offset: usize,
+                len: usize,
+            ) -> $crate::vfs::VfsResult<::core::option::Option<::core::ptr::NonNull<u8>>> {
+                $crate::shim::VfsShim::fetch(self, handle, offset, len)
+            }
+
+            fn unfetch(
+                &self,
+                handle: &mut Self::Handle,
+                offset: usize,
+                ptr: ::core::option::Option<::core::ptr::NonNull<u8>>,
+            ) -> $crate::vfs::VfsResult<()> {
+                $crate::shim::VfsShim::unfetch(self, handle, offset, ptr)
+            }
+        }
+    };
+}
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::vfs::{RegisterOpts, register_static};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use rusqlite::{Connection, OpenFlags};
+
+    struct CountWrites {
+        inner: BaseVfs,
+        writes: &'static AtomicUsize,
+    }
+
+    impl VfsShim for CountWrites {
+        type Inner = BaseVfs;
+        type Handle = BaseHandle;
+
+        fn inner(&self) -> &BaseVfs {
+            &self.inner
+        }
+
+        fn open(
+            &self,
+            path: Option<&str>,
+            opts: OpenOpts,
+            params: UriParams<'_>,
+        ) -> VfsResult<BaseHandle> {
+            self.inner.open(path, opts, params)
+        }
+
+        fn write(&self, handle: &mut BaseHandle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.inner.write(handle, offset, data)
+        }
+    }
+
+    crate::impl_vfs_shim!(for CountWrites);
+
+    #[test]
+    fn shim_over_os_vfs() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        // other tests may replace the default vfs, so name the built-in one
+        let base_name = if cfg!(windows) { "win32" } else { "unix" };
+        let base = BaseVfs::find(Some(base_name))?;
+        assert_eq!(base.name(), base_name);
+        let vfs = CountWrites { inner: base, writes: &WRITES };
+        register_static(c"shim_count".into(), vfs, RegisterOpts::default())?;
+
+        let dir = TempDir::new("shim")?;
+        let path = dir.file("main.db");
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Connection::open_with_flags_and_vfs(&path, flags, "shim_count")?;
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.execute_batch("create table t(x); insert into t values (1), (2);")?;
+        let sum: i64 = conn.query_row("select sum(x) from t", [], |row| row.get(0))?;
+        assert_eq!(sum, 3);
+        // the real file is visible to connections on the base vfs
+        let other = Connection::open_with_flags_and_vfs(&path, flags, base_name)?;
+        let count: i64 = other.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(count, 2);
+        assert!(WRITES.load(Ordering::Relaxed) > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,436 @@
+use alloc::string::String;
+use core::ptr::NonNull;
+
+use crate::flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode, SyncFlags};
+use crate::shim;
+use crate::vfs::{FileControlOp, UriParams, Vfs, VfsError, VfsHandle, VfsResult};
+
+/// An operation of a [`SpanVfs`], with the fields a structured tracer
+/// records about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call<'a> {
+    /// The name of the [`Vfs`] method, such as `"read"`.
+    pub op: &'static str,
+    /// The path of the file, or `None` for files without a name.
+    pub file: Option<&'a str>,
+    pub offset: Option<usize>,
+    pub len: Option<usize>,
+    /// The size a file is truncated to.
+    pub size: Option<usize>,
+}
+
+impl<'a> Call<'a> {
+    fn new(op: &'static str, file: Option<&'a str>) -> Self {
+        Self {
+            op,
+            file,
+            offset: None,
+            len: None,
+            size: None,
+        }
+    }
+
+    fn range(self, offset: usize, len: usize) -> Self {
+        Self {
+            offset: Some(offset),
+            len: Some(len),
+            ..self
+        }
+    }
+}
+
+/// Receives a span around every operation of a [`SpanVfs`], to bridge the
+/// operations into a structured tracing library. The `tracing` feature
+/// includes [`Tracing`], which emits the spans of the `tracing` crate.
+pub trait Tracer: Send + Sync {
+    type Span;
+
+    /// Called before the operation runs.
+    fn enter(&self, call: &Call<'_>) -> Self::Span;
+
+    /// Called after the operation, with whether it succeeded.
+    fn exit(&self, span: Self::Span, result: Result<(), VfsError>);
+}
+
+/// A [`Tracer`] which enters a debug level `tracing` span named `vfs` for
+/// every operation, with the fields of its [`Call`] and the error it failed
+/// with, if any.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Tracing;
+
+#[cfg(feature = "tracing")]
+impl Tracer for Tracing {
+    type Span = tracing::span::EnteredSpan;
+
+    fn enter(&self, call: &Call<'_>) -> Self::Span {
+        let span = tracing::debug_span!(
+            "vfs",
+            op = call.op,
+            file = call.file,
+            offset = call.offset,
+            len = call.len,
+            size = call.size,
+            error = tracing::field::Empty,
+        );
+        span.entered()
+    }
+
+    fn exit(&self, span: Self::Span, result: Result<(), VfsError>) {
+        if let Err(err) = result {
+            span.record("error", tracing::field::display(err));
+        }
+    }
+}
+
+/// A Vfs wrapper which wraps every file operation, and opening, deleting and
+/// checking for files, in a span from a [`Tracer`].
+///
+/// ```no_run
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::span::{SpanVfs, Tracer};
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// fn register(tracer: impl Tracer + 'static) -> VfsResult<()> {
+///     let vfs = SpanVfs::new(BaseVfs::find(None)?, tracer);
+///     register_static(c"spans".to_owned(), vfs, RegisterOpts::default())
+/// }
+/// ```
+pub struct SpanVfs<V, T> {
+    vfs: V,
+    tracer: T,
+}
+
+pub struct SpanHandle<H> {
+    inner: H,
+    name: Option<String>,
+}
+
+impl<H: VfsHandle> VfsHandle for SpanHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for SpanHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs, T: Tracer> SpanVfs<V, T> {
+    pub fn new(vfs: V, tracer: T) -> Self {
+        Self { vfs, tracer }
+    }
+
+    pub fn tracer(&self) -> &T {
+        &self.tracer
+    }
+
+    fn span<R>(&self, call: Call<'_>, op: impl FnOnce() -> VfsResult<R>) -> VfsResult<R> {
+        let span = self.tracer.enter(&call);
+        let result = op();
+        self.tracer
+            .exit(span, result.as_ref().map(|_| ()).map_err(|err| *err));
+        result
+    }
+}
+
+impl<V: Vfs, T: Tracer> shim::VfsShim for SpanVfs<V, T> {
+    type Inner = V;
+    type Handle = SpanHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let inner = self.span(Call::new("open", path), || {
+            self.vfs.open(path, opts, params)
+        })?;
+        Ok(SpanHandle { inner, name: path.map(Into::into) })
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.span(Call::new("delete", Some(path)), || self.vfs.delete(path))
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        self.span(Call::new("access", Some(path)), || {
+            self.vfs.access(path, flags)
+        })
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.span(Call::new("file_size", handle.name.as_deref()), || {
+            self.vfs.file_size(&mut handle.inner)
+        })
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        let call = Call {
+            size: Some(size),
+            ..Call::new("truncate", handle.name.as_deref())
+        };
+        self.span(call, || self.vfs.truncate(&mut handle.inner, size))
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let call = Call::new("write", handle.name.as_deref()).range(offset, data.len());
+        self.span(call, || self.vfs.write(&mut handle.inner, offset, data))
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let call = Call::new("read", handle.name.as_deref()).range(offset, data.len());
+        self.span(call, || self.vfs.read(&mut handle.inner, offset, data))
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.span(Call::new("lock", handle.name.as_deref()), || {
+            self.vfs.lock(&mut handle.inner, level)
+        })
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.span(Call::new("unlock", handle.name.as_deref()), || {
+            self.vfs.unlock(&mut handle.inner, level)
+        })
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.span(
+            Call::new("check_reserved_lock", handle.name.as_deref()),
+            || self.vfs.check_reserved_lock(&mut handle.inner),
+        )
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.span(Call::new("sync", handle.name.as_deref()), || {
+            self.vfs.sync(&mut handle.inner, flags)
+        })
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        let SpanHandle { inner, name } = handle;
+        self.span(Call::new("close", name.as_deref()), || {
+            self.vfs.close(inner)
+        })
+    }
+
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        self.span(Call::new("file_control", handle.name.as_deref()), || {
+            self.vfs.file_control(&mut handle.inner, op)
+        })
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        let call = Call::new("shm_map", handle.name.as_deref()).range(region * size, size);
+        self.span(call, || {
+            self.vfs.shm_map(&mut handle.inner, region, size, extend)
+        })
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        let call =
+            Call::new("shm_lock", handle.name.as_deref()).range(offset as usize, count as usize);
+        self.span(call, || {
+            self.vfs.shm_lock(&mut handle.inner, offset, count, mode)
+        })
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        self.span(Call::new("shm_unmap", handle.name.as_deref()), || {
+            self.vfs.shm_unmap(&mut handle.inner, delete)
+        })
+    }
+
+    fn fetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        len: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        let call = Call::new("fetch", handle.name.as_deref()).range(offset, len);
+        self.span(call, || self.vfs.fetch(&mut handle.inner, offset, len))
+    }
+
+    fn unfetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        ptr: Option<NonNull<u8>>,
+    ) -> VfsResult<()> {
+        let call = Call {
+            offset: Some(offset),
+            ..Call::new("unfetch", handle.name.as_deref())
+        };
+        self.span(call, || self.vfs.unfetch(&mut handle.inner, offset, ptr))
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs, T: Tracer] for SpanVfs<V, T>);
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::logger::SqliteLogger;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::vars;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use std::boxed::Box;
+
+    #[derive(Default)]
+    struct Recorder {
+        spans: spin::Mutex<Vec<String>>,
+    }
+
+    impl Tracer for Recorder {
+        type Span = usize;
+
+        fn enter(&self, call: &Call<'_>) -> usize {
+            let mut spans = self.spans.lock();
+            spans.push(format!("{call:?}"));
+            spans.len() - 1
+        }
+
+        fn exit(&self, span: usize, result: Result<(), VfsError>) {
+            let mut spans = self.spans.lock();
+            let line = format!(" -> {result:?}");
+            spans[span].push_str(&line);
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let vfs = SpanVfs::new(MockVfs::new(Box::new(NoopHooks)), Recorder::default());
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let mut handle = vfs.open(Some("span.db"), opts, UriParams::empty()).unwrap();
+        vfs.write(&mut handle, 0, &[1; 8]).unwrap();
+        vfs.read(&mut handle, 4, &mut [0; 8]).unwrap();
+        vfs.truncate(&mut handle, 6).unwrap();
+        let err = vfs.file_control(&mut handle, FileControlOp::CommitPhaseTwo);
+        assert!(err.is_err());
+        vfs.close(handle).unwrap();
+
+        let call = |op, offset: Option<usize>, len: Option<usize>, size: Option<usize>| {
+            format!(
+                "{:?}",
+                Call {
+                    op,
+                    file: Some("span.db"),
+                    offset,
+                    len,
+                    size
+                }
+            )
+        };
+        assert_eq!(
+            *vfs.tracer().spans.lock(),
+            [
+                call("open", None, None, None) + " -> Ok(())",
+                call("write", Some(0), Some(8), None) + " -> Ok(())",
+                call("read", Some(4), Some(8), None) + " -> Ok(())",
+                call("truncate", None, None, Some(6)) + " -> Ok(())",
+                call("file_control", None, None, None) + " -> Err(NotFound)",
+                call("close", None, None, None) + " -> Ok(())",
+            ]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_spans() {
+        use alloc::sync::Arc;
+        use core::fmt::{Debug, Write};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        struct Fields<'a>(&'a mut String);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                write!(self.0, " {}={value:?}", field.name()).unwrap();
+            }
+        }
+
+        // records the fields of every span
+        struct Subscriber(Arc<spin::Mutex<Vec<String>>>);
+
+        impl tracing::Subscriber for Subscriber {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.0.lock();
+                let mut line = String::from(span.metadata().name());
+                span.record(&mut Fields(&mut line));
+                spans.push(line);
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut spans = self.0.lock();
+                values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1]));
+            }
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let spans = Arc::default();
+        tracing::subscriber::with_default(Subscriber(Arc::clone(&spans)), || {
+            let vfs = SpanVfs::new(MockVfs::new(Box::new(NoopHooks)), Tracing);
+            vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+            let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+            let mut handle = vfs.open(Some("span.db"), opts, UriParams::empty()).unwrap();
+            vfs.write(&mut handle, 0, &[1; 8]).unwrap();
+            vfs.truncate(&mut handle, 6).unwrap();
+            let err = vfs.file_control(&mut handle, FileControlOp::CommitPhaseTwo);
+            assert!(err.is_err());
+            vfs.close(handle).unwrap();
+        });
+        assert_eq!(
+            *spans.lock(),
+            [
+                r#"vfs op="open" file="span.db""#,
+                r#"vfs op="write" file="span.db" offset=0 len=8"#,
+                r#"vfs op="truncate" file="span.db" size=6"#,
+                r#"vfs op="file_control" file="span.db" error=operation not supported"#,
+                r#"vfs op="close" file="span.db""#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,759 @@
+use alloc::collections::BTreeMap;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::audit::{Audit, AuditSink};
+use crate::checksum::ChecksumVfs;
+#[cfg(feature = "lz4")]
+use crate::compress::Lz4;
+#[cfg(feature = "zstd")]
+use crate::compress::Zstd;
+use crate::compress::{Codec, CompressedVfs};
+use crate::encrypt::{Cipher, EncryptedVfs};
+use crate::fault::FaultVfs;
+use crate::quota::{QuotaVfs, Quotas};
+use crate::readonly::ReadOnlyVfs;
+use crate::shim::BaseVfs;
+use crate::span::{SpanVfs, Tracer};
+use crate::stats::StatsVfs;
+use crate::throttle::{Throttle, ThrottleVfs};
+use crate::trace::TraceVfs;
+use crate::vars;
+#[cfg(feature = "static")]
+use crate::vfs::register_static;
+use crate::vfs::{
+    RegisterBatch, RegisterError, RegisterOpts, Vfs, VfsError, VfsResult, is_registered, lookup,
+};
+
+/// Builds a vfs out of layers, each wrapping the ones added before it, from
+/// the base vfs which stores the files up:
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use sqlite_plugin::encrypt::{Cipher, SALT_LEN};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::stack::{Checksum, Encrypt, VfsStack};
+/// # use sqlite_plugin::stats::StatsVfs;
+/// # use sqlite_plugin::vfs::RegisterOpts;
+/// # struct MyCipher;
+/// # impl Cipher for MyCipher {
+/// #     type Key = ();
+/// #     fn salt(&self) -> [u8; SALT_LEN] { [0; SALT_LEN] }
+/// #     fn derive_key(&self, _: &[u8], _: &[u8; SALT_LEN]) {}
+/// #     fn encrypt(&self, _: &(), _: u64, _: &mut [u8]) {}
+/// #     fn decrypt(&self, _: &(), _: u64, _: &mut [u8]) {}
+/// # }
+/// # fn clock() -> Duration { Duration::ZERO }
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// VfsStack::on(BaseVfs::find(None)?)
+///     .with(Encrypt(MyCipher))
+///     .with(Checksum)
+///     .with(|vfs| StatsVfs::new(vfs, clock))
+///     .register(c"prod".to_owned(), RegisterOpts::default())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The layers of this module check their place in the stack when it is
+/// compiled. Layers which rewrite the pages they store, like [`Encrypt`] and
+/// [`Compress`], can only be added over a vfs which is [`PageAgnostic`], as
+/// a layer below them that reads pages, like a [`ChecksumVfs`], would be
+/// handed ciphertext or compressed records instead:
+///
+/// ```compile_fail
+/// # use sqlite_plugin::compress::Codec;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::stack::{Checksum, Compress, VfsStack};
+/// # use sqlite_plugin::vfs::VfsResult;
+/// # fn stack(codec: impl Codec) -> VfsResult<()> {
+/// // checksums would be computed over compressed records
+/// VfsStack::on(BaseVfs::find(None)?).with(Checksum).with(Compress(codec));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Any other wrapper is added with a function which wraps a vfs, such as
+/// `ReadOnlyVfs::new`, whose place is not checked.
+pub struct VfsStack<V> {
+    vfs: V,
+}
+
+impl<V: Vfs> VfsStack<V> {
+    /// Starts a stack on `base`, which stores the files.
+    pub fn on(base: V) -> Self {
+        Self { vfs: base }
+    }
+
+    /// Adds `layer` over the layers added so far.
+    pub fn with<L: Layer<V>>(self, layer: L) -> VfsStack<L::Vfs> {
+        VfsStack { vfs: layer.wrap(self.vfs) }
+    }
+
+    /// The vfs made of every layer of the stack.
+    pub fn build(self) -> V {
+        self.vfs
+    }
+}
+
+#[cfg(feature = "static")]
+impl<V: Vfs + 'static> VfsStack<V> {
+    /// Registers the vfs made of every layer of the stack, see
+    /// [`register_static`].
+    pub fn register(self, name: CString, opts: RegisterOpts) -> VfsResult<()> {
+        register_static(name, self.vfs, opts)
+    }
+}
+
+/// A layer of a [`VfsStack`], which wraps the vfs made of the layers below
+/// it.
+pub trait Layer<V: Vfs> {
+    type Vfs: Vfs;
+
+    fn wrap(self, vfs: V) -> Self::Vfs;
+}
+
+impl<V: Vfs, W: Vfs, F: FnOnce(V) -> W> Layer<V> for F {
+    type Vfs = W;
+
+    fn wrap(self, vfs: V) -> W {
+        self(vfs)
+    }
+}
+
+/// A vfs none of whose layers read the contents of the pages stored
+/// through it, so that it can be wrapped by a layer which rewrites them.
+/// Implement this for a base vfs which stores files as it is given them.
+pub trait PageAgnostic: Vfs {}
+
+impl PageAgnostic for BaseVfs {}
+impl<V: PageAgnostic, S: AuditSink> PageAgnostic for Audit<V, S> {}
+impl<V: PageAgnostic, C: Codec> PageAgnostic for CompressedVfs<V, C> {}
+impl<V: PageAgnostic, C: Cipher> PageAgnostic for EncryptedVfs<V, C> {}
+impl<V: PageAgnostic> PageAgnostic for FaultVfs<V> {}
+impl<V: PageAgnostic> PageAgnostic for QuotaVfs<V> {}
+impl<V: PageAgnostic> PageAgnostic for ReadOnlyVfs<V> {}
+impl<V: PageAgnostic, T: Tracer> PageAgnostic for SpanVfs<V, T> {}
+impl<V: PageAgnostic> PageAgnostic for StatsVfs<V> {}
+impl<V: PageAgnostic> PageAgnostic for ThrottleVfs<V> {}
+impl<V: PageAgnostic> PageAgnostic for TraceVfs<V> {}
+
+/// Verifies page checksums with a [`ChecksumVfs`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Checksum;
+
+impl<V: Vfs> Layer<V> for Checksum {
+    type Vfs = ChecksumVfs<V>;
+
+    fn wrap(self, vfs: V) -> ChecksumVfs<V> {
+        ChecksumVfs::new(vfs)
+    }
+}
+
+/// Encrypts files with an [`EncryptedVfs`] using the cipher.
+pub struct Encrypt<C>(pub C);
+
+impl<V: PageAgnostic, C: Cipher> Layer<V> for Encrypt<C> {
+    type Vfs = EncryptedVfs<V, C>;
+
+    fn wrap(self, vfs: V) -> EncryptedVfs<V, C> {
+        EncryptedVfs::new(vfs, self.0)
+    }
+}
+
+/// Compresses databases with a [`CompressedVfs`] using the codec.
+pub struct Compress<C>(pub C);
+
+impl<V: PageAgnostic, C: Codec> Layer<V> for Compress<C> {
+    type Vfs = CompressedVfs<V, C>;
+
+    fn wrap(self, vfs: V) -> CompressedVfs<V, C> {
+        CompressedVfs::new(vfs, self.0)
+    }
+}
+
+/// A stack of layers described at runtime, for example by a configuration
+/// file loaded with serde when the `serde` feature is enabled, so that a
+/// deployment can change its layers without being rebuilt. In JSON:
+///
+/// ```json
+/// {
+///     "base": "unix",
+///     "layers": [
+///         { "layer": "checksum" },
+///         { "layer": "quota", "prefix_limits": { "/srv/tenants/a/": 1073741824 } },
+///         { "layer": "stats" }
+///     ]
+/// }
+/// ```
+///
+/// Register it with [`register_stack`], and change the options of its layers
+/// while it is in use with [`reload_stack`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct StackConfig {
+    /// The vfs which stores the files, or the default vfs if None.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub base: Option<String>,
+    /// The layers, from the one over the base up.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub layers: Vec<LayerConfig>,
+}
+
+/// A layer of a [`StackConfig`]. Encryption needs a [`Cipher`], so it can
+/// only be added in code, by a [`VfsStack`] which is then used as the base.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(tag = "layer", rename_all = "snake_case", deny_unknown_fields)
+)]
+pub enum LayerConfig {
+    /// A [`ReadOnlyVfs`].
+    ReadOnly,
+    /// A [`ChecksumVfs`].
+    Checksum,
+    /// A [`StatsVfs`].
+    Stats,
+    /// A [`TraceVfs`], which logs while `enabled`.
+    Trace {
+        #[cfg_attr(feature = "serde", serde(default = "enabled"))]
+        enabled: bool,
+    },
+    /// A [`ThrottleVfs`], with latencies in microseconds.
+    Throttle {
+        #[cfg_attr(feature = "serde", serde(default))]
+        read_latency_us: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        write_latency_us: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        sync_latency_us: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        read_bytes_per_sec: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        write_bytes_per_sec: Option<u64>,
+    },
+    /// A [`QuotaVfs`], with limits in bytes by path and by path prefix.
+    Quota {
+        #[cfg_attr(feature = "serde", serde(default))]
+        file_limits: BTreeMap<String, usize>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        prefix_limits: BTreeMap<String, usize>,
+    },
+    /// A [`CompressedVfs`] using [`Lz4`].
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// A [`CompressedVfs`] using [`Zstd`] at `level`, or the default level.
+    #[cfg(feature = "zstd")]
+    Zstd {
+        #[cfg_attr(feature = "serde", serde(default))]
+        level: Option<i32>,
+    },
+}
+
+impl LayerConfig {
+    // whether the layer reads the pages stored through it, see PageAgnostic
+    fn reads_pages(&self) -> bool {
+        matches!(self, Self::Checksum)
+    }
+
+    // whether the layer rewrites the pages stored through it
+    fn rewrites_pages(&self) -> bool {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => true,
+            #[cfg(feature = "zstd")]
+            Self::Zstd { .. } => true,
+            _ => false,
+        }
+    }
+
+    // the settings of a Throttle layer
+    fn throttle(&self) -> Option<Throttle> {
+        let &Self::Throttle {
+            read_latency_us,
+            write_latency_us,
+            sync_latency_us,
+            read_bytes_per_sec,
+            write_bytes_per_sec,
+        } = self
+        else {
+            return None;
+        };
+        Some(Throttle {
+            read_latency: Duration::from_micros(read_latency_us),
+            write_latency: Duration::from_micros(write_latency_us),
+            sync_latency: Duration::from_micros(sync_latency_us),
+            read_bytes_per_sec,
+            write_bytes_per_sec,
+        })
+    }
+
+    // the registered layer, if it is of this kind, with what `reload_stack`
+    // changes in it
+    fn live(&self, name: &str) -> Option<Live<'_>> {
+        match self {
+            Self::ReadOnly => lookup::<ReadOnlyVfs<BaseVfs>>(name).map(|_| Live::Fixed),
+            Self::Checksum => lookup::<ChecksumVfs<BaseVfs>>(name).map(|_| Live::Fixed),
+            Self::Stats => lookup::<StatsVfs<BaseVfs>>(name).map(|_| Live::Fixed),
+            &Self::Trace { enabled } => {
+                lookup::<TraceVfs<BaseVfs>>(name).map(|vfs| Live::Trace(vfs, enabled))
+            }
+            Self::Throttle { .. } => {
+                let throttle = self.throttle().unwrap_or_default();
+                lookup::<ThrottleVfs<BaseVfs>>(name).map(|vfs| Live::Throttle(vfs, throttle))
+            }
+            Self::Quota { file_limits, prefix_limits } => lookup::<QuotaVfs<BaseVfs>>(name)
+                .map(|vfs| Live::Quota(vfs.quotas(), file_limits, prefix_limits)),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => lookup::<CompressedVfs<BaseVfs, Lz4>>(name).map(|_| Live::Fixed),
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => {
+                let level = level.unwrap_or_else(|| Zstd::default().level());
+                lookup::<CompressedVfs<BaseVfs, Zstd>>(name)
+                    .filter(|vfs| vfs.codec().level() == level)
+                    .map(|_| Live::Fixed)
+            }
+        }
+    }
+
+    fn register(
+        &self,
+        batch: &mut RegisterBatch,
+        name: CString,
+        base: BaseVfs,
+        env: StackEnv,
+        opts: RegisterOpts,
+    ) {
+        match self {
+            Self::ReadOnly => batch.register(name, ReadOnlyVfs::new(base), opts),
+            Self::Checksum => batch.register(name, ChecksumVfs::new(base), opts),
+            Self::Stats => batch.register(name, StatsVfs::new(base, env.clock), opts),
+            &Self::Trace { enabled } => {
+                let vfs = TraceVfs::new(base, env.clock);
+                vfs.set_enabled(enabled);
+                batch.register(name, vfs, opts)
+            }
+            Self::Throttle { .. } => {
+                let throttle = self.throttle().unwrap_or_default();
+                let vfs = ThrottleVfs::new(base, throttle, env.clock, env.sleep);
+                batch.register(name, vfs, opts)
+            }
+            Self::Quota { file_limits, prefix_limits } => {
+                let (vfs, quotas) = QuotaVfs::new(base);
+                for (path, &limit) in file_limits {
+                    quotas.set_file_limit(path, Some(limit));
+                }
+                for (prefix, &limit) in prefix_limits {
+                    quotas.set_prefix_limit(prefix, Some(limit));
+                }
+                batch.register(name, vfs, opts)
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => batch.register(name, CompressedVfs::new(base, Lz4), opts),
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => {
+                let codec = level.map_or_else(Zstd::default, Zstd::new);
+                batch.register(name, CompressedVfs::new(base, codec), opts)
+            }
+        };
+    }
+}
+
+#[cfg(feature = "serde")]
+fn enabled() -> bool {
+    true
+}
+
+/// What the layers of a [`StackConfig`] use to tell the time and to wait.
+#[derive(Debug, Clone, Copy)]
+pub struct StackEnv {
+    /// A monotonic time since any fixed point.
+    pub clock: fn() -> Duration,
+    pub sleep: fn(Duration),
+}
+
+/// Registers the layers of `config` as the vfs `name`, see
+/// [`register_static`].
+///
+/// Each layer is registered as a vfs of its own which wraps the one below it
+/// by name: the top layer as `name`, and the others as `name/0`, `name/1`
+/// and so on from the one over the base up, so that [`lookup`] finds each of
+/// them. Only the top layer is given `opts`. Either every layer is registered
+/// or none of them, see [`RegisterBatch`], and as with a [`VfsStack`] a layer
+/// which rewrites pages can't be placed over one which reads them.
+///
+/// [`lookup`]: crate::vfs::lookup
+#[cfg(feature = "static")]
+pub fn register_stack(
+    name: &str,
+    config: &StackConfig,
+    env: StackEnv,
+    opts: RegisterOpts,
+) -> Result<(), RegisterError> {
+    register_stack_with(RegisterBatch::new(), name, config, env, opts)
+}
+
+/// Registers the layers of `config` as the vfs `name` using the dynamic API,
+/// see [`register_stack`].
+/// # Safety
+/// `p_api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct
+#[cfg(feature = "dynamic")]
+pub unsafe fn register_stack_dynamic(
+    p_api: *mut crate::sqlite3_api_routines,
+    name: &str,
+    config: &StackConfig,
+    env: StackEnv,
+    opts: RegisterOpts,
+) -> Result<(), RegisterError> {
+    let batch = unsafe { RegisterBatch::new_dynamic(p_api) }.map_err(|err| failure(name, err))?;
+    register_stack_with(batch, name, config, env, opts)
+}
+
+fn register_stack_with(
+    mut batch: RegisterBatch,
+    name: &str,
+    config: &StackConfig,
+    env: StackEnv,
+    opts: RegisterOpts,
+) -> Result<(), RegisterError> {
+    let len = config.layers.len();
+    for (i, layer) in config.layers.iter().enumerate() {
+        let misplaced =
+            layer.rewrites_pages() && config.layers[..i].iter().any(LayerConfig::reads_pages);
+        if misplaced {
+            return Err(failure(
+                &layer_name(name, i, len),
+                vars::SQLITE_MISUSE.into(),
+            ));
+        }
+    }
+
+    let c_name =
+        |name: &str| CString::new(name).map_err(|_| failure(name, vars::SQLITE_MISUSE.into()));
+    let Some((top, below)) = config.layers.split_last() else {
+        let base = batch
+            .find(config.base.as_deref())
+            .map_err(|err| failure(name, err))?;
+        batch.register(c_name(name)?, base, opts);
+        return batch.commit();
+    };
+    let mut base = config.base.clone();
+    for (i, layer) in below.iter().enumerate() {
+        let layer_name = layer_name(name, i, len);
+        let vfs = batch
+            .find(base.as_deref())
+            .map_err(|err| failure(&layer_name, err))?;
+        layer.register(
+            &mut batch,
+            c_name(&layer_name)?,
+            vfs,
+            env,
+            RegisterOpts::default(),
+        );
+        if batch.failed() {
+            return batch.commit();
+        }
+        base = Some(layer_name);
+    }
+    let vfs = batch
+        .find(base.as_deref())
+        .map_err(|err| failure(name, err))?;
+    top.register(&mut batch, c_name(name)?, vfs, env, opts);
+    batch.commit()
+}
+
+/// Changes the options of the stack registered as `name` by
+/// [`register_stack`] to those of `layers`, while it is in use, for example to
+/// change the limits of a throttle or quota layer or to pause tracing.
+///
+/// `layers` must be the layers of the stack, in the same order, and options
+/// which a layer only takes when it is created, like the level of a zstd
+/// layer, must be unchanged. Every layer is checked before any is changed,
+/// so that if they don't match, `SQLITE_MISUSE` is returned and the stack is
+/// left as it was. Connections may see some layers changed before others.
+pub fn reload_stack(name: &str, layers: &[LayerConfig]) -> VfsResult<()> {
+    let len = layers.len();
+    // a stack with more layers has one more under the name of the top one,
+    // and a stack without any is its base
+    let mismatched = match len {
+        0 => lookup::<BaseVfs>(name).is_none(),
+        _ => is_registered(&format!("{name}/{}", len - 1)),
+    };
+    if mismatched {
+        return Err(vars::SQLITE_MISUSE.into());
+    }
+    let live = layers
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| layer.live(&layer_name(name, i, len)))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(vars::SQLITE_MISUSE)?;
+    for layer in live {
+        layer.apply();
+    }
+    Ok(())
+}
+
+// a registered layer of a stack, with the options `reload_stack` gives it
+enum Live<'a> {
+    Fixed,
+    Trace(Arc<TraceVfs<BaseVfs>>, bool),
+    Throttle(Arc<ThrottleVfs<BaseVfs>>, Throttle),
+    Quota(
+        Quotas,
+        &'a BTreeMap<String, usize>,
+        &'a BTreeMap<String, usize>,
+    ),
+}
+
+impl Live<'_> {
+    fn apply(self) {
+        match self {
+            Self::Fixed => {}
+            Self::Trace(vfs, enabled) => vfs.set_enabled(enabled),
+            Self::Throttle(vfs, throttle) => vfs.set_throttle(throttle),
+            Self::Quota(quotas, file_limits, prefix_limits) => {
+                quotas.set_limits(file_limits.clone(), prefix_limits.clone());
+            }
+        }
+    }
+}
+
+// the name the layer at `i` of a stack of `len` layers is registered under
+fn layer_name(name: &str, i: usize, len: usize) -> String {
+    if i + 1 == len {
+        name.into()
+    } else {
+        format!("{name}/{i}")
+    }
+}
+
+fn failure(name: &str, err: VfsError) -> RegisterError {
+    RegisterError { failures: vec![(name.into(), err)] }
+}
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{TempDir, TestVfs, os_vfs, run_workload};
+
+    // stores every block as is
+    struct Store;
+
+    impl Codec for Store {
+        fn compress(&self, data: &[u8], out: &mut alloc::vec::Vec<u8>) {
+            out.extend_from_slice(data);
+        }
+
+        fn decompress(&self, data: &[u8], out: &mut [u8]) -> VfsResult<()> {
+            out.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn clock() -> Duration {
+        Duration::ZERO
+    }
+
+    fn sleep(_: Duration) {}
+
+    fn unregister_stack(name: &str, len: usize) {
+        for i in (0..len).rev() {
+            unsafe { crate::vfs::unregister(&layer_name(name, i, len)) }.unwrap();
+        }
+    }
+
+    #[test]
+    fn stack() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let vfs = VfsStack::on(os_vfs()?)
+            .with(Compress(Store))
+            .with(Checksum)
+            .with(|vfs| StatsVfs::new(vfs, clock))
+            .build();
+        let vfs = TestVfs::scoped_with("stack", vfs)?;
+        let dir = TempDir::new("stack")?;
+        let dump = run_workload(
+            vfs.name(),
+            &dir.file("main.db"),
+            "create table t(x); insert into t values ('plaintext');",
+        )?;
+        assert_eq!(dump, "CREATE TABLE t(x);\nt: 'plaintext'\n");
+        assert!(vfs.vfs().stats().writes.count > 0);
+
+        // the database is stored by the compressed layer
+        let raw = std::fs::read(dir.file("main.db"))?;
+        assert!(raw.starts_with(b"compressed-vfs1\0"));
+        Ok(())
+    }
+
+    #[test]
+    fn config_stack() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let dir = TempDir::new("config_stack")?;
+        let prefix = dir.file("tenant/");
+        let config = StackConfig {
+            base: Some(os_vfs()?.name().into()),
+            layers: vec![
+                LayerConfig::Checksum,
+                LayerConfig::Quota {
+                    file_limits: BTreeMap::new(),
+                    prefix_limits: [(prefix.clone(), 64 << 10)].into(),
+                },
+                LayerConfig::Stats,
+            ],
+        };
+        let env = StackEnv { clock, sleep };
+        register_stack("config_stack", &config, env, RegisterOpts::default())?;
+
+        std::fs::create_dir(dir.file("tenant"))?;
+        let dump = run_workload(
+            "config_stack",
+            &dir.file("tenant/main.db"),
+            "create table t(x); insert into t values ('plaintext');",
+        )?;
+        assert_eq!(dump, "CREATE TABLE t(x);\nt: 'plaintext'\n");
+        assert!(lookup::<ChecksumVfs<BaseVfs>>("config_stack/0").is_some());
+        assert!(lookup::<QuotaVfs<BaseVfs>>("config_stack/1").is_some());
+        let stats = lookup::<StatsVfs<BaseVfs>>("config_stack").unwrap();
+        assert!(stats.stats().writes.count > 0);
+
+        // the quota applies to the files of the stack
+        let err = run_workload(
+            "config_stack",
+            &dir.file("tenant/main.db"),
+            "insert into t values (zeroblob(1 << 20));",
+        )
+        .unwrap_err();
+        assert_eq!(err.code(vars::SQLITE_IOERR), vars::SQLITE_FULL);
+        unregister_stack("config_stack", 3);
+
+        // nothing is registered if a layer can't be
+        let config = StackConfig {
+            base: Some("config_missing_base".into()),
+            layers: vec![LayerConfig::Stats, LayerConfig::Trace { enabled: true }],
+        };
+        let err = register_stack("config_missing", &config, env, RegisterOpts::default());
+        let failures = vec![("config_missing/0".into(), VfsError::NotFound)];
+        assert_eq!(err, Err(RegisterError { failures }));
+        assert!(BaseVfs::find(Some("config_missing/0")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reload() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+        let dir = TempDir::new("reload_stack")?;
+        let quota = |limit: usize| LayerConfig::Quota {
+            file_limits: BTreeMap::new(),
+            prefix_limits: [(dir.file(""), limit)].into(),
+        };
+        let throttle = |write_latency_us| LayerConfig::Throttle {
+            read_latency_us: 0,
+            write_latency_us,
+            sync_latency_us: 0,
+            read_bytes_per_sec: None,
+            write_bytes_per_sec: None,
+        };
+        let config = StackConfig {
+            base: Some(os_vfs()?.name().into()),
+            layers: vec![
+                throttle(0),
+                quota(64 << 10),
+                LayerConfig::Trace { enabled: true },
+            ],
+        };
+        let env = StackEnv { clock, sleep };
+        register_stack("reload_stack", &config, env, RegisterOpts::default())?;
+        let workload = "create table if not exists t(x); insert into t values (zeroblob(1 << 20));";
+        let err = run_workload("reload_stack", &dir.file("main.db"), workload).unwrap_err();
+        assert_eq!(err.code(vars::SQLITE_IOERR), vars::SQLITE_FULL);
+
+        // layers which don't match those of the stack change nothing
+        let trace = LayerConfig::Trace { enabled: false };
+        let fewer = [throttle(10), quota(4 << 20)];
+        let other = [throttle(10), LayerConfig::Stats, trace.clone()];
+        for layers in [&fewer[..], &other[..], &[]] {
+            assert_eq!(
+                reload_stack("reload_stack", layers),
+                Err(VfsError::Other(vars::SQLITE_MISUSE))
+            );
+        }
+        let throttled = lookup::<ThrottleVfs<BaseVfs>>("reload_stack/0").unwrap();
+        assert_eq!(throttled.throttle(), Throttle::default());
+        let traced = lookup::<TraceVfs<BaseVfs>>("reload_stack").unwrap();
+        assert!(traced.enabled());
+
+        reload_stack("reload_stack", &[throttle(10), quota(4 << 20), trace])?;
+        assert_eq!(
+            throttled.throttle().write_latency,
+            Duration::from_micros(10)
+        );
+        assert!(!traced.enabled());
+        run_workload("reload_stack", &dir.file("main.db"), workload)?;
+        unregister_stack("reload_stack", 3);
+        Ok(())
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn config_stack_order() {
+        // compressed records would be checksummed
+        let config = StackConfig {
+            base: None,
+            layers: vec![LayerConfig::Checksum, LayerConfig::Lz4],
+        };
+        let env = StackEnv { clock, sleep };
+        let err = register_stack("config_order", &config, env, RegisterOpts::default());
+        let failures = vec![("config_order".into(), VfsError::Other(vars::SQLITE_MISUSE))];
+        assert_eq!(err, Err(RegisterError { failures }));
+        assert!(BaseVfs::find(Some("config_order")).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_json() -> Result<(), serde_json::Error> {
+        let config: StackConfig = serde_json::from_str(
+            r#"{
+                "layers": [
+                    { "layer": "checksum" },
+                    { "layer": "throttle", "write_latency_us": 500 },
+                    { "layer": "quota", "prefix_limits": { "/srv/a/": 1024 } },
+                    { "layer": "trace" }
+                ]
+            }"#,
+        )?;
+        let throttle = Throttle {
+            write_latency: Duration::from_micros(500),
+            ..Throttle::default()
+        };
+        assert_eq!(config.base, None);
+        assert_eq!(config.layers[0], LayerConfig::Checksum);
+        assert_eq!(config.layers[1].throttle(), Some(throttle));
+        assert_eq!(
+            config.layers[2],
+            LayerConfig::Quota {
+                file_limits: BTreeMap::new(),
+                prefix_limits: [("/srv/a/".into(), 1024)].into(),
+            }
+        );
+
+        assert_eq!(config.layers[3], LayerConfig::Trace { enabled: true });
+
+        // misspelled options are rejected
+        let err = serde_json::from_str::<StackConfig>(
+            r#"{ "layers": [{ "layer": "throttle", "write_latency": 500 }] }"#,
+        );
+        assert!(err.is_err());
+        Ok(())
+    }
+}
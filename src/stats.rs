@@ -0,0 +1,388 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+use core::time::Duration;
+use spin::Mutex;
+
+use crate::flags::{LockLevel, OpenKind, OpenOpts, SyncFlags};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{Pragma, PragmaErr, UriParams, Vfs, VfsHandle, VfsResult};
+
+/// The number and latency of one kind of operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub count: u64,
+    /// How many of the operations failed, including with `SQLITE_BUSY`.
+    pub errors: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl OpStats {
+    fn record(&mut self, elapsed: Duration, ok: bool) {
+        self.count += 1;
+        self.errors += u64::from(!ok);
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    fn merge(&mut self, other: &OpStats) {
+        self.count += other.count;
+        self.errors += other.errors;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// The operations counted by a [`StatsVfs`], for one handle or all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub reads: OpStats,
+    pub writes: OpStats,
+    pub syncs: OpStats,
+    pub truncates: OpStats,
+    /// Lock requests, whether or not they raise the lock level.
+    pub locks: OpStats,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: &Stats) {
+        self.reads.merge(&other.reads);
+        self.writes.merge(&other.writes);
+        self.syncs.merge(&other.syncs);
+        self.truncates.merge(&other.truncates);
+        self.locks.merge(&other.locks);
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// Formats the stats as `name=value` pairs, as returned by
+/// `pragma vfs_stats`, with latencies in microseconds.
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ops = [
+            ("reads", &self.reads),
+            ("writes", &self.writes),
+            ("syncs", &self.syncs),
+            ("truncates", &self.truncates),
+            ("locks", &self.locks),
+        ];
+        write!(
+            f,
+            "bytes_read={} bytes_written={}",
+            self.bytes_read, self.bytes_written
+        )?;
+        for (name, op) in ops {
+            write!(
+                f,
+                " {name}={} {name}_errors={} {name}_us={} {name}_max_us={}",
+                op.count,
+                op.errors,
+                op.total.as_micros(),
+                op.max.as_micros()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The stats of a file open through a [`StatsVfs`].
+#[derive(Debug, Clone)]
+pub struct HandleStats {
+    pub name: Option<String>,
+    pub kind: OpenKind,
+    pub stats: Stats,
+}
+
+/// A Vfs wrapper which counts the reads, writes, bytes, syncs, truncates and
+/// lock requests of every handle, and how long they took, so that a vfs need
+/// not keep its own metrics.
+///
+/// [`StatsVfs::stats`] returns the totals since the vfs was created, and
+/// [`StatsVfs::handle_stats`] those of each open handle. `pragma vfs_stats`
+/// returns the totals formatted as by [`Stats`]'s `Display`.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::stats::StatsVfs;
+/// # use sqlite_plugin::vfs::{self, RegisterOpts, VfsResult, register_static};
+/// # fn elapsed() -> Duration { Duration::ZERO }
+/// # fn main() -> VfsResult<()> {
+/// let vfs = StatsVfs::new(BaseVfs::find(None)?, elapsed);
+/// register_static(c"stats".to_owned(), vfs, RegisterOpts::default())?;
+/// // ... run a workload
+/// let vfs = vfs::lookup::<StatsVfs<BaseVfs>>("stats").unwrap();
+/// assert!(vfs.stats().syncs.count <= 2);
+/// # Ok(())
+/// # }
+/// ```
+pub struct StatsVfs<V> {
+    vfs: V,
+    clock: fn() -> Duration,
+    state: Mutex<State>,
+}
+
+pub struct StatsHandle<H> {
+    inner: H,
+    id: usize,
+    counters: Arc<Counters>,
+}
+
+struct Counters {
+    name: Option<String>,
+    kind: OpenKind,
+    stats: Mutex<Stats>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    open: BTreeMap<usize, Arc<Counters>>,
+    // the totals of the handles already closed
+    closed: Stats,
+}
+
+impl<H: VfsHandle> VfsHandle for StatsHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for StatsHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> StatsVfs<V> {
+    /// Wraps `vfs`, timing operations with `clock`, which returns a monotonic
+    /// time since any fixed point.
+    pub fn new(vfs: V, clock: fn() -> Duration) -> Self {
+        Self {
+            vfs,
+            clock,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The totals of every handle opened through the vfs.
+    pub fn stats(&self) -> Stats {
+        let state = self.state.lock();
+        let mut stats = state.closed;
+        for counters in state.open.values() {
+            stats.merge(&counters.stats.lock());
+        }
+        stats
+    }
+
+    /// The stats of every handle currently open through the vfs.
+    pub fn handle_stats(&self) -> Vec<HandleStats> {
+        let state = self.state.lock();
+        state
+            .open
+            .values()
+            .map(|counters| HandleStats {
+                name: counters.name.clone(),
+                kind: counters.kind,
+                stats: *counters.stats.lock(),
+            })
+            .collect()
+    }
+
+    /// Sets every count back to zero.
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        state.closed = Stats::default();
+        for counters in state.open.values() {
+            *counters.stats.lock() = Stats::default();
+        }
+    }
+
+    // runs `call`, and records it with `record`
+    fn timed<T>(
+        &self,
+        counters: &Counters,
+        call: impl FnOnce() -> VfsResult<T>,
+        record: impl FnOnce(&mut Stats, Duration, &VfsResult<T>),
+    ) -> VfsResult<T> {
+        let start = (self.clock)();
+        let result = call();
+        let elapsed = (self.clock)().saturating_sub(start);
+        record(&mut counters.stats.lock(), elapsed, &result);
+        result
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for StatsVfs<V> {
+    type Inner = V;
+    type Handle = StatsHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let inner = self.vfs.open(path, opts, params)?;
+        let counters = Arc::new(Counters {
+            name: path.map(Into::into),
+            kind: opts.kind(),
+            stats: Mutex::new(Stats::default()),
+        });
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, counters.clone());
+        Ok(StatsHandle { inner, id, counters })
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.timed(
+            &handle.counters,
+            || self.vfs.truncate(&mut handle.inner, size),
+            |stats, elapsed, result| stats.truncates.record(elapsed, result.is_ok()),
+        )
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.timed(
+            &handle.counters,
+            || self.vfs.write(&mut handle.inner, offset, data),
+            |stats, elapsed, result| {
+                stats.writes.record(elapsed, result.is_ok());
+                stats.bytes_written += result.as_ref().map_or(0, |&n| n as u64);
+            },
+        )
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        self.timed(
+            &handle.counters,
+            || self.vfs.read(&mut handle.inner, offset, data),
+            |stats, elapsed, result| {
+                stats.reads.record(elapsed, result.is_ok());
+                stats.bytes_read += result.as_ref().map_or(0, |&n| n as u64);
+            },
+        )
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.timed(
+            &handle.counters,
+            || self.vfs.lock(&mut handle.inner, level),
+            |stats, elapsed, result| stats.locks.record(elapsed, result.is_ok()),
+        )
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.timed(
+            &handle.counters,
+            || self.vfs.sync(&mut handle.inner, flags),
+            |stats, elapsed, result| stats.syncs.record(elapsed, result.is_ok()),
+        )
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        {
+            let mut state = self.state.lock();
+            state.open.remove(&handle.id);
+            state.closed.merge(&handle.counters.stats.lock());
+        }
+        self.vfs.close(handle.inner)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        if !pragma.name.eq_ignore_ascii_case("vfs_stats") {
+            return self.vfs.pragma(&mut handle.inner, pragma);
+        }
+        if !pragma.is_query() {
+            let msg = format!("{} is read only", pragma.name);
+            return Err(PragmaErr::Fail(vars::SQLITE_ERROR, Some(msg)));
+        }
+        Ok(Some(self.stats().to_string()))
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for StatsVfs<V>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::testing::TestVfs;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use rusqlite::{Connection, OpenFlags};
+    use std::boxed::Box;
+
+    // a clock which ticks a microsecond every time it is read
+    fn clock() -> Duration {
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        Duration::from_micros(NOW.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[test]
+    fn stats() -> Result<(), Box<dyn std::error::Error>> {
+        let mock = MockVfs::new(Box::new(NoopHooks));
+        let vfs = TestVfs::scoped_with("stats", StatsVfs::new(mock, clock))?;
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let conn = Connection::open_with_flags_and_vfs("main.db", flags, vfs.name())?;
+        conn.execute_batch(
+            "create table t(x);
+            insert into t values (randomblob(10000));",
+        )?;
+
+        let stats = vfs.vfs().stats();
+        assert!(stats.writes.count > 0 && stats.syncs.count > 0 && stats.locks.count > 0);
+        assert!(stats.bytes_written > 10000);
+        assert_eq!(stats.writes.errors, 0);
+        assert_eq!(
+            stats.writes.total,
+            Duration::from_micros(stats.writes.count)
+        );
+        assert_eq!(stats.writes.max, Duration::from_micros(1));
+
+        // the journal was closed, so only the database is still open
+        let handles = vfs.vfs().handle_stats();
+        assert_eq!(handles.len(), 1);
+        assert_eq!(handles[0].name.as_deref(), Some("main.db"));
+        assert_eq!(handles[0].kind, OpenKind::MainDb);
+        assert!(handles[0].stats.writes.count < stats.writes.count);
+
+        let line: String = conn.query_row("pragma vfs_stats", [], |row| row.get(0))?;
+        let bytes_written = std::format!("bytes_written={}", stats.bytes_written);
+        assert!(line.contains(&bytes_written), "{line}");
+        assert!(conn.execute_batch("pragma vfs_stats = 1").is_err());
+
+        vfs.vfs().reset();
+        assert_eq!(vfs.vfs().stats(), Stats::default());
+        drop(conn);
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+// fixtures use std
+extern crate std;
+
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+
+use std::path::{Path, PathBuf};
+
+use crate::shim::BaseVfs;
+use crate::vars;
+use crate::vfs::{self, RegisterOpts, Vfs, VfsError, VfsRegistration, VfsResult, register_scoped};
+
+/// A vfs registered under a unique name for the duration of a test, so that
+/// tests running in parallel never share a vfs or its state. The vfs is
+/// unregistered and dropped along with the fixture.
+///
+/// ```no_run
+/// # use rusqlite::{Connection, OpenFlags};
+/// # use sqlite_plugin::readonly::ReadOnlyVfs;
+/// # use sqlite_plugin::testing::{TestVfs, os_vfs};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let vfs = TestVfs::scoped_with("readonly", ReadOnlyVfs::new(os_vfs()?))?;
+/// let conn = Connection::open_with_flags_and_vfs("main.db", OpenFlags::default(), vfs.name())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Every connection using the vfs must be closed before the fixture is
+/// dropped. Otherwise the registration is leaked, as freeing it would leave
+/// those connections dangling.
+pub struct TestVfs<V> {
+    registration: VfsRegistration,
+    vfs: Arc<V>,
+}
+
+impl<V: Vfs + 'static> TestVfs<V> {
+    /// Registers a new `V::default()` under a unique name starting with `name`.
+    pub fn scoped(name: &str) -> VfsResult<Self>
+    where
+        V: Default,
+    {
+        Self::scoped_with(name, V::default())
+    }
+
+    /// Registers `vfs` under a unique name starting with `name`.
+    pub fn scoped_with(name: &str, vfs: V) -> VfsResult<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{name}-{id}");
+        let c_name = CString::new(name.as_str()).map_err(|_| vars::SQLITE_MISUSE)?;
+        // SAFETY: the unique name is only handed out by this fixture, which
+        // must outlive the connections using it
+        let registration = unsafe { register_scoped(c_name, vfs, RegisterOpts::default())? };
+        let vfs = vfs::lookup(&name).ok_or(vars::SQLITE_INTERNAL)?;
+        Ok(Self { registration, vfs })
+    }
+
+    /// The name the vfs is registered under, to be passed to `SQLite` when
+    /// opening a connection.
+    pub fn name(&self) -> &str {
+        self.registration.name()
+    }
+
+    pub fn vfs(&self) -> &V {
+        &self.vfs
+    }
+}
+
+/// The built-in vfs `SQLite` uses for files on disk, found by name as other
+/// tests may replace the default vfs.
+pub fn os_vfs() -> VfsResult<BaseVfs> {
+    BaseVfs::find(Some(if cfg!(windows) { "win32" } else { "unix" }))
+}
+
+/// A new directory under [`std::env::temp_dir`] for the files of a test,
+/// which is removed along with everything in it when the fixture is dropped.
+///
+/// ```no_run
+/// # use rusqlite::{Connection, OpenFlags};
+/// # use sqlite_plugin::testing::TempDir;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let dir = TempDir::new("my_vfs")?;
+/// let conn = Connection::open_with_flags_and_vfs(dir.file("main.db"), OpenFlags::default(), "my_vfs")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Connections to files in the directory should be closed before it is
+/// dropped, which declaring them after the fixture ensures.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a directory unique to the process whose name starts with
+    /// `name`.
+    pub fn new(name: &str) -> std::io::Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("{name}-{}-{id}", std::process::id()));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the file `name` in the directory, as passed to `SQLite`.
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Runs `workload` against a new database at `path` opened through the vfs
+/// registered as `vfs_name`, and returns a dump of the resulting logical
+/// content, to be compared against a golden snapshot:
+///
+/// ```
+/// # use sqlite_plugin::testing::{TempDir, TestVfs, os_vfs, run_workload};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let vfs = TestVfs::scoped_with("workload", os_vfs()?)?;
+/// let dir = TempDir::new("workload")?;
+/// let workload = "create table t(x); insert into t values (1), (2);";
+/// let dump = run_workload(vfs.name(), &dir.file("main.db"), workload)?;
+/// assert_eq!(dump, "CREATE TABLE t(x);\nt: 1\nt: 2\n");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The dump lists the schema followed by every row of every table, sorted
+/// so that it doesn't depend on the layout of the database file.
+pub fn run_workload(vfs_name: &str, path: &str, workload: &str) -> VfsResult<String> {
+    let conn = open_workload(vfs_name, path, workload)?;
+    logical_dump(&conn)
+}
+
+/// The logical content and page image of a database after a workload, see
+/// [`run_workload_image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The dump returned by [`run_workload`].
+    pub dump: String,
+    /// Every page of the database after a `VACUUM`, as read back through the
+    /// vfs.
+    pub image: Vec<u8>,
+}
+
+/// Like [`run_workload`], but also `VACUUM`s the database once the workload
+/// is done and reads back its pages. As `VACUUM` rebuilds the database from
+/// its content, the image is the same for every run of the workload, and
+/// two vfs that store the same content yield the same image, whatever
+/// their layout on disk. Comparing it catches corruption which leaves the
+/// logical content intact, such as damage to free pages or the header.
+pub fn run_workload_image(vfs_name: &str, path: &str, workload: &str) -> VfsResult<Snapshot> {
+    let conn = open_workload(vfs_name, path, workload)?;
+    let dump = logical_dump(&conn)?;
+    conn.execute_batch("vacuum").map_err(sqlite_err)?;
+    drop(conn);
+    let mut image = Vec::new();
+    crate::backup::backup_consistent(vfs_name, path, |chunk| {
+        image.extend_from_slice(chunk);
+        Ok(())
+    })?;
+    Ok(Snapshot { dump, image })
+}
+
+// opens a new database at `path` and runs `workload` on it
+fn open_workload(vfs_name: &str, path: &str, workload: &str) -> VfsResult<Connection> {
+    let conn = Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs_name,
+    )
+    .map_err(sqlite_err)?;
+    conn.execute_batch(workload).map_err(sqlite_err)?;
+    conn.execute_batch("pragma integrity_check")
+        .map_err(sqlite_err)?;
+    Ok(conn)
+}
+
+fn logical_dump(conn: &Connection) -> VfsResult<String> {
+    let mut out = String::new();
+    let mut stmt = conn
+        .prepare(
+            "select type, name, sql from sqlite_schema
+            where sql is not null order by type, name",
+        )
+        .map_err(sqlite_err)?;
+    let schema = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })
+        .map_err(sqlite_err)?;
+    let mut tables = Vec::new();
+    for entry in schema {
+        let (kind, name, sql) = entry.map_err(sqlite_err)?;
+        writeln!(out, "{sql};").map_err(fmt_err)?;
+        if kind == "table" {
+            tables.push(name);
+        }
+    }
+
+    for table in tables {
+        let mut stmt = conn
+            .prepare(&format!("select * from \"{}\"", table.replace('"', "\"\"")))
+            .map_err(sqlite_err)?;
+        let columns = stmt.column_count();
+        let mut lines = stmt
+            .query_map([], |row| {
+                let mut line = String::new();
+                let mut written = Ok(());
+                for i in 0..columns {
+                    let value = row.get_ref(i)?;
+                    if i > 0 {
+                        written = written.and_then(|()| line.write_str(", "));
+                    }
+                    written = written.and_then(|()| write_value(&mut line, value));
+                }
+                // a failed write is reported once the row is read
+                Ok(written.map(|()| line))
+            })
+            .map_err(sqlite_err)?
+            .map(|line| line.map_err(sqlite_err)?.map_err(fmt_err))
+            .collect::<VfsResult<Vec<_>>>()?;
+        lines.sort();
+        for line in lines {
+            writeln!(out, "{table}: {line}").map_err(fmt_err)?;
+        }
+    }
+    Ok(out)
+}
+
+fn write_value(out: &mut String, value: ValueRef<'_>) -> fmt::Result {
+    match value {
+        ValueRef::Null => out.write_str("NULL"),
+        ValueRef::Integer(v) => write!(out, "{v}"),
+        ValueRef::Real(v) => write!(out, "{v:?}"),
+        ValueRef::Text(v) => {
+            let text = String::from_utf8_lossy(v);
+            write!(out, "'{}'", text.replace('\'', "''"))
+        }
+        ValueRef::Blob(v) => {
+            out.write_str("x'")?;
+            for b in v {
+                write!(out, "{b:02x}")?;
+            }
+            out.write_char('\'')
+        }
+    }
+}
+
+// formatting into a string only fails if it runs out of memory
+fn fmt_err(_: fmt::Error) -> VfsError {
+    VfsError::Other(vars::SQLITE_NOMEM)
+}
+
+fn sqlite_err(err: rusqlite::Error) -> VfsError {
+    err.sqlite_error()
+        .map_or(VfsError::Other(vars::SQLITE_ERROR), |err| {
+            err.extended_code.into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::ffi;
+    use crate::mock::{MockVfs, NoopHooks};
+    use std::boxed::Box;
+
+    fn find(name: &str) -> *mut ffi::sqlite3_vfs {
+        let name = CString::new(name).unwrap();
+        unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }
+    }
+
+    #[test]
+    fn scoped() -> Result<(), Box<dyn std::error::Error>> {
+        let vfs = TestVfs::scoped_with("mock_scoped", MockVfs::new(Box::new(NoopHooks)))
+            .map_err(|_| "failed to register vfs")?;
+        let other = TestVfs::scoped_with("mock_scoped", MockVfs::new(Box::new(NoopHooks)))
+            .map_err(|_| "failed to register vfs")?;
+        assert_ne!(vfs.name(), other.name());
+
+        let name = String::from(vfs.name());
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs.name(),
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        // the vfs can't be freed while the connection holds files open
+        assert_eq!(unsafe { vfs::unregister(&name) }, Err(VfsError::Busy));
+        assert!(!find(&name).is_null());
+
+        drop(conn);
+        drop(vfs);
+        assert!(find(&name).is_null());
+        assert!(vfs::lookup::<MockVfs>(&name).is_none());
+        assert!(!find(other.name()).is_null());
+
+        Ok(())
+    }
+
+    #[test]
+    fn workload() {
+        let vfs = TestVfs::scoped_with("mock_workload", MockVfs::new(Box::new(NoopHooks))).unwrap();
+        let dump = run_workload(
+            vfs.name(),
+            "main.db",
+            "create table t (id integer primary key, val);
+            insert into t (val) values (1.5), ('it''s'), (x'beef'), (null);
+            delete from t where id = 1;",
+        )
+        .unwrap();
+        assert_eq!(
+            dump,
+            "CREATE TABLE t (id integer primary key, val);
+t: 2, 'it''s'
+t: 3, x'beef'
+t: 4, NULL
+"
+        );
+    }
+
+    #[test]
+    fn workload_image() -> Result<(), Box<dyn std::error::Error>> {
+        let workload = "create table t (id integer primary key, val);
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 100)
+            insert into t (val) select printf('%0512d', i) from n;
+            delete from t where id % 2 = 0;";
+        let mock = TestVfs::scoped_with("mock_image", MockVfs::new(Box::new(NoopHooks)))?;
+        let mock = run_workload_image(mock.name(), "main.db", workload)?;
+        assert!(mock.image.starts_with(b"SQLite format 3\0"));
+        assert_eq!(mock.image.len() % 4096, 0);
+
+        // the same content stored on disk yields the same image
+        let os = TestVfs::scoped_with("os_image", os_vfs()?)?;
+        let dir = TempDir::new("workload_image")?;
+        let os = run_workload_image(os.name(), &dir.file("main.db"), workload)?;
+        assert_eq!(os, mock);
+
+        Ok(())
+    }
+}
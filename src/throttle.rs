@@ -0,0 +1,216 @@
+use core::time::Duration;
+use spin::Mutex;
+
+use crate::flags::{OpenOpts, SyncFlags};
+use crate::shim;
+use crate::vfs::{UriParams, Vfs, VfsResult};
+
+/// How a [`ThrottleVfs`] slows down the vfs it wraps. The default adds no
+/// latency and no limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Throttle {
+    /// Added to every read.
+    pub read_latency: Duration,
+    /// Added to every write.
+    pub write_latency: Duration,
+    /// Added to every sync.
+    pub sync_latency: Duration,
+    /// The most bytes read per second, across every file.
+    pub read_bytes_per_sec: Option<u64>,
+    /// The most bytes written per second, across every file.
+    pub write_bytes_per_sec: Option<u64>,
+}
+
+/// A Vfs wrapper which simulates a slow disk or rate limited network storage,
+/// by sleeping before operations of the wrapped vfs as set by a
+/// [`Throttle`]. The settings can be changed while the vfs is in use.
+///
+/// Byte rates are enforced as if reads and writes each went through one
+/// queue, so that an operation waits for the bytes of the ones before it.
+/// Only reads, writes and syncs are slowed down, so this is a
+/// [`VfsShim`](shim::VfsShim) which uses the handles of the wrapped vfs.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::throttle::{Throttle, ThrottleVfs};
+/// # use sqlite_plugin::vfs::VfsResult;
+/// # fn clock() -> Duration { Duration::ZERO }
+/// # fn main() -> VfsResult<()> {
+/// let throttle = Throttle { write_bytes_per_sec: Some(1 << 20), ..Throttle::default() };
+/// let vfs = ThrottleVfs::new(BaseVfs::find(None)?, throttle, clock, std::thread::sleep);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ThrottleVfs<V> {
+    vfs: V,
+    clock: fn() -> Duration,
+    sleep: fn(Duration),
+    state: Mutex<State>,
+}
+
+struct State {
+    throttle: Throttle,
+    // when the bytes of earlier reads and writes will have passed
+    reads_done: Duration,
+    writes_done: Duration,
+}
+
+impl<V: Vfs> ThrottleVfs<V> {
+    /// Wraps `vfs`, waiting with `sleep` and measuring rates with `clock`,
+    /// which returns a monotonic time since any fixed point.
+    pub fn new(vfs: V, throttle: Throttle, clock: fn() -> Duration, sleep: fn(Duration)) -> Self {
+        let state = State {
+            throttle,
+            reads_done: Duration::ZERO,
+            writes_done: Duration::ZERO,
+        };
+        Self {
+            vfs,
+            clock,
+            sleep,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn throttle(&self) -> Throttle {
+        self.state.lock().throttle
+    }
+
+    pub fn set_throttle(&self, throttle: Throttle) {
+        self.state.lock().throttle = throttle;
+    }
+
+    fn wait(&self, duration: Duration) {
+        if !duration.is_zero() {
+            (self.sleep)(duration);
+        }
+    }
+}
+
+// queues `len` bytes after the ones which will have passed at `done`, and
+// returns how long until they have passed too
+fn queue(done: &mut Duration, now: Duration, len: usize, rate: Option<u64>) -> Duration {
+    let Some(rate) = rate.filter(|&rate| rate > 0) else {
+        return Duration::ZERO;
+    };
+    let nanos = len as u128 * 1_000_000_000 / u128::from(rate);
+    *done = (*done).max(now) + Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+    *done - now
+}
+
+impl<V: Vfs> shim::VfsShim for ThrottleVfs<V> {
+    type Inner = V;
+    type Handle = V::Handle;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<V::Handle> {
+        self.vfs.open(path, opts, params)
+    }
+
+    fn write(&self, handle: &mut V::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let wait = {
+            let mut state = self.state.lock();
+            let throttle = state.throttle;
+            let now = (self.clock)();
+            let rate = throttle.write_bytes_per_sec;
+            throttle.write_latency + queue(&mut state.writes_done, now, data.len(), rate)
+        };
+        self.wait(wait);
+        self.vfs.write(handle, offset, data)
+    }
+
+    fn read(&self, handle: &mut V::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let wait = {
+            let mut state = self.state.lock();
+            let throttle = state.throttle;
+            let now = (self.clock)();
+            let rate = throttle.read_bytes_per_sec;
+            throttle.read_latency + queue(&mut state.reads_done, now, data.len(), rate)
+        };
+        self.wait(wait);
+        self.vfs.read(handle, offset, data)
+    }
+
+    fn sync(&self, handle: &mut V::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.wait(self.state.lock().throttle.sync_latency);
+        self.vfs.sync(handle, flags)
+    }
+
+    fn supports_mmap(&self) -> bool {
+        // mapped pages would be read without being throttled
+        false
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for ThrottleVfs<V>);
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::logger::SqliteLogger;
+    use crate::mock::{MockVfs, NoopHooks};
+    use crate::vars;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::boxed::Box;
+
+    // a simulated clock, which sleeping advances
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn clock() -> Duration {
+        Duration::from_micros(NOW.load(Ordering::Relaxed))
+    }
+
+    fn sleep(duration: Duration) {
+        NOW.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn throttled() {
+        let throttle = Throttle {
+            write_latency: Duration::from_millis(1),
+            sync_latency: Duration::from_millis(10),
+            write_bytes_per_sec: Some(1000),
+            ..Throttle::default()
+        };
+        let vfs = ThrottleVfs::new(MockVfs::new(Box::new(NoopHooks)), throttle, clock, sleep);
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let mut file = vfs.open(Some("main.db"), opts, UriParams::empty()).unwrap();
+
+        // each write waits for its latency and for its bytes at 1000/s
+        vfs.write(&mut file, 0, &[1; 500]).unwrap();
+        assert_eq!(clock(), Duration::from_millis(501));
+        vfs.write(&mut file, 500, &[1; 500]).unwrap();
+        assert_eq!(clock(), Duration::from_millis(1002));
+        vfs.sync(&mut file, SyncFlags::new(0)).unwrap();
+        assert_eq!(clock(), Duration::from_millis(1012));
+        // reads are not limited
+        vfs.read(&mut file, 0, &mut [0; 1000]).unwrap();
+        assert_eq!(clock(), Duration::from_millis(1012));
+
+        // writes which come later than the rate allows don't wait for it
+        sleep(Duration::from_secs(1));
+        vfs.set_throttle(Throttle {
+            write_bytes_per_sec: Some(1000),
+            ..Throttle::default()
+        });
+        vfs.write(&mut file, 0, &[1; 100]).unwrap();
+        assert_eq!(clock(), Duration::from_millis(2112));
+        vfs.set_throttle(Throttle::default());
+        vfs.write(&mut file, 0, &[1; 100]).unwrap();
+        assert_eq!(clock(), Duration::from_millis(2112));
+        vfs.close(file).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode, SyncFlags};
+use crate::logger::{SqliteLogLevel, SqliteLogger};
+use crate::shim;
+use crate::vars;
+use crate::vfs::{
+    FileControlOp, JournalPolicy, Pragma, PragmaErr, SqliteErr, UriParams, Vfs, VfsHandle,
+    VfsResult,
+};
+
+/// A Vfs wrapper which logs every operation, with its arguments, result and
+/// duration, to the `SQLite` log at notice level, in the spirit of `SQLite`'s
+/// vfstrace shim. Lines look like:
+///
+/// ```text
+/// trace: read main.db#1 offset=0 len=4096 -> ok 4096 (3.1µs)
+/// trace: lock main.db#1 Reserved -> error 5 (file is locked) (1µs)
+/// ```
+///
+/// Files are labeled with their path and a number which tells apart the
+/// handles of different connections to the same file. Failures show the
+/// result code `SQLite` sees.
+///
+/// ```no_run
+/// # use std::sync::OnceLock;
+/// # use std::time::{Duration, Instant};
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::trace::TraceVfs;
+/// # use sqlite_plugin::vfs::{RegisterOpts, VfsResult, register_static};
+/// fn elapsed() -> Duration {
+///     static START: OnceLock<Instant> = OnceLock::new();
+///     START.get_or_init(Instant::now).elapsed()
+/// }
+///
+/// # fn main() -> VfsResult<()> {
+/// let vfs = TraceVfs::new(BaseVfs::find(None)?, elapsed);
+/// register_static(c"traced".to_owned(), vfs, RegisterOpts::default())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TraceVfs<V> {
+    vfs: V,
+    clock: fn() -> Duration,
+    logger: spin::Mutex<Option<SqliteLogger>>,
+    next_id: AtomicUsize,
+    enabled: AtomicBool,
+}
+
+pub struct TraceHandle<H> {
+    // the path and id, as shown in the log
+    label: String,
+    inner: H,
+}
+
+impl<H> fmt::Debug for TraceHandle<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label)
+    }
+}
+
+impl<V: Vfs> TraceVfs<V> {
+    /// Wraps `vfs`, timing operations with `clock`, which returns a monotonic
+    /// time since any fixed point.
+    pub fn new(vfs: V, clock: fn() -> Duration) -> Self {
+        Self {
+            vfs,
+            clock,
+            logger: spin::Mutex::new(None),
+            next_id: AtomicUsize::new(1),
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Whether operations are logged, which they are unless disabled with
+    /// [`set_enabled`](TraceVfs::set_enabled).
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Pauses or resumes logging, while the vfs is in use.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // `io_code` is the code dispatch reports a `VfsError::Io` from the call as
+    fn trace<T: fmt::Debug>(
+        &self,
+        io_code: SqliteErr,
+        op: fmt::Arguments<'_>,
+        call: impl FnOnce() -> VfsResult<T>,
+    ) -> VfsResult<T> {
+        self.traced(op, call, |result, line| match result {
+            Ok(value) => {
+                // unit results are shown as a bare ok
+                let value = format!("{value:?}");
+                line.push_str("ok");
+                if value != "()" {
+                    line.push(' ');
+                    line.push_str(&value);
+                }
+            }
+            Err(err) => {
+                let _ = write!(line, "error {} ({err})", err.code(io_code));
+            }
+        })
+    }
+
+    fn trace_value<T: fmt::Debug>(&self, op: fmt::Arguments<'_>, call: impl FnOnce() -> T) -> T {
+        self.traced(op, call, |value, line| {
+            let _ = write!(line, "{value:?}");
+        })
+    }
+
+    fn traced<R>(
+        &self,
+        op: fmt::Arguments<'_>,
+        call: impl FnOnce() -> R,
+        describe: impl FnOnce(&R, &mut String),
+    ) -> R {
+        let logger = *self.logger.lock();
+        let Some(logger) = logger.filter(|_| self.enabled()) else {
+            return call();
+        };
+        let start = (self.clock)();
+        let result = call();
+        let elapsed = (self.clock)().saturating_sub(start);
+        let mut line = format!("trace: {op} -> ");
+        describe(&result, &mut line);
+        let _ = write!(line, " ({elapsed:?})");
+        logger.log(SqliteLogLevel::Notice, line.as_bytes());
+        result
+    }
+}
+
+impl<H: VfsHandle> VfsHandle for TraceHandle<H> {
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.inner.in_memory()
+    }
+}
+
+impl<H: VfsHandle> shim::ShimHandle<H> for TraceHandle<H> {
+    fn inner_handle(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    fn into_inner_handle(self) -> H {
+        self.inner
+    }
+}
+
+impl<V: Vfs> shim::VfsShim for TraceVfs<V> {
+    type Inner = V;
+    type Handle = TraceHandle<V::Handle>;
+
+    fn inner(&self) -> &V {
+        &self.vfs
+    }
+
+    fn register_logger(&self, logger: SqliteLogger) {
+        *self.logger.lock() = Some(logger);
+        self.vfs.register_logger(logger)
+    }
+
+    fn probe(&self) -> VfsResult<()> {
+        self.trace(vars::SQLITE_ERROR, format_args!("probe"), || {
+            self.vfs.probe()
+        })
+    }
+
+    fn last_error(&self) -> Option<(i32, String)> {
+        self.trace_value(format_args!("last_error"), || self.vfs.last_error())
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        let op = format!("canonical_path {path}");
+        self.trace(vars::SQLITE_CANTOPEN_FULLPATH, format_args!("{op}"), || {
+            self.vfs.canonical_path(path)
+        })
+    }
+
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle> {
+        let name = path.unwrap_or("<anonymous>");
+        let op = format!("open {name} {opts:?} {params:?}");
+        self.trace(vars::SQLITE_CANTOPEN, format_args!("{op}"), || {
+            let inner = self.vfs.open(path, opts, params)?;
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            Ok(TraceHandle { label: format!("{name}#{id}"), inner })
+        })
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_DELETE,
+            format_args!("delete {path}"),
+            || self.vfs.delete(path),
+        )
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        let op = format!("access {path} {flags:?}");
+        self.trace(vars::SQLITE_IOERR_ACCESS, format_args!("{op}"), || {
+            self.vfs.access(path, flags)
+        })
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        self.trace(vars::SQLITE_IOERR, format_args!("temp_filename"), || {
+            self.vfs.temp_filename()
+        })
+    }
+
+    fn journal_policy(&self, db_path: &str) -> JournalPolicy {
+        self.trace_value(format_args!("journal_policy {db_path}"), || {
+            self.vfs.journal_policy(db_path)
+        })
+    }
+
+    fn bootstrap(&self, path: &str) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_CANTOPEN,
+            format_args!("bootstrap {path}"),
+            || self.vfs.bootstrap(path),
+        )
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.trace(
+            vars::SQLITE_IOERR_FSTAT,
+            format_args!("file_size {}", handle.label),
+            || self.vfs.file_size(&mut handle.inner),
+        )
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_TRUNCATE,
+            format_args!("truncate {} size={size}", handle.label),
+            || self.vfs.truncate(&mut handle.inner, size),
+        )
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.trace(
+            vars::SQLITE_IOERR_WRITE,
+            format_args!("write {} offset={offset} len={}", handle.label, data.len()),
+            || self.vfs.write(&mut handle.inner, offset, data),
+        )
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let len = data.len();
+        self.trace(
+            vars::SQLITE_IOERR_READ,
+            format_args!("read {} offset={offset} len={len}", handle.label),
+            || self.vfs.read(&mut handle.inner, offset, data),
+        )
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_LOCK,
+            format_args!("lock {} {level:?}", handle.label),
+            || self.vfs.lock(&mut handle.inner, level),
+        )
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_UNLOCK,
+            format_args!("unlock {} {level:?}", handle.label),
+            || self.vfs.unlock(&mut handle.inner, level),
+        )
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.trace(
+            vars::SQLITE_IOERR_CHECKRESERVEDLOCK,
+            format_args!("check_reserved_lock {}", handle.label),
+            || self.vfs.check_reserved_lock(&mut handle.inner),
+        )
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_FSYNC,
+            format_args!("sync {} {flags:?}", handle.label),
+            || self.vfs.sync(&mut handle.inner, flags),
+        )
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        let op = format!("close {}", handle.label);
+        self.trace(vars::SQLITE_IOERR_CLOSE, format_args!("{op}"), || {
+            self.vfs.close(handle.inner)
+        })
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        let op = format!("pragma {} {pragma:?}", handle.label);
+        self.trace_value(format_args!("{op}"), || {
+            self.vfs.pragma(&mut handle.inner, pragma)
+        })
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> usize {
+        self.trace_value(format_args!("sector_size {}", handle.label), || {
+            self.vfs.sector_size(&mut handle.inner)
+        })
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
+        self.trace_value(
+            format_args!("device_characteristics {}", handle.label),
+            || self.vfs.device_characteristics(&mut handle.inner),
+        )
+    }
+
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        let line = format!("file_control {} {op:?}", handle.label);
+        self.trace(vars::SQLITE_IOERR, format_args!("{line}"), || {
+            self.vfs.file_control(&mut handle.inner, op)
+        })
+    }
+
+    fn vfs_name(&self, registered: &str) -> Option<String> {
+        self.trace_value(format_args!("vfs_name {registered}"), || {
+            self.vfs.vfs_name(registered)
+        })
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.trace_value(format_args!("sleep {duration:?}"), || {
+            self.vfs.sleep(duration)
+        })
+    }
+
+    fn current_time(&self) -> Option<i64> {
+        self.trace_value(format_args!("current_time"), || self.vfs.current_time())
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = buf.len();
+        self.trace_value(format_args!("randomness len={len}"), || {
+            self.vfs.randomness(buf)
+        })
+    }
+
+    fn supports_shm(&self) -> bool {
+        self.trace_value(format_args!("supports_shm"), || self.vfs.supports_shm())
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        self.trace(
+            vars::SQLITE_IOERR_SHMMAP,
+            format_args!(
+                "shm_map {} region={region} size={size} extend={extend}",
+                handle.label
+            ),
+            || self.vfs.shm_map(&mut handle.inner, region, size, extend),
+        )
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_SHMLOCK,
+            format_args!(
+                "shm_lock {} offset={offset} count={count} {mode:?}",
+                handle.label
+            ),
+            || self.vfs.shm_lock(&mut handle.inner, offset, count, mode),
+        )
+    }
+
+    fn shm_barrier(&self, handle: &mut Self::Handle) {
+        self.trace_value(format_args!("shm_barrier {}", handle.label), || {
+            self.vfs.shm_barrier(&mut handle.inner)
+        })
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_SHMMAP,
+            format_args!("shm_unmap {} delete={delete}", handle.label),
+            || self.vfs.shm_unmap(&mut handle.inner, delete),
+        )
+    }
+
+    fn supports_mmap(&self) -> bool {
+        self.trace_value(format_args!("supports_mmap"), || self.vfs.supports_mmap())
+    }
+
+    fn fetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        len: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        self.trace(
+            vars::SQLITE_IOERR_MMAP,
+            format_args!("fetch {} offset={offset} len={len}", handle.label),
+            || self.vfs.fetch(&mut handle.inner, offset, len),
+        )
+    }
+
+    fn unfetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        ptr: Option<NonNull<u8>>,
+    ) -> VfsResult<()> {
+        self.trace(
+            vars::SQLITE_IOERR_MMAP,
+            format_args!("unfetch {} offset={offset} {ptr:?}", handle.label),
+            || self.vfs.unfetch(&mut handle.inner, offset, ptr),
+        )
+    }
+}
+
+crate::impl_vfs_shim!(impl[V: Vfs] for TraceVfs<V>);
+
+#[cfg(all(test, feature = "static"))]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::fault::{FaultPlan, FaultVfs, Schedule};
+    use crate::logger::{TEST_LOG_HOOK, set_log_hook};
+    use crate::mock::{MockVfs, NoopHooks};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::boxed::Box;
+
+    #[test]
+    fn trace() {
+        let _hook = TEST_LOG_HOOK.lock();
+        let lines = Arc::new(spin::Mutex::new(Vec::<String>::new()));
+        let captured = lines.clone();
+        set_log_hook(Some(Box::new(move |_, msg| {
+            if msg.starts_with("trace: ") && msg.contains("trace.db") {
+                captured.lock().push(msg.into());
+            }
+        })))
+        .unwrap();
+
+        // the second write fails, and is reported with the code of a write
+        let plan = FaultPlan::new().fail_write(Schedule::Nth(2));
+        let vfs = FaultVfs::new(MockVfs::new(Box::new(NoopHooks)), plan);
+        let vfs = TraceVfs::new(vfs, || Duration::ZERO);
+        vfs.register_logger(SqliteLogger::new(crate::ffi::sqlite3_log));
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE);
+        let mut handle = vfs
+            .open(Some("trace.db"), opts, UriParams::empty())
+            .unwrap();
+        vfs.write(&mut handle, 0, &[1; 8]).unwrap();
+        assert!(vfs.write(&mut handle, 8, &[1; 8]).is_err());
+        vfs.read(&mut handle, 4, &mut [0; 8]).unwrap();
+        let err = vfs.file_control(&mut handle, FileControlOp::CommitPhaseTwo);
+        assert!(err.is_err());
+        vfs.close(handle).unwrap();
+        set_log_hook(None).unwrap();
+
+        let lines = lines.lock();
+        assert_eq!(lines.len(), 6, "{lines:?}");
+        assert!(lines[0].starts_with("trace: open trace.db "));
+        assert!(lines[0].ends_with(" -> ok trace.db#1 (0ns)"));
+        assert_eq!(
+            lines[1..],
+            [
+                "trace: write trace.db#1 offset=0 len=8 -> ok 8 (0ns)",
+                "trace: write trace.db#1 offset=8 len=8 -> error 778 (io error) (0ns)",
+                "trace: read trace.db#1 offset=4 len=8 -> ok 4 (0ns)",
+                "trace: file_control trace.db#1 CommitPhaseTwo -> error 12 (operation not supported) (0ns)",
+                "trace: close trace.db#1 -> ok (0ns)",
+            ]
+        );
+    }
+}
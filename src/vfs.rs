@@ -1,14 +1,24 @@
-use crate::flags::{AccessFlags, LockLevel, OpenOpts};
-use crate::logger::SqliteLogger;
+use crate::flags::{
+    AccessFlags, CreateMode, LockLevel, OpenKind, OpenMode, OpenOpts, ShmLockMode, SyncFlags,
+};
+use crate::logger::{SqliteLogLevel, SqliteLogger};
 use crate::vars::SQLITE_ERROR;
 use crate::{ffi, vars};
 use alloc::borrow::Cow;
 use alloc::boxed::Box;
 use alloc::ffi::CString;
 use alloc::format;
-use alloc::string::String;
-use core::mem::{self, ManuallyDrop, MaybeUninit, size_of};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop, MaybeUninit, offset_of, size_of};
+use core::ptr::NonNull;
 use core::slice;
+use core::sync::atomic::{self, AtomicBool, AtomicI64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
 use core::{
     ffi::{CStr, c_char, c_int, c_void},
     ptr::null_mut,
@@ -19,7 +29,7 @@ use core::{
 pub const MIN_SQLITE_VERSION_NUMBER: i32 = 3044000;
 
 const DEFAULT_MAX_PATH_LEN: i32 = 512;
-pub const DEFAULT_SECTOR_SIZE: i32 = 4096;
+pub const DEFAULT_SECTOR_SIZE: usize = 4096;
 
 pub const DEFAULT_DEVICE_CHARACTERISTICS: i32 =
     // writes of any size are atomic
@@ -37,7 +47,80 @@ pub const DEFAULT_DEVICE_CHARACTERISTICS: i32 =
 /// A `SQLite3` extended error code
 pub type SqliteErr = i32;
 
-pub type VfsResult<T> = Result<T, SqliteErr>;
+/// An error returned by a [`Vfs`] method.
+///
+/// Each variant is reported to `SQLite` as the matching result code. [`Io`]
+/// is reported as the extended `SQLITE_IOERR_*` code of the failing method,
+/// such as `SQLITE_IOERR_READ` from [`Vfs::read`], so implementations don't
+/// need to pick the right one themselves.
+///
+/// [`Io`]: VfsError::Io
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VfsError {
+    /// The backend failed to perform the operation.
+    Io,
+    /// The file can't be opened (`SQLITE_CANTOPEN`).
+    CantOpen,
+    /// The file is locked by another connection (`SQLITE_BUSY`).
+    Busy,
+    /// The backend is out of space (`SQLITE_FULL`).
+    Full,
+    /// The operation is not supported (`SQLITE_NOTFOUND`).
+    NotFound,
+    /// Any other result code, which is reported to `SQLite` as is.
+    Other(SqliteErr),
+}
+
+impl VfsError {
+    /// Returns the result code reported to `SQLite`, where `io_code` is the
+    /// extended io error code of the failing method.
+    pub fn code(self, io_code: SqliteErr) -> SqliteErr {
+        match self {
+            Self::Io => io_code,
+            Self::CantOpen => vars::SQLITE_CANTOPEN,
+            Self::Busy => vars::SQLITE_BUSY,
+            Self::Full => vars::SQLITE_FULL,
+            Self::NotFound => vars::SQLITE_NOTFOUND,
+            Self::Other(code) => code,
+        }
+    }
+}
+
+/// Converts a raw result code, keeping extended codes as [`VfsError::Other`]
+/// so that they are reported unchanged.
+impl From<SqliteErr> for VfsError {
+    fn from(code: SqliteErr) -> Self {
+        match code {
+            vars::SQLITE_IOERR => Self::Io,
+            vars::SQLITE_CANTOPEN => Self::CantOpen,
+            vars::SQLITE_BUSY => Self::Busy,
+            vars::SQLITE_FULL => Self::Full,
+            vars::SQLITE_NOTFOUND => Self::NotFound,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io => f.write_str("io error"),
+            Self::CantOpen => f.write_str("unable to open file"),
+            Self::Busy => f.write_str("file is locked"),
+            Self::Full => f.write_str("backend is full"),
+            Self::NotFound => f.write_str("operation not supported"),
+            Self::Other(code) => write!(f, "sqlite error code {code}"),
+        }
+    }
+}
+
+impl core::error::Error for VfsError {}
+
+pub type VfsResult<T> = Result<T, VfsError>;
+
+/// A callback invoked with the path and new file change counter of a main
+/// database file after a write transaction against it has been committed.
+pub type ChangeCallback = Box<dyn Fn(&str, u32) + Send + Sync>;
 
 // FileWrapper needs to be repr(C) and have sqlite3_file as it's first member
 // because it's a "subclass" of sqlite3_file
@@ -46,24 +129,290 @@ struct FileWrapper<Handle> {
     file: ffi::sqlite3_file,
     vfs: *mut ffi::sqlite3_vfs,
     handle: MaybeUninit<Handle>,
+    state: MaybeUninit<FileState>,
+}
+
+/// Returns the `sqlite3_file` which `SQLite` uses to refer to `handle`, for
+/// interop with C code that calls its io methods directly.
+/// # Safety
+/// `handle` must be a handle passed by this crate to a method of a registered
+/// Vfs, other than `open`. Handles owned by a Vfs wrapped inside another Vfs
+/// are not stored in a `sqlite3_file` and produce a dangling pointer.
+pub unsafe fn raw_file<H>(handle: &H) -> *mut ffi::sqlite3_file {
+    let offset = offset_of!(FileWrapper<H>, handle);
+    let p_handle: *const H = handle;
+    unsafe { p_handle.byte_sub(offset) }.cast_mut().cast()
+}
+
+/// Returns the registered `sqlite3_vfs` which opened `handle`.
+/// # Safety
+/// See [`raw_file`].
+pub unsafe fn raw_vfs<H>(handle: &H) -> *mut ffi::sqlite3_vfs {
+    let p_file = unsafe { raw_file(handle) }.cast::<FileWrapper<H>>();
+    unsafe { (*p_file).vfs }
+}
+
+// bookkeeping the dispatch layer tracks for each open file
+#[derive(Debug)]
+struct FileState {
+    info: Arc<FileInfo>,
+    // set when the file is written to, cleared when the lock is released
+    dirty: bool,
+}
+
+// the parts of a file's state which are shared with the vfs, so that any
+// connection can list every file it serves
+struct FileInfo {
+    name: Option<String>,
+    opts: OpenOpts,
+    lock: AtomicU8,
+    // when the current lock level was entered, in milliseconds since the
+    // julian epoch, or 0 if unknown
+    locked_at: AtomicI64,
+    // the size last observed by the dispatch layer, or usize::MAX if unknown
+    size: AtomicUsize,
+    // the last FileOp forwarded to the Vfs, or 0
+    last_op: AtomicU8,
+    // whether the handle reported `in_memory`, set once it is open
+    in_memory: AtomicBool,
+}
+
+type OpenFiles = Arc<spin::Mutex<Vec<Arc<FileInfo>>>>;
+
+// paths of the databases made read-only with `set_frozen` or
+// `pragma sqlite_plugin_frozen`
+type FrozenFiles = Arc<spin::Mutex<Vec<String>>>;
+
+// whether `name` is a frozen database or one of its journals
+fn is_frozen(frozen: &FrozenFiles, name: Option<&str>) -> bool {
+    let Some(name) = name else {
+        return false;
+    };
+    frozen.lock().iter().any(|db| {
+        name.strip_prefix(db.as_str())
+            .is_some_and(|suffix| matches!(suffix, "" | "-journal" | "-wal"))
+    })
+}
+
+fn set_frozen_inner(frozen: &FrozenFiles, path: &str, freeze: bool) {
+    let mut frozen = frozen.lock();
+    frozen.retain(|db| db != path);
+    if freeze {
+        frozen.push(path.to_string());
+    }
+}
+
+impl FileInfo {
+    fn new(name: Option<String>, opts: OpenOpts) -> Self {
+        Self {
+            name,
+            opts,
+            lock: AtomicU8::new(LockLevel::Unlocked as u8),
+            locked_at: AtomicI64::new(0),
+            size: AtomicUsize::new(usize::MAX),
+            last_op: AtomicU8::new(0),
+            in_memory: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> LockLevel {
+        match self.lock.load(Ordering::Relaxed) {
+            0 => LockLevel::Unlocked,
+            1 => LockLevel::Shared,
+            2 => LockLevel::Reserved,
+            3 => LockLevel::Pending,
+            _ => LockLevel::Exclusive,
+        }
+    }
+
+    // p_vfs provides the clock used to time how long the lock is held
+    fn set_lock(&self, level: LockLevel, p_vfs: *mut ffi::sqlite3_vfs) {
+        if self.lock() != level {
+            let now = current_time_ms(p_vfs).unwrap_or(0);
+            self.locked_at.store(now, Ordering::Relaxed);
+            self.lock.store(level as u8, Ordering::Relaxed);
+        }
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.size.load(Ordering::Relaxed)).filter(|&size| size != usize::MAX)
+    }
+
+    fn record(&self, op: FileOp) {
+        self.last_op.store(op as u8, Ordering::Relaxed);
+    }
+
+    fn last_op(&self) -> Option<FileOp> {
+        match self.last_op.load(Ordering::Relaxed) {
+            1 => Some(FileOp::Read),
+            2 => Some(FileOp::Write),
+            3 => Some(FileOp::Truncate),
+            4 => Some(FileOp::Sync),
+            5 => Some(FileOp::FileSize),
+            6 => Some(FileOp::Lock),
+            7 => Some(FileOp::Unlock),
+            _ => None,
+        }
+    }
+
+    fn snapshot(&self, now: Option<i64>) -> OpenFileInfo {
+        let locked_at = self.locked_at.load(Ordering::Relaxed);
+        OpenFileInfo {
+            name: self.name.clone(),
+            kind: self.opts.kind(),
+            lock: self.lock(),
+            lock_held_ms: now
+                .filter(|_| locked_at != 0)
+                .map(|now| now.saturating_sub(locked_at).max(0)),
+            size: self.size(),
+            last_op: self.last_op(),
+        }
+    }
+}
+
+impl fmt::Debug for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileInfo")
+            .field("name", &self.name)
+            .field("opts", &self.opts)
+            .field("lock", &self.lock())
+            .field("size", &self.size())
+            .field("last_op", &self.last_op())
+            .finish()
+    }
+}
+
+// milliseconds since the julian epoch, according to the vfs
+fn current_time_ms(p_vfs: *mut ffi::sqlite3_vfs) -> Option<i64> {
+    let x_current_time_int64 = unsafe { p_vfs.as_ref() }?.xCurrentTimeInt64?;
+    let mut now = 0;
+    (unsafe { x_current_time_int64(p_vfs, &mut now) } == vars::SQLITE_OK).then_some(now)
+}
+
+/// An io method which the dispatch layer forwarded to a Vfs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    Read = 1,
+    Write,
+    Truncate,
+    Sync,
+    FileSize,
+    Lock,
+    Unlock,
+}
+
+/// A snapshot of a file open through a registered vfs, see [`open_files`].
+#[derive(Debug, Clone)]
+pub struct OpenFileInfo {
+    pub name: Option<String>,
+    pub kind: OpenKind,
+    pub lock: LockLevel,
+    /// How long the file has held its current lock level.
+    pub lock_held_ms: Option<i64>,
+    /// The size last observed by the dispatch layer.
+    pub size: Option<usize>,
+    pub last_op: Option<FileOp>,
 }
 
 struct AppData<Vfs> {
     base_vfs: *mut ffi::sqlite3_vfs,
-    vfs: Vfs,
+    vfs: Arc<Vfs>,
     io_methods: ffi::sqlite3_io_methods,
     sqlite_api: SqliteApi,
+    on_change: Option<ChangeCallback>,
+    sync_policy: SyncPolicy,
+    strict: bool,
+    handle_limits: HandleLimits,
+    database_files_pragma: bool,
+    log_last_error: bool,
+    // files currently open through this vfs, which must be empty to unregister
+    open_files: OpenFiles,
+    frozen: FrozenFiles,
+    // paths of the rollback journals kept in memory, see `JournalPolicy`
+    memory_journals: spin::Mutex<Vec<String>>,
 }
 
+/// Where the rollback journals of a database are stored, chosen per database
+/// by [`Vfs::journal_policy`]. WAL files are always opened through the Vfs,
+/// as other connections read them.
+///
+/// When a Vfs reports `SQLITE_IOCAP_BATCH_ATOMIC` for a database, `SQLite`
+/// already keeps its journal in memory, and only writes it through the Vfs
+/// if a batch atomic write fails.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JournalPolicy {
+    /// Journals are opened through the Vfs like any other file.
+    #[default]
+    Backend,
+    /// Journals are kept in memory by the dispatch layer and never reach the
+    /// Vfs. A database is only safe from a crash during a commit if its
+    /// journal survives the crash, so this is only honored for databases
+    /// whose handle reports [`VfsHandle::in_memory`]. For other databases a
+    /// warning is logged and journals are opened through the Vfs.
+    Memory,
+}
+
+/// A pragma `SQLite` does not recognize itself, passed to [`Vfs::pragma`].
+/// `SQLite` sends it to the file of the schema it names, such as `aux` in
+/// `pragma aux.cache_limit = 10`, or else to the main database, so the
+/// handle it is passed with tells which database it is for.
 #[derive(Debug)]
 pub struct Pragma<'a> {
     pub name: &'a str,
+    /// The dequoted value of `pragma name = value` or `pragma name(value)`,
+    /// or `None` if the pragma is queried.
     pub arg: Option<&'a str>,
 }
 
+impl Pragma<'_> {
+    /// Whether the pragma reads its value rather than setting it.
+    pub fn is_query(&self) -> bool {
+        self.arg.is_none()
+    }
+
+    /// The value as a boolean, spelled as for `SQLite`'s own boolean pragmas:
+    /// `on`, `yes`, `true` or a non-zero integer, and `off`, `no`, `false`
+    /// or zero. Returns `None` if the pragma is queried, and an error for
+    /// the user if the value is not a boolean.
+    pub fn as_bool(&self) -> Result<Option<bool>, PragmaErr> {
+        let Some(arg) = self.arg else {
+            return Ok(None);
+        };
+        match arg.to_ascii_lowercase().as_str() {
+            "on" | "yes" | "true" => Ok(Some(true)),
+            "off" | "no" | "false" => Ok(Some(false)),
+            _ => match arg.parse::<i64>() {
+                Ok(n) => Ok(Some(n != 0)),
+                Err(_) => Err(self.invalid_value()),
+            },
+        }
+    }
+
+    /// The value as a non-negative integer. Returns `None` if the pragma is
+    /// queried, and an error for the user if the value is not one.
+    pub fn as_u64(&self) -> Result<Option<u64>, PragmaErr> {
+        self.arg
+            .map(|arg| arg.parse().map_err(|_| self.invalid_value()))
+            .transpose()
+    }
+
+    fn invalid_value(&self) -> PragmaErr {
+        let msg = format!(
+            "invalid value for {}: {}",
+            self.name,
+            self.arg.unwrap_or_default()
+        );
+        PragmaErr::Fail(SQLITE_ERROR, Some(msg))
+    }
+}
+
+/// Why [`Vfs::pragma`] did not handle a pragma.
 #[derive(Debug)]
 pub enum PragmaErr {
+    /// The Vfs does not implement the pragma, so `SQLite` ignores it.
     NotFound,
+    /// The pragma failed with a result code, such as `SQLITE_ERROR`, and an
+    /// optional message which becomes the error message of the statement.
     Fail(SqliteErr, Option<String>),
 }
 
@@ -79,11 +428,206 @@ impl PragmaErr {
     }
 }
 
+/// A file control operation sent to [`Vfs::file_control`], decoded from the
+/// op code and argument `SQLite` passed. Pragmas are not file controls here,
+/// they are passed to [`Vfs::pragma`].
+#[derive(Debug)]
+pub enum FileControlOp<'a> {
+    /// `SQLITE_FCNTL_SIZE_HINT`: the file is about to grow to about this
+    /// many bytes, so space may be allocated ahead of time.
+    SizeHint(u64),
+    /// `SQLITE_FCNTL_CHUNK_SIZE`: the file should grow and shrink in
+    /// multiples of this many bytes.
+    ChunkSize(usize),
+    /// `SQLITE_FCNTL_BEGIN_ATOMIC_WRITE`: the writes up to the next commit or
+    /// rollback of the atomic write must be applied all at once or not at all.
+    BeginAtomicWrite,
+    /// `SQLITE_FCNTL_COMMIT_ATOMIC_WRITE`
+    CommitAtomicWrite,
+    /// `SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE`
+    RollbackAtomicWrite,
+    /// `SQLITE_FCNTL_PERSIST_WAL`: whether the WAL file is kept when the last
+    /// connection closes.
+    PersistWal(FileSetting<'a>),
+    /// `SQLITE_FCNTL_POWERSAFE_OVERWRITE`: whether the device has the
+    /// powersafe overwrite property.
+    PowersafeOverwrite(FileSetting<'a>),
+    /// `SQLITE_FCNTL_SYNC`: sent before a commit syncs the database, with the
+    /// name of the super-journal if the transaction spans databases.
+    Sync(Option<&'a CStr>),
+    /// `SQLITE_FCNTL_COMMIT_PHASETWO`: sent once a transaction has been
+    /// committed, before the locks are released.
+    CommitPhaseTwo,
+    /// Any other operation, with its op code and raw argument.
+    Raw(c_int, *mut c_void),
+}
+
+impl FileControlOp<'_> {
+    // `p_arg` must be the argument SQLite passed with `op`, valid for 'a
+    unsafe fn decode(op: c_int, p_arg: *mut c_void) -> Self {
+        match op {
+            vars::SQLITE_FCNTL_SIZE_HINT => {
+                if let Some(size) = unsafe { p_arg.cast::<i64>().as_ref() } {
+                    return Self::SizeHint((*size).max(0) as u64);
+                }
+            }
+            vars::SQLITE_FCNTL_CHUNK_SIZE => {
+                if let Some(size) = unsafe { p_arg.cast::<c_int>().as_ref() } {
+                    return Self::ChunkSize((*size).max(0) as usize);
+                }
+            }
+            vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => return Self::BeginAtomicWrite,
+            vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => return Self::CommitAtomicWrite,
+            vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => return Self::RollbackAtomicWrite,
+            vars::SQLITE_FCNTL_PERSIST_WAL => {
+                if let Some(arg) = unsafe { p_arg.cast::<c_int>().as_mut() } {
+                    return Self::PersistWal(FileSetting { arg });
+                }
+            }
+            vars::SQLITE_FCNTL_POWERSAFE_OVERWRITE => {
+                if let Some(arg) = unsafe { p_arg.cast::<c_int>().as_mut() } {
+                    return Self::PowersafeOverwrite(FileSetting { arg });
+                }
+            }
+            vars::SQLITE_FCNTL_SYNC => {
+                let super_journal = unsafe { p_arg.cast::<c_char>().as_ref() };
+                return Self::Sync(super_journal.map(|p| unsafe { CStr::from_ptr(p) }));
+            }
+            vars::SQLITE_FCNTL_COMMIT_PHASETWO => return Self::CommitPhaseTwo,
+            _ => {}
+        }
+        Self::Raw(op, p_arg)
+    }
+
+    // calls `f` with the op code and an argument in the form SQLite passes
+    // it, for forwarding the operation to another vfs
+    pub(crate) fn with_raw<R>(self, f: impl FnOnce(c_int, *mut c_void) -> R) -> R {
+        let op = self.op();
+        match self {
+            Self::SizeHint(size) => {
+                let mut size = i64::try_from(size).unwrap_or(i64::MAX);
+                f(op, (&raw mut size).cast())
+            }
+            Self::ChunkSize(size) => {
+                let mut size = c_int::try_from(size).unwrap_or(c_int::MAX);
+                f(op, (&raw mut size).cast())
+            }
+            Self::PersistWal(setting) | Self::PowersafeOverwrite(setting) => {
+                f(op, (setting.arg as *mut c_int).cast())
+            }
+            Self::Sync(super_journal) => f(
+                op,
+                super_journal.map_or(null_mut(), |p| p.as_ptr().cast_mut().cast()),
+            ),
+            Self::Raw(_, arg) => f(op, arg),
+            Self::BeginAtomicWrite
+            | Self::CommitAtomicWrite
+            | Self::RollbackAtomicWrite
+            | Self::CommitPhaseTwo => f(op, null_mut()),
+        }
+    }
+
+    /// The `SQLITE_FCNTL_*` op code of this operation.
+    pub fn op(&self) -> c_int {
+        match self {
+            Self::SizeHint(_) => vars::SQLITE_FCNTL_SIZE_HINT,
+            Self::ChunkSize(_) => vars::SQLITE_FCNTL_CHUNK_SIZE,
+            Self::BeginAtomicWrite => vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE,
+            Self::CommitAtomicWrite => vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE,
+            Self::RollbackAtomicWrite => vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE,
+            Self::PersistWal(_) => vars::SQLITE_FCNTL_PERSIST_WAL,
+            Self::PowersafeOverwrite(_) => vars::SQLITE_FCNTL_POWERSAFE_OVERWRITE,
+            Self::Sync(_) => vars::SQLITE_FCNTL_SYNC,
+            Self::CommitPhaseTwo => vars::SQLITE_FCNTL_COMMIT_PHASETWO,
+            Self::Raw(op, _) => *op,
+        }
+    }
+}
+
+/// A boolean setting which a file control either queries or changes. In both
+/// cases the Vfs reports the setting in effect afterwards with
+/// [`FileSetting::report`].
+#[derive(Debug)]
+pub struct FileSetting<'a> {
+    arg: &'a mut c_int,
+}
+
+impl FileSetting<'_> {
+    /// The value to change the setting to, or None if it is only queried.
+    pub fn requested(&self) -> Option<bool> {
+        (*self.arg >= 0).then_some(*self.arg != 0)
+    }
+
+    pub fn report(&mut self, current: bool) {
+        *self.arg = current.into();
+    }
+}
+
+/// Tracks the chunk size `SQLite` sets for a file with
+/// `SQLITE_FCNTL_CHUNK_SIZE`, and rounds file sizes up to it the way the unix
+/// vfs does, so that a file grows and shrinks in whole chunks. Keep one in
+/// each handle, pass it every file control, and round truncate sizes and
+/// size hints with [`Chunking::round`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    chunk_size: usize,
+}
+
+impl Chunking {
+    /// Records the chunk size if `op` sets it, returning whether it did.
+    pub fn file_control(&mut self, op: &FileControlOp<'_>) -> bool {
+        if let FileControlOp::ChunkSize(size) = *op {
+            self.chunk_size = size;
+            return true;
+        }
+        false
+    }
+
+    /// The configured chunk size, or 0 if none was set.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Rounds `size` up to a whole number of chunks.
+    pub fn round(&self, size: usize) -> usize {
+        if self.chunk_size == 0 {
+            return size;
+        }
+        size.div_ceil(self.chunk_size)
+            .saturating_mul(self.chunk_size)
+    }
+}
+
 fn fallible(mut cb: impl FnMut() -> Result<i32, SqliteErr>) -> i32 {
     cb().unwrap_or_else(|err| err)
 }
 
-unsafe fn lossy_cstr<'a>(p: *const c_char) -> VfsResult<Cow<'a, str>> {
+// reports a violated dispatch invariant, which aborts the process in strict
+// mode and otherwise returns `err` to SQLite
+fn violation<T: Vfs>(
+    appdata: &AppData<T>,
+    state: &FileState,
+    err: SqliteErr,
+    msg: fmt::Arguments<'_>,
+) -> SqliteErr {
+    if appdata.strict {
+        panic!("sqlite-plugin: {msg}; file state: {state:?}");
+    }
+    err
+}
+
+// mirrors the lock transitions SQLite's own vfs implementations assert on
+fn valid_lock_transition(current: LockLevel, level: LockLevel) -> bool {
+    match level {
+        _ if level <= current => true,
+        LockLevel::Unlocked | LockLevel::Pending => false,
+        LockLevel::Shared => current == LockLevel::Unlocked,
+        LockLevel::Reserved => current == LockLevel::Shared,
+        LockLevel::Exclusive => current >= LockLevel::Shared,
+    }
+}
+
+unsafe fn lossy_cstr<'a>(p: *const c_char) -> Result<Cow<'a, str>, SqliteErr> {
     unsafe {
         p.as_ref()
             .map(|p| CStr::from_ptr(p).to_string_lossy())
@@ -95,7 +639,7 @@ unsafe fn lossy_cstr<'a>(p: *const c_char) -> VfsResult<Cow<'a, str>> {
 // returns a pointer to the sqlite3 allocated string
 // # Safety
 // the returned pointer must be freed using sqlite3_free
-fn sqlite3_mprintf(api: &SqliteApi, s: &str) -> VfsResult<*mut c_char> {
+fn sqlite3_mprintf(api: &SqliteApi, s: &str) -> Result<*mut c_char, SqliteErr> {
     let s = CString::new(s).map_err(|_| vars::SQLITE_INTERNAL)?;
     let p = unsafe { (api.mprintf)(s.as_ptr()) };
     if p.is_null() {
@@ -108,7 +652,7 @@ fn sqlite3_mprintf(api: &SqliteApi, s: &str) -> VfsResult<*mut c_char> {
 macro_rules! unwrap_appdata {
     ($p_vfs:expr, $t_vfs:ty) => {
         unsafe {
-            let out: VfsResult<&AppData<$t_vfs>> = (*$p_vfs)
+            let out: Result<&AppData<$t_vfs>, SqliteErr> = (*$p_vfs)
                 .pAppData
                 .cast::<AppData<$t_vfs>>()
                 .as_ref()
@@ -120,15 +664,16 @@ macro_rules! unwrap_appdata {
 
 macro_rules! unwrap_vfs {
     ($p_vfs:expr, $t_vfs:ty) => {{
-        let out: VfsResult<&$t_vfs> = unwrap_appdata!($p_vfs, $t_vfs).map(|app_data| &app_data.vfs);
+        let out: Result<&$t_vfs, SqliteErr> =
+            unwrap_appdata!($p_vfs, $t_vfs).map(|app_data| &*app_data.vfs);
         out
     }};
 }
 
 macro_rules! unwrap_base_vfs {
     ($p_vfs:expr, $t_vfs:ty) => {{
-        let out: VfsResult<&mut ffi::sqlite3_vfs> =
-            unwrap_appdata!($p_vfs, $t_vfs).and_then(|app_data| {
+        let out: Result<&mut ffi::sqlite3_vfs, SqliteErr> = unwrap_appdata!($p_vfs, $t_vfs)
+            .and_then(|app_data| {
                 unsafe { app_data.base_vfs.as_mut() }.ok_or(vars::SQLITE_INTERNAL)
             });
         out
@@ -138,20 +683,116 @@ macro_rules! unwrap_base_vfs {
 macro_rules! unwrap_file {
     ($p_file:expr, $t_vfs:ty) => {
         unsafe {
-            let out: VfsResult<&mut FileWrapper<<$t_vfs>::Handle>> = $p_file
+            let out: Result<&mut FileWrapper<<$t_vfs>::Handle>, SqliteErr> = $p_file
                 .cast::<FileWrapper<<$t_vfs>::Handle>>()
                 .as_mut()
-                .ok_or(vars::SQLITE_INTERNAL);
+                .ok_or(vars::SQLITE_INTERNAL)
+                .and_then(check_open::<$t_vfs>);
             out
         }
     };
 }
 
+// x_close clears the io methods of a file, like SQLite does after calling
+// xClose, so a closed file is recognized if its memory is used again
+fn check_open<T: Vfs>(
+    file: &mut FileWrapper<T::Handle>,
+) -> Result<&mut FileWrapper<T::Handle>, SqliteErr> {
+    if !file.file.pMethods.is_null() {
+        return Ok(file);
+    }
+    // the handle and state of the file are gone, so only the vfs is reported
+    if unwrap_appdata!(file.vfs, T).is_ok_and(|appdata| appdata.strict) {
+        panic!("sqlite-plugin: io on a closed file");
+    }
+    Err(vars::SQLITE_MISUSE)
+}
+
 pub trait VfsHandle: Send {
     fn readonly(&self) -> bool;
     fn in_memory(&self) -> bool;
 }
 
+type UriParameterFn = unsafe extern "C" fn(*const c_char, *const c_char) -> *const c_char;
+type UriKeyFn = unsafe extern "C" fn(*const c_char, c_int) -> *const c_char;
+
+/// The query parameters of the URI a file was opened with, such as
+/// `cache_key` and `tier` in `file:main.db?cache_key=abc&tier=hot`. Journal
+/// and WAL files are given the parameters of their database. Parameters are
+/// only available to connections which open URI filenames, see
+/// [`Capabilities::uri`].
+#[derive(Clone, Copy)]
+pub struct UriParams<'a> {
+    filename: ffi::sqlite3_filename,
+    uri_parameter: Option<UriParameterFn>,
+    uri_key: Option<UriKeyFn>,
+    _filename: PhantomData<&'a CStr>,
+}
+
+impl<'a> UriParams<'a> {
+    /// Parameters for a file opened without any, for example when calling
+    /// [`Vfs::open`] directly.
+    pub fn empty() -> Self {
+        Self {
+            filename: core::ptr::null(),
+            uri_parameter: None,
+            uri_key: None,
+            _filename: PhantomData,
+        }
+    }
+
+    // `filename` must be a filename SQLite passed to xOpen, and outlive 'a
+    unsafe fn new(api: &SqliteApi, filename: ffi::sqlite3_filename) -> Self {
+        if filename.is_null() {
+            return Self::empty();
+        }
+        Self {
+            filename,
+            uri_parameter: Some(api.uri_parameter),
+            uri_key: Some(api.uri_key),
+            _filename: PhantomData,
+        }
+    }
+
+    // the filename SQLite passed to xOpen, or null
+    pub(crate) fn filename(&self) -> ffi::sqlite3_filename {
+        self.filename
+    }
+
+    /// Returns the value of the parameter `name`, or None if it was not given
+    /// or is not valid UTF-8. A parameter given without a value, as in
+    /// `file:main.db?nolock`, has an empty value.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        let uri_parameter = self.uri_parameter?;
+        let name = CString::new(name).ok()?;
+        let value = unsafe { uri_parameter(self.filename, name.as_ptr()) };
+        if value.is_null() {
+            return None;
+        }
+        unsafe { CStr::from_ptr(value) }.to_str().ok()
+    }
+
+    /// Iterates over the parameters in the order they were given. Parameters
+    /// with names which are not valid UTF-8 are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        let keys = (0..).map_while(move |i| {
+            let uri_key = self.uri_key?;
+            let key = unsafe { uri_key(self.filename, i) };
+            (!key.is_null()).then(|| unsafe { CStr::from_ptr(key) })
+        });
+        keys.filter_map(|key| {
+            let key = key.to_str().ok()?;
+            Some((key, self.get(key)?))
+        })
+    }
+}
+
+impl fmt::Debug for UriParams<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
 #[allow(unused_variables)]
 pub trait Vfs: Send + Sync {
     type Handle: VfsHandle;
@@ -161,16 +802,82 @@ pub trait Vfs: Send + Sync {
     /// register_{static,dynamic} call.
     fn register_logger(&self, logger: SqliteLogger);
 
-    /// construct a canonical version of the given path
+    /// Checks that the backend is reachable and correctly configured. This is
+    /// called during registration, after `register_logger`, and an error
+    /// fails the registration with that code rather than failing the first
+    /// query.
+    fn probe(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Describes the error behind the io error `SQLite` is currently handling,
+    /// as a system error number and a message. This is called after an
+    /// operation fails with an io error or `SQLITE_CANTOPEN`. The number is
+    /// reported by `sqlite3_system_errno`, and the message is written to the
+    /// `SQLite` log at error level if the vfs is registered with
+    /// `RegisterOpts::log_last_error`. `SQLite` has no way for a vfs to
+    /// replace the message returned by `sqlite3_errmsg`.
+    ///
+    /// The vfs is shared by every connection, so an implementation should
+    /// keep the last error per thread.
+    fn last_error(&self) -> Option<(i32, String)> {
+        None
+    }
+
+    /// Construct a canonical version of the given path. This implements
+    /// `xFullPathname`: the returned name is the one `SQLite` stores, passes
+    /// to `open`, and derives journal and WAL names from. The default passes
+    /// the path through unchanged, so virtual paths such as
+    /// `tenant-a/main.db` are kept as is.
     fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
         Ok(path)
     }
 
     // file system operations
-    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle>;
+    /// Opens the file at `path`. Files `SQLite` opens without a name are
+    /// passed the name generated by [`Vfs::temp_filename`], so `path` is only
+    /// None when that returns `VfsError::NotFound`, in which case a nameless
+    /// temporary file should be opened. `params` holds the query parameters
+    /// of the URI it was opened with.
+    fn open(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: UriParams<'_>,
+    ) -> VfsResult<Self::Handle>;
     fn delete(&self, path: &str) -> VfsResult<()>;
     fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool>;
 
+    /// Generates a name for a temporary file, which is passed to
+    /// [`Vfs::open`] in place of `None` for files `SQLite` opens without a
+    /// name, such as statement journals and the temporary databases of
+    /// `vacuum`, and returned for `SQLITE_FCNTL_TEMPFILENAME`. Names must be
+    /// unique among the files of the Vfs; the default names are only unique
+    /// within the process. Return `VfsError::NotFound` to keep such files
+    /// nameless.
+    fn temp_filename(&self) -> VfsResult<String> {
+        Ok(next_temp_filename())
+    }
+
+    /// Chooses where the rollback journals of the database at `db_path` are
+    /// stored, see [`JournalPolicy`].
+    fn journal_policy(&self, db_path: &str) -> JournalPolicy {
+        JournalPolicy::Backend
+    }
+
+    /// Called before a main database which does not exist yet is opened for
+    /// the first time with `SQLITE_OPEN_CREATE`, so that the Vfs can create it
+    /// from a template image or pre-populated pages before `SQLite` sees an
+    /// empty file. If the file still does not exist afterwards it is created
+    /// empty by `open` as usual. An error fails the open.
+    ///
+    /// Connections which race to open the same new database may each call
+    /// this, so creating the file must be atomic, for example by writing the
+    /// image elsewhere and renaming it into place.
+    fn bootstrap(&self, path: &str) -> VfsResult<()> {
+        Ok(())
+    }
+
     // file operations
     fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize>;
     fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()>;
@@ -185,12 +892,26 @@ pub trait Vfs: Send + Sync {
         Ok(())
     }
 
-    fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+    /// Whether any connection, in this process or another, holds a reserved
+    /// or greater lock on the file. `SQLite` asks this when it finds a
+    /// journal next to a database: a journal whose database is not reserved
+    /// was left behind by a crashed writer and is rolled back. A Vfs which
+    /// implements locking must report its lock state here, or a journal in
+    /// use by a live writer may be rolled back.
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        Ok(false)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
         Ok(())
     }
 
     fn close(&self, handle: Self::Handle) -> VfsResult<()>;
 
+    /// Answers a pragma run against a database open through the vfs, or
+    /// returns `PragmaErr::NotFound` to leave it to `SQLite`. Pragmas whose
+    /// names start with `sqlite_plugin_` are answered by this crate and are
+    /// never passed on.
     fn pragma(
         &self,
         handle: &mut Self::Handle,
@@ -200,31 +921,153 @@ pub trait Vfs: Send + Sync {
     }
 
     // system queries
-    fn sector_size(&self) -> i32 {
+    /// The sector size of the device `handle` is stored on, which is the
+    /// smallest unit that can be written without disturbing its neighbours.
+    /// `SQLite` pads journal headers to this size.
+    fn sector_size(&self, handle: &mut Self::Handle) -> usize {
         DEFAULT_SECTOR_SIZE
     }
 
-    fn device_characteristics(&self) -> i32 {
+    /// The `SQLITE_IOCAP_*` flags describing how writes to `handle` behave,
+    /// which may differ between files, such as reporting
+    /// `SQLITE_IOCAP_BATCH_ATOMIC` only for main database files.
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> i32 {
         DEFAULT_DEVICE_CHARACTERISTICS
     }
 
-    fn file_control(
+    /// Handles a file control operation. Return `VfsError::NotFound` for
+    /// operations the Vfs does not implement, which `SQLite` treats as a no-op.
+    fn file_control(&self, handle: &mut Self::Handle, op: FileControlOp<'_>) -> VfsResult<()> {
+        Err(VfsError::NotFound)
+    }
+
+    /// The name reported for `SQLITE_FCNTL_VFSNAME` and `pragma vfsname`,
+    /// given the name the vfs was registered under. A Vfs layered over
+    /// another reports both, outermost first and separated by a `/`, such as
+    /// `"cache/mem"`. Return `None` to receive `SQLITE_FCNTL_VFSNAME` in
+    /// [`Vfs::file_control`] and `pragma vfsname` in [`Vfs::pragma`].
+    fn vfs_name(&self, registered: &str) -> Option<String> {
+        Some(registered.into())
+    }
+
+    // system services
+    // these return None to use the default vfs, so that a simulated Vfs can
+    // control the time, sleeps and randomness SQLite sees
+
+    /// Sleeps for at least `duration`, returning how long was slept.
+    /// `SQLite` sleeps between retries while waiting on a busy lock.
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        None
+    }
+
+    /// The current time in milliseconds since the unix epoch, as seen by
+    /// functions such as `datetime('now')`.
+    fn current_time(&self) -> Option<i64> {
+        None
+    }
+
+    /// Fills `buf` with random bytes, returning how many were written.
+    /// `SQLite` seeds its random number generator only once per process, from
+    /// the default vfs, so this only takes effect for a vfs registered with
+    /// `make_default` before the first use of randomness.
+    fn randomness(&self, buf: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    // shared memory
+
+    /// Whether this Vfs implements the `shm_*` methods, which provide the
+    /// wal-index that WAL mode keeps in shared memory. This is checked once
+    /// at registration. Without shared memory, `SQLite` only allows WAL mode
+    /// in exclusive locking mode.
+    fn supports_shm(&self) -> bool {
+        false
+    }
+
+    /// Returns a pointer to wal-index region `region`, which is `size` bytes
+    /// long. Missing regions are created zeroed if `extend` is true, and
+    /// otherwise `None` is returned. The memory must stay valid until
+    /// `shm_unmap` and be shared by every handle open on the same database.
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        Err(VfsError::Io)
+    }
+
+    /// Acquires or releases `count` of the wal-index locks starting at
+    /// `offset`. Returns `SQLITE_BUSY` if a lock is held by another handle.
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        Err(VfsError::Io)
+    }
+
+    /// Ensures that memory writes to the wal-index made before the barrier
+    /// are visible to every other handle.
+    fn shm_barrier(&self, handle: &mut Self::Handle) {
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    /// Releases this handle's mapping of the wal-index, deleting the
+    /// wal-index itself if `delete` is true.
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    // memory mapped io
+
+    /// Whether this Vfs implements `fetch`. This is checked once at
+    /// registration and is reported by [`Capabilities::mmap`]. `SQLite` only
+    /// fetches pages once `pragma mmap_size` is set.
+    fn supports_mmap(&self) -> bool {
+        false
+    }
+
+    /// Returns a pointer to `len` bytes of the file starting at `offset`,
+    /// which `SQLite` reads pages from without copying them. Returning `None`
+    /// makes `SQLite` fall back to `read`. The memory must stay valid and
+    /// unchanged until it is released by `unfetch`.
+    fn fetch(
         &self,
         handle: &mut Self::Handle,
-        op: c_int,
-        p_arg: *mut c_void,
+        offset: usize,
+        len: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        Ok(None)
+    }
+
+    /// Releases the pointer `ptr` returned by `fetch` for `offset`. `ptr` is
+    /// `None` when `SQLite` holds no pointers into the file at all, which it
+    /// ensures before the file changes size, so any mappings may be dropped.
+    fn unfetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        ptr: Option<NonNull<u8>>,
     ) -> VfsResult<()> {
-        Err(vars::SQLITE_NOTFOUND)
+        Ok(())
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct SqliteApi {
     register: unsafe extern "C" fn(arg1: *mut ffi::sqlite3_vfs, arg2: c_int) -> c_int,
+    unregister: unsafe extern "C" fn(arg1: *mut ffi::sqlite3_vfs) -> c_int,
     find: unsafe extern "C" fn(arg1: *const c_char) -> *mut ffi::sqlite3_vfs,
     mprintf: unsafe extern "C" fn(arg1: *const c_char, ...) -> *mut c_char,
     log: unsafe extern "C" fn(arg1: c_int, arg2: *const c_char, ...),
     libversion_number: unsafe extern "C" fn() -> c_int,
+    compileoption_used: unsafe extern "C" fn(arg1: *const c_char) -> c_int,
+    uri_parameter: UriParameterFn,
+    uri_key: UriKeyFn,
 }
 
 impl SqliteApi {
@@ -232,10 +1075,14 @@ impl SqliteApi {
     fn new_static() -> Self {
         Self {
             register: ffi::sqlite3_vfs_register,
+            unregister: ffi::sqlite3_vfs_unregister,
             find: ffi::sqlite3_vfs_find,
             mprintf: ffi::sqlite3_mprintf,
             log: ffi::sqlite3_log,
             libversion_number: ffi::sqlite3_libversion_number,
+            compileoption_used: ffi::sqlite3_compileoption_used,
+            uri_parameter: ffi::sqlite3_uri_parameter,
+            uri_key: ffi::sqlite3_uri_key,
         }
     }
 
@@ -243,90 +1090,861 @@ impl SqliteApi {
     fn new_dynamic(api: &ffi::sqlite3_api_routines) -> VfsResult<Self> {
         Ok(Self {
             register: api.vfs_register.ok_or(vars::SQLITE_INTERNAL)?,
+            unregister: api.vfs_unregister.ok_or(vars::SQLITE_INTERNAL)?,
             find: api.vfs_find.ok_or(vars::SQLITE_INTERNAL)?,
             mprintf: api.mprintf.ok_or(vars::SQLITE_INTERNAL)?,
             log: api.log.ok_or(vars::SQLITE_INTERNAL)?,
             libversion_number: api.libversion_number.ok_or(vars::SQLITE_INTERNAL)?,
+            compileoption_used: api.compileoption_used.ok_or(vars::SQLITE_INTERNAL)?,
+            uri_parameter: api.uri_parameter.ok_or(vars::SQLITE_INTERNAL)?,
+            uri_key: api.uri_key.ok_or(vars::SQLITE_INTERNAL)?,
         })
     }
 }
 
-pub struct RegisterOpts {
-    pub make_default: bool,
+/// How the dispatch layer forwards sync requests for a kind of file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Forward every sync request to the Vfs as `SQLite` made it.
+    #[default]
+    Always,
+    /// Forward every sync request as a full sync, as if `pragma fullfsync`
+    /// were set for this kind of file.
+    Full,
+    /// Forward every sync request as a normal data-only sync marked with
+    /// [`SyncFlags::is_barrier`]. It only needs to order the writes before it
+    /// against the writes after it, so a Vfs may serve it with a cheaper
+    /// barrier than a flush to stable storage.
+    Barrier,
+    /// Report success to `SQLite` without calling the Vfs.
+    Never,
 }
 
-#[cfg(feature = "static")]
-pub fn register_static<T: Vfs>(name: CString, vfs: T, opts: RegisterOpts) -> VfsResult<()> {
-    register_inner(SqliteApi::new_static(), name, vfs, opts)
+impl SyncMode {
+    // the flags a sync request made with `flags` is forwarded with, or None
+    // if it is skipped
+    fn apply(self, flags: SyncFlags) -> Option<SyncFlags> {
+        match self {
+            Self::Always => Some(flags),
+            Self::Full => Some(SyncFlags::new(
+                (flags.flags() & !0x0f) | vars::SQLITE_SYNC_FULL,
+            )),
+            Self::Barrier => Some(SyncFlags::new(
+                vars::SQLITE_SYNC_NORMAL | vars::SQLITE_SYNC_DATAONLY | SyncFlags::BARRIER,
+            )),
+            Self::Never => None,
+        }
+    }
 }
 
-/// Register a vfs with `SQLite` using the dynamic API. This API is available when
-/// `SQLite` is initializing extensions.
-/// # Safety
-/// `p_api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct
-#[cfg(feature = "dynamic")]
-pub unsafe fn register_dynamic<T: Vfs>(
-    p_api: *mut ffi::sqlite3_api_routines,
-    name: CString,
-    vfs: T,
-    opts: RegisterOpts,
-) -> VfsResult<()> {
-    let api = unsafe { p_api.as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
-    let sqlite_api = SqliteApi::new_dynamic(api)?;
-    register_inner(sqlite_api, name, vfs, opts)
+/// Selects a [`SyncMode`] for each kind of file a vfs serves. Weakening syncs
+/// trades durability for latency; for example statement journals never
+/// need to survive a crash, while skipping syncs on the main database or its
+/// journal may lose committed transactions.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncPolicy {
+    pub main_db: SyncMode,
+    pub main_journal: SyncMode,
+    pub wal: SyncMode,
+    pub super_journal: SyncMode,
+    pub sub_journal: SyncMode,
+    /// Applies to temporary databases and journals, and transient databases.
+    pub temp: SyncMode,
 }
 
-fn register_inner<T: Vfs>(
-    sqlite_api: SqliteApi,
-    name: CString,
-    vfs: T,
-    opts: RegisterOpts,
-) -> VfsResult<()> {
-    let version = unsafe { (sqlite_api.libversion_number)() };
-    if version < MIN_SQLITE_VERSION_NUMBER {
-        panic!(
-            "sqlite3 must be at least version {}, found version {}",
-            MIN_SQLITE_VERSION_NUMBER, version
-        );
+impl SyncPolicy {
+    pub fn mode(&self, kind: &OpenKind) -> SyncMode {
+        match kind {
+            OpenKind::MainDb | OpenKind::Unknown => self.main_db,
+            OpenKind::MainJournal => self.main_journal,
+            OpenKind::Wal => self.wal,
+            OpenKind::SuperJournal => self.super_journal,
+            OpenKind::SubJournal => self.sub_journal,
+            OpenKind::TempDb | OpenKind::TempJournal | OpenKind::TransientDb => self.temp,
+        }
     }
+}
 
-    let io_methods = ffi::sqlite3_io_methods {
-        iVersion: 3,
-        xClose: Some(x_close::<T>),
-        xRead: Some(x_read::<T>),
-        xWrite: Some(x_write::<T>),
-        xTruncate: Some(x_truncate::<T>),
-        xSync: Some(x_sync::<T>),
-        xFileSize: Some(x_file_size::<T>),
-        xLock: Some(x_lock::<T>),
+/// What happens when opening a file would exceed a [`HandleLimits`] cap.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Fail the open with `SQLITE_BUSY`.
+    #[default]
+    Fail,
+    /// Wait up to `timeout_ms` for another handle to close, then fail the
+    /// open with `SQLITE_BUSY`. Waiting sleeps the opening thread with
+    /// [`Vfs::sleep`].
+    Wait { timeout_ms: u32 },
+}
+
+/// Caps the number of handles open at once through a vfs, so that a leaking
+/// connection pool cannot exhaust the connections or file descriptors owned by
+/// the Vfs. Each connection opens one handle for its database, plus one for
+/// each journal or WAL file while it uses them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HandleLimits {
+    /// The most handles open at once through the vfs.
+    pub max_handles: Option<usize>,
+    /// The most handles open at once for any one path.
+    pub max_handles_per_file: Option<usize>,
+    pub policy: LimitPolicy,
+}
+
+impl HandleLimits {
+    fn admits(&self, files: &[Arc<FileInfo>], name: Option<&str>) -> bool {
+        if self.max_handles.is_some_and(|max| files.len() >= max) {
+            return false;
+        }
+        let Some(max) = self.max_handles_per_file else {
+            return true;
+        };
+        name.is_none_or(|name| {
+            let count = files
+                .iter()
+                .filter(|info| info.name.as_deref() == Some(name))
+                .count();
+            count < max
+        })
+    }
+}
+
+// how long to sleep between attempts to acquire a handle under
+// `LimitPolicy::Wait`
+const HANDLE_WAIT_INTERVAL_MS: u32 = 10;
+
+// adds `info` to the open files once `limits` admit it
+fn reserve_handle(appdata: &AppData<impl Vfs>, info: &Arc<FileInfo>) -> Result<(), SqliteErr> {
+    let limits = &appdata.handle_limits;
+    let mut waited_ms = 0;
+    loop {
+        {
+            let mut files = appdata.open_files.lock();
+            if limits.admits(&files, info.name.as_deref()) {
+                files.push(info.clone());
+                return Ok(());
+            }
+        }
+        let LimitPolicy::Wait { timeout_ms } = limits.policy else {
+            return Err(vars::SQLITE_BUSY);
+        };
+        if waited_ms >= timeout_ms {
+            return Err(vars::SQLITE_BUSY);
+        }
+        let interval = HANDLE_WAIT_INTERVAL_MS.min(timeout_ms - waited_ms);
+        sleep(appdata, Duration::from_millis(interval.into()));
+        waited_ms += interval;
+    }
+}
+
+/// The generation of `sqlite3_io_methods` installed for the files of a vfs.
+/// Older generations leave out the methods a host `SQLite` may not expect.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoVersion {
+    /// Only the core file methods, so WAL mode needs exclusive locking mode.
+    V1,
+    /// Adds the shared memory methods, see [`Vfs::supports_shm`].
+    V2,
+    /// Adds the memory mapping methods, see [`Vfs::supports_mmap`].
+    #[default]
+    V3,
+}
+
+pub struct RegisterOpts {
+    pub make_default: bool,
+
+    /// Controls which sync requests are forwarded to the Vfs.
+    pub sync_policy: SyncPolicy,
+
+    /// Abort the process with a description of the file's state when the
+    /// dispatch layer detects a violated invariant, such as an invalid lock
+    /// transition, a negative offset, a short write or io on a closed file,
+    /// rather than returning an error code to `SQLite`. This also checks
+    /// that the Vfs does what its device characteristics promise: a file
+    /// reporting `SQLITE_IOCAP_BATCH_ATOMIC` must handle the atomic write
+    /// file controls.
+    pub strict: bool,
+
+    /// Called whenever a main database file served by this vfs drops its
+    /// exclusive lock after being written to, which is when a write
+    /// transaction commits in rollback journal mode. The callback receives the
+    /// path of the database and the file change counter from its header.
+    /// Databases in WAL mode or exclusive locking mode are not reported.
+    pub on_change: Option<ChangeCallback>,
+
+    /// Caps the number of handles open at once, see [`HandleLimits`].
+    pub handle_limits: HandleLimits,
+
+    /// Lets connections opened read-only read WAL databases through a Vfs
+    /// which does not support shared memory, such as followers reading from
+    /// a shared backend. `SQLite` then rebuilds the wal-index in heap memory
+    /// from the WAL file at the start of each read transaction, so the WAL
+    /// must only be appended to while it is being read, never restarted.
+    /// Writers still need exclusive locking mode for WAL databases.
+    /// Ignored if [`Vfs::supports_shm`] is true.
+    pub readonly_wal: bool,
+
+    /// The longest path the vfs accepts, reported to `SQLite` as
+    /// `mxPathname`. Longer paths fail to open with `SQLITE_CANTOPEN`.
+    /// Defaults to 512 bytes.
+    pub max_pathname: usize,
+
+    /// Which methods files opened through the vfs provide, see [`IoVersion`].
+    pub io_version: IoVersion,
+
+    /// Answers `pragma sqlite_plugin_database_files`, which lists the files
+    /// of the current database open through the vfs, with the kind, lock
+    /// level and last known size of each. Off by default, as it reveals the
+    /// state of other connections to the database.
+    pub database_files_pragma: bool,
+
+    /// Writes the message of each [`Vfs::last_error`] to the `SQLite` log at
+    /// error level. `SQLite` asks for the last error after every failed io
+    /// operation, so this is off by default.
+    pub log_last_error: bool,
+}
+
+impl Default for RegisterOpts {
+    fn default() -> Self {
+        Self {
+            make_default: false,
+            sync_policy: SyncPolicy::default(),
+            strict: false,
+            on_change: None,
+            handle_limits: HandleLimits::default(),
+            readonly_wal: false,
+            max_pathname: DEFAULT_MAX_PATH_LEN as usize,
+            io_version: IoVersion::default(),
+            database_files_pragma: false,
+            log_last_error: false,
+        }
+    }
+}
+
+/// The features of the host `SQLite` which a registered vfs can make use of,
+/// probed at registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// WAL mode with shared memory is available, so multiple connections can
+    /// read a WAL database concurrently.
+    pub wal: bool,
+    /// `SQLite` was built with `SQLITE_ENABLE_BATCH_ATOMIC_WRITE`, so
+    /// transactions on files for which the Vfs reports
+    /// `SQLITE_IOCAP_BATCH_ATOMIC` are committed with batch atomic writes
+    /// rather than a rollback journal.
+    pub batch_atomic: bool,
+    /// Memory mapped reads are available via `pragma mmap_size`.
+    pub mmap: bool,
+    /// `SQLite` interprets filenames as URIs by default (`SQLITE_USE_URI`).
+    /// URIs can still be enabled per connection with `SQLITE_OPEN_URI`.
+    pub uri: bool,
+}
+
+impl Capabilities {
+    fn probe<T: Vfs>(api: &SqliteApi, vfs: &T, io_version: IoVersion) -> Self {
+        let used = |opt: &CStr| unsafe { (api.compileoption_used)(opt.as_ptr()) } != 0;
+        Self {
+            wal: io_version >= IoVersion::V2 && vfs.supports_shm() && !used(c"OMIT_WAL"),
+            batch_atomic: used(c"ENABLE_BATCH_ATOMIC_WRITE"),
+            mmap: io_version >= IoVersion::V3 && vfs.supports_mmap() && !used(c"MAX_MMAP_SIZE=0"),
+            uri: used(c"USE_URI"),
+        }
+    }
+}
+
+struct Registered {
+    name: CString,
+    vfs: Arc<dyn Any + Send + Sync>,
+    capabilities: Capabilities,
+    open_files: OpenFiles,
+    frozen: FrozenFiles,
+    p_vfs: *mut ffi::sqlite3_vfs,
+    release: unsafe fn(*mut ffi::sqlite3_vfs) -> VfsResult<()>,
+}
+
+// p_vfs is only dereferenced while the registry is locked
+unsafe impl Send for Registered {}
+
+// every vfs registered by this crate, in registration order
+static REGISTRY: spin::Mutex<Vec<Registered>> = spin::Mutex::new(Vec::new());
+
+fn find_registered<R>(name: &str, f: impl FnOnce(&Registered) -> R) -> Option<R> {
+    let registry = REGISTRY.lock();
+    registry
+        .iter()
+        .rev()
+        .find(|registered| registered.name.as_bytes() == name.as_bytes())
+        .map(f)
+}
+
+/// Returns the Vfs most recently registered under `name`, if it was
+/// registered by this crate with type `T`.
+pub fn lookup<T: Vfs + 'static>(name: &str) -> Option<Arc<T>> {
+    find_registered(name, |registered| registered.vfs.clone().downcast().ok()).flatten()
+}
+
+// whether a vfs is registered under `name` by this crate
+pub(crate) fn is_registered(name: &str) -> bool {
+    find_registered(name, |_| ()).is_some()
+}
+
+// the name `vfs` was most recently registered under, if it is the instance
+// this crate registered rather than a copy of it
+#[cfg(feature = "static")]
+pub(crate) fn registered_name<T: Vfs + 'static>(vfs: &T) -> Option<String> {
+    let registry = REGISTRY.lock();
+    registry
+        .iter()
+        .rev()
+        .find(|registered| {
+            (registered.vfs.downcast_ref::<T>()).is_some_and(|other| core::ptr::eq(other, vfs))
+        })
+        .map(|registered| registered.name.to_string_lossy().into_owned())
+}
+
+/// A vfs registered with `SQLite`, by this crate or otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsInfo {
+    pub name: String,
+    /// The longest path the vfs accepts, `mxPathname`.
+    pub max_pathname: usize,
+    /// The version of the `sqlite3_vfs` struct, `iVersion`.
+    pub version: i32,
+    /// Whether connections opened without naming a vfs use this one.
+    pub is_default: bool,
+}
+
+impl VfsInfo {
+    // p_vfs must point to a registered vfs
+    #[cfg(feature = "static")]
+    unsafe fn new(p_vfs: &ffi::sqlite3_vfs, is_default: bool) -> Self {
+        let name = unsafe { lossy_cstr(p_vfs.zName) }.unwrap_or_default();
+        Self {
+            name: name.into_owned(),
+            max_pathname: p_vfs.mxPathname.try_into().unwrap_or(0),
+            version: p_vfs.iVersion,
+            is_default,
+        }
+    }
+}
+
+/// Returns the vfs registered with `SQLite` under `name`, or the default vfs
+/// if `name` is `None`.
+#[cfg(feature = "static")]
+pub fn find(name: Option<&str>) -> Option<VfsInfo> {
+    let name = name.map(CString::new).transpose().ok()?;
+    let p_name = name.as_deref().map_or(core::ptr::null(), CStr::as_ptr);
+    let p_vfs = unsafe { ffi::sqlite3_vfs_find(p_name).as_ref() }?;
+    let p_default = unsafe { ffi::sqlite3_vfs_find(core::ptr::null()) };
+    Some(unsafe { VfsInfo::new(p_vfs, core::ptr::eq(p_vfs, p_default)) })
+}
+
+/// Returns every vfs registered with `SQLite`, starting with the default.
+#[cfg(feature = "static")]
+pub fn list() -> Vec<VfsInfo> {
+    let mut out = Vec::new();
+    // the default vfs is the head of the list
+    let mut p_vfs = unsafe { ffi::sqlite3_vfs_find(core::ptr::null()) };
+    // SQLite guards the list with the main mutex, which sqlite3_vfs_find
+    // also takes and is not recursive
+    let mutex = unsafe { ffi::sqlite3_mutex_alloc(vars::SQLITE_MUTEX_STATIC_MAIN as c_int) };
+    unsafe { ffi::sqlite3_mutex_enter(mutex) };
+    while let Some(vfs) = unsafe { p_vfs.as_ref() } {
+        out.push(unsafe { VfsInfo::new(vfs, out.is_empty()) });
+        p_vfs = vfs.pNext;
+    }
+    unsafe { ffi::sqlite3_mutex_leave(mutex) };
+    out
+}
+
+/// Returns the capabilities of the vfs most recently registered by this crate
+/// under `name`.
+pub fn capabilities(name: &str) -> Option<Capabilities> {
+    find_registered(name, |registered| registered.capabilities)
+}
+
+/// Returns every file currently open through the vfs most recently registered
+/// by this crate under `name`, for diagnosing lock contention and leaked
+/// files.
+pub fn open_files(name: &str) -> Option<Vec<OpenFileInfo>> {
+    find_registered(name, |registered| {
+        let now = current_time_ms(registered.p_vfs);
+        let files = registered.open_files.lock();
+        files.iter().map(|info| info.snapshot(now)).collect()
+    })
+}
+
+/// Makes the database at `path` read-only, or writable again, for every
+/// connection using the vfs most recently registered by this crate under
+/// `name`, however they opened it. While it is frozen, writes to the database
+/// and its journals and new write transactions fail with `SQLITE_READONLY`.
+/// `path` is matched against the full path `SQLite` opens the database with.
+///
+/// Returns false if no such vfs is registered. A connection can also freeze
+/// its own database with `pragma sqlite_plugin_frozen = on`.
+pub fn set_frozen(name: &str, path: &str, frozen: bool) -> bool {
+    find_registered(name, |registered| {
+        set_frozen_inner(&registered.frozen, path, frozen)
+    })
+    .is_some()
+}
+
+/// Unregisters the vfs most recently registered by this crate under `name`
+/// and frees it. Returns `SQLITE_BUSY` while any file opened through it is
+/// still open, and `SQLITE_NOTFOUND` if there is no such vfs.
+/// # Safety
+/// No connection may be using this vfs, or be opened with it concurrently.
+/// Connections only hold files open while they use them, so a connection
+/// without open files, such as one to a temporary database, is not detected.
+pub unsafe fn unregister(name: &str) -> VfsResult<()> {
+    let mut registry = REGISTRY.lock();
+    let idx = registry
+        .iter()
+        .rposition(|registered| registered.name.as_bytes() == name.as_bytes())
+        .ok_or(vars::SQLITE_NOTFOUND)?;
+    let registered = &registry[idx];
+    unsafe { (registered.release)(registered.p_vfs) }?;
+    registry.remove(idx);
+    Ok(())
+}
+
+// unregisters p_vfs from SQLite and frees everything register_inner allocated
+unsafe fn release<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs) -> VfsResult<()> {
+    let appdata = unwrap_appdata!(p_vfs, T)?;
+    if !appdata.open_files.lock().is_empty() {
+        return Err(VfsError::Busy);
+    }
+    let result = unsafe { (appdata.sqlite_api.unregister)(p_vfs) };
+    if result != vars::SQLITE_OK {
+        return Err(result.into());
+    }
+    unsafe {
+        let vfs = Box::from_raw(p_vfs);
+        drop(Box::from_raw(vfs.pAppData.cast::<AppData<T>>()));
+        drop(CString::from_raw(vfs.zName.cast_mut()));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "static")]
+pub fn register_static<T: Vfs + 'static>(
+    name: CString,
+    vfs: T,
+    opts: RegisterOpts,
+) -> VfsResult<()> {
+    register_inner(SqliteApi::new_static(), name, vfs, opts)
+}
+
+/// Registers a vfs like [`register_static`], returning a guard which
+/// unregisters and drops it when it goes out of scope.
+/// # Safety
+/// Every connection using the vfs must be closed before the guard is
+/// dropped, see [`unregister`].
+#[cfg(feature = "static")]
+pub unsafe fn register_scoped<T: Vfs + 'static>(
+    name: CString,
+    vfs: T,
+    opts: RegisterOpts,
+) -> VfsResult<VfsRegistration> {
+    let guard = VfsRegistration {
+        name: name.to_str().map_err(|_| vars::SQLITE_MISUSE)?.into(),
+    };
+    register_static(name, vfs, opts)?;
+    Ok(guard)
+}
+
+/// Keeps a vfs registered by [`register_scoped`] until it is dropped. If a
+/// file opened through the vfs is still open by then, the registration is
+/// leaked rather than freed.
+#[must_use = "the vfs is unregistered when the guard is dropped"]
+#[derive(Debug)]
+pub struct VfsRegistration {
+    name: String,
+}
+
+impl VfsRegistration {
+    /// The name the vfs is registered under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for VfsRegistration {
+    fn drop(&mut self) {
+        // SAFETY: register_scoped requires every connection to be closed
+        // before the guard is dropped
+        let _ = unsafe { unregister(&self.name) };
+    }
+}
+
+/// Registers several vfs as one unit. If any of them fails to register,
+/// [`commit`](RegisterBatch::commit) unregisters the ones which succeeded and
+/// returns every failure, so that an extension either registers all of its
+/// vfs or none. A batch dropped without being committed also unregisters its
+/// vfs.
+///
+/// ```no_run
+/// # use sqlite_plugin::readonly::ReadOnlyVfs;
+/// # use sqlite_plugin::shim::BaseVfs;
+/// # use sqlite_plugin::vfs::{RegisterBatch, RegisterOpts};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut batch = RegisterBatch::new();
+/// batch.register(c"plain".to_owned(), BaseVfs::find(None)?, RegisterOpts::default());
+/// let readonly = ReadOnlyVfs::new(BaseVfs::find(None)?);
+/// batch.register(c"readonly".to_owned(), readonly, RegisterOpts::default());
+/// batch.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// `SQLite` can use each vfs as soon as it is registered, so no connection
+/// may open one before the batch is committed, see [`unregister`].
+#[must_use = "the vfs are unregistered unless the batch is committed"]
+pub struct RegisterBatch {
+    sqlite_api: SqliteApi,
+    registered: Vec<String>,
+    failures: Vec<(String, VfsError)>,
+}
+
+impl RegisterBatch {
+    /// A batch which registers vfs like [`register_static`].
+    #[cfg(feature = "static")]
+    pub fn new() -> Self {
+        Self::with_api(SqliteApi::new_static())
+    }
+
+    /// A batch which registers vfs like [`register_dynamic`].
+    /// # Safety
+    /// `p_api` must be a valid, aligned pointer to a `sqlite3_api_routines`
+    /// struct
+    #[cfg(feature = "dynamic")]
+    pub unsafe fn new_dynamic(p_api: *mut ffi::sqlite3_api_routines) -> VfsResult<Self> {
+        let api = unsafe { p_api.as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
+        Ok(Self::with_api(SqliteApi::new_dynamic(api)?))
+    }
+
+    fn with_api(sqlite_api: SqliteApi) -> Self {
+        Self {
+            sqlite_api,
+            registered: Vec::new(),
+            failures: Vec::new(),
+        }
+    }
+
+    /// Registers `vfs` under `name`. A failure is recorded, and reported by
+    /// [`commit`](RegisterBatch::commit) along with any others.
+    pub fn register<T: Vfs + 'static>(
+        &mut self,
+        name: CString,
+        vfs: T,
+        opts: RegisterOpts,
+    ) -> &mut Self {
+        let label = name.to_string_lossy().into_owned();
+        match register_inner(self.sqlite_api.clone(), name, vfs, opts) {
+            Ok(()) => self.registered.push(label),
+            Err(err) => self.failures.push((label, err)),
+        }
+        self
+    }
+
+    // a vfs registered with SQLite, found through the api of the batch
+    pub(crate) fn find(&self, name: Option<&str>) -> VfsResult<crate::shim::BaseVfs> {
+        let name = name
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| VfsError::NotFound)?;
+        let p_vfs = unsafe {
+            (self.sqlite_api.find)(name.as_deref().map_or(core::ptr::null(), CStr::as_ptr))
+        };
+        // SAFETY: the vfs SQLite knows of are threadsafe, and are registered
+        // until unregistered, which callers rule out
+        unsafe { crate::shim::BaseVfs::from_raw(p_vfs) }.ok_or(VfsError::NotFound)
+    }
+
+    pub(crate) fn failed(&self) -> bool {
+        !self.failures.is_empty()
+    }
+
+    /// Keeps every vfs of the batch registered, or if any of them failed to
+    /// register, unregisters the others and returns the failures.
+    pub fn commit(mut self) -> Result<(), RegisterError> {
+        if self.failures.is_empty() {
+            self.registered.clear();
+            return Ok(());
+        }
+        Err(RegisterError { failures: mem::take(&mut self.failures) })
+    }
+}
+
+#[cfg(feature = "static")]
+impl Default for RegisterBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for RegisterBatch {
+    fn drop(&mut self) {
+        for name in self.registered.iter().rev() {
+            // SAFETY: no connection may use the vfs of an uncommitted batch
+            let _ = unsafe { unregister(name) };
+        }
+    }
+}
+
+/// The vfs of a [`RegisterBatch`] which failed to register, by name, and
+/// why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterError {
+    pub failures: Vec<(String, VfsError)>,
+}
+
+impl RegisterError {
+    /// The result code to return from an extension entry point, which is that
+    /// of the first failure.
+    pub fn code(&self) -> SqliteErr {
+        self.failures
+            .first()
+            .map_or(vars::SQLITE_ERROR, |(_, err)| err.code(vars::SQLITE_ERROR))
+    }
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("failed to register")?;
+        for (i, (name, err)) in self.failures.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(
+                f,
+                "{sep}{name} ({err}, code {})",
+                err.code(vars::SQLITE_ERROR)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for RegisterError {}
+
+/// Register a vfs with `SQLite` using the dynamic API. This API is available when
+/// `SQLite` is initializing extensions.
+/// # Safety
+/// `p_api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct
+#[cfg(feature = "dynamic")]
+pub unsafe fn register_dynamic<T: Vfs + 'static>(
+    p_api: *mut ffi::sqlite3_api_routines,
+    name: CString,
+    vfs: T,
+    opts: RegisterOpts,
+) -> VfsResult<()> {
+    let api = unsafe { p_api.as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
+    let sqlite_api = SqliteApi::new_dynamic(api)?;
+    register_inner(sqlite_api, name, vfs, opts)
+}
+
+/// Registers `vfs` for the entry point defined by [`declare_vfs_extension`],
+/// returning the result code of the entry point.
+/// # Safety
+/// `p_api` must be the `sqlite3_api_routines` passed to the entry point.
+///
+/// [`declare_vfs_extension`]: crate::declare_vfs_extension
+#[doc(hidden)]
+#[cfg(feature = "dynamic")]
+pub unsafe fn init_extension<T: Vfs + 'static>(
+    p_api: *mut ffi::sqlite3_api_routines,
+    name: &str,
+    vfs: T,
+    opts: RegisterOpts,
+) -> c_int {
+    let Ok(name) = CString::new(name) else {
+        return vars::SQLITE_MISUSE;
+    };
+    match unsafe { register_dynamic(p_api, name, vfs, opts) } {
+        // the vfs must outlive every connection, not just the one loading it
+        Ok(()) => vars::SQLITE_OK_LOAD_PERMANENTLY,
+        Err(err) => err.code(vars::SQLITE_ERROR),
+    }
+}
+
+/// Defines the entry point of a loadable extension which registers a
+/// `$vfs::default()` under `$name`, with [`RegisterOpts`] if given:
+///
+/// ```no_run
+/// # use sqlite_plugin::flags::{AccessFlags, OpenOpts};
+/// # use sqlite_plugin::logger::SqliteLogger;
+/// # use sqlite_plugin::vfs::{UriParams, Vfs, VfsHandle, VfsResult};
+/// # #[derive(Default)]
+/// # struct MyVfs;
+/// # struct MyHandle;
+/// # impl VfsHandle for MyHandle {
+/// #     fn readonly(&self) -> bool { false }
+/// #     fn in_memory(&self) -> bool { true }
+/// # }
+/// # impl Vfs for MyVfs {
+/// #     type Handle = MyHandle;
+/// #     fn register_logger(&self, _: SqliteLogger) {}
+/// #     fn open(&self, _: Option<&str>, _: OpenOpts, _: UriParams<'_>) -> VfsResult<MyHandle> { Ok(MyHandle) }
+/// #     fn delete(&self, _: &str) -> VfsResult<()> { Ok(()) }
+/// #     fn access(&self, _: &str, _: AccessFlags) -> VfsResult<bool> { Ok(false) }
+/// #     fn file_size(&self, _: &mut MyHandle) -> VfsResult<usize> { Ok(0) }
+/// #     fn truncate(&self, _: &mut MyHandle, _: usize) -> VfsResult<()> { Ok(()) }
+/// #     fn write(&self, _: &mut MyHandle, _: usize, data: &[u8]) -> VfsResult<usize> { Ok(data.len()) }
+/// #     fn read(&self, _: &mut MyHandle, _: usize, _: &mut [u8]) -> VfsResult<usize> { Ok(0) }
+/// #     fn close(&self, _: MyHandle) -> VfsResult<()> { Ok(()) }
+/// # }
+/// sqlite_plugin::declare_vfs_extension!(MyVfs, "mem");
+/// # fn main() {}
+/// ```
+///
+/// The entry point is named `sqlite3_extension_init`, which `SQLite` looks
+/// for first when an extension is loaded without naming its entry point, so
+/// each library can declare a single extension. Extensions registering
+/// several vfs write their own entry point with [`register_dynamic`].
+#[cfg(feature = "dynamic")]
+#[macro_export]
+macro_rules! declare_vfs_extension {
+    ($vfs:ty, $name:expr) => {
+        $crate::declare_vfs_extension!($vfs, $name, $crate::vfs::RegisterOpts::default());
+    };
+    ($vfs:ty, $name:expr, $opts:expr) => {
+        /// Registers the vfs when `SQLite` loads the extension.
+        /// # Safety
+        /// This function should only be called by sqlite's extension loading mechanism.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn sqlite3_extension_init(
+            _db: *mut ::core::ffi::c_void,
+            _pz_err_msg: *mut *mut ::core::ffi::c_char,
+            p_api: *mut $crate::sqlite3_api_routines,
+        ) -> ::core::ffi::c_int {
+            let vfs = <$vfs as ::core::default::Default>::default();
+            unsafe { $crate::vfs::init_extension(p_api, $name, vfs, $opts) }
+        }
+    };
+}
+
+fn register_inner<T: Vfs + 'static>(
+    sqlite_api: SqliteApi,
+    name: CString,
+    vfs: T,
+    opts: RegisterOpts,
+) -> VfsResult<()> {
+    let RegisterOpts {
+        make_default,
+        sync_policy,
+        strict,
+        on_change,
+        handle_limits,
+        readonly_wal,
+        max_pathname,
+        io_version,
+        database_files_pragma,
+        log_last_error,
+    } = opts;
+    let max_pathname: c_int = max_pathname
+        .try_into()
+        .ok()
+        .filter(|len| *len > 0)
+        .ok_or(vars::SQLITE_MISUSE)?;
+
+    let version = unsafe { (sqlite_api.libversion_number)() };
+    if version < MIN_SQLITE_VERSION_NUMBER {
+        panic!(
+            "sqlite3 must be at least version {}, found version {}",
+            MIN_SQLITE_VERSION_NUMBER, version
+        );
+    }
+
+    vfs.register_logger(SqliteLogger::new(sqlite_api.log));
+    vfs.probe()?;
+
+    // SQLite only enables WAL mode when xShmMap is present
+    let shm = io_version >= IoVersion::V2 && vfs.supports_shm();
+    let readonly_shm = io_version >= IoVersion::V2 && !shm && readonly_wal;
+    let io_methods = ffi::sqlite3_io_methods {
+        iVersion: match io_version {
+            IoVersion::V1 => 1,
+            IoVersion::V2 => 2,
+            IoVersion::V3 => 3,
+        },
+        xClose: Some(x_close::<T>),
+        xRead: Some(x_read::<T>),
+        xWrite: Some(x_write::<T>),
+        xTruncate: Some(x_truncate::<T>),
+        xSync: Some(x_sync::<T>),
+        xFileSize: Some(x_file_size::<T>),
+        xLock: Some(x_lock::<T>),
         xUnlock: Some(x_unlock::<T>),
-        xCheckReservedLock: None,
+        xCheckReservedLock: Some(x_check_reserved_lock::<T>),
         xFileControl: Some(x_file_control::<T>),
         xSectorSize: Some(x_sector_size::<T>),
         xDeviceCharacteristics: Some(x_device_characteristics::<T>),
-        xShmMap: None,
-        xShmLock: None,
-        xShmBarrier: None,
-        xShmUnmap: None,
-        xFetch: None,
-        xUnfetch: None,
+        xShmMap: if shm {
+            Some(x_shm_map::<T>)
+        } else if readonly_shm {
+            Some(x_readonly_shm_map::<T>)
+        } else {
+            None
+        },
+        xShmLock: if shm {
+            Some(x_shm_lock::<T>)
+        } else if readonly_shm {
+            Some(x_readonly_shm_lock)
+        } else {
+            None
+        },
+        xShmBarrier: if shm {
+            Some(x_shm_barrier::<T>)
+        } else if readonly_shm {
+            Some(x_readonly_shm_barrier)
+        } else {
+            None
+        },
+        xShmUnmap: if shm {
+            Some(x_shm_unmap::<T>)
+        } else if readonly_shm {
+            Some(x_readonly_shm_unmap)
+        } else {
+            None
+        },
+        // always set, as SQLite calls these without checking for null once
+        // pragma mmap_size is set, unless iVersion is below 3
+        xFetch: Some(x_fetch::<T>),
+        xUnfetch: Some(x_unfetch::<T>),
     };
 
-    vfs.register_logger(SqliteLogger::new(sqlite_api.log));
-
+    let vfs = Arc::new(vfs);
+    let capabilities = Capabilities::probe(&sqlite_api, &*vfs, io_version);
+    let registry_name = name.clone();
+    let registry_vfs = vfs.clone();
+    let open_files = OpenFiles::default();
+    let frozen = FrozenFiles::default();
     let p_name = ManuallyDrop::new(name).as_ptr();
     let base_vfs = unsafe { (sqlite_api.find)(null_mut()) };
     let vfs_register = sqlite_api.register;
-    let p_appdata = Box::into_raw(Box::new(AppData { base_vfs, vfs, io_methods, sqlite_api }));
+    let p_appdata = Box::into_raw(Box::new(AppData {
+        base_vfs,
+        vfs,
+        io_methods,
+        sqlite_api,
+        on_change,
+        sync_policy,
+        strict,
+        handle_limits,
+        database_files_pragma,
+        log_last_error,
+        open_files: open_files.clone(),
+        frozen: frozen.clone(),
+        memory_journals: Default::default(),
+    }));
 
     let filewrapper_size: c_int = size_of::<FileWrapper<T::Handle>>()
+        .max(size_of::<MemoryJournal>())
         .try_into()
         .map_err(|_| vars::SQLITE_INTERNAL)?;
 
     let p_vfs = Box::into_raw(Box::new(ffi::sqlite3_vfs {
         iVersion: 3,
         szOsFile: filewrapper_size,
-        mxPathname: DEFAULT_MAX_PATH_LEN,
+        mxPathname: max_pathname,
         pNext: null_mut(),
         zName: p_name,
         pAppData: p_appdata.cast(),
@@ -341,14 +1959,14 @@ fn register_inner<T: Vfs>(
         xRandomness: Some(x_randomness::<T>),
         xSleep: Some(x_sleep::<T>),
         xCurrentTime: Some(x_current_time::<T>),
-        xGetLastError: None,
+        xGetLastError: Some(x_get_last_error::<T>),
         xCurrentTimeInt64: Some(x_current_time_int64::<T>),
         xSetSystemCall: None,
         xGetSystemCall: None,
         xNextSystemCall: None,
     }));
 
-    let result = unsafe { vfs_register(p_vfs, opts.make_default.into()) };
+    let result = unsafe { vfs_register(p_vfs, make_default.into()) };
     if result != vars::SQLITE_OK {
         // cleanup memory
         unsafe {
@@ -356,8 +1974,17 @@ fn register_inner<T: Vfs>(
             drop(Box::from_raw(p_appdata));
             drop(CString::from_raw(p_name as *mut c_char));
         };
-        Err(result)
+        Err(result.into())
     } else {
+        REGISTRY.lock().push(Registered {
+            name: registry_name,
+            vfs: registry_vfs,
+            capabilities,
+            open_files,
+            frozen,
+            p_vfs,
+            release: release::<T>,
+        });
         Ok(())
     }
 }
@@ -370,14 +1997,76 @@ unsafe extern "C" fn x_open<T: Vfs>(
     p_out_flags: *mut c_int,
 ) -> c_int {
     fallible(|| {
-        let opts = flags.into();
-        let name = unsafe { lossy_cstr(z_name) }.ok();
+        let opts: OpenOpts = flags.into();
         let vfs = unwrap_vfs!(p_vfs, T)?;
-        let handle = vfs.open(name.as_ref().map(|s| s.as_ref()), opts)?;
-
-        let out_file = unwrap_file!(p_file, T)?;
+        let name = match unsafe { lossy_cstr(z_name) } {
+            Ok(name) => Some(name),
+            Err(_) => match vfs.temp_filename() {
+                Ok(name) => Some(Cow::Owned(name)),
+                Err(VfsError::NotFound) => None,
+                Err(err) => return Err(err.code(vars::SQLITE_CANTOPEN)),
+            },
+        };
+        // the file is not open yet, so its io methods are unset
+        let out_file = unsafe { p_file.cast::<FileWrapper<T::Handle>>().as_mut() }
+            .ok_or(vars::SQLITE_INTERNAL)?;
         let appdata = unwrap_appdata!(p_vfs, T)?;
 
+        let creates = matches!(
+            opts.mode(),
+            OpenMode::ReadWrite {
+                create: CreateMode::Create | CreateMode::MustCreate
+            }
+        );
+        if let Some(path) = name
+            .as_deref()
+            .filter(|_| creates && opts.kind() == OpenKind::MainDb)
+        {
+            let exists = vfs
+                .access(path, AccessFlags::Exists)
+                .map_err(|err| err.code(vars::SQLITE_CANTOPEN))?;
+            if !exists {
+                vfs.bootstrap(path)
+                    .map_err(|err| err.code(vars::SQLITE_CANTOPEN))?;
+            }
+        }
+
+        if let Some(path) = name
+            .as_deref()
+            .filter(|_| opts.kind() == OpenKind::MainJournal)
+        {
+            if uses_memory_journal(appdata, path) {
+                let journal = unsafe { p_file.cast::<MemoryJournal>().as_mut() }
+                    .ok_or(vars::SQLITE_CANTOPEN)?;
+                journal.file.pMethods = &MEMORY_JOURNAL_IO_METHODS;
+                journal.data.write(Vec::new());
+                let mut journals = appdata.memory_journals.lock();
+                if !journals.iter().any(|other| other == path) {
+                    journals.push(path.to_string());
+                }
+                if let Some(p_out_flags) = unsafe { p_out_flags.as_mut() } {
+                    *p_out_flags = flags | vars::SQLITE_OPEN_MEMORY;
+                }
+                return Ok(vars::SQLITE_OK);
+            }
+        }
+
+        // the handle counts against the limits from before it is opened, so
+        // that concurrent opens cannot overshoot them
+        let info = Arc::new(FileInfo::new(name.as_ref().map(|s| s.to_string()), opts));
+        reserve_handle(appdata, &info)?;
+        let params = unsafe { UriParams::new(&appdata.sqlite_api, z_name) };
+        let handle = match vfs.open(name.as_ref().map(|s| s.as_ref()), opts, params) {
+            Ok(handle) => handle,
+            Err(err) => {
+                appdata
+                    .open_files
+                    .lock()
+                    .retain(|other| !Arc::ptr_eq(other, &info));
+                return Err(err.code(vars::SQLITE_CANTOPEN));
+            }
+        };
+
         if let Some(p_out_flags) = unsafe { p_out_flags.as_mut() } {
             let mut out_flags = flags;
             if handle.readonly() {
@@ -389,37 +2078,225 @@ unsafe extern "C" fn x_open<T: Vfs>(
             *p_out_flags = out_flags;
         }
 
+        info.in_memory.store(handle.in_memory(), Ordering::Relaxed);
         out_file.file.pMethods = &appdata.io_methods;
         out_file.vfs = p_vfs;
         out_file.handle.write(handle);
+        out_file.state.write(FileState { info, dirty: false });
 
         Ok(vars::SQLITE_OK)
     })
 }
 
-unsafe extern "C" fn x_delete<T: Vfs>(
-    p_vfs: *mut ffi::sqlite3_vfs,
-    z_name: ffi::sqlite3_filename,
-    _sync_dir: c_int,
-) -> c_int {
-    fallible(|| {
-        let name = unsafe { lossy_cstr(z_name)? };
-        let vfs = unwrap_vfs!(p_vfs, T)?;
-        vfs.delete(&name)?;
-        Ok(vars::SQLITE_OK)
-    })
+// the default names of temporary files, see `Vfs::temp_filename`
+pub(crate) fn next_temp_filename() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("etilqs_{n:016x}")
 }
 
-unsafe extern "C" fn x_access<T: Vfs>(
-    p_vfs: *mut ffi::sqlite3_vfs,
-    z_name: ffi::sqlite3_filename,
-    flags: c_int,
-    p_res_out: *mut c_int,
-) -> c_int {
-    fallible(|| {
-        let name = unsafe { lossy_cstr(z_name)? };
+// whether the rollback journal at `path` is kept in memory, which requires
+// the Vfs to ask for it and its database to be held in memory
+fn uses_memory_journal<T: Vfs>(appdata: &AppData<T>, path: &str) -> bool {
+    let Some(db_path) = path.strip_suffix("-journal") else {
+        return false;
+    };
+    if appdata.vfs.journal_policy(db_path) != JournalPolicy::Memory {
+        return false;
+    }
+    let in_memory = appdata.open_files.lock().iter().any(|info| {
+        info.opts.kind() == OpenKind::MainDb
+            && info.name.as_deref() == Some(db_path)
+            && info.in_memory.load(Ordering::Relaxed)
+    });
+    if !in_memory {
+        let msg = format!("{db_path} is not in memory, so its journal is not kept in memory");
+        SqliteLogger::new(appdata.sqlite_api.log).log(SqliteLogLevel::Warn, msg.as_bytes());
+    }
+    in_memory
+}
+
+// a rollback journal kept in memory, which is stored in the space SQLite
+// allocates for each file in place of a FileWrapper
+#[repr(C)]
+struct MemoryJournal {
+    file: ffi::sqlite3_file,
+    data: MaybeUninit<Vec<u8>>,
+}
+
+static MEMORY_JOURNAL_IO_METHODS: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(x_memory_close),
+    xRead: Some(x_memory_read),
+    xWrite: Some(x_memory_write),
+    xTruncate: Some(x_memory_truncate),
+    xSync: Some(x_memory_sync),
+    xFileSize: Some(x_memory_file_size),
+    xLock: Some(x_memory_lock),
+    xUnlock: Some(x_memory_lock),
+    xCheckReservedLock: Some(x_memory_check_reserved_lock),
+    xFileControl: Some(x_memory_file_control),
+    xSectorSize: Some(x_memory_sector_size),
+    xDeviceCharacteristics: Some(x_memory_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+// the data of the memory journal at `p_file`, if it is one
+unsafe fn memory_journal<'a>(p_file: *mut ffi::sqlite3_file) -> Result<&'a mut Vec<u8>, SqliteErr> {
+    let journal =
+        unsafe { p_file.cast::<MemoryJournal>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+    Ok(unsafe { journal.data.assume_init_mut() })
+}
+
+unsafe extern "C" fn x_memory_close(p_file: *mut ffi::sqlite3_file) -> c_int {
+    fallible(|| {
+        let journal =
+            unsafe { p_file.cast::<MemoryJournal>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+        unsafe { journal.data.assume_init_drop() };
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_memory_read(
+    p_file: *mut ffi::sqlite3_file,
+    buf: *mut c_void,
+    i_amt: c_int,
+    i_ofst: ffi::sqlite_int64,
+) -> c_int {
+    fallible(|| {
+        let data = unsafe { memory_journal(p_file)? };
+        let offset = usize::try_from(i_ofst).map_err(|_| vars::SQLITE_IOERR_READ)?;
+        let len = usize::try_from(i_amt).map_err(|_| vars::SQLITE_IOERR_READ)?;
+        let buf = unsafe { slice::from_raw_parts_mut(buf.cast::<u8>(), len) };
+        let available = data.get(offset..).unwrap_or_default();
+        let n = available.len().min(len);
+        buf[..n].copy_from_slice(&available[..n]);
+        if n < len {
+            // SQLite requires the rest of a short read to be zeroed
+            buf[n..].fill(0);
+            return Err(vars::SQLITE_IOERR_SHORT_READ);
+        }
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_memory_write(
+    p_file: *mut ffi::sqlite3_file,
+    buf: *const c_void,
+    i_amt: c_int,
+    i_ofst: ffi::sqlite_int64,
+) -> c_int {
+    fallible(|| {
+        let data = unsafe { memory_journal(p_file)? };
+        let offset = usize::try_from(i_ofst).map_err(|_| vars::SQLITE_IOERR_WRITE)?;
+        let len = usize::try_from(i_amt).map_err(|_| vars::SQLITE_IOERR_WRITE)?;
+        let buf = unsafe { slice::from_raw_parts(buf.cast::<u8>(), len) };
+        if data.len() < offset + len {
+            data.resize(offset + len, 0);
+        }
+        data[offset..offset + len].copy_from_slice(buf);
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_memory_truncate(
+    p_file: *mut ffi::sqlite3_file,
+    size: ffi::sqlite_int64,
+) -> c_int {
+    fallible(|| {
+        let data = unsafe { memory_journal(p_file)? };
+        let size = usize::try_from(size).map_err(|_| vars::SQLITE_IOERR_TRUNCATE)?;
+        data.truncate(size);
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_memory_sync(_p_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    vars::SQLITE_OK
+}
+
+unsafe extern "C" fn x_memory_file_size(
+    p_file: *mut ffi::sqlite3_file,
+    p_size: *mut ffi::sqlite3_int64,
+) -> c_int {
+    fallible(|| {
+        let data = unsafe { memory_journal(p_file)? };
+        let out = unsafe { p_size.as_mut() }.ok_or(vars::SQLITE_IOERR_FSTAT)?;
+        *out = data.len() as ffi::sqlite3_int64;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_memory_lock(_p_file: *mut ffi::sqlite3_file, _level: c_int) -> c_int {
+    vars::SQLITE_OK
+}
+
+unsafe extern "C" fn x_memory_check_reserved_lock(
+    _p_file: *mut ffi::sqlite3_file,
+    p_res_out: *mut c_int,
+) -> c_int {
+    if let Some(out) = unsafe { p_res_out.as_mut() } {
+        *out = 0;
+    }
+    vars::SQLITE_OK
+}
+
+unsafe extern "C" fn x_memory_file_control(
+    _p_file: *mut ffi::sqlite3_file,
+    _op: c_int,
+    _p_arg: *mut c_void,
+) -> c_int {
+    vars::SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn x_memory_sector_size(_p_file: *mut ffi::sqlite3_file) -> c_int {
+    DEFAULT_SECTOR_SIZE as c_int
+}
+
+unsafe extern "C" fn x_memory_device_characteristics(_p_file: *mut ffi::sqlite3_file) -> c_int {
+    DEFAULT_DEVICE_CHARACTERISTICS
+}
+
+unsafe extern "C" fn x_delete<T: Vfs>(
+    p_vfs: *mut ffi::sqlite3_vfs,
+    z_name: ffi::sqlite3_filename,
+    _sync_dir: c_int,
+) -> c_int {
+    fallible(|| {
+        let name = unsafe { lossy_cstr(z_name)? };
+        let vfs = unwrap_vfs!(p_vfs, T)?;
+        let appdata = unwrap_appdata!(p_vfs, T)?;
+        {
+            let mut journals = appdata.memory_journals.lock();
+            if let Some(i) = journals.iter().position(|journal| *journal == name) {
+                // the journal was never stored by the Vfs
+                journals.swap_remove(i);
+                return Ok(vars::SQLITE_OK);
+            }
+        }
+        vfs.delete(&name)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_DELETE))?;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_access<T: Vfs>(
+    p_vfs: *mut ffi::sqlite3_vfs,
+    z_name: ffi::sqlite3_filename,
+    flags: c_int,
+    p_res_out: *mut c_int,
+) -> c_int {
+    fallible(|| {
+        let name = unsafe { lossy_cstr(z_name)? };
         let vfs = unwrap_vfs!(p_vfs, T)?;
-        let result = vfs.access(&name, flags.into())?;
+        let result = vfs
+            .access(&name, flags.into())
+            .map_err(|err| err.code(vars::SQLITE_IOERR_ACCESS))?;
         let out = unsafe { p_res_out.as_mut() }.ok_or(vars::SQLITE_IOERR_ACCESS)?;
         *out = result as i32;
         Ok(vars::SQLITE_OK)
@@ -435,10 +2312,16 @@ unsafe extern "C" fn x_full_pathname<T: Vfs>(
     fallible(|| {
         let name = unsafe { lossy_cstr(z_name)? };
         let vfs = unwrap_vfs!(p_vfs, T)?;
-        let full_name = vfs.canonical_path(name)?;
+        let full_name = vfs
+            .canonical_path(name)
+            .map_err(|err| err.code(vars::SQLITE_CANTOPEN_FULLPATH))?;
         let n_out = n_out.try_into().map_err(|_| vars::SQLITE_INTERNAL)?;
+        // a truncated name would refer to a different file
+        if full_name.len() >= n_out {
+            return Err(vars::SQLITE_CANTOPEN_FULLPATH);
+        }
         let out = unsafe { slice::from_raw_parts_mut(z_out as *mut u8, n_out) };
-        let from = &full_name.as_bytes()[..full_name.len().min(n_out - 1)];
+        let from = full_name.as_bytes();
         // copy the name into the output buffer
         out[..from.len()].copy_from_slice(from);
         // add the trailing null byte
@@ -455,7 +2338,15 @@ unsafe extern "C" fn x_close<T: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_int {
         let vfs = unwrap_vfs!(file.vfs, T)?;
         let handle = mem::replace(&mut file.handle, MaybeUninit::uninit());
         let handle = unsafe { handle.assume_init() };
-        vfs.close(handle)?;
+        let state = unsafe { mem::replace(&mut file.state, MaybeUninit::uninit()).assume_init() };
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        appdata
+            .open_files
+            .lock()
+            .retain(|info| !Arc::ptr_eq(info, &state.info));
+        file.file.pMethods = core::ptr::null();
+        vfs.close(handle)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_CLOSE))?;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -468,11 +2359,22 @@ unsafe extern "C" fn x_read<T: Vfs>(
 ) -> c_int {
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        let buf_len: usize = i_amt.try_into().map_err(|_| vars::SQLITE_IOERR_READ)?;
-        let offset: usize = i_ofst.try_into().map_err(|_| vars::SQLITE_IOERR_READ)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let state = unsafe { file.state.assume_init_ref() };
+        let (buf_len, offset): (usize, usize) = i_amt
+            .try_into()
+            .ok()
+            .zip(i_ofst.try_into().ok())
+            .ok_or_else(|| {
+            let msg = format_args!("invalid read of {i_amt} bytes at offset {i_ofst}");
+            violation(appdata, state, vars::SQLITE_IOERR_READ, msg)
+        })?;
         let buf = unsafe { slice::from_raw_parts_mut(buf.cast::<u8>(), buf_len) };
-        vfs.read(unsafe { file.handle.assume_init_mut() }, offset, buf)?;
+        state.info.record(FileOp::Read);
+        appdata
+            .vfs
+            .read(unsafe { file.handle.assume_init_mut() }, offset, buf)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_READ))?;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -485,13 +2387,30 @@ unsafe extern "C" fn x_write<T: Vfs>(
 ) -> c_int {
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        let buf_len: usize = i_amt.try_into().map_err(|_| vars::SQLITE_IOERR_WRITE)?;
-        let offset: usize = i_ofst.try_into().map_err(|_| vars::SQLITE_IOERR_WRITE)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let state = unsafe { file.state.assume_init_mut() };
+        let (buf_len, offset): (usize, usize) = i_amt
+            .try_into()
+            .ok()
+            .zip(i_ofst.try_into().ok())
+            .ok_or_else(|| {
+            let msg = format_args!("invalid write of {i_amt} bytes at offset {i_ofst}");
+            violation(appdata, state, vars::SQLITE_IOERR_WRITE, msg)
+        })?;
+        if is_frozen(&appdata.frozen, state.info.name.as_deref()) {
+            return Err(vars::SQLITE_READONLY);
+        }
         let buf = unsafe { slice::from_raw_parts(buf.cast::<u8>(), buf_len) };
-        let n = vfs.write(unsafe { file.handle.assume_init_mut() }, offset, buf)?;
+        state.info.record(FileOp::Write);
+        let n = appdata
+            .vfs
+            .write(unsafe { file.handle.assume_init_mut() }, offset, buf)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_WRITE))?;
+        state.dirty = true;
+        state.info.size.fetch_max(offset + n, Ordering::Relaxed);
         if n != buf_len {
-            return Err(vars::SQLITE_IOERR_WRITE);
+            let msg = format_args!("short write of {n} out of {buf_len} bytes at offset {offset}");
+            return Err(violation(appdata, state, vars::SQLITE_IOERR_WRITE, msg));
         }
         Ok(vars::SQLITE_OK)
     })
@@ -503,18 +2422,39 @@ unsafe extern "C" fn x_truncate<T: Vfs>(
 ) -> c_int {
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        let size: usize = size.try_into().map_err(|_| vars::SQLITE_IOERR_TRUNCATE)?;
-        vfs.truncate(unsafe { file.handle.assume_init_mut() }, size)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let state = unsafe { file.state.assume_init_mut() };
+        let size: usize = size.try_into().map_err(|_| {
+            let msg = format_args!("invalid truncate to {size} bytes");
+            violation(appdata, state, vars::SQLITE_IOERR_TRUNCATE, msg)
+        })?;
+        if is_frozen(&appdata.frozen, state.info.name.as_deref()) {
+            return Err(vars::SQLITE_READONLY);
+        }
+        state.info.record(FileOp::Truncate);
+        appdata
+            .vfs
+            .truncate(unsafe { file.handle.assume_init_mut() }, size)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_TRUNCATE))?;
+        state.dirty = true;
+        state.info.size.store(size, Ordering::Relaxed);
         Ok(vars::SQLITE_OK)
     })
 }
 
-unsafe extern "C" fn x_sync<T: Vfs>(p_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+unsafe extern "C" fn x_sync<T: Vfs>(p_file: *mut ffi::sqlite3_file, flags: c_int) -> c_int {
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.sync(unsafe { file.handle.assume_init_mut() })?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let state = unsafe { file.state.assume_init_ref() };
+        let mode = appdata.sync_policy.mode(&state.info.opts.kind());
+        if let Some(flags) = mode.apply(flags.into()) {
+            state.info.record(FileOp::Sync);
+            appdata
+                .vfs
+                .sync(unsafe { file.handle.assume_init_mut() }, flags)
+                .map_err(|err| err.code(vars::SQLITE_IOERR_FSYNC))?;
+        }
         Ok(vars::SQLITE_OK)
     })
 }
@@ -526,7 +2466,12 @@ unsafe extern "C" fn x_file_size<T: Vfs>(
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
-        let size = vfs.file_size(unsafe { file.handle.assume_init_mut() })?;
+        let state = unsafe { file.state.assume_init_ref() };
+        state.info.record(FileOp::FileSize);
+        let size = vfs
+            .file_size(unsafe { file.handle.assume_init_mut() })
+            .map_err(|err| err.code(vars::SQLITE_IOERR_FSTAT))?;
+        state.info.size.store(size, Ordering::Relaxed);
         let p_size = unsafe { p_size.as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
         *p_size = size.try_into().map_err(|_| vars::SQLITE_IOERR_FSTAT)?;
         Ok(vars::SQLITE_OK)
@@ -536,9 +2481,49 @@ unsafe extern "C" fn x_file_size<T: Vfs>(
 unsafe extern "C" fn x_lock<T: Vfs>(p_file: *mut ffi::sqlite3_file, raw_lock: c_int) -> c_int {
     fallible(|| {
         let level: LockLevel = raw_lock.into();
+        let file = unwrap_file!(p_file, T)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let state = unsafe { file.state.assume_init_mut() };
+        // outside of strict mode invalid transitions are forwarded as is, like
+        // SQLite's own vfs implementations which only assert on them
+        let current = state.info.lock();
+        if appdata.strict && !valid_lock_transition(current, level) {
+            let msg = format_args!("invalid lock transition from {current:?} to {level:?}");
+            violation(appdata, state, vars::SQLITE_INTERNAL, msg);
+        }
+        // refusing the reserved lock fails a write transaction before it starts
+        if level >= LockLevel::Reserved && is_frozen(&appdata.frozen, state.info.name.as_deref()) {
+            return Err(vars::SQLITE_READONLY);
+        }
+        state.info.record(FileOp::Lock);
+        let result = appdata
+            .vfs
+            .lock(unsafe { file.handle.assume_init_mut() }, level);
+        if let Err(err) = result {
+            let err = err.code(vars::SQLITE_IOERR_LOCK);
+            if err & 0xff == vars::SQLITE_BUSY {
+                report_busy(appdata, file.vfs, &state.info, level);
+            }
+            return Err(err);
+        }
+        state.info.set_lock(current.max(level), file.vfs);
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_check_reserved_lock<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    p_res_out: *mut c_int,
+) -> c_int {
+    fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.lock(unsafe { file.handle.assume_init_mut() }, level)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let reserved = vfs
+            .check_reserved_lock(handle)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_CHECKRESERVEDLOCK))?;
+        let out = unsafe { p_res_out.as_mut() }.ok_or(vars::SQLITE_IOERR_CHECKRESERVEDLOCK)?;
+        *out = reserved as c_int;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -547,28 +2532,165 @@ unsafe extern "C" fn x_unlock<T: Vfs>(p_file: *mut ffi::sqlite3_file, raw_lock:
     fallible(|| {
         let level: LockLevel = raw_lock.into();
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.unlock(unsafe { file.handle.assume_init_mut() }, level)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let state = unsafe { file.state.assume_init_mut() };
+        let current = state.info.lock();
+        if appdata.strict && level > LockLevel::Shared {
+            let msg = format_args!("invalid unlock from {current:?} to {level:?}");
+            violation(appdata, state, vars::SQLITE_INTERNAL, msg);
+        }
+
+        let committed = state.info.opts.kind() == OpenKind::MainDb
+            && state.dirty
+            && current == LockLevel::Exclusive
+            && level < LockLevel::Exclusive;
+
+        // read the change counter while we still hold the exclusive lock
+        let change = match (&appdata.on_change, &state.info.name) {
+            (Some(on_change), Some(name)) if committed => {
+                read_change_counter(&*appdata.vfs, handle).map(|counter| (on_change, name, counter))
+            }
+            _ => None,
+        };
+
+        state.info.record(FileOp::Unlock);
+        appdata
+            .vfs
+            .unlock(handle, level)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_UNLOCK))?;
+        state.info.set_lock(current.min(level), file.vfs);
+        if level == LockLevel::Unlocked || committed {
+            state.dirty = false;
+        }
+
+        if let Some((on_change, name, counter)) = change {
+            on_change(name, counter);
+        }
         Ok(vars::SQLITE_OK)
     })
 }
 
+// logs which other connections in this process hold a lock on the same file
+// when a lock request fails with SQLITE_BUSY
+fn report_busy<T: Vfs>(
+    appdata: &AppData<T>,
+    p_vfs: *mut ffi::sqlite3_vfs,
+    info: &Arc<FileInfo>,
+    level: LockLevel,
+) {
+    let now = current_time_ms(p_vfs);
+    let name = info.name.as_deref().unwrap_or("<anonymous>");
+    let mut msg = format!("busy: {name} could not acquire a {level:?} lock");
+    let mut holders = 0;
+    for other in appdata.open_files.lock().iter() {
+        if Arc::ptr_eq(other, info) || other.name != info.name {
+            continue;
+        }
+        let other = other.snapshot(now);
+        if other.lock > LockLevel::Unlocked {
+            holders += 1;
+            let _ = write!(msg, "; another connection holds {:?}", other.lock);
+            if let Some(held) = other.lock_held_ms {
+                let _ = write!(msg, " for {held}ms");
+            }
+            if let Some(op) = other.last_op {
+                let _ = write!(msg, " (last op {op:?})");
+            }
+        }
+    }
+    if holders == 0 {
+        msg.push_str("; no other connection in this process holds a lock");
+    }
+    SqliteLogger::new(appdata.sqlite_api.log).log(SqliteLogLevel::Warn, msg.as_bytes());
+}
+
+// reads the file change counter stored at offset 24 of the database header
+fn read_change_counter<T: Vfs>(vfs: &T, handle: &mut T::Handle) -> Option<u32> {
+    let mut buf = [0; 4];
+    match vfs.read(handle, 24, &mut buf) {
+        Ok(4) => Some(u32::from_be_bytes(buf)),
+        _ => None,
+    }
+}
+
+// the result of `pragma sqlite_plugin_debug_state`, one `key=value` pair per
+// line
+fn debug_state(state: &FileState, file_size: VfsResult<usize>) -> String {
+    let name = state.info.name.as_deref().unwrap_or("<anonymous>");
+    let file_size = match file_size {
+        Ok(size) => size.to_string(),
+        Err(err) => format!("error {}", err.code(vars::SQLITE_IOERR_FSTAT)),
+    };
+    format!(
+        "name={name}\nkind={:?}\nmode={:?}\nflags={:#x}\nlock={:?}\ndirty={}\nfile_size={file_size}",
+        state.info.opts.kind(),
+        state.info.opts.mode(),
+        state.info.opts.flags(),
+        state.info.lock(),
+        state.dirty,
+    )
+}
+
+// `pragma sqlite_plugin_frozen` reports whether the current database is
+// frozen, and `pragma sqlite_plugin_frozen = on|off` freezes or thaws it
+fn frozen_pragma(
+    frozen: &FrozenFiles,
+    state: &FileState,
+    pragma: &Pragma<'_>,
+) -> Result<Option<String>, PragmaErr> {
+    let name = state.info.name.as_deref().ok_or(PragmaErr::NotFound)?;
+    if let Some(freeze) = pragma.as_bool()? {
+        set_frozen_inner(frozen, name, freeze);
+    }
+    let frozen = is_frozen(frozen, Some(name));
+    Ok(Some(if frozen { "1" } else { "0" }.to_string()))
+}
+
+// the result of `pragma sqlite_plugin_database_files`, one line per file of
+// the database `main` open through the vfs: the database itself, its journal
+// and its WAL
+fn database_files(files: &[Arc<FileInfo>], main: &str, now: Option<i64>) -> String {
+    let mut out = String::new();
+    let sibling = |name: &str| {
+        name.strip_prefix(main)
+            .is_some_and(|suffix| matches!(suffix, "" | "-journal" | "-wal"))
+    };
+    for info in files {
+        if !info.name.as_deref().is_some_and(sibling) {
+            continue;
+        }
+        let info = info.snapshot(now);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let name = info.name.as_deref().unwrap_or("<anonymous>");
+        let size = info
+            .size
+            .map_or_else(|| "unknown".to_string(), |size| size.to_string());
+        let _ = write!(out, "{name} kind={:?} lock={:?}", info.kind, info.lock);
+        if let Some(held) = info
+            .lock_held_ms
+            .filter(|_| info.lock > LockLevel::Unlocked)
+        {
+            let _ = write!(out, " held_ms={held}");
+        }
+        let _ = write!(out, " size={size}");
+        if let Some(op) = info.last_op {
+            let _ = write!(out, " last_op={op:?}");
+        }
+    }
+    out
+}
+
 unsafe extern "C" fn x_file_control<T: Vfs>(
     p_file: *mut ffi::sqlite3_file,
     op: c_int,
     p_arg: *mut c_void,
 ) -> c_int {
     /*
-    Other interesting ops:
-    SIZE_HINT: hint of how large the database will grow during the current transaction
-    COMMIT_PHASETWO: after transaction commits before file unlocks (only used in WAL mode)
-    VFS_NAME: should return this vfs's name + / + base vfs's name
-
     Atomic write support: (requires SQLITE_IOCAP_BATCH_ATOMIC device characteristic)
     Docs: https://www3.sqlite.org/cgi/src/technote/714f6cbbf78c8a1351cbd48af2b438f7f824b336
-    BEGIN_ATOMIC_WRITE: start an atomic write operation
-    COMMIT_ATOMIC_WRITE: commit an atomic write operation
-    ROLLBACK_ATOMIC_WRITE: rollback an atomic write operation
     */
 
     if op == vars::SQLITE_FCNTL_PRAGMA {
@@ -587,8 +2709,43 @@ unsafe extern "C" fn x_file_control<T: Vfs>(
                     .map(|p| CStr::from_ptr(p).to_string_lossy())
             };
             let pragma = Pragma { name: &name, arg: arg.as_deref() };
+            let handle = unsafe { file.handle.assume_init_mut() };
+
+            let pragma_result = if pragma
+                .name
+                .eq_ignore_ascii_case("sqlite_plugin_debug_state")
+            {
+                let state = unsafe { file.state.assume_init_ref() };
+                Ok(Some(debug_state(state, vfs.file_size(handle))))
+            } else if pragma
+                .name
+                .eq_ignore_ascii_case("sqlite_plugin_database_files")
+            {
+                let appdata = unwrap_appdata!(file.vfs, T)?;
+                if !appdata.database_files_pragma {
+                    return Err(vars::SQLITE_NOTFOUND);
+                }
+                let state = unsafe { file.state.assume_init_ref() };
+                let main = state.info.name.as_deref().unwrap_or_default();
+                let now = current_time_ms(file.vfs);
+                Ok(Some(database_files(&appdata.open_files.lock(), main, now)))
+            } else if pragma.name.eq_ignore_ascii_case("sqlite_plugin_frozen") {
+                let appdata = unwrap_appdata!(file.vfs, T)?;
+                let state = unsafe { file.state.assume_init_ref() };
+                frozen_pragma(&appdata.frozen, state, &pragma)
+            } else if let Some(name) = pragma
+                .name
+                .eq_ignore_ascii_case("vfsname")
+                .then(|| unsafe { vfs_name(file.vfs, vfs) })
+                .transpose()?
+                .flatten()
+            {
+                Ok(Some(name))
+            } else {
+                vfs.pragma(handle, pragma)
+            };
 
-            let (result, msg) = match vfs.pragma(unsafe { file.handle.assume_init_mut() }, pragma) {
+            let (result, msg) = match pragma_result {
                 Ok(msg) => (Ok(vars::SQLITE_OK), msg),
                 Err(PragmaErr::NotFound) => (Err(vars::SQLITE_NOTFOUND), None),
                 Err(PragmaErr::Fail(err, msg)) => (Err(err), msg),
@@ -608,7 +2765,215 @@ unsafe extern "C" fn x_file_control<T: Vfs>(
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.file_control(unsafe { file.handle.assume_init_mut() }, op, p_arg)?;
+        if op == vars::SQLITE_FCNTL_TEMPFILENAME {
+            let name = vfs
+                .temp_filename()
+                .map_err(|err| err.code(vars::SQLITE_IOERR))?;
+            let appdata = unwrap_appdata!(file.vfs, T)?;
+            let out =
+                unsafe { p_arg.cast::<*mut c_char>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+            // SQLite is responsible for eventually freeing the name
+            *out = sqlite3_mprintf(&appdata.sqlite_api, &name)?;
+            return Ok(vars::SQLITE_OK);
+        }
+        if op == vars::SQLITE_FCNTL_VFSNAME {
+            if let Some(name) = unsafe { vfs_name(file.vfs, vfs)? } {
+                let appdata = unwrap_appdata!(file.vfs, T)?;
+                let out =
+                    unsafe { p_arg.cast::<*mut c_char>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+                // SQLite is responsible for eventually freeing the name
+                *out = sqlite3_mprintf(&appdata.sqlite_api, &name)?;
+                return Ok(vars::SQLITE_OK);
+            }
+        }
+        let op = unsafe { FileControlOp::decode(op, p_arg) };
+        let atomic_write = matches!(
+            op,
+            FileControlOp::BeginAtomicWrite
+                | FileControlOp::CommitAtomicWrite
+                | FileControlOp::RollbackAtomicWrite
+        )
+        .then(|| format!("{op:?}"));
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let result = vfs.file_control(handle, op);
+        if let (Some(op), Err(VfsError::NotFound)) = (atomic_write, result) {
+            // SQLite only batches writes on files which claim to support it,
+            // and a rejected batch means the vfs lied about its capabilities
+            let appdata = unwrap_appdata!(file.vfs, T)?;
+            if appdata.strict
+                && vfs.device_characteristics(handle) & vars::SQLITE_IOCAP_BATCH_ATOMIC != 0
+            {
+                let state = unsafe { file.state.assume_init_ref() };
+                let msg = format_args!(
+                    "device characteristics include SQLITE_IOCAP_BATCH_ATOMIC, but {op} is not handled"
+                );
+                return Err(violation(appdata, state, vars::SQLITE_NOTFOUND, msg));
+            }
+        }
+        result.map_err(|err| err.code(vars::SQLITE_IOERR))?;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+// the name `vfs` reports for itself, given the sqlite3_vfs it is registered as
+unsafe fn vfs_name<T: Vfs>(
+    p_vfs: *mut ffi::sqlite3_vfs,
+    vfs: &T,
+) -> Result<Option<String>, SqliteErr> {
+    let p_vfs = unsafe { p_vfs.as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
+    let registered = unsafe { lossy_cstr(p_vfs.zName)? };
+    Ok(vfs.vfs_name(&registered))
+}
+
+unsafe extern "C" fn x_shm_map<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    i_pg: c_int,
+    pgsz: c_int,
+    b_extend: c_int,
+    pp: *mut *mut c_void,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let (region, size): (usize, usize) = i_pg
+            .try_into()
+            .ok()
+            .zip(pgsz.try_into().ok())
+            .ok_or(vars::SQLITE_IOERR_SHMMAP)?;
+        let pp = unsafe { pp.as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let region = vfs
+            .shm_map(handle, region, size, b_extend != 0)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_SHMMAP))?;
+        *pp = region.map_or(null_mut(), |p| p.as_ptr().cast());
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_shm_lock<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    offset: c_int,
+    n: c_int,
+    flags: c_int,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let (offset, count): (u32, u32) = offset
+            .try_into()
+            .ok()
+            .zip(n.try_into().ok())
+            .ok_or(vars::SQLITE_IOERR_SHMLOCK)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        vfs.shm_lock(handle, offset, count, flags.into())
+            .map_err(|err| err.code(vars::SQLITE_IOERR_SHMLOCK))?;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_shm_barrier<T: Vfs>(p_file: *mut ffi::sqlite3_file) {
+    if let Ok(file) = unwrap_file!(p_file, T) {
+        if let Ok(vfs) = unwrap_vfs!(file.vfs, T) {
+            vfs.shm_barrier(unsafe { file.handle.assume_init_mut() });
+        }
+    }
+}
+
+// the shared memory methods used with `RegisterOpts::readonly_wal`, which
+// never map a wal-index. For read-only connections SQLITE_READONLY_CANTINIT
+// makes SQLite build the wal-index in heap memory from the WAL instead.
+unsafe extern "C" fn x_readonly_shm_map<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    _i_pg: c_int,
+    _pgsz: c_int,
+    _b_extend: c_int,
+    pp: *mut *mut c_void,
+) -> c_int {
+    fallible(|| {
+        let pp = unsafe { pp.as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+        *pp = null_mut();
+        let file = unwrap_file!(p_file, T)?;
+        // the handle may be shared with writable opens, so check this open
+        let state = unsafe { file.state.assume_init_ref() };
+        if state.info.opts.mode().is_readonly() {
+            Err(vars::SQLITE_READONLY_CANTINIT)
+        } else {
+            Err(vars::SQLITE_IOERR_SHMMAP)
+        }
+    })
+}
+
+unsafe extern "C" fn x_readonly_shm_lock(
+    _p_file: *mut ffi::sqlite3_file,
+    _offset: c_int,
+    _n: c_int,
+    _flags: c_int,
+) -> c_int {
+    vars::SQLITE_OK
+}
+
+unsafe extern "C" fn x_readonly_shm_barrier(_p_file: *mut ffi::sqlite3_file) {}
+
+unsafe extern "C" fn x_readonly_shm_unmap(
+    _p_file: *mut ffi::sqlite3_file,
+    _delete_flag: c_int,
+) -> c_int {
+    vars::SQLITE_OK
+}
+
+unsafe extern "C" fn x_fetch<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    i_ofst: ffi::sqlite3_int64,
+    i_amt: c_int,
+    pp: *mut *mut c_void,
+) -> c_int {
+    fallible(|| {
+        let pp = unsafe { pp.as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+        *pp = null_mut();
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let (offset, len): (usize, usize) = i_ofst
+            .try_into()
+            .ok()
+            .zip(i_amt.try_into().ok())
+            .ok_or(vars::SQLITE_IOERR_MMAP)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let ptr = vfs
+            .fetch(handle, offset, len)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_MMAP))?;
+        if let Some(ptr) = ptr {
+            *pp = ptr.as_ptr().cast();
+        }
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_unfetch<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    i_ofst: ffi::sqlite3_int64,
+    p: *mut c_void,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let offset: usize = i_ofst.try_into().map_err(|_| vars::SQLITE_IOERR_MMAP)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        vfs.unfetch(handle, offset, NonNull::new(p.cast()))
+            .map_err(|err| err.code(vars::SQLITE_IOERR_MMAP))?;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_shm_unmap<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    delete_flag: c_int,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        vfs.shm_unmap(handle, delete_flag != 0)
+            .map_err(|err| err.code(vars::SQLITE_IOERR_SHMMAP))?;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -619,7 +2984,8 @@ unsafe extern "C" fn x_sector_size<T: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
-        Ok(vfs.sector_size())
+        let size = vfs.sector_size(unsafe { file.handle.assume_init_mut() });
+        Ok(size.try_into().unwrap_or(c_int::MAX))
     })
 }
 
@@ -627,7 +2993,7 @@ unsafe extern "C" fn x_device_characteristics<T: Vfs>(p_file: *mut ffi::sqlite3_
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
-        Ok(vfs.device_characteristics())
+        Ok(vfs.device_characteristics(unsafe { file.handle.assume_init_mut() }))
     })
 }
 
@@ -662,7 +3028,7 @@ unsafe extern "C" fn x_dlsym<T: Vfs>(
     p_handle: *mut c_void,
     z_symbol: *const c_char,
 ) -> Option<
-    unsafe extern "C" fn(arg1: *mut ffi::sqlite3_vfs, arg2: *mut c_void, zSymbol: *const c_char),
+    unsafe extern "C" fn(arg1: *mut ffi::sqlite3_vfs, arg2: *mut c_void, z_symbol: *const c_char),
 > {
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_dlsym) = vfs.xDlSym {
@@ -680,11 +3046,35 @@ unsafe extern "C" fn x_dlclose<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs, p_handle: *
     }
 }
 
+// the difference between the julian day epoch which SQLite measures time
+// from and the unix epoch
+pub(crate) const UNIX_EPOCH_JULIAN_MS: i64 = 210_866_760_000_000;
+
+// sleeps with the vfs, or the default vfs if it does not implement sleeping
+fn sleep<T: Vfs>(appdata: &AppData<T>, duration: Duration) -> Duration {
+    if let Some(slept) = appdata.vfs.sleep(duration) {
+        return slept;
+    }
+    let micros = c_int::try_from(duration.as_micros()).unwrap_or(c_int::MAX);
+    let slept = unsafe { appdata.base_vfs.as_mut() }
+        .and_then(|base_vfs| Some(unsafe { base_vfs.xSleep?(base_vfs, micros) }))
+        .unwrap_or(0);
+    Duration::from_micros(slept.max(0) as u64)
+}
+
 unsafe extern "C" fn x_randomness<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     n_byte: c_int,
     z_out: *mut c_char,
 ) -> c_int {
+    if let (Ok(appdata), Ok(n_byte)) = (unwrap_appdata!(p_vfs, T), usize::try_from(n_byte)) {
+        if !z_out.is_null() {
+            let buf = unsafe { slice::from_raw_parts_mut(z_out.cast::<u8>(), n_byte) };
+            if let Some(n) = appdata.vfs.randomness(buf) {
+                return n.min(n_byte) as c_int;
+            }
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_randomness) = vfs.xRandomness {
             return unsafe { x_randomness(vfs, n_byte, z_out) };
@@ -694,21 +3084,55 @@ unsafe extern "C" fn x_randomness<T: Vfs>(
 }
 
 unsafe extern "C" fn x_sleep<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
-    if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
-        if let Some(x_sleep) = vfs.xSleep {
-            return unsafe { x_sleep(vfs, microseconds) };
-        }
+    if let Ok(appdata) = unwrap_appdata!(p_vfs, T) {
+        let duration = Duration::from_micros(microseconds.max(0) as u64);
+        let slept = sleep(appdata, duration);
+        return c_int::try_from(slept.as_micros()).unwrap_or(c_int::MAX);
     }
     vars::SQLITE_INTERNAL
 }
 
-unsafe extern "C" fn x_current_time<T: Vfs>(
+unsafe extern "C" fn x_get_last_error<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
-    p_time: *mut f64,
+    n_byte: c_int,
+    z_err_msg: *mut c_char,
 ) -> c_int {
-    if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
-        if let Some(x_current_time) = vfs.xCurrentTime {
-            return unsafe { x_current_time(vfs, p_time) };
+    let Ok(appdata) = unwrap_appdata!(p_vfs, T) else {
+        return 0;
+    };
+    let Some((errno, msg)) = appdata.vfs.last_error() else {
+        return 0;
+    };
+    if appdata.log_last_error {
+        let line = format!("vfs error {errno}: {msg}");
+        SqliteLogger::new(appdata.sqlite_api.log).log(SqliteLogLevel::Error, line.as_bytes());
+    }
+
+    // SQLite passes no buffer when it only wants the error number
+    if let Ok(n_byte @ 1..) = usize::try_from(n_byte) {
+        if !z_err_msg.is_null() {
+            let out = unsafe { slice::from_raw_parts_mut(z_err_msg.cast::<u8>(), n_byte) };
+            let from = &msg.as_bytes()[..msg.len().min(n_byte - 1)];
+            out[..from.len()].copy_from_slice(from);
+            out[from.len()] = 0;
+        }
+    }
+    errno
+}
+
+unsafe extern "C" fn x_current_time<T: Vfs>(
+    p_vfs: *mut ffi::sqlite3_vfs,
+    p_time: *mut f64,
+) -> c_int {
+    if let Ok(appdata) = unwrap_appdata!(p_vfs, T) {
+        if let (Some(now), Some(out)) = (appdata.vfs.current_time(), unsafe { p_time.as_mut() }) {
+            *out = (now + UNIX_EPOCH_JULIAN_MS) as f64 / 86_400_000.0;
+            return vars::SQLITE_OK;
+        }
+    }
+    if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
+        if let Some(x_current_time) = vfs.xCurrentTime {
+            return unsafe { x_current_time(vfs, p_time) };
         }
     }
     vars::SQLITE_INTERNAL
@@ -718,6 +3142,12 @@ unsafe extern "C" fn x_current_time_int64<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     p_time: *mut i64,
 ) -> c_int {
+    if let Ok(appdata) = unwrap_appdata!(p_vfs, T) {
+        if let (Some(now), Some(out)) = (appdata.vfs.current_time(), unsafe { p_time.as_mut() }) {
+            *out = now + UNIX_EPOCH_JULIAN_MS;
+            return vars::SQLITE_OK;
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_current_time_int64) = vfs.xCurrentTimeInt64 {
             return unsafe { x_current_time_int64(vfs, p_time) };
@@ -736,7 +3166,9 @@ mod tests {
         flags::{CreateMode, OpenKind, OpenMode},
         mock::*,
     };
-    use alloc::vec::Vec;
+    use alloc::{sync::Arc, vec::Vec};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use parking_lot::Mutex;
     use rusqlite::{Connection, OpenFlags};
     use std::{boxed::Box, io::Write, println};
 
@@ -746,13 +3178,15 @@ mod tests {
 
     #[test]
     fn sanity() -> Result<(), Box<dyn std::error::Error>> {
+        // the log callback is process wide, like the log hook
+        let _hook = crate::logger::TEST_LOG_HOOK.lock();
         unsafe {
             rusqlite::trace::config_log(Some(log_handler)).unwrap();
         }
 
         struct H {}
         impl Hooks for H {
-            fn open(&mut self, path: &Option<&str>, opts: &OpenOpts) {
+            fn open(&mut self, path: &Option<&str>, opts: &OpenOpts, _params: &UriParams<'_>) {
                 let path = path.unwrap();
                 if path == "main.db" {
                     assert!(!opts.delete_on_close());
@@ -778,7 +3212,7 @@ mod tests {
         register_static(
             CString::new("mock").unwrap(),
             vfs,
-            RegisterOpts { make_default: true },
+            RegisterOpts { make_default: true, ..Default::default() },
         )
         .map_err(|_| "failed to register vfs")?;
 
@@ -820,4 +3254,1407 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn on_change() -> Result<(), Box<dyn std::error::Error>> {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let on_change = {
+            let changes = changes.clone();
+            Box::new(move |path: &str, counter: u32| {
+                changes.lock().push((path.to_string(), counter));
+            })
+        };
+
+        register_static(
+            CString::new("mock_on_change").unwrap(),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts {
+                on_change: Some(on_change),
+                ..Default::default()
+            },
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_on_change",
+        )?;
+
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1)", [])?;
+        let n: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 1);
+
+        // each write transaction is reported once with an increasing counter,
+        // while the read-only query is not reported at all
+        let changes = changes.lock();
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|(path, _)| path == "main.db"));
+        assert!(changes[0].1 < changes[1].1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_policy() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            syncs: Arc<Mutex<Vec<SyncFlags>>>,
+        }
+        impl Hooks for H {
+            fn sync(&mut self, _handle: MockHandle, flags: SyncFlags) {
+                self.syncs.lock().push(flags);
+            }
+        }
+
+        let syncs = |name: &str, sync_policy: SyncPolicy| {
+            let syncs = Arc::new(Mutex::new(Vec::new()));
+            let vfs = MockVfs::new(Box::new(H { syncs: syncs.clone() }));
+            let opts = RegisterOpts { sync_policy, ..Default::default() };
+            register_static(CString::new(name).unwrap(), vfs, opts)
+                .map_err(|_| "failed to register vfs")?;
+
+            let conn = Connection::open_with_flags_and_vfs(
+                "main.db",
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                name,
+            )?;
+            conn.execute("create table t (val int)", [])?;
+            conn.execute("insert into t (val) values (1)", [])?;
+            let syncs = syncs.lock().clone();
+            Ok::<_, Box<dyn std::error::Error>>(syncs)
+        };
+        let all = |mode| SyncPolicy {
+            main_db: mode,
+            main_journal: mode,
+            ..Default::default()
+        };
+
+        let always = syncs("mock_sync_always", SyncPolicy::default())?;
+        assert!(!always.is_empty());
+        assert!(
+            always
+                .iter()
+                .all(|flags| !flags.is_full() && !flags.is_barrier())
+        );
+
+        let full = syncs("mock_sync_full", all(SyncMode::Full))?;
+        assert_eq!(full.len(), always.len());
+        assert!(full.iter().all(|flags| flags.is_full()));
+
+        let barrier = syncs("mock_sync_barrier", all(SyncMode::Barrier))?;
+        assert_eq!(barrier.len(), always.len());
+        assert!(
+            barrier
+                .iter()
+                .all(|flags| flags.data_only() && flags.is_barrier() && !flags.is_full())
+        );
+
+        assert!(syncs("mock_sync_never", all(SyncMode::Never))?.is_empty());
+
+        Ok(())
+    }
+    // runs one invariant violation through the io methods of a vfs
+    // registered with `strict`, and returns the result code SQLite would see
+    fn strict_violation(case: &str, strict: bool) -> c_int {
+        // a shim which reports every write as one byte short, and claims
+        // batch atomic writes which it rejects
+        struct ShortWrites(MockVfs);
+        impl crate::shim::VfsShim for ShortWrites {
+            type Inner = MockVfs;
+            type Handle = MockHandle;
+
+            fn inner(&self) -> &MockVfs {
+                &self.0
+            }
+
+            fn open(
+                &self,
+                path: Option<&str>,
+                opts: OpenOpts,
+                params: UriParams<'_>,
+            ) -> VfsResult<MockHandle> {
+                self.0.open(path, opts, params)
+            }
+
+            fn write(
+                &self,
+                handle: &mut MockHandle,
+                offset: usize,
+                buf: &[u8],
+            ) -> VfsResult<usize> {
+                self.0.write(handle, offset, buf).map(|n| n - 1)
+            }
+
+            fn device_characteristics(&self, handle: &mut MockHandle) -> i32 {
+                self.0.device_characteristics(handle) | vars::SQLITE_IOCAP_BATCH_ATOMIC
+            }
+        }
+        crate::impl_vfs_shim!(for ShortWrites);
+
+        let name = format!("mock_strict_{case}_{strict}");
+        let vfs = ShortWrites(MockVfs::new(Box::new(NoopHooks)));
+        let opts = RegisterOpts { strict, ..Default::default() };
+        register_static(CString::new(name.as_str()).unwrap(), vfs, opts).unwrap();
+
+        let name = CString::new(name).unwrap();
+        let p_vfs = unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) };
+        let sz_os_file = unsafe { (*p_vfs).szOsFile } as usize;
+        let mut file = alloc::vec![0u64; sz_os_file.div_ceil(8)];
+        let p_file = file.as_mut_ptr().cast::<ffi::sqlite3_file>();
+        let flags =
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE;
+        let rc = unsafe {
+            (*p_vfs).xOpen.unwrap()(p_vfs, c"main.db".as_ptr(), p_file, flags, null_mut())
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+
+        let methods = unsafe { *(*p_file).pMethods };
+        let mut buf = [0u8; 4];
+        let p_buf = buf.as_mut_ptr().cast::<c_void>();
+        unsafe {
+            let rc = match case {
+                "lock" => methods.xLock.unwrap()(p_file, vars::SQLITE_LOCK_RESERVED),
+                "offset" => methods.xRead.unwrap()(p_file, p_buf, 4, -1),
+                "short_write" => methods.xWrite.unwrap()(p_file, p_buf, 4, 0),
+                "batch_atomic" => methods.xFileControl.unwrap()(
+                    p_file,
+                    vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE,
+                    null_mut(),
+                ),
+                "closed" => {
+                    assert_eq!(methods.xClose.unwrap()(p_file), vars::SQLITE_OK);
+                    return methods.xRead.unwrap()(p_file, p_buf, 4, 0);
+                }
+                _ => unreachable!(),
+            };
+            methods.xClose.unwrap()(p_file);
+            rc
+        }
+    }
+
+    #[test]
+    fn strict() {
+        // strict mode aborts the process, so each violation is repeated in a
+        // child process running only this test
+        const CASE_VAR: &str = "SQLITE_PLUGIN_STRICT_CASE";
+        if let Ok(case) = std::env::var(CASE_VAR) {
+            strict_violation(&case, true);
+            return;
+        }
+
+        let cases = [
+            (
+                "lock",
+                vars::SQLITE_OK,
+                "invalid lock transition from Unlocked to Reserved",
+            ),
+            (
+                "offset",
+                vars::SQLITE_IOERR_READ,
+                "invalid read of 4 bytes at offset -1",
+            ),
+            (
+                "short_write",
+                vars::SQLITE_IOERR_WRITE,
+                "short write of 3 out of 4 bytes",
+            ),
+            (
+                "batch_atomic",
+                vars::SQLITE_NOTFOUND,
+                "device characteristics include SQLITE_IOCAP_BATCH_ATOMIC, but BeginAtomicWrite is not handled",
+            ),
+            ("closed", vars::SQLITE_MISUSE, "io on a closed file"),
+        ];
+        for (case, rc, msg) in cases {
+            // otherwise invalid lock transitions are forwarded as is, and
+            // the rest fail with an error code
+            assert_eq!(strict_violation(case, false), rc, "{case}");
+
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "vfs::tests::strict",
+                    "--nocapture",
+                    "--test-threads=1",
+                ])
+                .env(CASE_VAR, case)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(!output.status.success(), "{case} did not abort");
+            assert!(
+                stderr.contains(&format!("sqlite-plugin: {msg}")),
+                "{case}: {stderr}"
+            );
+        }
+    }
+
+    #[test]
+    fn lock_transitions() {
+        use LockLevel::*;
+        assert!(valid_lock_transition(Unlocked, Shared));
+        assert!(valid_lock_transition(Shared, Reserved));
+        assert!(valid_lock_transition(Shared, Exclusive));
+        assert!(valid_lock_transition(Reserved, Exclusive));
+        assert!(valid_lock_transition(Pending, Exclusive));
+        assert!(valid_lock_transition(Exclusive, Shared));
+
+        assert!(!valid_lock_transition(Unlocked, Reserved));
+        assert!(!valid_lock_transition(Unlocked, Exclusive));
+        assert!(!valid_lock_transition(Shared, Pending));
+        assert!(!valid_lock_transition(Reserved, Pending));
+    }
+    #[test]
+    fn raw_pointers() {
+        let mut wrapper = MaybeUninit::<FileWrapper<MockHandle>>::zeroed();
+        let wrapper = unsafe { wrapper.assume_init_mut() };
+        let p_vfs = 0x1000 as *mut ffi::sqlite3_vfs;
+        wrapper.vfs = p_vfs;
+        let handle = wrapper.handle.write(MockHandle::new(0, false));
+
+        let p_file = unsafe { raw_file(handle) };
+        assert_eq!(p_file, &raw mut wrapper.file);
+        assert_eq!(unsafe { raw_vfs(handle) }, p_vfs);
+    }
+
+    #[test]
+    fn lookup_registered() {
+        let vfs = MockVfs::new(Box::new(NoopHooks));
+        register_static(
+            CString::new("mock_lookup").unwrap(),
+            vfs,
+            RegisterOpts::default(),
+        )
+        .unwrap();
+
+        assert!(lookup::<MockVfs>("mock_lookup").is_some());
+        // the wrong type or an unknown name finds nothing
+        assert!(lookup::<crate::leak::LeakCheck<MockVfs>>("mock_lookup").is_none());
+        assert!(lookup::<MockVfs>("mock_missing").is_none());
+
+        // the mock vfs has no shared memory or memory mapping support
+        let capabilities = capabilities("mock_lookup").unwrap();
+        assert!(!capabilities.wal);
+        assert!(!capabilities.mmap);
+        assert!(!capabilities.batch_atomic);
+    }
+
+    #[test]
+    fn find_and_list() {
+        register_static(
+            CString::from(c"mock_list"),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .unwrap();
+
+        let info = find(Some("mock_list")).unwrap();
+        assert_eq!(info.max_pathname, DEFAULT_MAX_PATH_LEN as usize);
+        assert!(!info.is_default);
+        assert!(find(None).unwrap().is_default);
+        assert!(find(Some("mock_missing")).is_none());
+
+        // the list also holds the vfs built into SQLite
+        let list = list();
+        assert!(list[0].is_default);
+        assert!(list.contains(&info));
+        assert!(
+            list.iter()
+                .any(|vfs| vfs.name == "unix" || vfs.name == "win32")
+        );
+    }
+
+    #[test]
+    fn register_opts_io() -> Result<(), Box<dyn std::error::Error>> {
+        struct ShmHooks;
+        impl Hooks for ShmHooks {
+            fn supports_shm(&mut self) -> bool {
+                true
+            }
+        }
+
+        register_static(
+            CString::from(c"mock_io_v1"),
+            MockVfs::new(Box::new(ShmHooks)),
+            RegisterOpts {
+                max_pathname: 4096,
+                io_version: IoVersion::V1,
+                ..Default::default()
+            },
+        )
+        .map_err(|_| "failed to register vfs")?;
+        assert_eq!(find(Some("mock_io_v1")).unwrap().max_pathname, 4096);
+        assert!(!capabilities("mock_io_v1").unwrap().wal);
+
+        // paths past the default limit fit
+        let path = "a/".repeat(DEFAULT_MAX_PATH_LEN as usize);
+        let conn = Connection::open_with_flags_and_vfs(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_io_v1",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        // without shared memory methods WAL mode is refused
+        let mode: String = conn.query_row("pragma journal_mode = wal", [], |row| row.get(0))?;
+        assert_eq!(mode, "delete");
+
+        let opts = RegisterOpts { max_pathname: 0, ..Default::default() };
+        let err = register_static(
+            CString::from(c"mock_io_invalid"),
+            MockVfs::new(Box::new(NoopHooks)),
+            opts,
+        );
+        assert_eq!(err, Err(VfsError::Other(vars::SQLITE_MISUSE)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scoped_registration() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            dropped: Arc<AtomicBool>,
+        }
+        impl Hooks for H {}
+        impl Drop for H {
+            fn drop(&mut self) {
+                self.dropped.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let vfs = MockVfs::new(Box::new(H { dropped: dropped.clone() }));
+        let registration = unsafe {
+            register_scoped(CString::from(c"mock_scoped"), vfs, RegisterOpts::default())?
+        };
+        assert_eq!(registration.name(), "mock_scoped");
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_scoped",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        // the vfs can't be unregistered while the database is open
+        assert_eq!(unsafe { unregister("mock_scoped") }, Err(VfsError::Busy));
+        drop(conn);
+        drop(registration);
+        assert!(dropped.load(Ordering::Relaxed));
+        assert!(lookup::<MockVfs>("mock_scoped").is_none());
+        assert_eq!(
+            unsafe { unregister("mock_scoped") },
+            Err(VfsError::NotFound)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_state_pragma() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::new("mock_debug_state").unwrap(),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts {
+                database_files_pragma: true,
+                ..Default::default()
+            },
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let open = |path| {
+            Connection::open_with_flags_and_vfs(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                "mock_debug_state",
+            )
+        };
+        let conn = open("main.db")?;
+        conn.execute("create table t (val int)", [])?;
+        // files of other databases are not listed
+        let other = open("other.db")?;
+        other.execute("create table t (val int)", [])?;
+
+        // pragmas are handled while the statement is prepared, outside of any transaction
+        let state: String =
+            conn.query_row("pragma sqlite_plugin_debug_state", [], |row| row.get(0))?;
+        let lines: Vec<&str> = state.lines().collect();
+        assert!(lines.contains(&"name=main.db"));
+        assert!(lines.contains(&"kind=MainDb"));
+        assert!(lines.contains(&"lock=Unlocked"));
+        assert!(lines.contains(&"file_size=8192"));
+
+        // only the main db is open once the journal has been deleted
+        let files: String =
+            conn.query_row("pragma sqlite_plugin_database_files", [], |row| row.get(0))?;
+        assert_eq!(
+            files,
+            "main.db kind=MainDb lock=Unlocked size=8192 last_op=Unlock"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_files_opt_in() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::new("mock_database_files").unwrap(),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_database_files",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        // while it is off the pragma is unknown, and returns no rows
+        let files = conn.query_row("pragma sqlite_plugin_database_files", [], |row| {
+            row.get::<_, String>(0)
+        });
+        assert_eq!(files, Err(rusqlite::Error::QueryReturnedNoRows));
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_files_report() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::new("mock_open_files").unwrap(),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_open_files",
+        )?;
+        conn.execute_batch("create table t (val int); begin; insert into t values (1);")?;
+
+        // the open write transaction holds a reserved lock and the journal
+        let files = open_files("mock_open_files").unwrap();
+        assert_eq!(files.len(), 2);
+        let main = files.iter().find(|f| f.kind == OpenKind::MainDb).unwrap();
+        assert_eq!(main.name.as_deref(), Some("main.db"));
+        assert_eq!(main.lock, LockLevel::Reserved);
+        assert!(main.lock_held_ms.is_some());
+        assert!(files.iter().any(|f| f.kind == OpenKind::MainJournal));
+
+        conn.execute_batch("commit")?;
+        let files = open_files("mock_open_files").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].lock, LockLevel::Unlocked);
+        assert_eq!(files[0].last_op, Some(FileOp::Unlock));
+
+        Ok(())
+    }
+
+    #[test]
+    fn wal_mode() -> Result<(), Box<dyn std::error::Error>> {
+        struct ShmHooks;
+        impl Hooks for ShmHooks {
+            fn supports_shm(&mut self) -> bool {
+                true
+            }
+        }
+
+        let open = |name: &str, hooks: Box<dyn Hooks + Send>| {
+            register_static(
+                CString::new(name).unwrap(),
+                MockVfs::new(hooks),
+                RegisterOpts::default(),
+            )
+            .map_err(|_| "failed to register vfs")?;
+            let conn = Connection::open_with_flags_and_vfs(
+                "main.db",
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                name,
+            )?;
+            let mode: String = conn.query_row("pragma journal_mode = wal", [], |row| row.get(0))?;
+            Ok::<_, Box<dyn std::error::Error>>((conn, mode))
+        };
+
+        // without shared memory SQLite keeps the rollback journal
+        let (_, mode) = open("mock_no_shm", Box::new(NoopHooks))?;
+        assert_eq!(mode, "delete");
+
+        let (conn, mode) = open("mock_shm", Box::new(ShmHooks))?;
+        assert_eq!(mode, "wal");
+        assert!(capabilities("mock_shm").unwrap().wal);
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1), (2)", [])?;
+        let n: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mmap_fallback() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::from(c"mock_mmap"),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        assert!(!capabilities("mock_mmap").unwrap().mmap);
+
+        // fetch returns nothing by default, so SQLite reads pages normally
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_mmap",
+        )?;
+        conn.execute_batch("pragma mmap_size = 1048576")?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1), (2)", [])?;
+        let n: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_probe() {
+        struct Unreachable;
+        impl Hooks for Unreachable {
+            fn probe(&mut self) -> VfsResult<()> {
+                Err(VfsError::CantOpen)
+            }
+        }
+
+        let result = register_static(
+            CString::from(c"mock_unreachable"),
+            MockVfs::new(Box::new(Unreachable)),
+            RegisterOpts::default(),
+        );
+        assert_eq!(result, Err(VfsError::CantOpen));
+        assert!(lookup::<MockVfs>("mock_unreachable").is_none());
+    }
+
+    #[test]
+    fn register_batch() {
+        struct Unreachable;
+        impl Hooks for Unreachable {
+            fn probe(&mut self) -> VfsResult<()> {
+                Err(VfsError::CantOpen)
+            }
+        }
+        let mock = || MockVfs::new(Box::new(NoopHooks));
+
+        let mut batch = RegisterBatch::new();
+        batch
+            .register(c"mock_batch_ok".into(), mock(), RegisterOpts::default())
+            .register(
+                c"mock_batch_unreachable".into(),
+                MockVfs::new(Box::new(Unreachable)),
+                RegisterOpts::default(),
+            )
+            .register(c"mock_batch_after".into(), mock(), RegisterOpts::default());
+        // the vfs are usable until the batch is done
+        assert!(lookup::<MockVfs>("mock_batch_ok").is_some());
+        let err = batch.commit().unwrap_err();
+        assert_eq!(
+            err.failures,
+            [("mock_batch_unreachable".to_string(), VfsError::CantOpen)]
+        );
+        assert_eq!(err.code(), vars::SQLITE_CANTOPEN);
+        assert_eq!(
+            err.to_string(),
+            "failed to register mock_batch_unreachable (unable to open file, code 14)"
+        );
+        // every vfs of a failed batch is unregistered
+        assert!(lookup::<MockVfs>("mock_batch_ok").is_none());
+        assert!(lookup::<MockVfs>("mock_batch_after").is_none());
+        assert!(find(Some("mock_batch_ok")).is_none());
+
+        let mut batch = RegisterBatch::new();
+        batch.register(
+            c"mock_batch_dropped".into(),
+            mock(),
+            RegisterOpts::default(),
+        );
+        drop(batch);
+        assert!(lookup::<MockVfs>("mock_batch_dropped").is_none());
+
+        let mut batch = RegisterBatch::new();
+        batch
+            .register(c"mock_batch_a".into(), mock(), RegisterOpts::default())
+            .register(c"mock_batch_b".into(), mock(), RegisterOpts::default());
+        assert_eq!(batch.commit(), Ok(()));
+        assert!(lookup::<MockVfs>("mock_batch_a").is_some());
+        assert!(lookup::<MockVfs>("mock_batch_b").is_some());
+    }
+
+    #[test]
+    fn chunking() {
+        let mut chunking = Chunking::default();
+        assert_eq!(chunking.round(1000), 1000);
+        assert!(!chunking.file_control(&FileControlOp::SizeHint(1000)));
+        assert!(chunking.file_control(&FileControlOp::ChunkSize(4096)));
+        assert_eq!(chunking.chunk_size(), 4096);
+        assert_eq!(chunking.round(0), 0);
+        assert_eq!(chunking.round(1), 4096);
+        assert_eq!(chunking.round(8192), 8192);
+        assert_eq!(chunking.round(8193), 12288);
+    }
+
+    #[test]
+    fn pragma_values() {
+        let pragma = |arg| Pragma { name: "cache_limit", arg };
+        assert!(pragma(None).is_query());
+        assert_eq!(pragma(None).as_bool().unwrap(), None);
+        assert_eq!(pragma(Some("ON")).as_bool().unwrap(), Some(true));
+        assert_eq!(pragma(Some("no")).as_bool().unwrap(), Some(false));
+        assert_eq!(pragma(Some("2")).as_bool().unwrap(), Some(true));
+        assert_eq!(pragma(Some("0")).as_bool().unwrap(), Some(false));
+        assert!(pragma(Some("maybe")).as_bool().is_err());
+
+        assert_eq!(pragma(None).as_u64().unwrap(), None);
+        assert_eq!(pragma(Some("4096")).as_u64().unwrap(), Some(4096));
+        match pragma(Some("-1")).as_u64() {
+            Err(PragmaErr::Fail(code, msg)) => {
+                assert_eq!(code, vars::SQLITE_ERROR);
+                assert_eq!(msg.as_deref(), Some("invalid value for cache_limit: -1"));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(
+            VfsError::Io.code(vars::SQLITE_IOERR_READ),
+            vars::SQLITE_IOERR_READ
+        );
+        assert_eq!(
+            VfsError::Busy.code(vars::SQLITE_IOERR_LOCK),
+            vars::SQLITE_BUSY
+        );
+        assert_eq!(VfsError::from(vars::SQLITE_FULL), VfsError::Full);
+        // extended codes are passed through unchanged
+        let noent = VfsError::from(vars::SQLITE_IOERR_DELETE_NOENT);
+        assert_eq!(noent, VfsError::Other(vars::SQLITE_IOERR_DELETE_NOENT));
+        assert_eq!(
+            noent.code(vars::SQLITE_IOERR_DELETE),
+            vars::SQLITE_IOERR_DELETE_NOENT
+        );
+    }
+
+    #[test]
+    fn sync_flags() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            flags: Arc<Mutex<Vec<SyncFlags>>>,
+        }
+        impl Hooks for H {
+            fn sync(&mut self, _handle: MockHandle, flags: SyncFlags) {
+                self.flags.lock().push(flags);
+            }
+        }
+
+        let flags = Arc::new(Mutex::new(Vec::new()));
+        let vfs = MockVfs::new(Box::new(H { flags: flags.clone() }));
+        register_static(
+            CString::from(c"mock_sync_flags"),
+            vfs,
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_sync_flags",
+        )?;
+
+        conn.execute("create table t (val int)", [])?;
+        assert!(!flags.lock().is_empty());
+        assert!(flags.lock().iter().all(|flags| !flags.is_full()));
+
+        flags.lock().clear();
+        conn.execute_batch("pragma fullfsync = on")?;
+        conn.execute("insert into t (val) values (1)", [])?;
+        assert!(flags.lock().iter().any(|flags| flags.is_full()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn per_handle_queries() -> Result<(), Box<dyn std::error::Error>> {
+        struct SmallSectors;
+        impl Hooks for SmallSectors {
+            fn sector_size(&mut self, _handle: MockHandle) -> usize {
+                512
+            }
+            fn device_characteristics(&mut self, _handle: MockHandle) -> i32 {
+                vars::SQLITE_IOCAP_ATOMIC512
+            }
+        }
+
+        register_static(
+            CString::from(c"mock_sector_size"),
+            MockVfs::new(Box::new(SmallSectors)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_sector_size",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        let mut p_file: *mut ffi::sqlite3_file = null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle().cast(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_FILE_POINTER,
+                (&raw mut p_file).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        let methods = unsafe { &*(*p_file).pMethods };
+        assert_eq!(unsafe { methods.xSectorSize.unwrap()(p_file) }, 512);
+        assert_eq!(
+            unsafe { methods.xDeviceCharacteristics.unwrap()(p_file) },
+            vars::SQLITE_IOCAP_ATOMIC512
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn frozen_database() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::from(c"mock_frozen"),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_frozen",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        assert!(set_frozen("mock_frozen", "main.db", true));
+        let err = conn
+            .execute("insert into t (val) values (1)", [])
+            .unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(rusqlite::ErrorCode::ReadOnly));
+        // reads still work
+        let n: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 0);
+
+        let frozen: String = conn.query_row("pragma sqlite_plugin_frozen", [], |row| row.get(0))?;
+        assert_eq!(frozen, "1");
+        let frozen: String =
+            conn.query_row("pragma sqlite_plugin_frozen = off", [], |row| row.get(0))?;
+        assert_eq!(frozen, "0");
+        conn.execute("insert into t (val) values (1)", [])?;
+
+        assert!(!set_frozen("mock_missing", "main.db", true));
+        Ok(())
+    }
+
+    #[test]
+    fn pragma_errors() -> Result<(), Box<dyn std::error::Error>> {
+        struct H;
+        impl Hooks for H {
+            fn pragma(
+                &mut self,
+                _handle: MockHandle,
+                pragma: Pragma<'_>,
+            ) -> Result<Option<String>, PragmaErr> {
+                if pragma.name != "mem_limit" {
+                    return Err(PragmaErr::NotFound);
+                }
+                match pragma.as_u64()? {
+                    Some(limit) if limit > 100 => Err(PragmaErr::Fail(
+                        vars::SQLITE_ERROR,
+                        Some("limit exceeds configured maximum".to_string()),
+                    )),
+                    _ => Ok(None),
+                }
+            }
+        }
+
+        register_static(
+            CString::from(c"mock_pragma_errors"),
+            MockVfs::new(Box::new(H)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_pragma_errors",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        conn.execute_batch("pragma mem_limit = 10")?;
+        let err = conn
+            .execute_batch("pragma main.mem_limit = 1000")
+            .unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(rusqlite::ErrorCode::Unknown));
+        assert!(err.to_string().contains("limit exceeds configured maximum"));
+        let err = conn.execute_batch("pragma mem_limit = lots").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid value for mem_limit: lots")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn handle_limits() -> Result<(), Box<dyn std::error::Error>> {
+        let handle_limits = HandleLimits {
+            max_handles_per_file: Some(1),
+            policy: LimitPolicy::Wait { timeout_ms: 20 },
+            ..Default::default()
+        };
+        register_static(
+            CString::from(c"mock_limits"),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts { handle_limits, ..Default::default() },
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let open = |path| Connection::open_with_flags_and_vfs(path, flags, "mock_limits");
+
+        let conn = open("main.db")?;
+        conn.execute("create table t (val int)", [])?;
+        let err = open("main.db").unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy)
+        );
+        // other files have their own limit
+        open("other.db")?.execute("create table t (val int)", [])?;
+
+        drop(conn);
+        open("main.db")?;
+        Ok(())
+    }
+
+    #[test]
+    fn uri_params() -> Result<(), Box<dyn std::error::Error>> {
+        type Opened = (String, Option<String>, Vec<(String, String)>);
+        struct H {
+            opened: Arc<Mutex<Vec<Opened>>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, path: &Option<&str>, _opts: &OpenOpts, params: &UriParams<'_>) {
+                let path = path.unwrap_or_default().to_string();
+                let tier = params.get("tier").map(ToString::to_string);
+                let all = params
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                self.opened.lock().push((path, tier, all));
+            }
+        }
+
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let vfs = MockVfs::new(Box::new(H { opened: opened.clone() }));
+        register_static(CString::from(c"mock_uri"), vfs, RegisterOpts::default())
+            .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags(
+            "file:main.db?vfs=mock_uri&cache_key=abc&tier=hot",
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        let opened = opened.lock();
+        let (path, tier, all) = &opened[0];
+        assert_eq!(path, "main.db");
+        assert_eq!(tier.as_deref(), Some("hot"));
+        let expected = [("vfs", "mock_uri"), ("cache_key", "abc"), ("tier", "hot")];
+        assert!(
+            all.iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .eq(expected)
+        );
+        // the journal sees the parameters of its database
+        let (path, tier, _) = &opened[1];
+        assert_eq!(path, "main.db-journal");
+        assert_eq!(tier.as_deref(), Some("hot"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_reserved_lock() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            checks: Arc<AtomicUsize>,
+        }
+        impl Hooks for H {
+            fn check_reserved_lock(&mut self, _handle: MockHandle) -> bool {
+                self.checks.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+
+        let checks = Arc::new(AtomicUsize::new(0));
+        let vfs = MockVfs::new(Box::new(H { checks: checks.clone() }));
+        register_static(
+            CString::from(c"mock_reserved"),
+            vfs.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_reserved",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t (val) values (1)", [])?;
+
+        // a journal next to the database, as left by a writer in another
+        // process which is in the middle of a transaction
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_JOURNAL | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut journal = vfs.open(Some("main.db-journal"), opts, UriParams::empty())?;
+        vfs.write(&mut journal, 0, &[0xd9; 512])?;
+        vfs.close(journal)?;
+
+        // the journal belongs to a live writer, so it is not rolled back
+        let n: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 1);
+        assert!(checks.load(Ordering::Relaxed) > 0);
+        assert!(vfs.access("main.db-journal", AccessFlags::Exists)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn last_error() -> Result<(), Box<dyn std::error::Error>> {
+        struct H;
+        impl Hooks for H {
+            fn last_error(&mut self) -> Option<(i32, String)> {
+                Some((5, "block 7 is unreadable".to_string()))
+            }
+        }
+
+        let vfs = MockVfs::new(Box::new(H));
+        register_static(
+            CString::from(c"mock_last_error"),
+            vfs.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_last_error",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        // reads and writes through the open handle fail once the file is gone
+        vfs.delete("main.db")?;
+        let err = conn
+            .execute("insert into t (val) values (1)", [])
+            .unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::SystemIoFailure)
+        );
+        let errno = unsafe { ffi::sqlite3_system_errno(conn.handle().cast()) };
+        assert_eq!(errno, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bootstrap() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            template: Arc<Mutex<Vec<u8>>>,
+        }
+        impl Hooks for H {
+            fn bootstrap(&mut self, path: &str) -> Option<Vec<u8>> {
+                path.starts_with("tenant-")
+                    .then(|| self.template.lock().clone())
+            }
+        }
+
+        let template = Arc::new(Mutex::new(Vec::new()));
+        let vfs = MockVfs::new(Box::new(H { template: template.clone() }));
+        register_static(
+            CString::from(c"mock_bootstrap"),
+            vfs.clone(),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let open = |path| {
+            Connection::open_with_flags_and_vfs(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                "mock_bootstrap",
+            )
+        };
+
+        open("template.db")?.execute("create table t (val int)", [])?;
+        crate::backup::backup_consistent("mock_bootstrap", "template.db", |buf| {
+            template.lock().extend_from_slice(buf);
+            Ok(())
+        })?;
+
+        // new tenant databases start out with the template schema
+        let conn = open("tenant-a.db")?;
+        conn.execute("insert into t (val) values (1)", [])?;
+        // other databases start out empty
+        let conn = open("other.db")?;
+        let tables: i64 =
+            conn.query_row("select count(*) from sqlite_schema", [], |row| row.get(0))?;
+        assert_eq!(tables, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_journal() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            opened: Arc<Mutex<Vec<String>>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, path: &Option<&str>, _opts: &OpenOpts, _params: &UriParams<'_>) {
+                if let Some(path) = path {
+                    self.opened.lock().push(path.to_string());
+                }
+            }
+            fn journal_policy(&mut self, _db_path: &str) -> JournalPolicy {
+                JournalPolicy::Memory
+            }
+            fn in_memory(&mut self, path: &str) -> bool {
+                path == "mem.db"
+            }
+        }
+
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        register_static(
+            CString::from(c"mock_memory_journal"),
+            MockVfs::new(Box::new(H { opened: opened.clone() })),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let open = |path| {
+            Connection::open_with_flags_and_vfs(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                "mock_memory_journal",
+            )
+        };
+
+        for path in ["mem.db", "disk.db"] {
+            let mut conn = open(path)?;
+            conn.execute("create table t (val int)", [])?;
+            conn.execute("insert into t (val) values (1)", [])?;
+            let tx = conn.transaction()?;
+            tx.execute("update t set val = 2", [])?;
+            tx.execute("insert into t (val) values (3)", [])?;
+            tx.rollback()?;
+            let vals: i64 = conn.query_row("select sum(val) from t", [], |row| row.get(0))?;
+            assert_eq!(vals, 1);
+        }
+
+        // only the database held in memory keeps its journal from the Vfs
+        let opened = opened.lock();
+        assert!(!opened.iter().any(|path| path == "mem.db-journal"));
+        assert!(opened.iter().any(|path| path == "disk.db-journal"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn vfs_name() -> Result<(), Box<dyn std::error::Error>> {
+        struct H;
+        impl Hooks for H {
+            fn vfs_name(&mut self, registered: &str) -> Option<String> {
+                Some(format!("shim/{registered}"))
+            }
+        }
+
+        register_static(
+            CString::from(c"mock_vfsname"),
+            MockVfs::new(Box::new(H)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_vfsname",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+
+        let name: String = conn.query_row("pragma vfsname", [], |row| row.get(0))?;
+        assert_eq!(name, "shim/mock_vfsname");
+
+        let mut p_name: *mut c_char = null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle().cast(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_VFSNAME,
+                (&mut p_name as *mut *mut c_char).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        let name = unsafe { CStr::from_ptr(p_name) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ffi::sqlite3_free(p_name.cast()) };
+        assert_eq!(name, "shim/mock_vfsname");
+
+        Ok(())
+    }
+
+    #[test]
+    fn readonly_wal() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::from(c"mock_readonly_wal"),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts { readonly_wal: true, ..Default::default() },
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        // without shared memory, the writer needs exclusive locking mode
+        let writer = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_readonly_wal",
+        )?;
+        writer.pragma_update(None, "locking_mode", "exclusive")?;
+        let mode: String = writer.query_row("pragma journal_mode = wal", [], |row| row.get(0))?;
+        assert_eq!(mode, "wal");
+        writer.pragma_update(None, "wal_autocheckpoint", 0)?;
+        writer.execute("create table t (val int)", [])?;
+        writer.execute("insert into t (val) values (1), (2)", [])?;
+
+        // the commits are only in the WAL, which the reader rebuilds its
+        // wal-index from
+        let reader = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            "mock_readonly_wal",
+        )?;
+        let sum: i64 = reader.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(sum, 3);
+
+        writer.execute("insert into t (val) values (3)", [])?;
+        let sum: i64 = reader.query_row("select sum(val) from t", [], |row| row.get(0))?;
+        assert_eq!(sum, 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn temp_filename() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            temp_dbs: Arc<Mutex<Vec<Option<String>>>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, path: &Option<&str>, opts: &OpenOpts, _params: &UriParams<'_>) {
+                if opts.kind() == OpenKind::TempDb {
+                    self.temp_dbs.lock().push(path.map(|path| path.to_string()));
+                }
+            }
+        }
+
+        let temp_dbs = Arc::new(Mutex::new(Vec::new()));
+        register_static(
+            CString::from(c"mock_temp_filename"),
+            MockVfs::new(Box::new(H { temp_dbs: temp_dbs.clone() })),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_temp_filename",
+        )?;
+        // a temporary table which spills out of its tiny cache to a file
+        conn.execute_batch(
+            "pragma temp_store = file;
+            create temp table t (val blob);
+            pragma temp.cache_size = 2;
+            insert into t (val) values (zeroblob(100000));",
+        )?;
+
+        // files SQLite opens without a name are given one
+        let temp_dbs = temp_dbs.lock();
+        assert!(!temp_dbs.is_empty());
+        assert!(temp_dbs.iter().all(Option::is_some));
+
+        let mut p_name: *mut c_char = null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle().cast(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_TEMPFILENAME,
+                (&mut p_name as *mut *mut c_char).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        let name = unsafe { CStr::from_ptr(p_name) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ffi::sqlite3_free(p_name.cast()) };
+        assert!(name.starts_with("etilqs_"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_path() -> Result<(), Box<dyn std::error::Error>> {
+        register_static(
+            CString::from(c"mock_paths"),
+            MockVfs::new(Box::new(NoopHooks)),
+            RegisterOpts::default(),
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+
+        let conn = Connection::open_with_flags_and_vfs("tenant-a/main.db", flags, "mock_paths")?;
+        conn.execute("create table t (val int)", [])?;
+        assert_eq!(conn.path(), Some("tenant-a/main.db"));
+
+        // names which don't fit in mxPathname are rejected rather than truncated
+        let long = "a/".repeat(DEFAULT_MAX_PATH_LEN as usize);
+        let err = Connection::open_with_flags_and_vfs(long, flags, "mock_paths").unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::CannotOpen)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn simulated_system() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            slept: Arc<Mutex<Vec<Duration>>>,
+        }
+        impl Hooks for H {
+            fn sleep(&mut self, duration: Duration) -> Option<Duration> {
+                self.slept.lock().push(duration);
+                Some(duration)
+            }
+            fn current_time(&mut self) -> Option<i64> {
+                // 2000-01-01T00:00:00Z
+                Some(946_684_800_000)
+            }
+            fn randomness(&mut self, buf: &mut [u8]) -> Option<usize> {
+                buf.fill(7);
+                Some(buf.len())
+            }
+        }
+
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let vfs = MockVfs::new(Box::new(H { slept: slept.clone() }));
+        register_static(CString::from(c"mock_sim"), vfs, RegisterOpts::default())
+            .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_sim",
+        )?;
+        let now: String = conn.query_row("select datetime('now')", [], |row| row.get(0))?;
+        assert_eq!(now, "2000-01-01 00:00:00");
+
+        let p_vfs = unsafe { ffi::sqlite3_vfs_find(c"mock_sim".as_ptr()) };
+        let vfs = unsafe { p_vfs.as_ref() }.unwrap();
+        let mut buf = [0 as c_char; 4];
+        let n = unsafe { vfs.xRandomness.unwrap()(p_vfs, 4, buf.as_mut_ptr()) };
+        assert_eq!((n, buf), (4, [7; 4]));
+        let n = unsafe { vfs.xSleep.unwrap()(p_vfs, 1500) };
+        assert_eq!(n, 1500);
+        assert_eq!(*slept.lock(), [Duration::from_micros(1500)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_ops() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            ops: Arc<Mutex<Vec<String>>>,
+        }
+        impl Hooks for H {
+            fn file_control(
+                &mut self,
+                _handle: MockHandle,
+                op: FileControlOp<'_>,
+            ) -> VfsResult<()> {
+                let recorded = match op {
+                    FileControlOp::SizeHint(size) => format!("size_hint {size}"),
+                    FileControlOp::ChunkSize(size) => format!("chunk_size {size}"),
+                    FileControlOp::PersistWal(mut setting) => {
+                        let requested = setting.requested();
+                        setting.report(true);
+                        format!("persist_wal {requested:?}")
+                    }
+                    FileControlOp::Raw(op, _) => format!("raw {op}"),
+                    _ => return Err(VfsError::NotFound),
+                };
+                self.ops.lock().push(recorded);
+                Ok(())
+            }
+        }
+
+        let ops = Arc::new(Mutex::new(Vec::new()));
+        let vfs = MockVfs::new(Box::new(H { ops: ops.clone() }));
+        register_static(CString::from(c"mock_fcntl"), vfs, RegisterOpts::default())
+            .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_fcntl",
+        )?;
+        conn.execute("create table t (val blob)", [])?;
+        conn.execute("insert into t (val) values (zeroblob(20000))", [])?;
+        assert!(ops.lock().iter().any(|op| op.starts_with("size_hint ")));
+
+        let file_control = |op, arg: &mut c_int| unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle().cast(),
+                c"main".as_ptr(),
+                op,
+                (arg as *mut c_int).cast(),
+            )
+        };
+        ops.lock().clear();
+        assert_eq!(
+            file_control(vars::SQLITE_FCNTL_CHUNK_SIZE, &mut 65536),
+            vars::SQLITE_OK
+        );
+        let mut persist = -1;
+        assert_eq!(
+            file_control(vars::SQLITE_FCNTL_PERSIST_WAL, &mut persist),
+            vars::SQLITE_OK
+        );
+        assert_eq!(persist, 1);
+        assert_eq!(
+            file_control(vars::SQLITE_FCNTL_LOCK_TIMEOUT, &mut 0),
+            vars::SQLITE_OK
+        );
+        assert_eq!(
+            *ops.lock(),
+            [
+                "chunk_size 65536".to_string(),
+                "persist_wal None".to_string(),
+                format!("raw {}", vars::SQLITE_FCNTL_LOCK_TIMEOUT),
+            ]
+        );
+
+        Ok(())
+    }
 }